@@ -27,6 +27,10 @@ impl FPUState {
 	pub fn save(&self) {
 		// TODO
 	}
+
+	pub fn save_switched(&self) {
+		// TODO
+	}
 }
 
 pub fn generate_random_number() -> Option<u32> {
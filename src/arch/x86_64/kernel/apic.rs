@@ -18,16 +18,18 @@ use arch::x86_64::kernel::smp_boot_code::SMP_BOOT_CODE;
 use arch::x86_64::kernel::{BOOT_INFO, BootInfo};
 use arch::x86_64::kernel::copy_safe::*;
 use arch::x86_64::mm::paging;
-use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags, print_page_table_entry, LargePageSize};
+use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags, LargePageSize};
+use arch::x86_64::mm::mpk;
 use arch::x86_64::mm::virtualmem;
 use config::*;
-use core::sync::atomic::spin_loop_hint;
+use core::sync::atomic::{spin_loop_hint, AtomicUsize, Ordering};
 use core::{cmp, fmt, intrinsics, mem, u32};
 use core::intrinsics::volatile_load;
 use core::ptr::copy_nonoverlapping;
 use environment;
 use mm;
 use scheduler;
+use synch::spinlock::Spinlock;
 use x86::controlregs::*;
 use x86::msr::*;
 
@@ -54,6 +56,7 @@ const IOAPIC_REG_VER: u32 = 0x0001;
 const IOAPIC_REG_TABLE: u32 = 0x0010;
 
 const TLB_FLUSH_INTERRUPT_NUMBER: u8 = 112;
+const PKRU_AUDIT_INTERRUPT_NUMBER: u8 = 114;
 const WAKEUP_INTERRUPT_NUMBER: u8 = 121;
 pub const TIMER_INTERRUPT_NUMBER: u8 = 123;
 const ERROR_INTERRUPT_NUMBER: u8 = 126;
@@ -71,6 +74,24 @@ const SMP_BOOT_CODE_OFFSET_ENTRY: usize = 0x08;
 
 const X2APIC_ENABLE: u64 = 1 << 10;
 
+/// Maximum number of cores, whose PKRU audit result is recorded.
+const MAX_AUDITED_CORES: usize = 64;
+/// Serializes PKRU audits, because all cores report to the same results.
+safe_global_var!(static PKRU_AUDIT_LOCK: Spinlock<()> = Spinlock::new(()));
+/// Number of cores, which have reported the result of the current PKRU audit
+safe_global_var!(static PKRU_AUDIT_ACKS: AtomicUsize = AtomicUsize::new(0));
+/// Result of the current PKRU audit per core: 0 if the PKRU is valid, otherwise the open protection key + 1
+safe_global_var!(static mut PKRU_AUDIT_RESULTS: [u8; MAX_AUDITED_CORES] = [0; MAX_AUDITED_CORES]);
+/// Cores (one bit per core ID), which also have to flush their global TLB entries at the next TLB Flush Interrupt.
+/// Cores with an ID beyond the width of the bitmap always flush their global entries.
+safe_global_var!(static GLOBAL_FLUSH_PENDING: AtomicUsize = AtomicUsize::new(0));
+/// Maximum number of cores, which acknowledge TLB shootdowns (see `ipi_tlb_flush_sync`).
+const MAX_FLUSH_ACK_CORES: usize = 64;
+/// Number of the last TLB shootdown, which has been requested by `ipi_tlb_flush_sync`
+safe_global_var!(static TLB_FLUSH_REQUEST: AtomicUsize = AtomicUsize::new(0));
+/// Number of the last requested TLB shootdown per core, which the core has completed
+safe_global_var!(static mut TLB_FLUSH_DONE: [usize; MAX_FLUSH_ACK_CORES] = [0; MAX_FLUSH_ACK_CORES]);
+
 safe_global_var!(static mut LOCAL_APIC_ADDRESS: usize = 0);
 safe_global_var!(static mut IOAPIC_ADDRESS: usize = 0);
 
@@ -138,14 +159,68 @@ impl fmt::Display for IoApicRecord {
 }
 
 extern "x86-interrupt" fn tlb_flush_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	debug!("Received TLB Flush Interrupt");
-	unsafe {
-		cr3_write(cr3());
+
+	let core_id = core_id();
+	// The flush covers every shootdown, which has been requested so far.
+	let request = TLB_FLUSH_REQUEST.load(Ordering::SeqCst);
+	let global = core_id >= mem::size_of::<usize>() * 8
+		|| GLOBAL_FLUSH_PENDING.fetch_and(!(1 << core_id), Ordering::SeqCst) & (1 << core_id) != 0;
+	if global {
+		paging::flush_tlb_with_global();
+	} else {
+		unsafe {
+			cr3_write(cr3());
+		}
 	}
+	acknowledge_tlb_flush(core_id, request);
 	eoi();
 }
 
+/// Records that the core `core_id` has completed all TLB shootdowns up to `request`.
+fn acknowledge_tlb_flush(core_id: usize, request: usize) {
+	if core_id >= MAX_FLUSH_ACK_CORES {
+		return;
+	}
+
+	let done = unsafe { &mut TLB_FLUSH_DONE[core_id] as *mut usize };
+	loop {
+		let current = unsafe { intrinsics::atomic_load(done) };
+		if current >= request || unsafe { intrinsics::atomic_cxchg(done, current, request) }.1 {
+			break;
+		}
+	}
+}
+
+extern "x86-interrupt" fn pkru_audit_handler(stack_frame: &mut irq::ExceptionStackFrame) {
+	// The PKRU of the interrupted context is read before anything else changes it.
+	let pkru = mpk::mpk_get_pkru();
+	let _gs = GsEntryGuard::new();
+	// The interrupted context belongs to the user domain, if it runs on the user stack of its task.
+	let user = scheduler::current_task_ref()
+		.map_or(false, |task| task.stacks.is_user_stack(stack_frame.stack_pointer as usize));
+	let result = mpk::check_pkru(pkru, user);
+	let core_id = core_id();
+	if core_id < MAX_AUDITED_CORES {
+		unsafe {
+			PKRU_AUDIT_RESULTS[core_id] = result.map_or_else(|key| key + 1, |_| 0);
+		}
+	}
+	PKRU_AUDIT_ACKS.fetch_add(1, Ordering::SeqCst);
+	eoi();
+
+	if result.is_err() && PKRU_AUDIT_HALT {
+		error!("Halting core {} after a failed PKRU audit", core_id);
+		irq::disable();
+		loop {
+			processor::halt();
+		}
+	}
+}
+
 extern "x86-interrupt" fn error_interrupt_handler(stack_frame: &mut irq::ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	error!("APIC LVT Error Interrupt");
 	error!("ESR: {:#X}", local_apic_read(IA32_X2APIC_ESR));
 	error!("{:#?}", stack_frame);
@@ -154,11 +229,13 @@ extern "x86-interrupt" fn error_interrupt_handler(stack_frame: &mut irq::Excepti
 }
 
 extern "x86-interrupt" fn spurious_interrupt_handler(stack_frame: &mut irq::ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	error!("Spurious Interrupt: {:#?}", stack_frame);
 	scheduler::abort();
 }
 
 extern "x86-interrupt" fn wakeup_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	debug!("Received Wakeup Interrupt");
 	eoi();
 }
@@ -303,6 +380,7 @@ pub fn init() {
 
 	// Set gates to ISRs for the APIC interrupts we are going to enable.
 	idt::set_gate(TLB_FLUSH_INTERRUPT_NUMBER, tlb_flush_handler as usize, 0);
+	idt::set_gate(PKRU_AUDIT_INTERRUPT_NUMBER, pkru_audit_handler as usize, 0);
 	idt::set_gate(ERROR_INTERRUPT_NUMBER, error_interrupt_handler as usize, 0);
 	idt::set_gate(
 		SPURIOUS_INTERRUPT_NUMBER,
@@ -489,7 +567,10 @@ pub fn init_next_processor_variables(core_id: usize) {
 	let stack = mm::allocate(KERNEL_STACK_SIZE, false);
 	let mut boxed_percore = PerCoreVariables::new(core_id);
 	let percore_ptr = &mut boxed_percore as *mut _;
-	let alloc_percore = mm::allocate(mem::size_of::<PerCoreVariables>(), true) as *mut PerCoreVariables;
+	debug_assert!(mem::size_of::<PerCoreVariables>() <= BasePageSize::SIZE);
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable();
+	let alloc_percore = mm::allocate_page(mm::SAFE_MEM_REGION, flags).0 as *mut PerCoreVariables;
 	list_add(alloc_percore as usize);
 	list_add(percore_ptr as usize);
 	copy_from_safe(percore_ptr, 1);
@@ -514,6 +595,14 @@ pub fn init_next_processor_variables(core_id: usize) {
 /// This is partly confirmed by https://wiki.osdev.org/Symmetric_Multiprocessing
 #[cfg(not(test))]
 pub fn boot_application_processors() {
+	// The Local APIC IDs are stored contiguously, starting with the boot processor.
+	let apic_ids = unsafe { CPU_LOCAL_APIC_IDS };
+	let possible_cpus = apic_ids.iter().take_while(|apic_id| **apic_id != 255).count();
+	if possible_cpus <= 1 {
+		info!("No application processors have been detected");
+		return;
+	}
+
 	// We shouldn't have any problems fitting the boot code into a single page, but let's better be sure.
 	assert!(
 		SMP_BOOT_CODE.len() < BasePageSize::SIZE,
@@ -527,7 +616,7 @@ pub fn boot_application_processors() {
 		SMP_BOOT_CODE_ADDRESS
 	);
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(mm::SAFE_MEM_REGION);
+	flags.normal().writable().allow_wx().pkey(mm::SAFE_MEM_REGION);
 	paging::map::<BasePageSize>(SMP_BOOT_CODE_ADDRESS, SMP_BOOT_CODE_ADDRESS, 1, flags);
 	unsafe {
         isolate_function_strong!(copy_nonoverlapping(
@@ -552,11 +641,10 @@ pub fn boot_application_processors() {
 	}
 
 	// Now wake up each application processor.
-	let apic_ids = unsafe { CPU_LOCAL_APIC_IDS };
 	let core_id = core_id();
 
-	for core_id_to_boot in 0..apic_ids.len() {
-		if core_id_to_boot != core_id && core_id_to_boot != 255 {
+	for core_id_to_boot in 0..possible_cpus {
+		if core_id_to_boot != core_id {
 			let apic_id = apic_ids[core_id_to_boot];
 			let destination = u64::from(apic_id) << 32;
 
@@ -596,8 +684,20 @@ pub fn boot_application_processors() {
 
 			// Wait until the application processor has finished initializing.
 			// It will indicate this by counting up cpu_online.
+			let mut waited = 0;
 			while current_processor_count == arch::get_processor_count() {
+				if waited == AP_BOOT_TIMEOUT {
+					// The next processor would take over the Core ID of this one.
+					// Hence, a late start of this processor can't be handled.
+					warn!(
+						"CPU {} hasn't responded within {} ms, stop booting application processors",
+						core_id_to_boot, AP_BOOT_TIMEOUT
+					);
+					return;
+				}
+
 				processor::udelay(1000);
+				waited += 1;
 			}
 		}
 	}
@@ -629,6 +729,99 @@ pub fn ipi_tlb_flush() {
 	}
 }
 
+/// Like `ipi_tlb_flush`, but the other cores also flush their global TLB entries,
+/// which survive the reload of CR3.
+pub fn ipi_tlb_flush_global() {
+	let core_id = core_id();
+	let others = if core_id < mem::size_of::<usize>() * 8 {
+		!(1 << core_id)
+	} else {
+		!0
+	};
+	GLOBAL_FLUSH_PENDING.fetch_or(others, Ordering::SeqCst);
+
+	ipi_tlb_flush();
+}
+
+/// Like `ipi_tlb_flush_global` (if `global` is set) or `ipi_tlb_flush`, but returns only after all other
+/// cores have flushed their TLBs. Hence, page tables and frames, which they may have cached, can be reused.
+///
+/// While waiting, the core serves the shootdowns of other cores itself, so two cores, which wait for
+/// each other with disabled interrupts, don't deadlock.
+pub fn ipi_tlb_flush_sync(global: bool) {
+	let processor_count = arch::get_processor_count();
+	if processor_count <= 1 {
+		return;
+	}
+
+	let request = TLB_FLUSH_REQUEST.fetch_add(1, Ordering::SeqCst) + 1;
+	if global {
+		ipi_tlb_flush_global();
+	} else {
+		ipi_tlb_flush();
+	}
+
+	let core_id = core_id();
+	for core in (0..cmp::min(processor_count, MAX_FLUSH_ACK_CORES)).filter(|core| *core != core_id) {
+		while unsafe { intrinsics::atomic_load(&TLB_FLUSH_DONE[core]) } < request {
+			let pending = TLB_FLUSH_REQUEST.load(Ordering::SeqCst);
+			if core_id < MAX_FLUSH_ACK_CORES
+				&& unsafe { intrinsics::atomic_load(&TLB_FLUSH_DONE[core_id]) } < pending
+			{
+				paging::flush_tlb_with_global();
+				acknowledge_tlb_flush(core_id, pending);
+			}
+			spin_loop_hint();
+		}
+	}
+}
+
+/// Lets all other cores verify the PKRU of their interrupted context (see `mpk::verify_pkru`)
+/// and waits for their results. Returns the ID of each offending core together with the open key.
+///
+/// Must be called with enabled interrupts, otherwise two concurrent audits would wait for each other.
+pub fn ipi_pkru_audit() -> Vec<(usize, u8)> {
+	let processor_count = arch::get_processor_count();
+	let mut violations = Vec::new();
+	if processor_count <= 1 {
+		return violations;
+	}
+
+	let _lock = PKRU_AUDIT_LOCK.lock();
+	let apic_ids = unsafe { CPU_LOCAL_APIC_IDS };
+	let core_id = core_id();
+
+	PKRU_AUDIT_ACKS.store(0, Ordering::SeqCst);
+
+	let mut targets = 0;
+	for core_id_to_interrupt in 0..processor_count {
+		if core_id_to_interrupt != core_id {
+			let local_apic_id = apic_ids[core_id_to_interrupt];
+			let destination = u64::from(local_apic_id) << 32;
+			local_apic_write(
+				IA32_X2APIC_ICR,
+				destination
+					| APIC_ICR_LEVEL_ASSERT | APIC_ICR_DELIVERY_MODE_FIXED
+					| u64::from(PKRU_AUDIT_INTERRUPT_NUMBER),
+			);
+			targets += 1;
+		}
+	}
+
+	while PKRU_AUDIT_ACKS.load(Ordering::SeqCst) < targets {
+		spin_loop_hint();
+	}
+
+	for core in 0..processor_count.min(MAX_AUDITED_CORES) {
+		let result = unsafe { PKRU_AUDIT_RESULTS[core] };
+		if core != core_id && result != 0 {
+			violations.push((core, result - 1));
+		}
+	}
+
+	violations
+}
+
 /// Send an inter-processor interrupt to wake up a CPU Core that is in a HALT state.
 pub fn wakeup_core(core_id_to_wakeup: usize) {
 	if core_id_to_wakeup != core_id() {
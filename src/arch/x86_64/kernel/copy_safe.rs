@@ -1,17 +1,29 @@
 #![allow(dead_code)]
 use core::ptr::{write_bytes, copy_nonoverlapping};
 use core::mem::size_of;
+use core::sync::atomic::{AtomicUsize, Ordering};
 use x86::msr::*;
 use mm;
+use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::processor;
 
 safe_global_var!(static mut LIST: [usize;100] = [0;100]);
 safe_global_var!(static SIZE: usize = 0x1000);
 
+/// Allocates the staging buffer of the current core.
+///
+/// Every core owns a separate buffer, which is referenced by its PerCoreVariables
+/// and by its IA32_KERNEL_GSBASE. Therefore, concurrent kernel entries on
+/// different cores never stage their data in the same buffer.
 pub fn unsafe_storage_init() {
+        if is_unsafe_storage_init() {
+                return;
+        }
+
         let unsafe_storage = mm::unsafe_allocate(SIZE, true);
         unsafe {
-                info!("Init unsafe_storage: {:#X}", unsafe_storage);
+                info!("Init unsafe_storage of core {}: {:#X}", core_id(), unsafe_storage);
+                PERCORE.unsafe_storage.set(unsafe_storage);
                 wrmsr(IA32_KERNEL_GSBASE, unsafe_storage as u64);
                 list_add(processor::readgs());
         }
@@ -37,18 +49,20 @@ pub fn get_unsafe_storage() -> usize {
 }
 
 pub fn list_add(addr: usize) {
-        safe_global_var!(static mut IDX: usize = 0);
+        // all cores register their addresses, so we have to reserve the slots atomically
+        safe_global_var!(static IDX: AtomicUsize = AtomicUsize::new(0));
         unsafe {
                 if LIST.iter().any(|v| v == &addr) {
                         return;
                 }
-                if IDX >= 100 {
+                let idx = IDX.fetch_add(1, Ordering::SeqCst);
+                if idx >= 100 {
+                        IDX.store(100, Ordering::SeqCst);
                         error!("LIST is full!!");
                         error!(" ");
                         return;
                 }
-                LIST[IDX] = addr;
-                IDX+=1;
+                LIST[idx] = addr;
         };
 }
 
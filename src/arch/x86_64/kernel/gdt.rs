@@ -10,9 +10,9 @@
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::{BOOT_INFO, BootInfo};
 use arch::x86_64::kernel::copy_safe::*;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
 use config::*;
 use core::{intrinsics, mem};
-use scheduler::task::TaskStatus;
 use x86::bits64::segmentation::*;
 use x86::bits64::task::*;
 use x86::dtables::{DescriptorTablePointer, lgdt};
@@ -29,10 +29,16 @@ pub const GDT_FIRST_TSS: u16 = 3;
 /// We dynamically allocate a GDT large enough to hold the maximum number of entries.
 const GDT_ENTRIES: usize = 8192;
 
-/// We use IST1 through IST4.
-/// Each critical exception (NMI, Double Fault, Machine Check) gets a dedicated one while IST1 is shared for all other
-/// interrupts. See also irq.rs.
-const IST_ENTRIES: usize = 4;
+/// The TSS provides IST1 through IST7.
+const MAX_IST_ENTRIES: usize = 7;
+
+/// We use IST1 through IST_ENTRIES (see config.rs).
+/// Each critical exception (NMI, Double Fault, Machine Check) gets a dedicated one if enough entries are configured
+/// while IST1 is shared for all other interrupts. See also irq.rs.
+/// Fails to compile if more entries are configured than the architecture supports
+/// or if IST1 is missing, which every task switches to on an exception.
+#[allow(dead_code)]
+const IST_ENTRIES_ARE_VALID: [(); 0] = [(); (IST_ENTRIES < 1 || IST_ENTRIES > MAX_IST_ENTRIES) as usize];
 
 unsafe_global_var!(static mut GDT: *mut Gdt = 0 as *mut Gdt);
 safe_global_var!(static mut GDTR: DescriptorTablePointer<Descriptor> = DescriptorTablePointer {
@@ -48,7 +54,10 @@ pub fn init() {
     let gdt_ref;
 	unsafe {
 		// Dynamically allocate memory for the GDT.
-		GDT = ::mm::allocate(mem::size_of::<Gdt>(), true) as *mut Gdt;
+		debug_assert!(mem::size_of::<Gdt>() <= BasePageSize::SIZE);
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		GDT = ::mm::allocate_page(::mm::SAFE_MEM_REGION, flags).0 as *mut Gdt;
 
         // Get gdt reference
         isolation_start!();
@@ -83,6 +92,9 @@ pub fn init() {
 }
 
 pub fn add_current_core() {
+	// Each core stages its copies in its own buffer.
+	unsafe_storage_init();
+
 	unsafe {
 		// Load the GDT for the current core.
 		/*
@@ -103,9 +115,9 @@ pub fn add_current_core() {
 		load_ss(SegmentSelector::new(GDT_KERNEL_DATA, Ring::Ring0));
 	}
 
-	// Dynamically allocate memory for a Task-State Segment (TSS) for this core.
+	// The TSS is staged on the stack and copied to its own page afterwards,
+	// which persists as long as the core and is referenced by the GDT.
 	let mut boxed_tss = TaskStateSegment::new();
-	//let mut boxed_tss = TaskStateSegment::new(); /* FIXME maybe some memory leak? */
 
 	// Every task later gets its own stack, so this boot stack is only used by the Idle task on each core.
 	// When switching to another task on this core, this entry is replaced.
@@ -126,13 +138,28 @@ pub fn add_current_core() {
 		let ist = ::mm::user_allocate(KERNEL_STACK_SIZE, true);
 		boxed_tss.ist[i] = (ist + KERNEL_STACK_SIZE - 0x10) as u64;
 	}
-
-	// Add this TSS to the GDT.
+	debug_assert!(
+		boxed_tss.ist[IST_ENTRIES..].iter().all(|ist| *ist == 0),
+		"Unconfigured IST entries must not reference a stack"
+	);
+
+	// Copy the TSS to its persistent page. The stack copy is dead after this function returns.
+	let alloc_tss = {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		mm::allocate_page(0, flags).0 as *mut TaskStateSegment
+	};
+	let tss = &boxed_tss as *const TaskStateSegment;
+	list_add(alloc_tss as usize);
+	list_add(tss as usize);
+	copy_from_safe(tss, 1);
+	copy_to_safe(alloc_tss, 1);
+	clear_unsafe_storage();
+
+	// Add the persistent TSS to the GDT.
 	let idx = GDT_FIRST_TSS as usize + (core_id() as usize) * 2;
-	//let tss = Box::into_raw(boxed_tss);
-	let tss = &mut boxed_tss as *mut _;
+	let base = alloc_tss as u64;
 	{
-		let base = tss as u64;
 		let tss_descriptor: Descriptor64 =
 			<DescriptorBuilder as GateDescriptorBuilder<u64>>::tss_descriptor(
 				base,
@@ -166,29 +193,63 @@ pub fn add_current_core() {
 	let sel = SegmentSelector::new(idx as u16, Ring::Ring0);
 	unsafe {
 		load_tr(sel);
+	}
 
-		let alloc_tss = mm::user_allocate(mem::size_of::<TaskStateSegment>(), true) as *mut TaskStateSegment;
-		list_add(alloc_tss as usize);
-		list_add(tss as usize);
-		copy_from_safe(tss, 1);
-		copy_to_safe(alloc_tss, 1);
-		clear_unsafe_storage();
-		// Store it in the PerCoreVariables structure for further manipulation.
+	// The task register has to reference the persistent TSS.
+	let loaded_idx = unsafe { tr() }.index() as usize;
+	let loaded_base = unsafe {
+		isolation_start!();
+		let gdt_ref = &(*GDT);
+		isolation_end!();
+		tss_base(&gdt_ref.entries[loaded_idx..loaded_idx + 2])
+	};
+	assert!(
+		loaded_idx == idx && loaded_base == base,
+		"TR references the TSS at {:#X} instead of {:#X}",
+		loaded_base,
+		base
+	);
+
+	// Store it in the PerCoreVariables structure for further manipulation.
+	unsafe {
 		PERCORE.tss.safe_set(alloc_tss);
 	}
 }
 
+/// Returns the base address of the 64-bit TSS descriptor, which occupies the two entries of `descriptor`.
+fn tss_base(descriptor: &[Descriptor]) -> u64 {
+	let low = descriptor[0].as_u64();
+	let high = descriptor[1].as_u64();
+
+	((low >> 16) & 0xFF_FFFF) | (((low >> 56) & 0xFF) << 24) | ((high & 0xFFFF_FFFF) << 32)
+}
+
 #[no_mangle]
 pub extern "C" fn set_current_kernel_stack() {
 	let current_task_borrowed = core_scheduler().current_task.borrow();
-	let stack_size = if current_task_borrowed.status == TaskStatus::TaskIdle {
-		KERNEL_STACK_SIZE
-	} else {
-		DEFAULT_STACK_SIZE
-	};
+	// The idle task runs on the boot stack, which has KERNEL_STACK_SIZE bytes.
+	let stack_size = current_task_borrowed.stacks.stack_size();
 
 	let tss = unsafe { &mut (*PERCORE.tss.safe_get()) };
 
 	tss.rsp[0] = (current_task_borrowed.stacks.stack + stack_size - 0x10) as u64;
 	tss.ist[0] = (current_task_borrowed.stacks.ist0 + KERNEL_STACK_SIZE - 0x10) as u64;
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn tss_base_is_decoded_from_the_descriptor() {
+		let base: u64 = 0xFFFF_8012_3456_7000;
+		let descriptor: Descriptor64 =
+			<DescriptorBuilder as GateDescriptorBuilder<u64>>::tss_descriptor(base, base + 0x67, true)
+				.present()
+				.dpl(Ring::Ring0)
+				.finish();
+		let entries = unsafe { mem::transmute::<Descriptor64, [Descriptor; 2]>(descriptor) };
+
+		assert_eq!(tss_base(&entries), base);
+	}
+}
@@ -9,6 +9,7 @@
 #![allow(dead_code)]
 
 use arch::x86_64::kernel::gdt;
+use config::IST_ENTRIES;
 use core::sync::atomic::{AtomicBool, Ordering};
 use x86::bits64::paging::VAddr;
 use x86::dtables::{DescriptorTablePointer, lidt};
@@ -128,6 +129,13 @@ pub fn install() {
 /// * `ist_index` - Index of the Interrupt Stack Table (IST) to switch to.
 ///                 A zero value means that the stack won't be switched, a value of 1 refers to the first IST entry, etc.
 pub fn set_gate(index: u8, handler: usize, ist_index: u8) {
+	assert!(
+		ist_index as usize <= IST_ENTRIES,
+		"IST{} of interrupt {} isn't allocated",
+		ist_index,
+		index
+	);
+
 	let sel = SegmentSelector::new(gdt::GDT_KERNEL_CODE, Ring::Ring0);
 	let entry = IdtEntry::new(
 		VAddr::from_usize(handler),
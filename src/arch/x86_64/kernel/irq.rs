@@ -11,6 +11,7 @@ use arch::x86_64::kernel::idt;
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::processor;
 use arch::x86_64::mm::paging;
+use config::IST_ENTRIES;
 use core::fmt;
 use scheduler;
 use x86::bits64::rflags;
@@ -106,24 +107,34 @@ pub fn nested_enable(was_enabled: bool) {
 	}
 }
 
+/// Returns `ist_index` if this IST is allocated for each core (see `IST_ENTRIES`),
+/// otherwise 0 to handle the exception on the current stack.
+fn ist_index(ist_index: u8) -> u8 {
+	if ist_index as usize <= IST_ENTRIES {
+		ist_index
+	} else {
+		0
+	}
+}
+
 pub fn install() {
 	// Set gates to the Interrupt Service Routines (ISRs) for all 32 CPU exceptions.
-	// All of them use a dedicated stack per task (IST1) to prevent clobbering the current task stack.
-	// Some critical exceptions also get their own stacks to always execute on a known good stack:
-	//   - Non-Maskable Interrupt Exception (IST2)
-	//   - Double Fault Exception (IST3)
-	//   - Machine Check Exception (IST4)
+	// Some critical exceptions get their own stacks to always execute on a known good stack:
+	//   - Non-Maskable Interrupt Exception (IST1, a dedicated stack per task)
+	//   - Double Fault Exception (IST2)
+	//   - Machine Check Exception (IST3)
+	// All other exceptions and, if fewer ISTs are configured, these ones are handled on the current stack.
 	//
 	// Refer to Intel Vol. 3A, 6.14.5 Interrupt Stack Table.
 	idt::set_gate(0, divide_error_exception as usize, 0);
 	idt::set_gate(1, debug_exception as usize, 0);
-	idt::set_gate(2, nmi_exception as usize, 1);
+	idt::set_gate(2, nmi_exception as usize, ist_index(1));
 	idt::set_gate(3, breakpoint_exception as usize, 0);
 	idt::set_gate(4, overflow_exception as usize, 0);
 	idt::set_gate(5, bound_range_exceeded_exception as usize, 0);
 	idt::set_gate(6, invalid_opcode_exception as usize, 0);
 	idt::set_gate(7, device_not_available_exception as usize, 0);
-	idt::set_gate(8, double_fault_exception as usize, 2);
+	idt::set_gate(8, double_fault_exception as usize, ist_index(2));
 	idt::set_gate(9, coprocessor_segment_overrun_exception as usize, 0);
 	idt::set_gate(10, invalid_tss_exception as usize, 0);
 	idt::set_gate(11, segment_not_present_exception as usize, 0);
@@ -148,15 +159,15 @@ pub fn install() {
         /* For debugging */
         {
             info!("handler old virt: {:#X}, new virt: {:#X}, phy:{:#X}, flags:{:#X}", curr_page_fault_handler, remapped_page_fault_handler, physical_addr, flags.bits());
-            print_page_table_entry::<LargePageSize>(curr_page_fault_handler);
-            print_page_table_entry::<BasePageSize>(remapped_page_fault_handler);
+            print_entry(curr_page_fault_handler);
+            print_entry(remapped_page_fault_handler);
         }
 */
 	idt::set_gate(14, paging::page_fault_handler as usize, 0);
         idt::set_gate(15, reserved_exception as usize, 0);
 	idt::set_gate(16, floating_point_exception as usize, 0);
 	idt::set_gate(17, alignment_check_exception as usize, 0);
-	idt::set_gate(18, machine_check_exception as usize, 3);
+	idt::set_gate(18, machine_check_exception as usize, ist_index(3));
 	idt::set_gate(19, simd_floating_point_exception as usize, 0);
 	idt::set_gate(20, virtualization_exception as usize, 0);
 	idt::set_gate(21, reserved_exception as usize, 0);
@@ -216,6 +227,7 @@ pub extern "C" fn irq_install_handler(irq_number: u32, handler: usize) {
 }
 
 fn unhandled_interrupt(irq_number: u8) {
+	let _gs = GsEntryGuard::new();
 	warn!("Receive unhandled interrupt {}", irq_number);
 	apic::eoi();
 }
@@ -349,6 +361,7 @@ extern "x86-interrupt" fn unhandled_interrupt31(_stack_frame: &mut ExceptionStac
 }
 
 extern "x86-interrupt" fn unknown_interrupt(_stack_frame: &mut ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	info!("Receive unknown interrupt");
 	apic::eoi();
 }
@@ -364,6 +377,7 @@ extern "x86-interrupt" fn debug_exception(stack_frame: &mut ExceptionStackFrame)
 }
 
 extern "x86-interrupt" fn nmi_exception(stack_frame: &mut ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	error!("Non-Maskable Interrupt (NMI) Exception: {:#?}", stack_frame);
 	scheduler::abort();
 }
@@ -389,6 +403,7 @@ extern "x86-interrupt" fn invalid_opcode_exception(stack_frame: &mut ExceptionSt
 }
 
 extern "x86-interrupt" fn device_not_available_exception(_stack_frame: &mut ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	// We set the CR0_TASK_SWITCHED flag every time we switch to a task.
 	// This causes the "Device Not Available" Exception (int #7) to be thrown as soon as we use the FPU for the first time.
 
@@ -447,6 +462,7 @@ extern "x86-interrupt" fn general_protection_exception(
 	stack_frame: &mut ExceptionStackFrame,
 	error_code: u64,
 ) {
+	let _gs = GsEntryGuard::new();
 	error!(
 		"General Protection (#GP) Exception: {:#?}, error {:#X}",
 		stack_frame, error_code
@@ -488,3 +504,19 @@ extern "x86-interrupt" fn reserved_exception(stack_frame: &mut ExceptionStackFra
 	error!("Reserved Exception: {:#?}", stack_frame);
 	scheduler::abort();
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn ist_indices_are_allocated() {
+		for index in 0..8 {
+			assert!(ist_index(index) as usize <= IST_ENTRIES);
+		}
+
+		assert_eq!(ist_index(0), 0);
+		assert_eq!(ist_index(IST_ENTRIES as u8), IST_ENTRIES as u8);
+		assert_eq!(ist_index(IST_ENTRIES as u8 + 1), 0);
+	}
+}
@@ -19,6 +19,7 @@ pub mod pit;
 pub mod processor;
 pub mod scheduler;
 pub mod serial;
+pub mod signal;
 pub mod copy_safe;
 #[cfg(not(test))]
 mod smp_boot_code;
@@ -413,7 +414,6 @@ pub fn boot_processor_init() {
 	::mm::init();
 	::mm::print_information();
 	environment::init();
-	copy_safe::unsafe_storage_init();
 	gdt::init();
 	gdt::add_current_core();
 	idt::install();
@@ -450,7 +450,6 @@ pub fn boot_application_processors() {
 pub fn application_processor_init() {
 	percore::init();
 	processor::configure();
-	copy_safe::unsafe_storage_init();
 	gdt::add_current_core();
 	idt::install();
 	apic::init_x2apic();
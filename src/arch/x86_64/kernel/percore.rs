@@ -23,6 +23,10 @@ pub struct PerCoreVariables {
 	scheduler: PerCoreVariable<*mut PerCoreScheduler>,
 	/// Task State Segment (TSS) allocated for this CPU Core.
 	pub tss: PerCoreVariable<*mut TaskStateSegment>,
+	/// Staging buffer of copy_safe allocated for this CPU Core.
+	pub unsafe_storage: PerCoreVariable<usize>,
+	/// Address of this structure, used to check if GS points to it.
+	this: PerCoreVariable<usize>,
 }
 
 impl PerCoreVariables {
@@ -31,6 +35,8 @@ impl PerCoreVariables {
 			core_id: PerCoreVariable::new(core_id),
 			scheduler: PerCoreVariable::new(ptr::null_mut() as *mut PerCoreScheduler),
 			tss: PerCoreVariable::new(ptr::null_mut() as *mut TaskStateSegment),
+			unsafe_storage: PerCoreVariable::new(0),
+			this: PerCoreVariable::new(0),
 		}
 	}
 }
@@ -206,6 +212,18 @@ pub fn core_scheduler() -> &'static mut PerCoreScheduler {
 	}
 }
 
+/// Returns the scheduler of the current core or `None` if it hasn't been installed yet.
+#[inline]
+pub fn try_core_scheduler() -> Option<&'static mut PerCoreScheduler> {
+	let scheduler = if is_unsafe_storage_init() {
+		unsafe { PERCORE.scheduler.safe_get() }
+	} else {
+		unsafe { PERCORE.scheduler.get() }
+	};
+
+	unsafe { scheduler.as_mut() }
+}
+
 #[no_mangle]
 #[inline]
 pub fn safe_core_scheduler() -> &'static mut PerCoreScheduler {
@@ -255,10 +273,63 @@ pub fn init() {
 			// Store the address to the PerCoreVariables structure allocated for this core in GS.
 			address = intrinsics::volatile_load(&(*BOOT_INFO).current_percore_address);
 		}
-		if address == 0 {
-			wrmsr(IA32_GS_BASE, &PERCORE as *const _ as u64);
+		let address = if address == 0 {
+			&PERCORE as *const _ as usize
 		} else {
-			wrmsr(IA32_GS_BASE, address as u64);
+			address
+		};
+		wrmsr(IA32_GS_BASE, address as u64);
+
+		if is_unsafe_storage_init() {
+			PERCORE.this.safe_set(address);
+		} else {
+			PERCORE.this.set(address);
+		}
+	}
+}
+
+/// Restores the kernel GS base for an interrupt or exception handler.
+///
+/// The kernel temporarily swaps GS to the copy_safe staging buffer (`swapgs; ...; swapgs`).
+/// An interrupt arriving inside such a window would find the staging buffer in GS and the
+/// PerCoreVariables in IA32_KERNEL_GSBASE. The guard detects this case from the MSR, because
+/// a GS-relative access would go through a base, which isn't trustworthy at this point.
+/// The inactive base holds the PerCoreVariables if the `this` field behind it points to itself.
+/// The guard issues exactly one `swapgs` on entry and the matching one when it is dropped.
+pub struct GsEntryGuard {
+	swapped: bool,
+}
+
+impl GsEntryGuard {
+	#[inline]
+	pub fn new() -> Self {
+		// Without a staging buffer, GS is never swapped.
+		if !is_unsafe_storage_init() {
+			return Self { swapped: false };
+		}
+
+		let swapped = unsafe {
+			let inactive = rdmsr(IA32_KERNEL_GSBASE) as usize;
+			inactive != 0
+				&& ptr::read_volatile(&(*(inactive as *const PerCoreVariables)).this.data) == inactive
+		};
+		if swapped {
+			unsafe {
+				asm!("swapgs" :::: "volatile");
+			}
+		}
+
+		Self { swapped: swapped }
+	}
+}
+
+impl Drop for GsEntryGuard {
+	#[inline]
+	fn drop(&mut self) {
+		if self.swapped {
+			unsafe {
+				asm!("swapgs" :::: "volatile");
+			}
 		}
 	}
 }
@@ -15,6 +15,7 @@ use arch::x86_64::kernel::pic;
 use arch::x86_64::kernel::pit;
 use arch::x86_64::kernel::{BOOT_INFO, BootInfo};
 use arch::x86_64::kernel::copy_safe::*;
+use arch::x86_64::mm::physicalmem;
 use core::sync::atomic::spin_loop_hint;
 use core::{fmt, intrinsics, u32};
 use environment;
@@ -28,6 +29,37 @@ const IA32_MISC_ENABLE_ENHANCED_SPEEDSTEP: u64 = 1 << 16;
 const IA32_MISC_ENABLE_SPEEDSTEP_LOCK: u64 = 1 << 20;
 const IA32_MISC_ENABLE_TURBO_DISABLE: u64 = 1 << 38;
 
+/// Memory types of the Page Attribute Table (Intel Vol. 3A, Table 11-10)
+const PAT_UNCACHEABLE: u64 = 0x00;
+const PAT_WRITE_COMBINING: u64 = 0x01;
+const PAT_WRITE_THROUGH: u64 = 0x04;
+const PAT_WRITE_BACK: u64 = 0x06;
+const PAT_UNCACHED: u64 = 0x07;
+
+/// Layout of the Page Attribute Table. A 4 KiB page selects its entry by the index PAT * 4 + PCD * 2 + PWT.
+///
+/// | Index | PAT | PCD | PWT | Memory type                                      |
+/// |-------|-----|-----|-----|--------------------------------------------------|
+/// | 0     | 0   | 0   | 0   | Write-Back (`CachePolicy::WriteBack`)            |
+/// | 1     | 0   | 0   | 1   | Write-Through (`CachePolicy::WriteThrough`)      |
+/// | 2     | 0   | 1   | 0   | Uncached (UC-)                                   |
+/// | 3     | 0   | 1   | 1   | Uncacheable (`CachePolicy::Uncached`)            |
+/// | 4     | 1   | 0   | 0   | Write-Combining (`CachePolicy::WriteCombining`)  |
+/// | 5     | 1   | 0   | 1   | Write-Through                                    |
+/// | 6     | 1   | 1   | 0   | Uncached (UC-)                                   |
+/// | 7     | 1   | 1   | 1   | Uncacheable                                      |
+///
+/// Entries 0 to 3 keep their power-up defaults, so mappings without the PAT bit behave as before.
+/// Only entry 4 differs from the default (Write-Back).
+const PAT_LAYOUT: u64 = PAT_WRITE_BACK
+	| PAT_WRITE_THROUGH << 8
+	| PAT_UNCACHED << 16
+	| PAT_UNCACHEABLE << 24
+	| PAT_WRITE_COMBINING << 32
+	| PAT_WRITE_THROUGH << 40
+	| PAT_UNCACHED << 48
+	| PAT_UNCACHEABLE << 56;
+
 // MSR EFER bits
 const EFER_SCE: u64 = (1 << 0);
 const EFER_LME: u64 = (1 << 8);
@@ -49,6 +81,7 @@ safe_global_var!(static mut SUPPORTS_RDRAND: bool = false);
 safe_global_var!(static mut SUPPORTS_TSC_DEADLINE: bool = false);
 safe_global_var!(static mut SUPPORTS_X2APIC: bool = false);
 safe_global_var!(static mut SUPPORTS_XSAVE: bool = false);
+safe_global_var!(static mut SUPPORTS_PAT: bool = false);
 
 safe_global_var!(static mut SUPPORTS_PKU: bool = false);
 safe_global_var!(static mut SUPPORTS_OSPKE: bool = false);
@@ -203,6 +236,19 @@ impl FPUState {
 		}
 	}
 
+	/// Saves the FPU registers, even if the task switched flag is set.
+	/// The flag is preserved, so that the next FPU access of the current task still raises an exception.
+	pub fn save_switched(&mut self) {
+		let cr0 = unsafe { cr0() };
+		unsafe {
+			asm!("clts" :::: "volatile");
+		}
+		self.save();
+		unsafe {
+			cr0_write(cr0);
+		}
+	}
+
 	pub fn restore_common(&self) {
 		unsafe {
 			//isolation_start!();
@@ -734,6 +780,7 @@ pub fn detect_features() {
 		SUPPORTS_TSC_DEADLINE = feature_info.has_tsc_deadline();
 		SUPPORTS_X2APIC = feature_info.has_x2apic();
 		SUPPORTS_XSAVE = feature_info.has_xsave();
+		SUPPORTS_PAT = feature_info.has_pat();
 
         SUPPORTS_PKU = extended_feature_info.has_pku();
 
@@ -755,12 +802,42 @@ pub fn fpu_init() {
     }
 }
 
+/// Verifies that the bootloader has enabled 4-level paging (48-bit virtual addresses).
+fn check_paging_mode(cr4: Cr4) -> Result<(), &'static str> {
+	if cr4.contains(Cr4::CR4_ENABLE_LA57) {
+		Err("5-level paging (CR4.LA57) is enabled, but HermitCore only supports 4-level paging")
+	} else {
+		Ok(())
+	}
+}
+
+/// Programs the Page Attribute Table with `PAT_LAYOUT`, unless the loader has already done so.
+///
+/// The table has to be identical on all cores, so every core calls this function in `configure`.
+/// No mapping uses the PAT bit before, hence no cached line has to be written back.
+pub fn setup_pat() {
+	if !supports_pat() {
+		return;
+	}
+
+	let pat = unsafe { rdmsr(IA32_PAT) };
+	if pat != PAT_LAYOUT {
+		debug!("Set PAT from 0x{:x} to 0x{:x}", pat, PAT_LAYOUT);
+		unsafe {
+			wrmsr(IA32_PAT, PAT_LAYOUT);
+		}
+	}
+}
+
 pub fn configure() {
 	// setup MSR EFER
 	unsafe {
 		wrmsr(IA32_EFER, rdmsr(IA32_EFER) | EFER_LMA | EFER_SCE | EFER_NXE);
 	}
 
+	// setup MSR PAT, which provides write-combining memory
+	setup_pat();
+
 	//
 	// CR0 CONFIGURATION
 	//
@@ -789,6 +866,12 @@ pub fn configure() {
 	//
 	let mut cr4 = unsafe { cr4() };
 
+	// The page tables and the recursive mapping assume 4-level paging.
+	// LA57 can't be switched off while paging is enabled, so refuse to continue.
+	if let Err(msg) = check_paging_mode(cr4) {
+		panic!("{}", msg);
+	}
+
 	// Enable Machine Check Exceptions.
 	// No need to check for support here, all x86-64 CPUs support it.
 	cr4.insert(Cr4::CR4_ENABLE_MACHINE_CHECK);
@@ -953,6 +1036,11 @@ pub fn supports_xsave() -> bool {
 	unsafe { SUPPORTS_XSAVE }
 }
 
+#[inline]
+pub fn supports_pat() -> bool {
+	unsafe { SUPPORTS_PAT }
+}
+
 #[inline]
 pub fn supports_pku() -> bool {
 	unsafe { SUPPORTS_PKU }
@@ -993,6 +1081,8 @@ pub fn halt() {
 /// Shutdown the system
 pub fn shutdown() -> ! {
 	info!("Shutting down system");
+	// The cached frames of all cores are returned to the free list.
+	physicalmem::flush_frame_caches();
 	acpi::poweroff();
 
 	loop {
@@ -1089,3 +1179,26 @@ pub fn udelay(usecs: u64) {
 		spin_loop_hint();
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn five_level_paging_is_rejected() {
+		let mut cr4 = Cr4::CR4_ENABLE_PAE | Cr4::CR4_ENABLE_SSE;
+		assert!(check_paging_mode(cr4).is_ok());
+
+		cr4.insert(Cr4::CR4_ENABLE_LA57);
+		assert!(check_paging_mode(cr4).is_err());
+	}
+
+	#[test]
+	fn pat_layout_only_adds_write_combining() {
+		// power-up default: WB, WT, UC-, UC, WB, WT, UC-, UC
+		let default: u64 = 0x0007_0406_0007_0406;
+		assert_eq!(PAT_LAYOUT & 0xFFFF_FFFF, default & 0xFFFF_FFFF);
+		assert_eq!((PAT_LAYOUT >> 32) & 0xFF, PAT_WRITE_COMBINING);
+		assert_eq!(PAT_LAYOUT >> 40, default >> 40);
+	}
+}
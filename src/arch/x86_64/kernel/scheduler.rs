@@ -16,6 +16,8 @@ use arch::x86_64::kernel::irq;
 use arch::x86_64::kernel::percore::*;
 use arch::x86_64::kernel::processor;
 use arch::x86_64::kernel::copy_safe::*;
+use arch::x86_64::mm::mpk;
+use arch::x86_64::mm::paging::{BasePageSize, PageSize};
 use config::*;
 use core::cell::RefCell;
 use core::mem;
@@ -78,6 +80,10 @@ pub struct TaskStacks {
 	pub isolated_stack: usize,
 	/// User stack
 	pub user_stack: usize,
+	/// Size of the stack and the user stack
+	stack_size: usize,
+	/// Whether an unmapped guard page lies below the stack and the user stack
+	guarded: bool,
 
 	//pub current_kernel_stack: usize,
 	//pub current_user_stack: usize,
@@ -104,11 +110,53 @@ impl TaskStacks {
 			ist0: ist0,
 			isolated_stack: isolated_stack,
 			user_stack: user_stack,
+			stack_size: DEFAULT_STACK_SIZE,
+			guarded: false,
 			//current_kernel_stack: 0xaaaabeefusize,
 			//current_user_stack: user_stack + DEFAULT_STACK_SIZE,
 		}
 	}
 
+	/// Allocates stacks like `new`, but the stack and the user stack have `stack_size` bytes
+	/// (rounded up to whole pages) and an unmapped guard page below them.
+	pub fn with_stack_size(stack_size: usize) -> Self {
+		let stack_size = align_up!(stack_size, BasePageSize::SIZE);
+		let stack = guarded_stack(::mm::allocate(stack_size + BasePageSize::SIZE, true));
+		let ist0 = ::mm::user_allocate(KERNEL_STACK_SIZE, true);
+		let isolated_stack = ::mm::unsafe_allocate(DEFAULT_STACK_SIZE, true);
+		let user_stack = guarded_stack(::mm::user_allocate(stack_size + BasePageSize::SIZE, true));
+
+		Self {
+			is_boot_stack: false,
+			stack: stack,
+			ist0: ist0,
+			isolated_stack: isolated_stack,
+			user_stack: user_stack,
+			stack_size: stack_size,
+			guarded: true,
+		}
+	}
+
+	/// Returns true if `address` lies within the user stack.
+	pub fn is_user_stack(&self, address: usize) -> bool {
+		self.user_stack != 0 && address >= self.user_stack && address - self.user_stack < self.stack_size
+	}
+
+	/// Returns the size of the stack and the user stack.
+	pub fn stack_size(&self) -> usize {
+		self.stack_size
+	}
+
+	/// Returns the number of bytes allocated for the stacks of a task.
+	/// The boot stacks are part of the kernel image and don't count.
+	pub fn size(&self) -> usize {
+		if self.is_boot_stack {
+			0
+		} else {
+			2 * self.stack_size + DEFAULT_STACK_SIZE + KERNEL_STACK_SIZE
+		}
+	}
+
 	pub fn from_boot_stacks() -> Self {
 		let tss = unsafe { &(*PERCORE.tss.get()) };
 		let stack = tss.rsp[0] as usize + 0x10 - KERNEL_STACK_SIZE;
@@ -122,29 +170,55 @@ impl TaskStacks {
 			ist0: ist0,
 			isolated_stack: 0usize,
 			user_stack: 0usize,
+			stack_size: KERNEL_STACK_SIZE,
+			guarded: false,
 			//current_kernel_stack: 0xeeeebeefusize,
 			//current_user_stack: 0xffffbeefusize,
 		}
 	}
 }
 
-impl Drop for TaskStacks {
-	fn drop(&mut self) {
-		if !self.is_boot_stack {
+impl TaskStacks {
+	/// Deallocates the stacks. They must not be in use anymore and are only released once.
+	pub fn release(&mut self) {
+		if !self.is_boot_stack && self.stack != 0 {
 			debug!("Deallocating stack {:#X} and ist0 {:#X}", self.stack, self.ist0);
 
-			::mm::deallocate(self.stack, DEFAULT_STACK_SIZE);
+			::mm::deallocate(self.stack, self.stack_size);
 			::mm::deallocate(self.ist0, KERNEL_STACK_SIZE);
 
 			debug!("Deallocating isolated_stack {:#X}", self.stack);
 
 			::mm::deallocate(self.isolated_stack, DEFAULT_STACK_SIZE);
 
-			::mm::deallocate(self.user_stack, DEFAULT_STACK_SIZE);
+			::mm::deallocate(self.user_stack, self.stack_size);
+
+			if self.guarded {
+				::arch::mm::virtualmem::deallocate(self.stack - BasePageSize::SIZE, BasePageSize::SIZE);
+				::arch::mm::virtualmem::deallocate(self.user_stack - BasePageSize::SIZE, BasePageSize::SIZE);
+			}
+
+			self.stack = 0;
 		}
 	}
 }
 
+impl Drop for TaskStacks {
+	fn drop(&mut self) {
+		self.release();
+	}
+}
+
+/// Unmaps the lowest page of the stack at `start` and returns the start of the remaining stack.
+/// An overflow of the stack hits the unmapped page and faults instead of corrupting the memory below.
+fn guarded_stack(start: usize) -> usize {
+	let physical_address = ::arch::mm::paging::virtual_to_physical(start);
+	::arch::mm::paging::unmap::<BasePageSize>(start, 1);
+	::arch::mm::physicalmem::deallocate(physical_address, BasePageSize::SIZE);
+
+	start + BasePageSize::SIZE
+}
+
 extern "C" fn leave_task() -> ! {
 	core_scheduler().exit(0);
 }
@@ -219,15 +293,16 @@ impl TaskFrame for Task {
 		/* This function initializes an empty stack frame.
 		   So we can just set pages to SHARE_MEM_REGION then set it back to SAFE_MEM_RGION after the initializtion.
 		*/
-		use arch::x86_64::mm::paging::{set_pkey_on_page_table_entry, BasePageSize};
-		set_pkey_on_page_table_entry::<BasePageSize>(self.stacks.stack, DEFAULT_STACK_SIZE/4096, mm::SHARED_MEM_REGION);
+		use arch::x86_64::mm::paging::set_pkey_on_page_table_entry;
+		let stack_size = self.stacks.stack_size();
+		set_pkey_on_page_table_entry::<BasePageSize>(self.stacks.stack, stack_size/4096, mm::SHARED_MEM_REGION);
 		unsafe {
 			// Mark the entire stack with 0xCD.
 			let temp_stack = self.stacks.stack;
-			isolate_function_weak!(write_bytes(temp_stack as *mut u8, 0xCD, DEFAULT_STACK_SIZE));
+			isolate_function_weak!(write_bytes(temp_stack as *mut u8, 0xCD, stack_size));
 
 			// Set a marker for debugging at the very top.
-			let mut stack = (self.stacks.stack + DEFAULT_STACK_SIZE - 0x10) as *mut usize;
+			let mut stack = (self.stacks.stack + stack_size - 0x10) as *mut usize;
 			isolation_start!();
 			*stack = 0xDEAD_BEEFusize;
 			isolation_end!();
@@ -251,16 +326,33 @@ impl TaskFrame for Task {
 			(*state_ref).rdi = func as usize;
 			(*state_ref).rsi = arg as usize;
 			(*state_ref).rflags = 0x1202usize;
+			if environment::mpk_enabled() {
+				// The task starts in the kernel domain, which has no access to the sealed page tables.
+				(*state_ref).pkru = mpk::KERNEL_PKRU as usize;
+			}
 
 			// Set the task's stack pointer entry to the stack we have just crafted.
 			self.last_stack_pointer = stack as usize;
-			self.user_stack_pointer = self.stacks.user_stack as usize + DEFAULT_STACK_SIZE;
+			self.user_stack_pointer = self.stacks.user_stack as usize + stack_size;
 		}
-		set_pkey_on_page_table_entry::<BasePageSize>(self.stacks.stack, DEFAULT_STACK_SIZE/4096, mm::SAFE_MEM_REGION);
+		set_pkey_on_page_table_entry::<BasePageSize>(self.stacks.stack, stack_size/4096, mm::SAFE_MEM_REGION);
+	}
+}
+
+/// Updates the PKRU, which `switch` restores for the task suspended at `stack_pointer`:
+/// the protection keys in the bit set `closed` deny any access, those in `opened` permit it.
+pub fn update_saved_pkru(stack_pointer: usize, closed: u16, opened: u16) {
+	use arch::x86_64::mm::mpk::no_access_bits;
+
+	let state = stack_pointer as *mut State;
+	unsafe {
+		let pkru = ((*state).pkru as u32 | no_access_bits(closed)) & !no_access_bits(opened);
+		(*state).pkru = pkru as usize;
 	}
 }
 
 extern "x86-interrupt" fn timer_handler(_stack_frame: &mut irq::ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	core_scheduler().blocked_tasks.lock().handle_waiting_tasks();
 	apic::eoi();
 	core_scheduler().scheduler();
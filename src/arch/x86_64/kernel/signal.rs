@@ -0,0 +1,215 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Synchronous delivery of isolation violations to the handler of the offending task.
+//!
+//! In addition, a task may probe an address for an expected fault (see `expect_fault`).
+
+use arch::x86_64::kernel::irq::ExceptionStackFrame;
+use arch::x86_64::kernel::percore::core_id;
+use arch::x86_64::kernel::scheduler::TaskStacks;
+use arch::x86_64::mm::mpk::STATIC_KEY_BITS;
+use core::intrinsics;
+
+/// Signal number of an isolation violation
+pub const SIGSEGV: i32 = 11;
+
+pub use arch::x86_64::mm::mpk::USER_PKRU;
+
+/// Size of the area below the stack pointer, which the interrupted function may still use
+const RED_ZONE: u64 = 128;
+
+/// Entry point of the fault handler in the context of the offending task.
+///
+/// The address of the handler is on top of the stack, followed by the interrupt frame of the fault.
+/// The trampoline preserves the registers, which the handler may clobber, and resumes the task at
+/// the return address of the handler, i.e., the faulting instruction is executed again. Hence, the
+/// handler has to remove the cause of the fault. Otherwise, the task is aborted by the repeated fault,
+/// because the handler is reset before it is invoked.
+#[inline(never)]
+#[naked]
+extern "C" fn fault_trampoline() {
+	unsafe {
+		asm!(
+			"push %rax\n\t\
+			push %rcx\n\t\
+			push %rdx\n\t\
+			push %rsi\n\t\
+			push %rdi\n\t\
+			push %r8\n\t\
+			push %r9\n\t\
+			push %r10\n\t\
+			push %r11\n\t\
+			mov 72(%rsp), %rax\n\t\
+			sub $$512, %rsp\n\t\
+			fxsave (%rsp)\n\t\
+			cld\n\t\
+			mov $$11, %edi\n\t\
+			call *%rax\n\t\
+			fxrstor (%rsp)\n\t\
+			add $$512, %rsp\n\t\
+			pop %r11\n\t\
+			pop %r10\n\t\
+			pop %r9\n\t\
+			pop %r8\n\t\
+			pop %rdi\n\t\
+			pop %rsi\n\t\
+			pop %rdx\n\t\
+			pop %rcx\n\t\
+			pop %rax\n\t\
+			add $$8, %rsp\n\t\
+			iretq"
+			:::: "volatile"
+		);
+		intrinsics::unreachable()
+	}
+}
+
+/// Entry point of a page fault handler in the context of the faulting task.
+///
+/// The fault address, the error code and the address of the handler are on top of the stack.
+/// In contrast to `fault_trampoline`, the task is terminated if the handler returns.
+#[inline(never)]
+#[naked]
+extern "C" fn page_fault_trampoline() {
+	unsafe {
+		asm!(
+			"pop %rdi\n\t\
+			pop %rsi\n\t\
+			pop %rax\n\t\
+			call *%rax\n\t\
+			mov $$-1, %edi\n\t\
+			call sys_thread_exit"
+			:::: "volatile"
+		);
+	}
+}
+
+/// Returns true if the fault interrupted user code, i.e., the PKRU at the time of the fault is the one
+/// of the user domain and the interrupted stack pointer lies within the user stack of the task.
+/// Faults of the kernel or of an isolated domain are never passed to a handler of the task.
+pub fn is_user_context(stack_frame: &ExceptionStackFrame, pkru: u32, stacks: &TaskStacks) -> bool {
+	// The bits of the dynamic keys depend on the task (see `user_start!`).
+	(!::environment::mpk_enabled() || pkru & STATIC_KEY_BITS == USER_PKRU) && stacks.is_user_stack(stack_frame.stack_pointer as usize)
+}
+
+/// Redirects the interrupted task to `handler`, which is invoked with `SIGSEGV` on the stack of the task.
+/// Once the handler returns, the task resumes at the interrupted instruction (see `fault_trampoline`).
+pub fn deliver_fault(stack_frame: &mut ExceptionStackFrame, handler: extern "C" fn(i32)) {
+	// Skip the red zone and push the handler together with a copy of the interrupt frame.
+	// The stack is 16-byte aligned, after the trampoline has saved 9 registers.
+	let stack_pointer = align_down!(stack_frame.stack_pointer - RED_ZONE - 6 * 8, 16) + 8;
+
+	unsafe {
+		let stack = stack_pointer as *mut u64;
+		*stack = handler as u64;
+		*stack.offset(1) = stack_frame.instruction_pointer;
+		*stack.offset(2) = stack_frame.code_segment;
+		*stack.offset(3) = stack_frame.cpu_flags;
+		*stack.offset(4) = stack_frame.stack_pointer;
+		*stack.offset(5) = stack_frame.stack_segment;
+	}
+	stack_frame.stack_pointer = stack_pointer;
+	stack_frame.instruction_pointer = fault_trampoline as u64;
+}
+
+/// Redirects the interrupted task to the page fault handler at `handler`, which is invoked as
+/// `extern "C" fn(fault_address: usize, error: u32)` on the stack of the task.
+pub fn deliver_page_fault(stack_frame: &mut ExceptionStackFrame, handler: usize, fault_address: usize, error: u32) {
+	// The stack is 16-byte aligned after the arguments and the handler are popped.
+	let stack_pointer = align_down!(stack_frame.stack_pointer - RED_ZONE, 16) - 24;
+
+	unsafe {
+		let stack = stack_pointer as *mut u64;
+		*stack = fault_address as u64;
+		*stack.offset(1) = error as u64;
+		*stack.offset(2) = handler as u64;
+	}
+	stack_frame.stack_pointer = stack_pointer;
+	stack_frame.instruction_pointer = page_fault_trampoline as u64;
+}
+
+/// Address, at which each core expects a fault of `probe_read` or `probe_write`
+safe_global_var!(static mut EXPECTED_FAULTS: [Option<usize>; 64] = [None; 64]);
+
+/// Reads a byte at `address` with the permissions of the caller.
+/// Returns 0 if the access succeeded or 1 if it faulted with an expectation armed by `expect_fault`.
+#[inline(never)]
+#[naked]
+pub extern "C" fn probe_read(_address: usize) -> i32 {
+	unsafe {
+		asm!(
+			"movb (%rdi), %al\n\t\
+			xor %eax, %eax\n\t\
+			ret"
+			:::: "volatile"
+		);
+		intrinsics::unreachable()
+	}
+}
+
+/// Like `probe_read`, but writes the byte at `address`.
+/// The byte is combined atomically with 0, so a successful write doesn't modify the memory.
+#[inline(never)]
+#[naked]
+pub extern "C" fn probe_write(_address: usize) -> i32 {
+	unsafe {
+		asm!(
+			"lock orb $$0, (%rdi)\n\t\
+			xor %eax, %eax\n\t\
+			ret"
+			:::: "volatile"
+		);
+		intrinsics::unreachable()
+	}
+}
+
+/// Continuation of a probe, whose access has faulted.
+/// The probes don't touch the stack, so this returns to the caller of the probe.
+#[inline(never)]
+#[naked]
+extern "C" fn probe_fault() {
+	unsafe {
+		asm!(
+			"mov $$1, %eax\n\t\
+			ret"
+			:::: "volatile"
+		);
+	}
+}
+
+/// Arms a one-shot expectation of a fault at `address` for the next probe on the current core.
+pub fn expect_fault(address: usize) {
+	unsafe {
+		EXPECTED_FAULTS[core_id()] = Some(address);
+	}
+}
+
+/// Disarms the expectation of the current core. Returns `true` if it was still armed,
+/// i.e. the probe hasn't faulted.
+pub fn disarm_fault() -> bool {
+	unsafe { EXPECTED_FAULTS[core_id()].take().is_some() }
+}
+
+/// Called by the page fault handler. If a probe has faulted at the expected address, the
+/// expectation is disarmed, the probe returns 1 and `true` is returned.
+pub fn deliver_expected_fault(stack_frame: &mut ExceptionStackFrame, fault_address: usize) -> bool {
+	let instruction_pointer = stack_frame.instruction_pointer;
+	if instruction_pointer != probe_read as u64 && instruction_pointer != probe_write as u64 {
+		return false;
+	}
+
+	unsafe {
+		if EXPECTED_FAULTS[core_id()] != Some(fault_address) {
+			return false;
+		}
+		EXPECTED_FAULTS[core_id()] = None;
+	}
+	stack_frame.instruction_pointer = probe_fault as u64;
+
+	true
+}
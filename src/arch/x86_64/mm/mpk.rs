@@ -1,18 +1,88 @@
 #![allow(dead_code)]
 
+use alloc::vec::Vec;
 use arch::x86_64::mm::paging;
 use arch::x86_64::mm::paging::PageSize;
+use arch::x86_64::kernel::apic;
+use arch::x86_64::kernel::percore::{core_id, core_scheduler};
 use arch::x86_64::kernel::processor;
+use core::ptr;
+use core::sync::atomic::{AtomicU16, AtomicUsize, Ordering};
+use config::{COUNT_PKRU_WRITES, PKRU_AUDIT_INTERVAL};
+use environment;
+use mm;
 
 const EINVAL: i32 = 22;
+const ENOSPC: i32 = 28;
 const ENOSYS: i32 = 38;
 
+/* Number of protection keys defined by the architecture (PKU) */
+const MPK_KEYS: usize = 16;
+
+/* Keys 0 to 4 are statically used by the kernel (default, safe, unsafe, shared region and page tables) */
+const MPK_STATIC_KEYS: u16 = 0x1F;
+
+/* PKRU bits of the static keys, which the domain switches replace (see user_start!) */
+pub const STATIC_KEY_BITS: u32 = 0x3FF;
+
+/* Static PKRU bits of the kernel domain: only the page tables (key 4) are closed */
+pub const KERNEL_PKRU: u32 = 0x300;
+
+/* Static PKRU bits of the user domain: all keys of the kernel (1 to 4) are closed */
+pub const USER_PKRU: u32 = 0x3FC;
+
+/* Bitmap of the dynamically allocated keys */
+safe_global_var!(static ALLOCATED_KEYS: AtomicU16 = AtomicU16::new(0));
+
+/* Number of mapped pages, which are tagged with the corresponding key */
+safe_global_var!(static MAPPED_PAGES: [AtomicUsize; MPK_KEYS] = [
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+    AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0), AtomicUsize::new(0),
+]);
+
+#[derive(Clone, Copy, Debug, PartialEq)]
 pub enum MpkPerm {
     MpkRw,
     MpkRo,
     MpkNone
 }
 
+impl MpkPerm {
+    /* Returns the bits of the PKRU, which grant this permission to the key.
+     * The lower bit of a key is AD (access disable), the upper one WD (write disable). */
+    pub const fn to_pkru_bits(self, key: u8) -> u32 {
+        return [0b00u32, 0b10, 0b11][self as usize] << (2 * key as u32);
+    }
+
+    /* Returns the permission of the key in the PKRU. AD alone denies any access as well. */
+    pub fn from_pkru_bits(pkru: u32, key: u8) -> MpkPerm {
+        match (pkru >> (2 * key as u32)) & 0b11 {
+            0b00 => MpkPerm::MpkRw,
+            0b10 => MpkPerm::MpkRo,
+            _ => MpkPerm::MpkNone,
+        }
+    }
+}
+
+/* Returns true if `pkru` permits a read (or a write, if `write` is set) of memory tagged with `key`.
+ * Only the PKRU is decoded, the flags of the page table entry aren't taken into account. */
+pub fn would_allow(pkru: u32, key: u8, write: bool) -> bool {
+    match MpkPerm::from_pkru_bits(pkru, key) {
+        MpkPerm::MpkRw => true,
+        MpkPerm::MpkRo => !write,
+        MpkPerm::MpkNone => false,
+    }
+}
+
+/* Returns the PKRU bits, which deny any access to the keys in the bit set `keys` (bit n = key n). */
+pub fn no_access_bits(keys: u16) -> u32 {
+    (0..16u8)
+        .filter(|key| keys & (1 << key) != 0)
+        .fold(0, |bits, key| bits | MpkPerm::MpkNone.to_pkru_bits(key))
+}
+
 #[inline]
 fn rdpkru() -> u32 {
 
@@ -32,6 +102,12 @@ fn rdpkru() -> u32 {
 #[inline]
 fn wrpkru(val: u32) {
 
+    /* The counters belong to the unsafe domain, so they are updated while it is accessible */
+    let count_after = would_allow(val, mm::UNSAFE_MEM_REGION, true);
+    if COUNT_PKRU_WRITES && !count_after && would_allow(rdpkru(), mm::UNSAFE_MEM_REGION, true) {
+        count_pkru_writes(1);
+    }
+
     unsafe {
         asm!("mov $0, %eax;
               xor %ecx, %ecx;
@@ -43,6 +119,44 @@ fn wrpkru(val: u32) {
              : "eax", "ecx", "edx"
              : "volatile");
     }
+
+    if COUNT_PKRU_WRITES && count_after {
+        count_pkru_writes(1);
+    }
+}
+
+/* Number of PKRU writes of a core, padded to a cache line.
+ * Only the core itself increments its counter, so no atomic operation is needed. */
+#[derive(Clone, Copy)]
+#[repr(align(64))]
+struct PkruWrites(u64);
+
+const MAX_COUNTED_CORES: usize = 64;
+
+/* PKRU writes per core, see config::COUNT_PKRU_WRITES. The counters are updated within isolated
+ * sections as well, so they belong to the unsafe domain. */
+unsafe_global_var!(static mut PKRU_WRITES: [PkruWrites; MAX_COUNTED_CORES] = [PkruWrites(0); MAX_COUNTED_CORES]);
+
+/* Add 'n' PKRU writes to the counter of the current core. The PKRU has to permit the unsafe domain.
+ * An interrupt between the load and the store may lose the writes of its handler. */
+#[inline]
+pub fn count_pkru_writes(n: u64) {
+    let core = core_id();
+    if core < MAX_COUNTED_CORES {
+        unsafe {
+            let counter = &mut PKRU_WRITES[core].0 as *mut u64;
+            ptr::write_volatile(counter, ptr::read_volatile(counter) + n);
+        }
+    }
+}
+
+/* Return the number of PKRU writes of 'core' since the boot, 0 if the writes aren't counted */
+pub fn pkru_write_count(core: u32) -> u64 {
+    if core as usize >= MAX_COUNTED_CORES {
+        return 0;
+    }
+
+    return unsafe { ptr::read_volatile(&PKRU_WRITES[core as usize].0) };
 }
 
 pub fn mpk_swap_pkru(new_pkru: u32) -> u32 {
@@ -59,50 +173,53 @@ pub fn mpk_swap_pkru(new_pkru: u32) -> u32 {
 
 fn pkru_set_ro(key: u8, val: &mut u32) -> i32 {
 
-    if key > 15
+    if key as usize >= MPK_KEYS
     {
         return -EINVAL;
     }
 
-    *val &= !(1 << (key * 2));
-    *val |= 1 << ((key*2) + 1);
+    *val &= !MpkPerm::MpkNone.to_pkru_bits(key);
+    *val |= MpkPerm::MpkRo.to_pkru_bits(key);
 
     return 0;
 }
 
 fn pkru_set_rw(key: u8, val: &mut u32) -> i32 {
 
-    if key > 15
+    if key as usize >= MPK_KEYS
     {
         return -EINVAL;
     }
 
-    *val &= !(1 << (key*2));
-    *val &= !(1 << ((key*2) + 1));
+    *val &= !MpkPerm::MpkNone.to_pkru_bits(key);
+    *val |= MpkPerm::MpkRw.to_pkru_bits(key);
 
     return 0;
 }
 
 fn pkru_set_no_access(key: u8, val: &mut u32) -> i32 {
 
-    if key > 15
+    if key as usize >= MPK_KEYS
     {
         return -EINVAL;
     }
 
-    *val |= 1 << (key * 2);
-    *val |= 1 << ((key * 2) + 1);
+    *val &= !MpkPerm::MpkNone.to_pkru_bits(key);
+    *val |= MpkPerm::MpkNone.to_pkru_bits(key);
 
     return 0;
 }
 
+/* Tag the pages covering [addr, addr + size[ with 'key'.
+ * The key is part of the cached translation. Hence, set_pkey_on_page_table_entry flushes
+ * the stale TLB entries on all cores and the caller doesn't have to flush. */
 pub fn mpk_mem_set_key<S: PageSize>(mut addr: usize, mut size: usize, key: u8) -> i32 {
 
     if processor::supports_ospke() == false {
         return -ENOSYS;
     }
 
-    if key > 15
+    if key as usize >= num_keys()
     {
         return -EINVAL;
     }
@@ -124,12 +241,39 @@ pub fn mpk_mem_set_key<S: PageSize>(mut addr: usize, mut size: usize, key: u8) -
     return 0;
 }
 
+/* Number of protection keys, which are available with or without PKU */
+fn keys_supported(pku: bool) -> usize {
+    if pku {
+        MPK_KEYS
+    } else {
+        0
+    }
+}
+
+/* Return the number of supported protection keys, 0 if MPK isn't available */
+pub fn num_keys() -> usize {
+    keys_supported(processor::supports_ospke())
+}
+
+/* Change the permission of 'key' on the current core.
+ * The PKRU isn't cached in the TLB, so the new permission applies to the following accesses
+ * without flushing any TLB entry. */
 pub fn mpk_set_perm(key: u8, perm: MpkPerm) -> i32 {
 
     if processor::supports_ospke() == false {
         return -ENOSYS;
     }
 
+    if key as usize >= num_keys()
+    {
+        return -EINVAL;
+    }
+
+    /* Without enforcement (-nompk), the PKRU stays fully open */
+    if environment::mpk_enabled() == false {
+        return 0;
+    }
+
     let mut pkru: u32;
     pkru = rdpkru();
 
@@ -173,7 +317,255 @@ pub fn mpk_get_pkru() -> u32 {
 /* Set the pkru value to 'val' */
 pub fn mpk_set_pkru(val: u32) {
 
-    if processor::supports_ospke() == true {
+    if processor::supports_ospke() == true && environment::mpk_enabled() == true {
         wrpkru(val);
     }
-}
\ No newline at end of file
+}
+/* Allocate an unused protection key, returns the key or a negative error code */
+pub fn mpk_pkey_alloc() -> i32 {
+
+    if processor::supports_ospke() == false {
+        return -ENOSYS;
+    }
+
+    loop {
+        let allocated = ALLOCATED_KEYS.load(Ordering::SeqCst);
+        let used = allocated | MPK_STATIC_KEYS;
+        let all_keys = ((1u32 << num_keys()) - 1) as u16;
+        if used & all_keys == all_keys {
+            return -ENOSPC;
+        }
+
+        let key = (!used).trailing_zeros() as u16;
+        if ALLOCATED_KEYS
+            .compare_exchange(allocated, allocated | (1 << key), Ordering::SeqCst, Ordering::SeqCst)
+            .is_ok()
+        {
+            return key as i32;
+        }
+    }
+}
+
+/* Release a dynamically allocated protection key */
+pub fn mpk_pkey_free(key: u8) -> i32 {
+
+    if key as usize >= num_keys() || (MPK_STATIC_KEYS & (1 << key)) != 0 {
+        return -EINVAL;
+    }
+
+    let old = ALLOCATED_KEYS.fetch_and(!(1 << key), Ordering::SeqCst);
+    if old & (1 << key) == 0 {
+        return -EINVAL;
+    }
+
+    /* Pages left behind by the previous owner must not be accessible with the default permission */
+    if MAPPED_PAGES[key as usize].load(Ordering::SeqCst) > 0 {
+        warn!("Free protection key {}, which is still used by mapped pages", key);
+    }
+
+    return 0;
+}
+
+/* Returns the first static key, which `pkru` opens (for reading or writing) although `expected` closes it */
+pub fn first_open_key(pkru: u32, expected: u32) -> Option<u8> {
+    let open = expected & !pkru & STATIC_KEY_BITS;
+    if open == 0 {
+        None
+    } else {
+        Some((open.trailing_zeros() / 2) as u8)
+    }
+}
+
+/*
+ * Check the PKRU of a context in the user domain (`user`) or in the kernel domain and return the first
+ * static key, which is open although the domain must not access it. Each static key has to be at least
+ * as restricted as in USER_PKRU or KERNEL_PKRU respectively. The kernel opens the key of the sealed page
+ * tables only while the paging code accesses them (see paging::page_tables_closed). The dynamic keys
+ * are managed per task and aren't checked.
+ *
+ * WRPKRU is unprivileged, so the register itself can't be trusted. A context, which is interrupted
+ * within the few instructions of a domain switch (e.g., kernel_enter! before it has left the user stack),
+ * is reported as well.
+ */
+pub fn check_pkru(pkru: u32, user: bool) -> Result<(), u8> {
+
+    if processor::supports_ospke() == false || environment::mpk_enabled() == false {
+        return Ok(());
+    }
+
+    let expected = if user {
+        USER_PKRU
+    } else if paging::page_tables_closed() {
+        KERNEL_PKRU
+    } else {
+        KERNEL_PKRU & !MpkPerm::MpkNone.to_pkru_bits(mm::PAGE_TABLE_MEM_REGION)
+    };
+
+    match first_open_key(pkru, expected) {
+        Some(key) => Err(key),
+        None => Ok(()),
+    }
+}
+
+/* Check the PKRU of the current context, which runs in the kernel domain */
+pub fn verify_pkru() -> Result<(), u8> {
+    check_pkru(rdpkru(), false)
+}
+
+/* Verify the PKRU on all cores, returns the ID of each offending core together with the open key */
+pub fn audit_all_cores() -> Vec<(usize, u8)> {
+    let mut violations = apic::ipi_pkru_audit();
+
+    if let Err(key) = verify_pkru() {
+        violations.push((core_id(), key));
+    }
+
+    for &(core, key) in violations.iter() {
+        error!("PKRU audit: core {} has opened protection key {}!", core, key);
+    }
+
+    return violations;
+}
+
+/* Low-priority kernel task, which audits all cores every PKRU_AUDIT_INTERVAL milliseconds */
+pub extern "C" fn audit_task(_arg: usize) {
+    loop {
+        let wakeup_time = processor::get_timer_ticks() + PKRU_AUDIT_INTERVAL * 1000;
+        let core_scheduler = core_scheduler();
+        core_scheduler
+            .blocked_tasks
+            .lock()
+            .add(core_scheduler.current_task.clone(), Some(wakeup_time));
+        core_scheduler.reschedule();
+
+        audit_all_cores();
+    }
+}
+
+/* Returns true if 'key' is dynamically allocated */
+pub fn mpk_pkey_is_allocated(key: u8) -> bool {
+    (key as usize) < num_keys() && ALLOCATED_KEYS.load(Ordering::SeqCst) & (1 << key) != 0
+}
+
+/* Returns the number of mapped pages, which are tagged with 'key' */
+pub fn mpk_mapped_pages(key: u8) -> usize {
+    MAPPED_PAGES[(key & 0xF) as usize].load(Ordering::SeqCst)
+}
+
+/* Return for each key whether it is in use (statically by the kernel or dynamically allocated) and the
+ * number of pages tagged with it. The pages are counted in 4 KiB units by walking the page tables, so
+ * pages, which keep a key after it has been freed, are found as well. */
+pub fn key_usage() -> [(bool, usize); MPK_KEYS] {
+    let mut usage = [(false, 0); MPK_KEYS];
+    let in_use = ALLOCATED_KEYS.load(Ordering::SeqCst) | MPK_STATIC_KEYS;
+
+    for key in 0..num_keys() {
+        usage[key].0 = in_use & (1 << key) != 0;
+    }
+
+    for (_start, size, _flags, pkey) in paging::mapped_regions() {
+        usage[(pkey & 0xF) as usize].1 += size / paging::BasePageSize::SIZE;
+    }
+
+    return usage;
+}
+
+/* Account a page, which is now tagged with 'key' */
+pub fn mpk_page_get(key: u8) {
+    MAPPED_PAGES[(key & 0xF) as usize].fetch_add(1, Ordering::SeqCst);
+}
+
+/*
+ * A page tagged with 'key' is gone. A dynamic key is reclaimed when its last page is unmapped, so
+ * long-running applications don't exhaust the key space. Afterwards, the former owner must neither
+ * use the key nor pass it to mpk_pkey_free, because mpk_pkey_alloc may already have handed it out again.
+ * A key, which has never tagged a page, is only released by mpk_pkey_free.
+ */
+pub fn mpk_page_put(key: u8) {
+    let key = key & 0xF;
+    let counter = &MAPPED_PAGES[key as usize];
+
+    /* Pages mapped by the loader were never accounted, so the counter must not wrap around */
+    let mut old = counter.load(Ordering::SeqCst);
+    while old > 0 {
+        match counter.compare_exchange_weak(old, old - 1, Ordering::SeqCst, Ordering::SeqCst) {
+            Ok(_) => {
+                if old == 1 && mpk_pkey_is_allocated(key) {
+                    debug!("Reclaim protection key {}, no page is using it", key);
+                    mpk_pkey_free(key);
+                }
+                return;
+            }
+            Err(current) => old = current,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn key_count_depends_on_pku() {
+        assert_eq!(keys_supported(false), 0);
+        assert_eq!(keys_supported(true), 16);
+    }
+
+    #[test]
+    fn pkru_bits_round_trip() {
+        /* isolation_start! denies the access to the safe domain (key 1) with 0xC */
+        assert_eq!(MpkPerm::MpkNone.to_pkru_bits(1), 0xC);
+        assert_eq!(MpkPerm::MpkRo.to_pkru_bits(1), 0x8);
+        assert_eq!(MpkPerm::MpkRw.to_pkru_bits(1), 0x0);
+
+        for key in 0..MPK_KEYS as u8 {
+            for &perm in [MpkPerm::MpkRw, MpkPerm::MpkRo, MpkPerm::MpkNone].iter() {
+                let pkru = perm.to_pkru_bits(key);
+                assert_eq!(MpkPerm::from_pkru_bits(pkru, key), perm);
+                assert_eq!(MpkPerm::from_pkru_bits(!pkru, key ^ 1), MpkPerm::MpkNone);
+            }
+        }
+
+        /* AD without WD denies any access */
+        assert_eq!(MpkPerm::from_pkru_bits(0b01 << 4, 2), MpkPerm::MpkNone);
+    }
+
+    #[test]
+    fn no_access_bits_of_key_sets() {
+        assert_eq!(no_access_bits(0), 0);
+        assert_eq!(no_access_bits(1 << 5 | 1 << 15), 0xC000_0C00);
+        /* the domain switches keep the bits of the dynamic keys 5 to 15 */
+        assert_eq!(no_access_bits(0xFFE0), 0xFFFF_FC00);
+    }
+
+    #[test]
+    fn open_static_keys_are_found() {
+        assert_eq!(first_open_key(USER_PKRU, USER_PKRU), None);
+        assert_eq!(first_open_key(KERNEL_PKRU, KERNEL_PKRU), None);
+        /* the user domain may be more restricted, but the kernel PKRU opens the keys 1 to 3 */
+        assert_eq!(first_open_key(USER_PKRU, KERNEL_PKRU), None);
+        assert_eq!(first_open_key(KERNEL_PKRU, USER_PKRU), Some(1));
+        /* write access to the safe domain is found as well */
+        assert_eq!(first_open_key(USER_PKRU & !0x8, USER_PKRU), Some(1));
+        assert_eq!(first_open_key(0, KERNEL_PKRU), Some(4));
+        /* the dynamic keys aren't checked */
+        assert_eq!(first_open_key(USER_PKRU, USER_PKRU | 0xC00), None);
+    }
+
+    #[test]
+    fn would_allow_decodes_ad_and_wd() {
+        /* kernel PKRU: keys 4 (page tables) closed, all others open */
+        assert!(would_allow(0x300, 1, true));
+        assert!(!would_allow(0x300, 4, false));
+        /* user PKRU: keys 1 to 4 closed, key 0 open */
+        assert!(would_allow(0x3FC, 0, true));
+        assert!(!would_allow(0x3FC, 2, false));
+
+        /* WD only permits reads */
+        assert!(would_allow(0b10 << 6, 3, false));
+        assert!(!would_allow(0b10 << 6, 3, true));
+        /* AD only denies both */
+        assert!(!would_allow(0b01 << 6, 3, false));
+        assert!(!would_allow(0b01 << 6, 3, true));
+    }
+}
@@ -7,20 +7,31 @@
 
 #![allow(dead_code)]
 
+use alloc::vec::Vec;
 use arch::x86_64::kernel::apic;
 use arch::x86_64::kernel::get_mbinfo;
 use arch::x86_64::kernel::irq;
+use arch::x86_64::kernel::percore::{core_id, try_core_scheduler, GsEntryGuard};
 //use arch::x86_64::kernel::is_uhyve;
 use arch::x86_64::kernel::processor;
+use arch::x86_64::kernel::signal;
+use arch::x86_64::mm::mpk;
 use arch::x86_64::mm::paddr_to_slice;
 use arch::x86_64::mm::physicalmem;
+use arch::x86_64::mm::virtualmem;
+use config::PAGE_FAULT_RETRY_LIMIT;
+use core::cmp;
+use core::intrinsics;
 use core::marker::PhantomData;
 use core::mem;
+use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
 use core::ptr::write_bytes;
+use core::slice;
 use environment;
 use mm;
 use multiboot::Multiboot;
 use scheduler;
+use synch::spinlock::SpinlockIrqSave;
 use x86::controlregs;
 use x86::irq::PageFaultError;
 
@@ -30,6 +41,200 @@ const BOOT_GDT: usize = 0x1000;
 /// Pointer to the root page table (PML4)
 const PML4_ADDRESS: *mut PageTable<PML4> = 0xFFFF_FFFF_FFFF_F000 as *mut PageTable<PML4>;
 
+/// Number of page tables, which have been allocated at runtime and are still in use.
+/// The tables set up by the loader aren't included.
+safe_global_var!(static PAGE_TABLE_PAGES: AtomicUsize = AtomicUsize::new(0));
+
+/// Number of pages, which a core has flushed from its TLB by INVLPG, padded to a cache line.
+/// Only the core itself increments its counter, so the cores don't contend for it.
+#[derive(Clone, Copy)]
+#[repr(align(64))]
+struct LocalFlushes(usize);
+
+/// Maximum number of cores, whose INVLPG instructions are counted.
+const MAX_COUNTED_CORES: usize = 64;
+
+/// Pages, which have been flushed by INVLPG, per core (see `local_flush_count`).
+safe_global_var!(static mut LOCAL_FLUSHES: [LocalFlushes; MAX_COUNTED_CORES] = [LocalFlushes(0); MAX_COUNTED_CORES]);
+
+/// Set by `seal` once the page tables are tagged with `mm::PAGE_TABLE_MEM_REGION`.
+safe_global_var!(static SEALED: AtomicBool = AtomicBool::new(false));
+
+/// Sorted and disjoint ranges `[start, end)`, which have been made permanently read-only by `freeze`.
+safe_global_var!(static FROZEN_RANGES: SpinlockIrqSave<Vec<(usize, usize)>> = SpinlockIrqSave::new(Vec::new()));
+
+/// Start of the first and end of the last range in `FROZEN_RANGES`, no range has been frozen yet.
+safe_global_var!(static FROZEN_START: AtomicUsize = AtomicUsize::new(core::usize::MAX));
+safe_global_var!(static FROZEN_END: AtomicUsize = AtomicUsize::new(0));
+
+/// 4 KiB pages, which have been made read-only by `watch_region` and haven't been written since.
+safe_global_var!(static WATCHED_PAGES: SpinlockIrqSave<Vec<usize>> = SpinlockIrqSave::new(Vec::new()));
+
+/// Serializes `reclaim_tables`, so that an empty table is released only once.
+safe_global_var!(static TABLE_RECLAIM_LOCK: SpinlockIrqSave<()> = SpinlockIrqSave::new(()));
+
+/// PKRU bits, which deny any access to the page tables (access and write disable of their key).
+const PAGE_TABLE_PKRU: u32 = mpk::MpkPerm::MpkNone.to_pkru_bits(mm::PAGE_TABLE_MEM_REGION);
+
+/// Number of page table accesses, which are currently open on all cores (see `page_tables_closed`).
+///
+/// A single counter avoids the lookup of the core ID in the hot path of every paging function.
+/// As a consequence, an audit can't tell which core has opened the key, see `page_tables_closed`.
+safe_global_var!(static OPEN_ACCESSES: AtomicUsize = AtomicUsize::new(0));
+
+/// Opens the protection key of the sealed page tables until it is dropped.
+///
+/// Every function of this module, which reads or writes the page tables, holds such a guard.
+/// The guard restores the previous PKRU value, so nested guards are fine.
+/// As long as the page tables aren't sealed, the guard doesn't touch PKRU at all.
+struct PageTableAccess {
+	pkru: Option<u32>,
+}
+
+impl PageTableAccess {
+	fn open() -> Self {
+		if !SEALED.load(Ordering::Relaxed) {
+			return Self { pkru: None };
+		}
+
+		let pkru = mpk::mpk_get_pkru();
+		// The access is counted while the key is open, so an audit never mistakes it for an intrusion.
+		OPEN_ACCESSES.fetch_add(1, Ordering::SeqCst);
+		mpk::mpk_set_pkru(pkru & !PAGE_TABLE_PKRU);
+		Self { pkru: Some(pkru) }
+	}
+}
+
+impl Drop for PageTableAccess {
+	fn drop(&mut self) {
+		if let Some(pkru) = self.pkru {
+			mpk::mpk_set_pkru(pkru);
+			OPEN_ACCESSES.fetch_sub(1, Ordering::SeqCst);
+		}
+	}
+}
+
+/// Maximum number of cores, which are able to defer their TLB shootdowns.
+const MAX_DEFERRING_CORES: usize = 64;
+
+#[derive(Clone, Copy)]
+struct DeferredFlush {
+	/// Number of nested `with_deferred_flush` scopes
+	depth: usize,
+	/// Whether a shootdown has been suppressed in the current scope
+	pending: bool,
+}
+
+safe_global_var!(static mut DEFERRED_FLUSHES: [DeferredFlush; MAX_DEFERRING_CORES] =
+	[DeferredFlush { depth: 0, pending: false }; MAX_DEFERRING_CORES]);
+
+/// Runs `f`, which may update many page table entries, and flushes the TLBs of the other cores
+/// only once at the end instead of after each operation.
+///
+/// The current core still invalidates each updated entry immediately. The other cores flush all
+/// their entries including the global ones and acknowledge it, before this function returns.
+/// Hence, no stale translation is observable once the scope is closed.
+/// `f` runs with disabled interrupts, so it stays on the current core and must not block.
+/// Scopes may be nested, the outermost one issues the shootdown.
+pub fn with_deferred_flush<F: FnOnce()>(f: F) {
+	let irq = irq::nested_disable();
+	let core_id = core_id();
+	if core_id >= MAX_DEFERRING_CORES {
+		f();
+		irq::nested_enable(irq);
+		return;
+	}
+
+	unsafe {
+		DEFERRED_FLUSHES[core_id].depth += 1;
+	}
+	f();
+	let pending = unsafe {
+		let deferred = &mut DEFERRED_FLUSHES[core_id];
+		deferred.depth -= 1;
+		let pending = deferred.depth == 0 && deferred.pending;
+		if deferred.depth == 0 {
+			deferred.pending = false;
+		}
+		pending
+	};
+
+	if pending {
+		// The shootdown is acknowledged by all cores, which fulfils the promise above.
+		apic::ipi_tlb_flush_sync(true);
+	}
+	irq::nested_enable(irq);
+}
+
+/// Returns `true` and records the shootdown if the current core defers its shootdowns.
+fn defer_flush() -> bool {
+	let irq = irq::nested_disable();
+	let deferred = match unsafe { DEFERRED_FLUSHES.get_mut(core_id()) } {
+		Some(deferred) if deferred.depth > 0 => {
+			deferred.pending = true;
+			true
+		}
+		_ => false,
+	};
+	irq::nested_enable(irq);
+
+	deferred
+}
+
+/// Flushes the TLB entry of `virtual_address` on the current core.
+fn flush_page(virtual_address: usize) {
+	let core_id = core_id();
+	if core_id < MAX_COUNTED_CORES {
+		// An interrupt between the load and the store may lose the flushes of its handler.
+		unsafe {
+			let counter = &mut LOCAL_FLUSHES[core_id].0 as *mut usize;
+			intrinsics::volatile_store(counter, intrinsics::volatile_load(counter) + 1);
+		}
+	}
+	unsafe {
+		asm!("invlpg ($0)" :: "r"(virtual_address) : "memory" : "volatile");
+	}
+}
+
+/// Returns the number of pages, which the current core has flushed from its TLB by INVLPG since the boot.
+pub fn local_flush_count() -> usize {
+	match core_id() {
+		core_id if core_id < MAX_COUNTED_CORES => unsafe { intrinsics::volatile_load(&LOCAL_FLUSHES[core_id].0) },
+		_ => 0,
+	}
+}
+
+/// Lets the other cores flush their TLBs unless the shootdown is deferred.
+fn remote_tlb_flush() {
+	if !defer_flush() {
+		apic::ipi_tlb_flush();
+	}
+}
+
+/// Like `remote_tlb_flush`, but the other cores also flush their global entries.
+fn remote_tlb_flush_global() {
+	if !defer_flush() {
+		apic::ipi_tlb_flush_global();
+	}
+}
+
+/// Returns `true` if the key of the page tables has to be closed on every core,
+/// i.e. the page tables are sealed and the paging code doesn't access them at the moment.
+/// While any core accesses the page tables, the key may be open on all of them.
+pub fn page_tables_closed() -> bool {
+	SEALED.load(Ordering::Relaxed) && OPEN_ACCESSES.load(Ordering::SeqCst) == 0
+}
+
+/// Returns the flags of an entry, which references a new subtable.
+fn table_entry_flags() -> PageTableEntryFlags {
+	let mut flags = PageTableEntryFlags::WRITABLE;
+	if SEALED.load(Ordering::Relaxed) {
+		flags.pkey(mm::PAGE_TABLE_MEM_REGION);
+	}
+
+	flags
+}
+
 /// Number of Offset bits of a virtual address for a 4 KiB page, which are shifted away to get its Page Frame Number (PFN).
 const PAGE_BITS: usize = 12;
 
@@ -73,16 +278,45 @@ bitflags! {
 		/// be flushed from the TLB when CR3 is reset.
 		const GLOBAL = 1 << 8;
 
+		/// Ignored by the CPU: Set if the mapping may be writable and executable at the same time.
+		const ALLOW_WX = 1 << 9;
+
+		/// Ignored by the CPU: Set if the mapping may cover the page at virtual address 0.
+		const ALLOW_NULL = 1 << 10;
+
 		/// Set if code execution shall be disabled for memory referenced by this entry.
 		const EXECUTE_DISABLE = 1 << 63;
 	}
 }
 
+/// Caching policy of a mapping, which is selected by the WRITE_THROUGH and CACHE_DISABLE flags
+/// and, for 4 KiB pages, the PAT bit (see `processor::PAT_LAYOUT`).
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum CachePolicy {
+	/// Reads and writes are cached (default for normal memory)
+	WriteBack,
+	/// Reads are cached, writes go through to memory
+	WriteThrough,
+	/// Nothing is cached (default for device memory)
+	Uncached,
+	/// Nothing is cached, but writes are combined in a buffer before they reach the memory (framebuffers)
+	WriteCombining,
+}
+
+impl Default for CachePolicy {
+	fn default() -> Self {
+		CachePolicy::Uncached
+	}
+}
+
 impl PageTableEntryFlags {
 	/// An empty set of flags for unused/zeroed table entries.
 	/// Needed as long as empty() is no const function.
 	const BLANK: PageTableEntryFlags = PageTableEntryFlags { bits: 0 };
 
+	/// Only for 4 KiB page entries: selects the upper half of the Page Attribute Table.
+	const PAT: PageTableEntryFlags = PageTableEntryFlags::HUGE_PAGE;
+
 	pub fn device(&mut self) -> &mut Self {
 		self.insert(PageTableEntryFlags::CACHE_DISABLE);
 		self
@@ -98,6 +332,23 @@ impl PageTableEntryFlags {
 		self
 	}
 
+	/// Selects the caching policy of a 4 KiB page. Write-combining relies on the PAT bit,
+	/// which shares its position with HUGE_PAGE and has another meaning for larger pages.
+	pub fn cache_policy(&mut self, policy: CachePolicy) -> &mut Self {
+		self.remove(
+			PageTableEntryFlags::WRITE_THROUGH | PageTableEntryFlags::CACHE_DISABLE | PageTableEntryFlags::PAT,
+		);
+		match policy {
+			CachePolicy::WriteBack => {}
+			CachePolicy::WriteThrough => self.insert(PageTableEntryFlags::WRITE_THROUGH),
+			CachePolicy::Uncached => {
+				self.insert(PageTableEntryFlags::WRITE_THROUGH | PageTableEntryFlags::CACHE_DISABLE)
+			}
+			CachePolicy::WriteCombining => self.insert(PageTableEntryFlags::PAT),
+		}
+		self
+	}
+
 	pub fn writable(&mut self) -> &mut Self {
 		self.insert(PageTableEntryFlags::WRITABLE);
 		self
@@ -108,6 +359,29 @@ impl PageTableEntryFlags {
 		self
 	}
 
+	pub fn allow_wx(&mut self) -> &mut Self {
+		self.insert(PageTableEntryFlags::ALLOW_WX);
+		self
+	}
+
+	pub fn global(&mut self) -> &mut Self {
+		self.insert(PageTableEntryFlags::GLOBAL);
+		self
+	}
+
+	pub fn allow_null(&mut self) -> &mut Self {
+		self.insert(PageTableEntryFlags::ALLOW_NULL);
+		self
+	}
+
+	/// Returns `true` if the flags describe a writable and executable mapping, which hasn't been
+	/// explicitly permitted by `allow_wx`.
+	pub fn violates_wx(self) -> bool {
+		self.contains(PageTableEntryFlags::WRITABLE)
+			&& !self.contains(PageTableEntryFlags::EXECUTE_DISABLE)
+			&& !self.contains(PageTableEntryFlags::ALLOW_WX)
+	}
+
 	pub fn pkey(&mut self, key: u8) -> &mut Self {
 		let pkey: usize = (key as usize)& 15;
 		let pkey_flag: PageTableEntryFlags = PageTableEntryFlags { bits: (pkey << 59) };
@@ -144,6 +418,11 @@ impl PageTableEntry {
             & ((BasePageSize::SIZE - 1) | (PageTableEntryFlags::EXECUTE_DISABLE).bits())
     }
 
+	/// Return the protection key stored in this entry.
+	pub fn pkey(self) -> u8 {
+		((self.physical_address_and_flags >> 59) & 0xF) as u8
+	}
+
 	/// Returns whether this entry is valid (present).
 	fn is_present(self) -> bool {
 		(self.physical_address_and_flags & PageTableEntryFlags::PRESENT.bits()) != 0
@@ -155,7 +434,7 @@ impl PageTableEntry {
 	}
 
 	/// Returns `true` if the page is accessible from the user space
-	fn is_user(self) -> bool {
+	pub fn is_user(self) -> bool {
 		(self.physical_address_and_flags & PageTableEntryFlags::USER_ACCESSIBLE.bits()) != 0
 	}
 
@@ -165,23 +444,16 @@ impl PageTableEntry {
 	///
 	/// * `physical_address` - The physical memory address this entry shall translate to
 	/// * `flags` - Flags from PageTableEntryFlags (note that the PRESENT and ACCESSED flags are set automatically)
-	fn set(&mut self, physical_address: usize, flags: PageTableEntryFlags) {
-        if flags.contains(PageTableEntryFlags::HUGE_PAGE) {
-			// HUGE_PAGE may indicate a 2 MiB or 1 GiB page.
-			// We don't know this here, so we can only verify that at least the offset bits for a 2 MiB page are zero.
-			assert!(
-				physical_address % LargePageSize::SIZE == 0,
-				"Physical address is not on a 2 MiB page boundary (physical_address = {:#X})",
-				physical_address
-			);
-		} else {
-			// Verify that the offset bits for a 4 KiB page are zero.
-			assert!(
-				physical_address % BasePageSize::SIZE == 0,
-				"Physical address is not on a 4 KiB page boundary (physical_address = {:#X})",
-				physical_address
-			);
-		}
+	/// * `size` - Size of the mapped page or `BasePageSize::SIZE` for an entry, which references a page table
+	fn set(&mut self, physical_address: usize, flags: PageTableEntryFlags, size: usize) {
+		// HUGE_PAGE can't tell the size of the page, because it is the PAT bit of a 4 KiB page.
+		// Hence, the caller passes the size of the mapped page or of the page table.
+		assert!(
+			physical_address % size == 0,
+			"Physical address is not aligned to the page size {:#X} (physical_address = {:#X})",
+			size,
+			physical_address
+		);
 
 		// Verify that the physical address does not exceed the CPU's physical address width.
 		assert!(
@@ -261,7 +533,7 @@ impl<S: PageSize> Page<S> {
 
 	/// Flushes this page from the TLB of this CPU.
 	fn flush_from_tlb(self) {
-		unsafe {asm!("invlpg ($0)" :: "r"(self.virtual_address) : "memory" : "volatile");}
+		flush_page(self.virtual_address);
 	}
 
 	/// Returns whether the given virtual address is a valid one in the x86-64 memory model.
@@ -431,10 +703,16 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 		let index = page.table_index::<L>();
 		let flush = self.entries[index].is_present();
 
+		if flush {
+			mpk::mpk_page_put(self.entries[index].pkey());
+		}
+
 		self.entries[index].set(
 			physical_address,
 			PageTableEntryFlags::DIRTY | S::MAP_EXTRA_FLAG | flags,
+			S::SIZE,
 		);
+		mpk::mpk_page_get(self.entries[index].pkey());
 
 		if flush {
 			page.flush_from_tlb();
@@ -490,8 +768,10 @@ impl<L: PageTableLevel> PageTableMethods for PageTable<L> {
 		self.entries[index].is_present());
 	*/
 		if self.entries[index].is_present() {
+			mpk::mpk_page_put(self.entries[index].pkey());
 			self.entries[index].physical_address_and_flags = 
 					self.entries[index].physical_address_and_flags & !(0xF << 59) | (pkey as usize)<< 59;
+			mpk::mpk_page_get(pkey);
 			page.flush_from_tlb();
 		} else {
 			panic!("Level {} entry is not present!!", L::LEVEL);
@@ -566,8 +846,10 @@ where
 				let subtable = self.subtable::<S>(page);
 				subtable.set_pkey_on_page_table_entry::<S>(page, pkey);
 			} else {
+				mpk::mpk_page_put(self.entries[index].pkey());
 				self.entries[index].physical_address_and_flags = 
 						self.entries[index].physical_address_and_flags & !(0xF << 59) | (pkey as usize)<< 59;
+				mpk::mpk_page_get(pkey);
 				page.flush_from_tlb();
 			}
 		} else {
@@ -595,7 +877,8 @@ where
 			if !self.entries[index].is_present() {
 				// Allocate a single 4 KiB page for the new entry and mark it as a valid, writable subtable.
 				let physical_address = physicalmem::allocate(BasePageSize::SIZE).unwrap();
-			    self.entries[index].set(physical_address, PageTableEntryFlags::WRITABLE);
+			    self.entries[index].set(physical_address, table_entry_flags(), BasePageSize::SIZE);
+				PAGE_TABLE_PAGES.fetch_add(1, Ordering::SeqCst);
 
 				// Mark all entries as unused in the newly created table.
 				let subtable = self.subtable::<S>(page);
@@ -654,7 +937,7 @@ where
 		}
 
 		if send_ipi {
-			apic::ipi_tlb_flush();
+			remote_tlb_flush();
 		}
 	}
 }
@@ -688,38 +971,189 @@ pub fn get_existing_flags<S: PageSize>(virtual_address: usize) -> usize {
     }
 }
 
-pub fn print_page_table_entry<S: PageSize>(virtual_address: usize) -> usize {
-    let entry: PageTableEntry;
-    //error!("[pkey_print]v address: {:#X} p address: {:#X}", virtual_address, get_physical_address::<S>(virtual_address));
-    if let Some(result) = get_page_table_entry::<S>(virtual_address) {
-        entry = result as PageTableEntry;
-        error!("[print pte]virt: {:#X}, phys1: {:#X}, phys2: {:#X}, PTE: {:#X}", virtual_address, virt_to_phys(virtual_address), get_physical_address::<S>(virtual_address), entry.physical_address_and_flags);
-    } else {
-        panic!("No page table entry for virtual address {:#X}", virtual_address);
-    }
-    return 0;
+/// Prints the decoded entry, which maps `virtual_address`, regardless of the size of its page.
+pub fn print_entry(virtual_address: usize) {
+	let (entry, size) = match get_leaf_entry(virtual_address) {
+		Some(leaf) => leaf,
+		None => {
+			info!("{:#X} isn't mapped", virtual_address);
+			return;
+		}
+	};
+
+	let page_size = match size {
+		HugePageSize::SIZE => "1 GiB",
+		LargePageSize::SIZE => "2 MiB",
+		_ => "4 KiB",
+	};
+	let flag = |flag: PageTableEntryFlags| entry.physical_address_and_flags & flag.bits() != 0;
+
+	info!(
+		"{:#X}: {} page {:#X} -> {:#X}, PTE {:#X}",
+		virtual_address,
+		page_size,
+		align_down!(virtual_address, size),
+		entry.address() & !(size - 1),
+		entry.physical_address_and_flags
+	);
+	// The bit of HUGE_PAGE is the PAT bit of a 4 KiB page (e.g., write-combining memory).
+	info!(
+		"present {}, writable {}, NX {}, user {}, global {}, huge {}, PAT {}, pkey {}",
+		flag(PageTableEntryFlags::PRESENT),
+		flag(PageTableEntryFlags::WRITABLE),
+		flag(PageTableEntryFlags::EXECUTE_DISABLE),
+		flag(PageTableEntryFlags::USER_ACCESSIBLE),
+		flag(PageTableEntryFlags::GLOBAL),
+		size != BasePageSize::SIZE,
+		size == BasePageSize::SIZE && flag(PageTableEntryFlags::PAT),
+		entry.pkey()
+	);
+}
+
+/// Forgets the previous faults of the current task, because the last one has been resolved
+/// or the task continues elsewhere.
+fn reset_fault_streak() {
+	if let Some(mut task) = scheduler::current_task_ref() {
+		task.fault_streak.reset();
+	}
+}
+
+/// Handler of a task, which is invoked instead of aborting the task.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TaskFaultHandler {
+	/// Handler of isolation violations, see `scheduler::set_fault_handler`
+	Isolation(extern "C" fn(i32)),
+	/// Address of the handler of other page faults
+	PageFault(usize),
+}
+
+/// Takes the handler of the task, which is responsible for a fault with `pferror`.
+///
+/// An isolation violation is passed to the fault handler, any other fault to the page fault handler.
+/// The handler is reset, so that a fault inside the handler aborts the task.
+fn take_task_handler(
+	pferror: PageFaultError,
+	fault_handler: &mut Option<extern "C" fn(i32)>,
+	page_fault_handler: &mut Option<usize>,
+) -> Option<TaskFaultHandler> {
+	if pferror.contains(PageFaultError::PK) {
+		if let Some(handler) = fault_handler.take() {
+			return Some(TaskFaultHandler::Isolation(handler));
+		}
+	}
+
+	page_fault_handler.take().map(TaskFaultHandler::PageFault)
 }
 
 pub extern "x86-interrupt" fn page_fault_handler(
 	stack_frame: &mut irq::ExceptionStackFrame,
 	error_code: u64,
 ) {
-	unsafe {
-        asm!("xor %eax, %eax;
-              xor %ecx, %ecx;
-              xor %edx, %edx;
-              wrpkru;
-              lfence"
-             :
-             :
-             : "eax", "ecx", "edx"
-             : "volatile");
-        }
+	let pkru = mpk::mpk_get_pkru();
+	if ::environment::mpk_enabled() {
+		unsafe {
+			asm!("mov $$0x300, %eax;
+			      xor %ecx, %ecx;
+			      xor %edx, %edx;
+			      wrpkru;
+			      lfence"
+				:
+				:
+				: "eax", "ecx", "edx"
+				: "volatile");
+		}
+	}
+	let _gs = GsEntryGuard::new();
 
 	let virtual_address = unsafe { controlregs::cr2() };
+	let pferror = PageFaultError::from_bits_truncate(error_code as u32);
+
+	// A task, which faults at the same address again and again, would never make progress.
+	// Every path, which resolves the fault, ends the streak. Hence, only faults, which are
+	// repeated without any change (e.g., a page mapped by another core), are counted.
+	let streak = scheduler::current_task_ref().map_or(0, |mut task| task.fault_streak.record(virtual_address));
+	if streak > PAGE_FAULT_RETRY_LIMIT {
+		panic!(
+			"Fault storm: {} consecutive page faults at {:#X} (error = {}, instruction_pointer = {:#X})",
+			streak, virtual_address, pferror, stack_frame.instruction_pointer
+		);
+	}
+
+	// A missing page of a reservation with demand paging is mapped and the access is repeated.
+	if !pferror.contains(PageFaultError::P) {
+		match mm::handle_demand_fault(virtual_address) {
+			mm::DemandFault::Mapped => {
+				reset_fault_streak();
+				mpk::mpk_set_pkru(pkru);
+				return;
+			}
+			mm::DemandFault::Retry => {
+				mpk::mpk_set_pkru(pkru);
+				return;
+			}
+			mm::DemandFault::Unhandled => {}
+		}
+	}
+
+	// The first write to a watched page is logged and repeated after the page is writable again.
+	if pferror.contains(PageFaultError::P | PageFaultError::WR)
+		&& !pferror.contains(PageFaultError::PK)
+		&& unwatch_page(virtual_address)
+	{
+		info!(
+			"First write to watched page at {:#X} (instruction_pointer = {:#X})",
+			virtual_address, stack_frame.instruction_pointer
+		);
+		reset_fault_streak();
+		mpk::mpk_set_pkru(pkru);
+		return;
+	}
+
+	// An expected fault of a probe (see `signal::expect_fault`) returns to the caller of the probe.
+	if signal::deliver_expected_fault(stack_frame, virtual_address) {
+		reset_fault_streak();
+		unsafe {
+			controlregs::cr2_write(0);
+		}
+		mpk::mpk_set_pkru(pkru);
+		return;
+	}
+
+	// A fault of user code is passed to a handler of the task, if the task has registered one.
+	let handler = scheduler::current_task_ref().and_then(|mut task| {
+		let task = &mut *task;
+		if !signal::is_user_context(stack_frame, pkru, &task.stacks) {
+			return None;
+		}
+		take_task_handler(pferror, &mut task.fault_handler, &mut task.page_fault_handler)
+	});
+	if let Some(handler) = handler {
+		// The task continues in its handler.
+		reset_fault_streak();
+		match handler {
+			TaskFaultHandler::Isolation(handler) => {
+				info!(
+					"Isolation violation at {:#X} (instruction_pointer = {:#X}), invoking the fault handler of the task",
+					virtual_address, stack_frame.instruction_pointer
+				);
+				signal::deliver_fault(stack_frame, handler);
+			}
+			TaskFaultHandler::PageFault(handler) => {
+				info!(
+					"Page fault at {:#X} (error = {}, instruction_pointer = {:#X}), invoking the page fault handler of the task",
+					virtual_address, pferror, stack_frame.instruction_pointer
+				);
+				signal::deliver_page_fault(stack_frame, handler, virtual_address, pferror.bits());
+			}
+		}
+		unsafe {
+			controlregs::cr2_write(0);
+		}
+		mpk::mpk_set_pkru(pkru);
+		return;
+	}
 
 	// Anything else is an error!
-	let pferror = PageFaultError::from_bits_truncate(error_code as u32);
 	error!("Page Fault (#PF) Exception: {:#?}", stack_frame);
     if pferror.bits() & 0b100000 != 0 {
         error!("virtual_address = {:#X}, page fault error = There was a protection key violation.", virtual_address);
@@ -739,6 +1173,18 @@ pub extern "x86-interrupt" fn page_fault_handler(
 	// clear cr2 to signalize that the pagefault is solved by the pagefault handler
 	unsafe {controlregs::cr2_write(0);}
 
+	if try_core_scheduler().is_none() {
+		// There is no task to abort, because the fault occurred before the scheduler was initialized.
+		error!(
+			"Page fault during early boot: virtual_address = {:#X}, error = {}, instruction_pointer = {:#X}",
+			virtual_address, pferror, stack_frame.instruction_pointer
+		);
+
+		loop {
+			processor::halt();
+		}
+	}
+
 	scheduler::abort();
 }
 
@@ -752,25 +1198,304 @@ fn get_page_range<S: PageSize>(virtual_address: usize, count: usize) -> PageIter
 pub fn get_page_table_entry<S: PageSize>(virtual_address: usize) -> Option<PageTableEntry> {
 	trace!("Looking up Page Table Entry for {:#X}", virtual_address);
 
+	let _access = PageTableAccess::open();
 	let page = Page::<S>::including_address(virtual_address);
 	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
 	root_pagetable.get_page_table_entry(page)
 }
 
+/// Returns the entry that finally maps the given virtual address together with the size
+/// of the page it maps, regardless of whether a 4 KiB, 2 MiB or 1 GiB page is used.
+pub fn get_leaf_entry(virtual_address: usize) -> Option<(PageTableEntry, usize)> {
+	if processor::supports_1gib_pages() {
+		let entry = get_page_table_entry::<HugePageSize>(virtual_address)?;
+		if entry.is_huge() {
+			return Some((entry, HugePageSize::SIZE));
+		}
+	}
+
+	let entry = get_page_table_entry::<LargePageSize>(virtual_address)?;
+	if entry.is_huge() {
+		return Some((entry, LargePageSize::SIZE));
+	}
+
+	get_page_table_entry::<BasePageSize>(virtual_address).map(|entry| (entry, BasePageSize::SIZE))
+}
+
+/// Accesses to a virtual address, which are possible without a fault.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Access {
+	pub read: bool,
+	pub write: bool,
+	pub execute: bool,
+}
+
+impl Access {
+	/// Access rights of an unmapped address.
+	pub const NONE: Access = Access {
+		read: false,
+		write: false,
+		execute: false,
+	};
+
+	/// Combines the flags of a present leaf entry with the permission, which `pkru` grants to `pkey`.
+	///
+	/// Protection keys only restrict data accesses. Instruction fetches are controlled by the NX bit alone.
+	fn from_entry(flags: PageTableEntryFlags, pkey: u8, pkru: u32) -> Self {
+		Access {
+			read: mpk::would_allow(pkru, pkey, false),
+			write: mpk::would_allow(pkru, pkey, true) && flags.contains(PageTableEntryFlags::WRITABLE),
+			execute: !flags.contains(PageTableEntryFlags::EXECUTE_DISABLE),
+		}
+	}
+}
+
+/// Predicts, which accesses to `virtual_address` are possible with the current PKRU value.
+///
+/// Only the flags of the leaf entry are taken into account, the tables above it are expected to grant full access.
+pub fn effective_access(virtual_address: usize) -> Access {
+	match get_leaf_entry(virtual_address) {
+		Some((entry, _)) => Access::from_entry(
+			PageTableEntryFlags::from_bits_truncate(entry.get_flags()),
+			entry.pkey(),
+			mpk::mpk_get_pkru(),
+		),
+		None => Access::NONE,
+	}
+}
+
+/// Returns a pointer to the entry of the table of the given level (from 0 for PT through 3 for PML4),
+/// which translates the given virtual address. The entry is accessed through the self-reference.
+fn entry_pointer(level: usize, virtual_address: usize) -> *mut PageTableEntry {
+	let (table, shift) = match level {
+		3 => (0xFFFF_FFFF_FFFF_F000usize, 39),
+		2 => (0xFFFF_FFFF_FFE0_0000usize, 30),
+		1 => (0xFFFF_FFFF_C000_0000usize, 21),
+		_ => (0xFFFF_FF80_0000_0000usize, 12),
+	};
+	let index = (virtual_address & 0xFFFF_FFFF_FFFF) >> shift;
+
+	unsafe { (table as *mut PageTableEntry).add(index) }
+}
+
+/// Size of the virtual address range covered by a single PML4 entry (512 GiB).
+const PML4_ENTRY_SPAN: usize = 1 << (PAGE_BITS + 3 * PAGE_MAP_BITS);
+
+/// An iterator over all mapped virtual memory regions.
+///
+/// Contiguous pages with identical flags and protection key are coalesced into a single region.
+/// Each region is returned as `(start, length, flags, pkey)`.
+pub struct MappedRegions {
+	/// Next address to look at, without the sign extension of canonical addresses.
+	position: usize,
+	/// Region, which has already been read but does not belong to the previously returned one.
+	pending: Option<(usize, usize, PageTableEntryFlags, u8)>,
+}
+
+impl MappedRegions {
+	/// Returns the canonical form of the given 48-bit address.
+	fn canonical(address: usize) -> usize {
+		if address & (1 << 47) != 0 {
+			address | 0xFFFF_0000_0000_0000
+		} else {
+			address
+		}
+	}
+
+	/// Reads the entry at the given index of the table of the given level through the self-reference.
+	fn read_entry(level: usize, address: usize) -> PageTableEntry {
+		let _access = PageTableAccess::open();
+		unsafe { *entry_pointer(level, address) }
+	}
+
+	/// Returns the next mapped page as `(virtual address, page size, entry)`.
+	fn next_page(&mut self) -> Option<(usize, usize, PageTableEntry)> {
+		// The last PML4 entry is the self-reference and doesn't map any regular memory.
+		while self.position < (1 << PAGE_MAP_BITS) * PML4_ENTRY_SPAN - PML4_ENTRY_SPAN {
+			let mut size = PML4_ENTRY_SPAN;
+
+			for level in (0..4).rev() {
+				let entry = Self::read_entry(level, self.position);
+				if !entry.is_present() {
+					break;
+				}
+
+				if level == 0 || (level < 3 && entry.is_huge()) {
+					let address = Self::canonical(self.position);
+					self.position += size;
+					return Some((address, size, entry));
+				}
+
+				size >>= PAGE_MAP_BITS;
+			}
+
+			// Nothing is mapped in the remaining part of the current table, skip it.
+			self.position = align_down!(self.position, size) + size;
+		}
+
+		None
+	}
+
+	fn next_region(&mut self) -> Option<(usize, usize, PageTableEntryFlags, u8)> {
+		self.next_page().map(|(address, size, entry)| {
+			let mut flags = PageTableEntryFlags::from_bits_truncate(entry.physical_address_and_flags);
+			// The hardware updates these flags, so they shall not prevent coalescing.
+			flags.remove(PageTableEntryFlags::ACCESSED | PageTableEntryFlags::DIRTY);
+			(address, size, flags, entry.pkey())
+		})
+	}
+}
+
+impl Iterator for MappedRegions {
+	type Item = (usize, usize, PageTableEntryFlags, u8);
+
+	fn next(&mut self) -> Option<Self::Item> {
+		let mut region = self.pending.take().or_else(|| self.next_region())?;
+
+		while let Some(next) = self.next_region() {
+			if next.0 == region.0 + region.1 && next.2 == region.2 && next.3 == region.3 {
+				region.1 += next.1;
+			} else {
+				self.pending = Some(next);
+				break;
+			}
+		}
+
+		Some(region)
+	}
+}
+
+/// Returns an iterator over all mapped virtual memory regions.
+pub fn mapped_regions() -> MappedRegions {
+	MappedRegions {
+		position: 0,
+		pending: None,
+	}
+}
+
+/// Returns the virtual addresses of all pages, which have been written since the last call with
+/// `clear` set. Each address is the start of a page of 4 KiB, 2 MiB or 1 GiB.
+///
+/// If `clear` is set, the DIRTY flag of the returned pages is reset, so the next pass only
+/// captures pages, which have been dirtied in the meantime (incremental checkpointing).
+pub fn collect_dirty_pages(clear: bool) -> Vec<usize> {
+	let _access = PageTableAccess::open();
+	let mut regions = mapped_regions();
+	let mut dirty = Vec::new();
+
+	while let Some((address, size, entry)) = regions.next_page() {
+		if entry.physical_address_and_flags & PageTableEntryFlags::DIRTY.bits() == 0 {
+			continue;
+		}
+
+		dirty.push(address);
+
+		if clear {
+			let level = match size {
+				HugePageSize::SIZE => HugePageSize::MAP_LEVEL,
+				LargePageSize::SIZE => LargePageSize::MAP_LEVEL,
+				_ => BasePageSize::MAP_LEVEL,
+			};
+			unsafe {
+				intrinsics::atomic_and(
+					entry_pointer(level, address) as *mut usize,
+					!PageTableEntryFlags::DIRTY.bits(),
+				);
+				flush_page(address);
+			}
+		}
+	}
+
+	if clear && !dirty.is_empty() {
+		remote_tlb_flush();
+	}
+
+	dirty
+}
+
+/// Returns the number of pages per protection key, which have been accessed since the previous call.
+///
+/// The ACCESSED flag of the counted pages is cleared, so the next call samples a fresh interval.
+/// A page of 2 MiB or 1 GiB counts as a single page.
+pub fn sample_access_by_key() -> [u64; 16] {
+	let _access = PageTableAccess::open();
+	let mut regions = mapped_regions();
+	let mut accessed = [0u64; 16];
+
+	while let Some((address, size, entry)) = regions.next_page() {
+		if entry.physical_address_and_flags & PageTableEntryFlags::ACCESSED.bits() == 0 {
+			continue;
+		}
+
+		accessed[usize::from(entry.pkey())] += 1;
+		unsafe {
+			intrinsics::atomic_and(
+				entry_pointer(leaf_level(size), address) as *mut usize,
+				!PageTableEntryFlags::ACCESSED.bits(),
+			);
+			flush_page(address);
+		}
+	}
+
+	if accessed.iter().any(|&count| count > 0) {
+		remote_tlb_flush();
+	}
+
+	accessed
+}
+
 pub fn set_page_table_entry<S: PageSize>(virtual_address: usize, entry: usize) {
 	trace!("Looking up Page Table Entry for {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
+	let _access = PageTableAccess::open();
 	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
 	root_pagetable.set_page_table_entry(page, entry);
 }
 
+/// Tags `count` pages at `virtual_address` with the protection key `pkey`.
+///
+/// The key is part of the cached translation, so the TLB entry of a re-keyed page has to be flushed.
+/// The current core flushes every page by INVLPG, which also removes global entries.
+/// The other cores may also cache the translation, so they are interrupted as well.
+/// For global pages, they flush their global entries, because the reload of CR3 by the
+/// TLB Flush Interrupt doesn't remove them and an access through the stale entry would
+/// still be checked against the old key.
 pub fn set_pkey_on_page_table_entry<S: PageSize>(virtual_address: usize, count: usize, pkey: u8) {
 	trace!("Looking up Page Table Entry for {:#X}", virtual_address);
-	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
-	for i in 0..count {
-		let page = Page::<S>::including_address(virtual_address + S::SIZE*i);
-		root_pagetable.set_pkey_on_page_table_entry(page, pkey);
+	let mut global = false;
+	{
+		let _access = PageTableAccess::open();
+		let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+		for i in 0..count {
+			let page = Page::<S>::including_address(virtual_address + S::SIZE*i);
+			global |= root_pagetable
+				.get_page_table_entry(page)
+				.map_or(false, |entry| entry.get_flags() & PageTableEntryFlags::GLOBAL.bits() != 0);
+			root_pagetable.set_pkey_on_page_table_entry(page, pkey);
+		}
+	}
+
+	if global {
+		remote_tlb_flush_global();
+	} else if count > 0 {
+		remote_tlb_flush();
+	}
+}
+
+/// Flushes all TLB entries of the current core including the global ones.
+/// Toggling CR4.PGE removes the global entries, while a reload of CR3 keeps them.
+pub fn flush_tlb_with_global() {
+	use x86::controlregs::Cr4;
+
+	unsafe {
+		let cr4 = controlregs::cr4();
+		if cr4.contains(Cr4::CR4_ENABLE_GLOBAL_PAGES) {
+			controlregs::cr4_write(cr4 - Cr4::CR4_ENABLE_GLOBAL_PAGES);
+			controlregs::cr4_write(cr4);
+		} else {
+			controlregs::cr3_write(controlregs::cr3());
+		}
 	}
 }
 
@@ -778,6 +1503,7 @@ pub fn get_physical_address<S: PageSize>(virtual_address: usize) -> usize {
 	trace!("Getting physical address forlet new_entry =  {:#X}", virtual_address);
 
 	let page = Page::<S>::including_address(virtual_address);
+	let _access = PageTableAccess::open();
 	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
     let address = root_pagetable
 		.get_page_table_entry(page)
@@ -801,6 +1527,7 @@ pub fn virtual_to_physical(virtual_address: usize) -> usize {
 		]
 	});
 
+	let _access = PageTableAccess::open();
 	for i in (0..3).rev() {
 		page_bits = page_bits - PAGE_MAP_BITS;
 
@@ -826,12 +1553,75 @@ pub extern "C" fn virt_to_phys(virtual_address: usize) -> usize {
 	virtual_to_physical(virtual_address)
 }
 
-pub fn map<S: PageSize>(
-	virtual_address: usize,
-	physical_address: usize,
-	count: usize,
-	flags: PageTableEntryFlags,
-) {
+/// Returns `true` if the page of size S at `virtual_address` may be mapped with `flags`.
+///
+/// Writable and executable mappings require `allow_wx`. A mapping, which covers the page at
+/// virtual address 0, requires `allow_null`, so the null pointer keeps trapping.
+/// Pages, which have been frozen by `freeze`, are never replaced.
+fn is_permitted_mapping<S: PageSize>(virtual_address: usize, flags: PageTableEntryFlags) -> bool {
+	debug_assert!(
+		!flags.violates_wx(),
+		"Writable and executable mapping at virtual address {:#X}",
+		virtual_address
+	);
+	if flags.violates_wx() {
+		warn!(
+			"Refuse writable and executable mapping at virtual address {:#X}",
+			virtual_address
+		);
+		return false;
+	}
+
+	if align_down!(virtual_address, S::SIZE) == 0 && !flags.contains(PageTableEntryFlags::ALLOW_NULL) {
+		warn!(
+			"Refuse mapping at virtual address {:#X}, which covers the null page",
+			virtual_address
+		);
+		return false;
+	}
+
+	if is_frozen(align_down!(virtual_address, S::SIZE), S::SIZE) {
+		warn!(
+			"Refuse mapping at virtual address {:#X}, which covers a frozen page",
+			virtual_address
+		);
+		return false;
+	}
+
+	true
+}
+
+/// Returns `true` if each of the `count` pages of size S starting at `virtual_address`
+/// may be mapped with `flags` (see `is_permitted_mapping`).
+fn is_permitted_range<S: PageSize>(virtual_address: usize, count: usize, flags: PageTableEntryFlags) -> bool {
+	(0..count).all(|i| is_permitted_mapping::<S>(virtual_address + i * S::SIZE, flags))
+}
+
+/// Checks that both base addresses of a mapping are aligned to the page size.
+///
+/// Otherwise, an unaligned virtual address would be silently rounded down and
+/// an unaligned physical address would only fail deep inside `PageTableEntry::set`.
+fn assert_aligned<S: PageSize>(virtual_address: usize, physical_address: usize) {
+	assert!(
+		virtual_address % S::SIZE == 0,
+		"Virtual address {:#X} of the mapping isn't aligned to the page size {:#X}",
+		virtual_address,
+		S::SIZE
+	);
+	assert!(
+		physical_address % S::SIZE == 0,
+		"Physical address {:#X} of the mapping isn't aligned to the page size {:#X}",
+		physical_address,
+		S::SIZE
+	);
+}
+
+pub fn map<S: PageSize>(
+	virtual_address: usize,
+	physical_address: usize,
+	count: usize,
+	flags: PageTableEntryFlags,
+) {
 	trace!(
 		"Mapping virtual address {:#X} to physical address {:#X} ({} pages)",
 		virtual_address,
@@ -839,11 +1629,804 @@ pub fn map<S: PageSize>(
 		count
 	);
 
+	assert_aligned::<S>(virtual_address, physical_address);
+	if !is_permitted_range::<S>(virtual_address, count, flags) {
+		return;
+	}
+
+	let _access = PageTableAccess::open();
 	let range = get_page_range::<S>(virtual_address, count);
 	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
 	root_pagetable.map_pages(range, physical_address, flags);
 }
 
+/// Maps `count` base pages to the physically contiguous frames at `physical_address`.
+///
+/// If the pages share a single PT, only the first page walks the page table hierarchy and
+/// creates missing tables. The remaining entries are written directly into the same PT.
+/// Otherwise, this behaves like `map`.
+pub fn map_cluster(
+	virtual_address: usize,
+	physical_address: usize,
+	count: usize,
+	flags: PageTableEntryFlags,
+) {
+	let last_page = virtual_address + count.saturating_sub(1) * BasePageSize::SIZE;
+	if count < 2
+		|| align_down!(virtual_address, LargePageSize::SIZE) != align_down!(last_page, LargePageSize::SIZE)
+	{
+		map::<BasePageSize>(virtual_address, physical_address, count, flags);
+		return;
+	}
+
+	assert_aligned::<BasePageSize>(virtual_address, physical_address);
+	// The remaining entries are written directly, so every page is checked up front.
+	if !is_permitted_range::<BasePageSize>(virtual_address, count, flags) {
+		return;
+	}
+
+	let _access = PageTableAccess::open();
+	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+	let mut send_ipi = root_pagetable.map_page::<BasePageSize>(
+		Page::<BasePageSize>::including_address(virtual_address),
+		physical_address,
+		flags,
+	);
+
+	for i in 1..count {
+		let address = virtual_address + i * BasePageSize::SIZE;
+		let entry = unsafe { &mut *entry_pointer(BasePageSize::MAP_LEVEL, address) };
+		let flush = entry.is_present();
+
+		if flush {
+			mpk::mpk_page_put(entry.pkey());
+		}
+		entry.set(
+			physical_address + i * BasePageSize::SIZE,
+			PageTableEntryFlags::DIRTY | flags,
+			BasePageSize::SIZE,
+		);
+		mpk::mpk_page_get(entry.pkey());
+
+		if flush {
+			Page::<BasePageSize>::including_address(address).flush_from_tlb();
+			send_ipi = true;
+		}
+	}
+
+	if send_ipi {
+		remote_tlb_flush();
+	}
+}
+
+/// Maps a single page of size S without iterating over a page range.
+pub fn map_page<S: PageSize>(virtual_address: usize, physical_address: usize, flags: PageTableEntryFlags) {
+	assert_aligned::<S>(virtual_address, physical_address);
+	if !is_permitted_mapping::<S>(virtual_address, flags) {
+		return;
+	}
+
+	let _access = PageTableAccess::open();
+	let page = Page::<S>::including_address(virtual_address);
+	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
+	if root_pagetable.map_page::<S>(page, physical_address, flags) {
+		remote_tlb_flush();
+	}
+}
+
+/// Maps a single page of size S like `map_page`, unless the page is already mapped.
+///
+/// Missing page tables and the entry itself are installed by compare-exchange, so concurrent callers
+/// (e.g., the page fault handlers of several cores) never overwrite each other's frame.
+/// Returns `false` if the page is mapped already or the mapping isn't permitted, the caller keeps its frame then.
+pub fn map_page_if_absent<S: PageSize>(
+	virtual_address: usize,
+	physical_address: usize,
+	flags: PageTableEntryFlags,
+) -> bool {
+	assert_aligned::<S>(virtual_address, physical_address);
+	if !is_permitted_mapping::<S>(virtual_address, flags) {
+		return false;
+	}
+
+	let _access = PageTableAccess::open();
+	let page = Page::<S>::including_address(virtual_address);
+	for level in (S::MAP_LEVEL + 1..4).rev() {
+		let entry = entry_pointer(level, page.address());
+		let current = unsafe { *entry };
+		if current.is_present() {
+			if current.is_huge() {
+				return false;
+			}
+			continue;
+		}
+
+		// The new table is cleared before it becomes visible.
+		let table = physicalmem::allocate(BasePageSize::SIZE).unwrap();
+		zero_frames(table, BasePageSize::SIZE);
+		let mut table_entry = PageTableEntry {
+			physical_address_and_flags: 0,
+		};
+		table_entry.set(table, table_entry_flags(), BasePageSize::SIZE);
+		let (_, installed) = unsafe {
+			intrinsics::atomic_cxchg(
+				entry as *mut usize,
+				current.physical_address_and_flags,
+				table_entry.physical_address_and_flags,
+			)
+		};
+		if installed {
+			PAGE_TABLE_PAGES.fetch_add(1, Ordering::SeqCst);
+		} else {
+			// Another core has installed the table in the meantime.
+			physicalmem::deallocate(table, BasePageSize::SIZE);
+		}
+	}
+
+	let entry = entry_pointer(S::MAP_LEVEL, page.address());
+	let current = unsafe { *entry };
+	if current.is_present() {
+		return false;
+	}
+
+	let mut new_entry = PageTableEntry {
+		physical_address_and_flags: 0,
+	};
+	new_entry.set(
+		physical_address,
+		PageTableEntryFlags::DIRTY | S::MAP_EXTRA_FLAG | flags,
+		S::SIZE,
+	);
+	// Entries, which aren't present, aren't cached by the TLBs, so no flush is required.
+	let (_, installed) = unsafe {
+		intrinsics::atomic_cxchg(
+			entry as *mut usize,
+			current.physical_address_and_flags,
+			new_entry.physical_address_and_flags,
+		)
+	};
+	if installed {
+		mpk::mpk_page_get(new_entry.pkey());
+	}
+
+	installed
+}
+
+/// Atomically repoints the mapped page of size S at `virtual_address` to `physical_address`.
+///
+/// The page table entry is replaced by a single atomic exchange, so the page never becomes unmapped
+/// and every access observes either the previous or the new frame.
+/// Other cores may keep using the previous frame until they have handled the TLB shootdown.
+///
+/// Returns the previous physical frame, which the caller may recycle.
+pub fn remap_atomic<S: PageSize>(
+	virtual_address: usize,
+	physical_address: usize,
+	flags: PageTableEntryFlags,
+) -> usize {
+	trace!(
+		"Atomically remapping virtual address {:#X} to physical address {:#X}",
+		virtual_address,
+		physical_address
+	);
+
+	assert_aligned::<S>(virtual_address, physical_address);
+	let _access = PageTableAccess::open();
+	let page = Page::<S>::including_address(virtual_address);
+	if get_page_table_entry::<S>(page.address()).is_none() {
+		panic!("No page table entry for virtual address {:#X}", virtual_address);
+	}
+
+	let mut new_entry = PageTableEntry {
+		physical_address_and_flags: 0,
+	};
+	new_entry.set(
+		physical_address,
+		PageTableEntryFlags::DIRTY | S::MAP_EXTRA_FLAG | flags,
+		S::SIZE,
+	);
+
+	let entry = entry_pointer(S::MAP_LEVEL, page.address());
+	let old_entry = PageTableEntry {
+		physical_address_and_flags: unsafe {
+			intrinsics::atomic_xchg(
+				entry as *mut usize,
+				new_entry.physical_address_and_flags,
+			)
+		},
+	};
+
+	mpk::mpk_page_get(new_entry.pkey());
+	mpk::mpk_page_put(old_entry.pkey());
+
+	page.flush_from_tlb();
+	remote_tlb_flush();
+
+	old_entry.address()
+}
+
+/// Replaces the 2 MiB page at `virtual_address` by a page table of 4 KiB pages.
+///
+/// The new pages translate to the same physical memory and keep the flags and the protection key
+/// of the large page. The memory stays accessible during the split, so it may hold live data.
+pub fn split_large_page(virtual_address: usize) {
+	let _access = PageTableAccess::open();
+	let page = Page::<LargePageSize>::including_address(virtual_address);
+	let entry = entry_pointer(LargePageSize::MAP_LEVEL, page.address());
+	let old_entry = match get_page_table_entry::<LargePageSize>(page.address()) {
+		Some(entry) if entry.is_huge() => entry,
+		_ => panic!("No 2 MiB page at virtual address {:#X}", virtual_address),
+	};
+
+	// Fill the new table through a temporary mapping before it becomes visible.
+	let table_physical = physicalmem::allocate(BasePageSize::SIZE).unwrap();
+	PAGE_TABLE_PAGES.fetch_add(1, Ordering::SeqCst);
+	let table_virtual = virtualmem::allocate(BasePageSize::SIZE).unwrap();
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable().pkey(mm::SAFE_MEM_REGION);
+	map::<BasePageSize>(table_virtual, table_physical, 1, flags);
+
+	let base_flags = (old_entry.get_flags() & !PageTableEntryFlags::HUGE_PAGE.bits())
+		| ((old_entry.pkey() as usize) << 59);
+	let table = unsafe { &mut *(table_virtual as *mut PageTable<PT>) };
+	for (i, base_entry) in table.entries.iter_mut().enumerate() {
+		base_entry.physical_address_and_flags =
+			(old_entry.address() + i * BasePageSize::SIZE) | base_flags;
+	}
+
+	unmap::<BasePageSize>(table_virtual, 1);
+	virtualmem::deallocate(table_virtual, BasePageSize::SIZE);
+
+	let mut new_entry = PageTableEntry {
+		physical_address_and_flags: 0,
+	};
+	new_entry.set(table_physical, table_entry_flags(), BasePageSize::SIZE);
+	unsafe {
+		intrinsics::atomic_store(entry as *mut usize, new_entry.physical_address_and_flags);
+	}
+
+	mpk::mpk_page_put(old_entry.pkey());
+	for _ in 0..LargePageSize::SIZE / BasePageSize::SIZE {
+		mpk::mpk_page_get(old_entry.pkey());
+	}
+
+	page.flush_from_tlb();
+	remote_tlb_flush();
+}
+
+/// Replaces the 4 KiB pages of each 2 MiB-aligned range in `[virtual_address, virtual_address + size)`
+/// by a single 2 MiB page, if the 512 pages translate to contiguous, 2 MiB-aligned physical memory
+/// with identical flags and protection key. The page table of the promoted range is released.
+///
+/// This is the reverse of `split_large_page`. Returns `false` if no range could be promoted.
+pub fn try_promote(virtual_address: usize, size: usize) -> bool {
+	let start = align_up!(virtual_address, LargePageSize::SIZE);
+	let end = align_down!(virtual_address + size, LargePageSize::SIZE);
+	let mut promoted = false;
+
+	for address in (start..end).step_by(LargePageSize::SIZE) {
+		promoted |= promote_large_page(address);
+	}
+
+	promoted
+}
+
+fn promote_large_page(virtual_address: usize) -> bool {
+	let _access = PageTableAccess::open();
+	match get_page_table_entry::<LargePageSize>(virtual_address) {
+		Some(entry) if !entry.is_huge() => {}
+		_ => return false,
+	}
+
+	// The hardware updates ACCESSED and DIRTY, so they don't prevent the promotion.
+	// HUGE_PAGE is the PAT bit of a 4 KiB page and has another meaning for a 2 MiB page.
+	let volatile_bits = PageTableEntryFlags::ACCESSED.bits() | PageTableEntryFlags::DIRTY.bits();
+	let first = unsafe { *entry_pointer(BasePageSize::MAP_LEVEL, virtual_address) };
+	let first_bits = first.physical_address_and_flags & !volatile_bits;
+	if !first.is_present()
+		|| first.address() % LargePageSize::SIZE != 0
+		|| first.get_flags() & PageTableEntryFlags::HUGE_PAGE.bits() != 0
+	{
+		return false;
+	}
+
+	let count = LargePageSize::SIZE / BasePageSize::SIZE;
+	let contiguous = (1..count).all(|i| {
+		let entry = unsafe { *entry_pointer(BasePageSize::MAP_LEVEL, virtual_address + i * BasePageSize::SIZE) };
+		entry.physical_address_and_flags & !volatile_bits == first_bits + i * BasePageSize::SIZE
+	});
+	if !contiguous {
+		return false;
+	}
+
+	let mut flags = PageTableEntryFlags::from_bits_truncate(first.get_flags());
+	flags.remove(PageTableEntryFlags::ACCESSED | PageTableEntryFlags::DIRTY);
+	flags.pkey(first.pkey());
+	let mut new_entry = PageTableEntry {
+		physical_address_and_flags: 0,
+	};
+	new_entry.set(
+		first.address(),
+		PageTableEntryFlags::DIRTY | LargePageSize::MAP_EXTRA_FLAG | flags,
+		LargePageSize::SIZE,
+	);
+
+	let entry = entry_pointer(LargePageSize::MAP_LEVEL, virtual_address);
+	let table = PageTableEntry {
+		physical_address_and_flags: unsafe {
+			intrinsics::atomic_xchg(entry as *mut usize, new_entry.physical_address_and_flags)
+		},
+	};
+
+	mpk::mpk_page_get(first.pkey());
+	for _ in 0..count {
+		mpk::mpk_page_put(first.pkey());
+	}
+
+	for page in (virtual_address..virtual_address + LargePageSize::SIZE).step_by(BasePageSize::SIZE) {
+		flush_page(page);
+	}
+	// Other cores may still walk the old table through their paging-structure caches, so it is only
+	// reused after all of them have flushed. The shootdown isn't deferred by `with_deferred_flush`.
+	apic::ipi_tlb_flush_sync(flags.contains(PageTableEntryFlags::GLOBAL));
+
+	// Tables of the loader aren't part of the physical memory allocator.
+	if physicalmem::is_managed(table.address()) {
+		physicalmem::deallocate(table.address(), BasePageSize::SIZE);
+		PAGE_TABLE_PAGES.fetch_sub(1, Ordering::SeqCst);
+	}
+
+	true
+}
+
+/// Returns the pages in `[start, end)`, which haven't been accessed since the previous call.
+///
+/// The ACCESSED flag of all other pages is cleared, so that the next call only sees the accesses in between.
+/// Each page is returned with its size and its entry, which can be passed to `take_zero_page`.
+pub fn idle_pages(start: usize, end: usize) -> Vec<(usize, usize, PageTableEntry)> {
+	let _access = PageTableAccess::open();
+	let mut idle = Vec::new();
+	let mut send_ipi = false;
+	let mut address = start;
+
+	while address < end {
+		let (entry, size) = match get_leaf_entry(address) {
+			Some(leaf) => leaf,
+			None => {
+				address += BasePageSize::SIZE;
+				continue;
+			}
+		};
+		let page = align_down!(address, size);
+		address = page + size;
+		if page < start || page + size > end {
+			continue;
+		}
+
+		if entry.physical_address_and_flags & PageTableEntryFlags::ACCESSED.bits() != 0 {
+			unsafe {
+				intrinsics::atomic_and(
+					entry_pointer(leaf_level(size), page) as *mut usize,
+					!PageTableEntryFlags::ACCESSED.bits(),
+				);
+				flush_page(page);
+			}
+			send_ipi = true;
+		} else {
+			idle.push((page, size, entry));
+		}
+	}
+
+	if send_ipi {
+		remote_tlb_flush();
+	}
+
+	idle
+}
+
+/// Unmaps the idle page at `virtual_address`, which has been returned by `idle_pages`,
+/// if it only contains zeros and its entry is still unchanged.
+///
+/// No core has cached the translation since its ACCESSED flag has been cleared.
+/// Hence, the contents can't change without setting the flag, which lets the exchange of the entry fail.
+/// On success, the caller becomes the owner of the frame.
+pub fn take_zero_page(virtual_address: usize, size: usize, entry: PageTableEntry) -> bool {
+	if !is_zero_frame(entry.address(), size) {
+		return false;
+	}
+
+	let _access = PageTableAccess::open();
+	let level = leaf_level(size);
+	let (_, exchanged) = unsafe {
+		intrinsics::atomic_cxchg(
+			entry_pointer(level, virtual_address) as *mut usize,
+			entry.physical_address_and_flags,
+			0,
+		)
+	};
+	if exchanged {
+		mpk::mpk_page_put(entry.pkey());
+		reclaim_tables(level, virtual_address);
+	}
+
+	exchanged
+}
+
+/// Returns the level of the table, which holds the entry of a page of the given size.
+fn leaf_level(size: usize) -> usize {
+	match size {
+		HugePageSize::SIZE => HugePageSize::MAP_LEVEL,
+		LargePageSize::SIZE => LargePageSize::MAP_LEVEL,
+		_ => BasePageSize::MAP_LEVEL,
+	}
+}
+
+/// Returns `true` if the frame of `size` bytes at `physical_address` only contains zeros.
+/// The frame is read through a temporary mapping, so the ACCESSED flags of its other mappings are kept.
+fn is_zero_frame(physical_address: usize, size: usize) -> bool {
+	let virtual_address = virtualmem::allocate(size).unwrap();
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().read_only().execute_disable();
+	map::<BasePageSize>(virtual_address, physical_address, size / BasePageSize::SIZE, flags);
+
+	let words = unsafe { slice::from_raw_parts(virtual_address as *const u64, size / mem::size_of::<u64>()) };
+	let zero = words.iter().all(|word| *word == 0);
+
+	unmap::<BasePageSize>(virtual_address, size / BasePageSize::SIZE);
+	virtualmem::deallocate(virtual_address, size);
+
+	zero
+}
+
+/// Fills the frames of `size` bytes at `physical_address` with zeros through a temporary mapping.
+///
+/// The frames are cleared before they are mapped at their final address,
+/// so that no other core can observe their previous contents.
+pub fn zero_frames(physical_address: usize, size: usize) {
+	let virtual_address = virtualmem::allocate(size).unwrap();
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable();
+	map::<BasePageSize>(virtual_address, physical_address, size / BasePageSize::SIZE, flags);
+
+	unsafe {
+		write_bytes(virtual_address as *mut u8, 0, size);
+	}
+
+	unmap::<BasePageSize>(virtual_address, size / BasePageSize::SIZE);
+	virtualmem::deallocate(virtual_address, size);
+}
+
+/// Makes the writable pages of `[virtual_address, virtual_address + size)` read-only until their first write.
+///
+/// The page fault handler logs the instruction pointer of the first write to a watched page,
+/// makes the page writable again and repeats the write. Covered 2 MiB pages are split,
+/// so that every 4 KiB page is watched on its own. Fails if a page isn't mapped or is a 1 GiB page.
+pub fn watch_region(virtual_address: usize, size: usize) -> Result<(), ()> {
+	let start = align_down!(virtual_address, BasePageSize::SIZE);
+	let end = align_up!(virtual_address + size, BasePageSize::SIZE);
+
+	// Splitting unmaps a temporary mapping, so it has to happen before the watch list is locked.
+	split_into_base_pages(start, end)?;
+
+	let _access = PageTableAccess::open();
+	let mut watched = WATCHED_PAGES.lock();
+	for page in (start..end).step_by(BasePageSize::SIZE) {
+		let old_flags = unsafe {
+			intrinsics::atomic_and(
+				entry_pointer(BasePageSize::MAP_LEVEL, page) as *mut usize,
+				!PageTableEntryFlags::WRITABLE.bits(),
+			)
+		};
+		if old_flags & PageTableEntryFlags::WRITABLE.bits() != 0 && !watched.contains(&page) {
+			watched.push(page);
+		}
+
+		flush_page(page);
+	}
+
+	remote_tlb_flush();
+	Ok(())
+}
+
+/// Splits the 2 MiB pages, which cover `[start, end)`, so that the range is mapped by 4 KiB pages.
+/// Fails if a page isn't mapped or is a 1 GiB page.
+fn split_into_base_pages(start: usize, end: usize) -> Result<(), ()> {
+	for page in (start..end).step_by(BasePageSize::SIZE) {
+		match get_leaf_entry(page) {
+			None | Some((_, HugePageSize::SIZE)) => return Err(()),
+			_ => {}
+		}
+	}
+
+	for page in (start..end).step_by(LargePageSize::SIZE) {
+		if let Some((_, LargePageSize::SIZE)) = get_leaf_entry(page) {
+			split_large_page(page);
+		}
+	}
+	if let Some((_, LargePageSize::SIZE)) = get_leaf_entry(end - BasePageSize::SIZE) {
+		split_large_page(end - BasePageSize::SIZE);
+	}
+
+	Ok(())
+}
+
+/// Sets or clears `WRITABLE` on the pages of `[virtual_address, virtual_address + size)`.
+/// Covered 2 MiB pages are split. Fails if a page isn't mapped or is a 1 GiB page.
+///
+/// A writable page is never executable, so `EXECUTE_DISABLE` is set together with `WRITABLE`.
+/// Frozen pages have to be refused by the caller (see `is_frozen`).
+pub fn set_writable(virtual_address: usize, size: usize, writable: bool) -> Result<(), ()> {
+	let start = align_down!(virtual_address, BasePageSize::SIZE);
+	let end = align_up!(virtual_address + size, BasePageSize::SIZE);
+	split_into_base_pages(start, end)?;
+
+	update_writable(start, end, writable);
+	Ok(())
+}
+
+/// Sets or clears `WRITABLE` on the 4 KiB pages of `[start, end)`, which have already been split.
+fn update_writable(start: usize, end: usize, writable: bool) {
+	let _access = PageTableAccess::open();
+	for page in (start..end).step_by(BasePageSize::SIZE) {
+		let entry = entry_pointer(BasePageSize::MAP_LEVEL, page) as *mut usize;
+		unsafe {
+			if writable {
+				intrinsics::atomic_or(
+					entry,
+					(PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE).bits(),
+				);
+			} else {
+				intrinsics::atomic_and(entry, !PageTableEntryFlags::WRITABLE.bits());
+			}
+		}
+		flush_page(page);
+	}
+
+	remote_tlb_flush();
+}
+
+/// Makes the pages of `[virtual_address, virtual_address + size)` permanently read-only.
+///
+/// The range is recorded, so `map` refuses to replace its pages and the callers of `set_writable`
+/// refuse to make them writable again. A watch on the pages is dropped, because the page fault
+/// handler would make a watched page writable. Fails if a page isn't mapped or is a 1 GiB page.
+/// Nothing is recorded in this case.
+pub fn freeze(virtual_address: usize, size: usize) -> Result<(), ()> {
+	let start = align_down!(virtual_address, BasePageSize::SIZE);
+	let end = align_up!(virtual_address + size, BasePageSize::SIZE);
+	split_into_base_pages(start, end)?;
+
+	// The range is recorded before the pages become read-only, so that they can't be made writable in between.
+	// Hereafter, nothing can fail.
+	{
+		let mut ranges = FROZEN_RANGES.lock();
+		insert_range(&mut ranges, start, end);
+		FROZEN_START.store(ranges[0].0, Ordering::SeqCst);
+		FROZEN_END.store(ranges[ranges.len() - 1].1, Ordering::SeqCst);
+	}
+	WATCHED_PAGES
+		.lock()
+		.retain(|page| *page < start || *page >= end);
+	update_writable(start, end, false);
+
+	Ok(())
+}
+
+/// Inserts `[start, end)` into the sorted and disjoint `ranges`. Overlapping or adjacent ranges are merged.
+fn insert_range(ranges: &mut Vec<(usize, usize)>, start: usize, end: usize) {
+	let (mut start, mut end) = (start, end);
+	ranges.retain(|&(other_start, other_end)| {
+		if other_start <= end && start <= other_end {
+			start = cmp::min(start, other_start);
+			end = cmp::max(end, other_end);
+			false
+		} else {
+			true
+		}
+	});
+
+	let index = match ranges.binary_search_by_key(&start, |&(other_start, _)| other_start) {
+		Ok(index) | Err(index) => index,
+	};
+	ranges.insert(index, (start, end));
+}
+
+/// Returns `true` if `[start, end)` overlaps with any of the sorted and disjoint `ranges`.
+fn overlaps_range(ranges: &[(usize, usize)], start: usize, end: usize) -> bool {
+	// All ranges in front of `index` begin before `end`, only the last of them may reach `start`.
+	let index = match ranges.binary_search_by_key(&end, |&(other_start, _)| other_start) {
+		Ok(index) | Err(index) => index,
+	};
+
+	index > 0 && ranges[index - 1].1 > start
+}
+
+/// Returns `true` if any page of `[virtual_address, virtual_address + size)` has been frozen.
+///
+/// `map` checks every page, so a range outside of all frozen ranges is answered without taking the lock.
+pub fn is_frozen(virtual_address: usize, size: usize) -> bool {
+	let end = virtual_address + size;
+	if end <= FROZEN_START.load(Ordering::SeqCst) || virtual_address >= FROZEN_END.load(Ordering::SeqCst) {
+		return false;
+	}
+
+	overlaps_range(&FROZEN_RANGES.lock(), virtual_address, end)
+}
+
+/// Makes the watched page, which contains `virtual_address`, writable again.
+/// Returns `false` if the page isn't watched.
+fn unwatch_page(virtual_address: usize) -> bool {
+	let page = align_down!(virtual_address, BasePageSize::SIZE);
+	{
+		let mut watched = WATCHED_PAGES.lock();
+		match watched.iter().position(|p| *p == page) {
+			Some(index) => {
+				watched.swap_remove(index);
+			}
+			None => return false,
+		}
+	}
+
+	let _access = PageTableAccess::open();
+	unsafe {
+		intrinsics::atomic_or(
+			entry_pointer(BasePageSize::MAP_LEVEL, page) as *mut usize,
+			PageTableEntryFlags::WRITABLE.bits(),
+		);
+		flush_page(page);
+	}
+	remote_tlb_flush();
+
+	true
+}
+
+/// Releases the table of the given level, which translates `virtual_address`, if it has become empty.
+/// Continues with the tables above it up to the PDPT. Only tables allocated at runtime are released.
+fn reclaim_tables(level: usize, virtual_address: usize) {
+	let mut released = [0usize; 3];
+	let mut count = 0;
+
+	{
+		let _lock = TABLE_RECLAIM_LOCK.lock();
+		for level in level..3 {
+			let table = align_down!(entry_pointer(level, virtual_address) as usize, BasePageSize::SIZE);
+			let first_entry = table as *const PageTableEntry;
+			let is_empty = (0..1 << PAGE_MAP_BITS)
+				.all(|i| unsafe { (*first_entry.add(i)).physical_address_and_flags == 0 });
+			if !is_empty {
+				break;
+			}
+
+			let parent = entry_pointer(level + 1, virtual_address);
+			let parent_entry = unsafe { *parent };
+			if !physicalmem::is_managed(parent_entry.address()) {
+				// The loader has set up this table.
+				break;
+			}
+
+			let (_, exchanged) = unsafe {
+				intrinsics::atomic_cxchg(parent as *mut usize, parent_entry.physical_address_and_flags, 0)
+			};
+			if !exchanged {
+				break;
+			}
+			flush_page(table);
+			released[count] = parent_entry.address();
+			count += 1;
+		}
+	}
+
+	if count > 0 {
+		// The tables are reused only after no other core can walk them anymore. The lock isn't held,
+		// because a core, which spins on it with disabled interrupts, couldn't acknowledge the flush.
+		apic::ipi_tlb_flush_sync(false);
+		for table_physical in released[..count].iter() {
+			physicalmem::deallocate(*table_physical, BasePageSize::SIZE);
+			PAGE_TABLE_PAGES.fetch_sub(1, Ordering::SeqCst);
+		}
+	}
+}
+
+/// Tags the entries of the table at `level`, which covers the range starting at `address`,
+/// and of all its subtables with the protection key of the page tables.
+fn seal_table(level: usize, address: usize) {
+	let span = 1usize << (PAGE_BITS + level * PAGE_MAP_BITS);
+	let pkey_bits = usize::from(mm::PAGE_TABLE_MEM_REGION) << 59;
+
+	for index in 0..(1 << PAGE_MAP_BITS) {
+		let entry_address = address + index * span;
+		let entry = unsafe { &mut *entry_pointer(level, entry_address) };
+		if !entry.is_present() || entry.is_huge() {
+			continue;
+		}
+
+		// The last PML4 entry is the self-reference, whose subtables are the page tables themselves.
+		let self_reference = level == PML4::LEVEL && index == (1 << PAGE_MAP_BITS) - 1;
+		if level > PD::LEVEL && !self_reference {
+			seal_table(level - 1, entry_address);
+		}
+
+		// The key of an entry, which references a table, only applies to accesses through the self-reference.
+		entry.physical_address_and_flags = (entry.physical_address_and_flags & !(0xF << 59)) | pkey_bits;
+	}
+}
+
+/// Tags all page tables with the protection key `mm::PAGE_TABLE_MEM_REGION`.
+///
+/// Neither the kernel nor the user domain has access to this key. Only the functions of this
+/// module open the key while they access the page tables and close it again before they return.
+/// Hence, a write primitive outside of the paging code can't modify the page tables to reopen a domain.
+/// Tables, which are allocated afterwards, are tagged with the key as well.
+///
+/// Sealing isn't free: every call into this module, which touches the page tables, pays for
+/// a RDPKRU and two WRPKRU instructions (each serialized by a LFENCE). `map` and `unmap` open the
+/// key once per call, so mapping a range in a single call is much cheaper than mapping each page.
+///
+/// Fails if the CPU doesn't provide the key.
+pub fn seal() -> Result<(), ()> {
+	if usize::from(mm::PAGE_TABLE_MEM_REGION) >= mpk::num_keys() {
+		return Err(());
+	}
+
+	// From now on, every access to the page tables opens the key, even while the tables are re-tagged.
+	SEALED.store(true, Ordering::SeqCst);
+	{
+		let _access = PageTableAccess::open();
+		seal_table(PML4::LEVEL, 0);
+	}
+
+	unsafe {
+		controlregs::cr3_write(controlregs::cr3());
+	}
+	remote_tlb_flush();
+
+	info!("Page tables are sealed with protection key {}", mm::PAGE_TABLE_MEM_REGION);
+	Ok(())
+}
+
+/// Returns the memory in bytes, which is occupied by page tables allocated at runtime.
+pub fn page_table_memory() -> usize {
+	PAGE_TABLE_PAGES.load(Ordering::SeqCst) * BasePageSize::SIZE
+}
+
+/// Removes the mapping of `count` pages of size S starting at `virtual_address`.
+///
+/// Each protection key keeps track of the number of pages tagged with it.
+/// A dynamically allocated key is reclaimed when its last page is unmapped.
+pub fn unmap<S: PageSize>(virtual_address: usize, count: usize) {
+	trace!(
+		"Unmapping virtual address {:#X} ({} pages)",
+		virtual_address,
+		count
+	);
+
+	let _access = PageTableAccess::open();
+	let range = get_page_range::<S>(virtual_address, count);
+	let mut send_ipi = false;
+
+	for page in range {
+		if let Some(old_entry) = get_page_table_entry::<S>(page.address()) {
+			unsafe {
+				(*entry_pointer(S::MAP_LEVEL, page.address())).physical_address_and_flags = 0;
+			}
+			page.flush_from_tlb();
+			mpk::mpk_page_put(old_entry.pkey());
+			reclaim_tables(S::MAP_LEVEL, page.address());
+			send_ipi = true;
+		}
+	}
+
+	if send_ipi {
+		remote_tlb_flush();
+	}
+
+	// A later mapping of the range mustn't inherit a watch.
+	let end = virtual_address + count * S::SIZE;
+	WATCHED_PAGES
+		.lock()
+		.retain(|page| *page < virtual_address || *page >= end);
+}
+
 pub fn identity_map(start_address: usize, end_address: usize) {
 	let first_page = Page::<BasePageSize>::including_address(start_address);
 	let last_page = Page::<BasePageSize>::including_address(end_address);
@@ -853,6 +2436,7 @@ pub fn identity_map(start_address: usize, end_address: usize) {
 		last_page.address()
 	);
 
+	let _access = PageTableAccess::open();
 	let root_pagetable = unsafe { &mut *PML4_ADDRESS };
 	let range = Page::<BasePageSize>::range(first_page, last_page);
 	let mut flags = PageTableEntryFlags::empty();
@@ -919,3 +2503,181 @@ pub fn init_page_tables() {
 		identity_map(cmdline, cmdline + cmdsize - 1);
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn writable_executable_mappings_are_flagged() {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable();
+		assert!(flags.violates_wx());
+
+		flags.allow_wx();
+		assert!(!flags.violates_wx());
+
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		assert!(!flags.violates_wx());
+
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().read_only();
+		assert!(!flags.violates_wx());
+	}
+
+	#[test]
+	fn frozen_ranges_are_merged_and_found() {
+		let mut ranges = Vec::new();
+		insert_range(&mut ranges, 0x5000, 0x6000);
+		insert_range(&mut ranges, 0x1000, 0x2000);
+		insert_range(&mut ranges, 0x2000, 0x3000);
+		assert_eq!(ranges, [(0x1000, 0x3000), (0x5000, 0x6000)]);
+
+		assert!(overlaps_range(&ranges, 0x2FFF, 0x3000));
+		assert!(overlaps_range(&ranges, 0x4000, 0x5001));
+		assert!(!overlaps_range(&ranges, 0x3000, 0x5000));
+		assert!(!overlaps_range(&ranges, 0x0, 0x1000));
+		assert!(!overlaps_range(&ranges, 0x6000, 0x7000));
+
+		insert_range(&mut ranges, 0x2800, 0x5800);
+		assert_eq!(ranges, [(0x1000, 0x6000)]);
+	}
+
+	#[test]
+	#[should_panic(expected = "Physical address 0x201000 of the mapping isn't aligned to the page size 0x200000")]
+	fn unaligned_large_page_mappings_are_reported() {
+		assert_aligned::<BasePageSize>(0x1000, 0x201000);
+		assert_aligned::<LargePageSize>(0x200000, 0x201000);
+	}
+
+	#[test]
+	fn cache_policy_sets_the_caching_flags() {
+		let caching = PageTableEntryFlags::WRITE_THROUGH | PageTableEntryFlags::CACHE_DISABLE;
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+
+		flags.cache_policy(CachePolicy::default());
+		assert_eq!(flags & caching, caching);
+
+		flags.cache_policy(CachePolicy::WriteThrough);
+		assert_eq!(flags & caching, PageTableEntryFlags::WRITE_THROUGH);
+
+		flags.cache_policy(CachePolicy::WriteCombining);
+		assert!((flags & caching).is_empty());
+		assert!(flags.contains(PageTableEntryFlags::PAT));
+
+		flags.cache_policy(CachePolicy::WriteBack);
+		assert!((flags & caching).is_empty());
+		assert!(!flags.contains(PageTableEntryFlags::PAT));
+		assert!(flags.contains(PageTableEntryFlags::WRITABLE | PageTableEntryFlags::EXECUTE_DISABLE));
+	}
+
+	#[test]
+	fn sealed_tables_are_only_open_inside_paging() {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+
+		// neither the kernel nor the user domain may access the sealed page tables
+		for pkru in &[0x300, 0x3FC] {
+			assert_eq!(
+				Access::from_entry(flags, mm::PAGE_TABLE_MEM_REGION, *pkru),
+				Access::NONE
+			);
+		}
+
+		// the paging module opens the key without changing the other ones
+		let kernel = 0x300 & !PAGE_TABLE_PKRU;
+		assert!(Access::from_entry(flags, mm::PAGE_TABLE_MEM_REGION, kernel).write);
+		let user = 0x3FC & !PAGE_TABLE_PKRU;
+		assert_eq!(user, 0xFC);
+		assert_eq!(Access::from_entry(flags, mm::SAFE_MEM_REGION, user), Access::NONE);
+	}
+
+	#[test]
+	fn null_page_requires_override() {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		assert!(!is_permitted_mapping::<BasePageSize>(0, flags));
+		assert!(!is_permitted_mapping::<BasePageSize>(0xFFF, flags));
+		assert!(!is_permitted_mapping::<LargePageSize>(0x1000, flags));
+		assert!(is_permitted_mapping::<BasePageSize>(0x1000, flags));
+		assert!(is_permitted_mapping::<BasePageSize>(0x400000, flags));
+		assert!(is_permitted_range::<BasePageSize>(0x1000, 16, flags));
+		assert!(!is_permitted_range::<BasePageSize>(0, 16, flags));
+
+		flags.allow_null();
+		assert!(is_permitted_mapping::<BasePageSize>(0, flags));
+	}
+
+	#[test]
+	fn read_only_key_masks_writable_page() {
+		let key = 1;
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		flags.insert(PageTableEntryFlags::PRESENT);
+
+		// all keys grant full access
+		let access = Access::from_entry(flags, key, 0);
+		assert!(access.read && access.write && !access.execute);
+
+		// write disable bit of the key (MpkRo)
+		let access = Access::from_entry(flags, key, 1 << (2 * key + 1));
+		assert!(access.read);
+		assert!(!access.write);
+
+		// access disable bit of another key doesn't matter
+		let access = Access::from_entry(flags, key, 1 << (2 * (key + 1)));
+		assert!(access.read && access.write);
+
+		// access and write disable bits of the key (MpkNone)
+		let access = Access::from_entry(flags, key, 0b11 << (2 * key));
+		assert_eq!(access, Access { read: false, write: false, execute: false });
+	}
+
+	extern "C" fn handle_violation(_: i32) {}
+
+	#[test]
+	fn faults_are_passed_to_the_matching_task_handler() {
+		let pk = PageFaultError::PK | PageFaultError::P | PageFaultError::WR | PageFaultError::US;
+		let not_present = PageFaultError::US;
+
+		let mut fault_handler = Some(handle_violation as extern "C" fn(i32));
+		let mut page_fault_handler = Some(0x1000);
+		assert_eq!(
+			take_task_handler(pk, &mut fault_handler, &mut page_fault_handler),
+			Some(TaskFaultHandler::Isolation(handle_violation))
+		);
+		assert!(fault_handler.is_none());
+		assert_eq!(page_fault_handler, Some(0x1000));
+
+		let mut fault_handler = Some(handle_violation as extern "C" fn(i32));
+		assert_eq!(
+			take_task_handler(not_present, &mut fault_handler, &mut page_fault_handler),
+			Some(TaskFaultHandler::PageFault(0x1000))
+		);
+		assert!(fault_handler.is_some());
+		assert!(page_fault_handler.is_none());
+
+		// A violation without a fault handler falls back to the page fault handler.
+		let mut fault_handler = None;
+		let mut page_fault_handler = Some(0x2000);
+		assert_eq!(
+			take_task_handler(pk, &mut fault_handler, &mut page_fault_handler),
+			Some(TaskFaultHandler::PageFault(0x2000))
+		);
+		assert_eq!(take_task_handler(pk, &mut fault_handler, &mut page_fault_handler), None);
+	}
+
+	#[test]
+	fn fault_streak_counts_consecutive_faults_at_the_same_address() {
+		let mut streak = ::scheduler::task::FaultStreak::new();
+		assert_eq!(streak.record(0x1000), 1);
+		assert_eq!(streak.record(0x1000), 2);
+		assert_eq!(streak.record(0x2000), 1);
+		assert_eq!(streak.record(0x2000), 2);
+
+		streak.reset();
+		assert_eq!(streak.record(0x2000), 1);
+	}
+}
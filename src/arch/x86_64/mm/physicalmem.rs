@@ -5,10 +5,12 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use arch::x86_64::kernel::percore::{core_id, try_core_scheduler};
 use arch::x86_64::kernel::{get_limit, get_mbinfo};
 use arch::x86_64::mm::paddr_to_slice;
-use arch::x86_64::mm::paging::{BasePageSize, PageSize};
+use arch::x86_64::mm::paging::{BasePageSize, LargePageSize, PageSize};
 use collections::Node;
+use config::DUMP_FREE_LISTS;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use mm;
 use mm::freelist::{FreeList, FreeListEntry};
@@ -17,6 +19,145 @@ use synch::spinlock::*;
 
 safe_global_var!(static PHYSICAL_FREE_LIST: SpinlockIrqSave<FreeList> = SpinlockIrqSave::new(FreeList::new()));
 safe_global_var!(static TOTAL_MEMORY: AtomicUsize = AtomicUsize::new(0));
+/// Maximum number of memory regions, which are recorded by `add_region`.
+const MAX_MANAGED_REGIONS: usize = 16;
+/// Physical memory regions `(start, end)`, which have been passed to the free list at boot time.
+safe_global_var!(static mut MANAGED_REGIONS: [(usize, usize); MAX_MANAGED_REGIONS] = [(0, 0); MAX_MANAGED_REGIONS]);
+
+/// Maximum number of cores, which own a frame cache. Additional cores use the free list directly.
+const MAX_FRAME_CACHES: usize = 64;
+
+/// Number of frames, which a frame cache is able to hold.
+const FRAME_CACHE_SIZE: usize = 32;
+
+/// Number of frames, which are moved between a frame cache and the free list under one lock acquisition.
+const FRAME_BATCH: usize = FRAME_CACHE_SIZE / 2;
+
+/// Free 4 KiB frames of a core (magazine), which are allocated and released without taking the lock of the free list.
+struct FrameCache {
+	frames: [usize; FRAME_CACHE_SIZE],
+	count: usize,
+}
+
+impl FrameCache {
+	const fn new() -> Self {
+		Self {
+			frames: [0; FRAME_CACHE_SIZE],
+			count: 0,
+		}
+	}
+
+	/// Takes `FRAME_BATCH` frames from the free list, preferably as one contiguous range.
+	fn refill(&mut self) {
+		let mut free_list = PHYSICAL_FREE_LIST.lock();
+		if let Ok(start) = free_list.allocate(FRAME_BATCH * BasePageSize::SIZE) {
+			for frame in (start..start + FRAME_BATCH * BasePageSize::SIZE).step_by(BasePageSize::SIZE) {
+				self.push(frame);
+			}
+			return;
+		}
+
+		while self.count < FRAME_BATCH {
+			match free_list.allocate(BasePageSize::SIZE) {
+				Ok(frame) => self.push(frame),
+				Err(()) => break,
+			}
+		}
+	}
+
+	/// Returns frames to the free list until `keep` frames are left.
+	fn drain(&mut self, keep: usize) {
+		let mut free_list = PHYSICAL_FREE_LIST.lock();
+		while self.count > keep {
+			let frame = self.pop().unwrap();
+			free_list.deallocate(frame, BasePageSize::SIZE);
+		}
+	}
+
+	fn push(&mut self, frame: usize) {
+		self.frames[self.count] = frame;
+		self.count += 1;
+		CACHED_FRAMES.fetch_add(1, Ordering::Relaxed);
+	}
+
+	fn pop(&mut self) -> Option<usize> {
+		if self.count == 0 {
+			return None;
+		}
+
+		self.count -= 1;
+		CACHED_FRAMES.fetch_sub(1, Ordering::Relaxed);
+		Some(self.frames[self.count])
+	}
+}
+
+/// Frame caches of the cores. A cache is only locked by its own core, unless all caches are flushed.
+safe_global_var!(static FRAME_CACHES: [SpinlockIrqSave<FrameCache>; MAX_FRAME_CACHES] = [
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+	SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()), SpinlockIrqSave::new(FrameCache::new()),
+]);
+
+/// Number of frames in all frame caches, which are still free, but not part of the free list.
+safe_global_var!(static CACHED_FRAMES: AtomicUsize = AtomicUsize::new(0));
+
+/// Runs `f` on the frame cache of the current core with disabled interrupts.
+/// Returns `None` if the core doesn't own a frame cache.
+fn with_frame_cache<F, R>(f: F) -> Option<R>
+where
+	F: FnOnce(&mut FrameCache) -> R,
+{
+	// The per-core data isn't available before the scheduler of the core has been initialized.
+	if try_core_scheduler().is_none() {
+		return None;
+	}
+
+	let core_id = core_id();
+	if core_id < MAX_FRAME_CACHES {
+		Some(f(&mut FRAME_CACHES[core_id].lock()))
+	} else {
+		None
+	}
+}
+
+/// Returns the frames of all frame caches to the free list,
+/// e.g. before the system goes down or if a larger allocation has failed.
+pub fn flush_frame_caches() {
+	for cache in FRAME_CACHES.iter() {
+		cache.lock().drain(0);
+	}
+}
+
+/// Passes the free memory `[start, end)` to the free list and records it for `is_managed`.
+fn add_region(start: usize, end: usize) {
+	let entry = Node::new(FreeListEntry {
+		start: start,
+		end: end,
+	});
+	PHYSICAL_FREE_LIST.lock().list.push(entry);
+
+	// Frames of regions beyond the table are never considered as managed, so they are leaked at worst.
+	unsafe {
+		if let Some(region) = MANAGED_REGIONS.iter_mut().find(|region| region.1 == 0) {
+			*region = (start, end);
+		}
+	}
+}
+
+/// Returns `true` if the frame at `physical_address` belongs to the memory of the free list.
+/// Other frames, e.g. the page tables of the loader, must not be passed to `deallocate`.
+pub fn is_managed(physical_address: usize) -> bool {
+	unsafe {
+		MANAGED_REGIONS
+			.iter()
+			.any(|&(start, end)| start <= physical_address && physical_address < end)
+	}
+}
 
 fn detect_from_multiboot_info() -> Result<(), ()> {
 	let mb_info = get_mbinfo();
@@ -43,12 +184,8 @@ fn detect_from_multiboot_info() -> Result<(), ()> {
 			m.base_address() as usize
 		};
 
-		let entry = Node::new(FreeListEntry {
-			start: start_address,
-			end: (m.base_address() + m.length()) as usize,
-		});
 		let _ = TOTAL_MEMORY.fetch_add((m.base_address() + m.length()) as usize, Ordering::SeqCst);
-		PHYSICAL_FREE_LIST.lock().list.push(entry);
+		add_region(start_address, (m.base_address() + m.length()) as usize);
 	}
 
 	assert!(
@@ -65,12 +202,8 @@ fn detect_from_limits() -> Result<(), ()> {
 		return Err(());
 	}
 
-	let entry = Node::new(FreeListEntry {
-		start: mm::kernel_end_address(),
-		end: limit,
-	});
 	TOTAL_MEMORY.store(limit, Ordering::SeqCst);
-	PHYSICAL_FREE_LIST.lock().list.push(entry);
+	add_region(mm::kernel_end_address(), limit);
 
 	Ok(())
 }
@@ -85,6 +218,25 @@ pub fn total_memory_size() -> usize {
 	TOTAL_MEMORY.load(Ordering::SeqCst)
 }
 
+/// Returns the number of bytes, which aren't allocated yet. This includes the frame caches of the cores.
+pub fn free_memory_size() -> usize {
+	PHYSICAL_FREE_LIST.lock().size() + CACHED_FRAMES.load(Ordering::Relaxed) * BasePageSize::SIZE
+}
+
+/// Allocates from the free list. If it can't satisfy the request, the frame caches
+/// of all cores are returned to the free list and the allocation is repeated.
+fn allocate_from_free_list<F>(allocate: F) -> Result<usize, ()>
+where
+	F: Fn(&mut FreeList) -> Result<usize, ()>,
+{
+	// The free list has to be unlocked before the frame caches are flushed.
+	let result = allocate(&mut PHYSICAL_FREE_LIST.lock());
+	result.or_else(|_| {
+		flush_frame_caches();
+		allocate(&mut PHYSICAL_FREE_LIST.lock())
+	})
+}
+
 pub fn allocate(size: usize) -> Result<usize, ()> {
 	assert!(size > 0);
 	assert!(
@@ -94,7 +246,20 @@ pub fn allocate(size: usize) -> Result<usize, ()> {
 		BasePageSize::SIZE
 	);
 
-	PHYSICAL_FREE_LIST.lock().allocate(size)
+	// Single frames are served by the frame cache of the current core.
+	if size == BasePageSize::SIZE {
+		let frame = with_frame_cache(|cache| {
+			if cache.count == 0 {
+				cache.refill();
+			}
+			cache.pop()
+		});
+		if let Some(Some(frame)) = frame {
+			return Ok(frame);
+		}
+	}
+
+	allocate_from_free_list(|free_list| free_list.allocate(size))
 }
 
 pub fn allocate_aligned(size: usize, alignment: usize) -> Result<usize, ()> {
@@ -113,7 +278,34 @@ pub fn allocate_aligned(size: usize, alignment: usize) -> Result<usize, ()> {
 		BasePageSize::SIZE
 	);
 
-	PHYSICAL_FREE_LIST.lock().allocate_aligned(size, alignment)
+	if size == BasePageSize::SIZE {
+		return allocate(size);
+	}
+
+	allocate_from_free_list(|free_list| free_list.allocate_aligned(size, alignment))
+}
+
+/// Allocates `size` bytes aligned to `preferred_alignment`.
+/// If no such region is available, the alignment is relaxed step by step down to `LargePageSize` and `BasePageSize`.
+///
+/// Returns the physical address together with the alignment, which could be achieved.
+pub fn allocate_best_effort(size: usize, preferred_alignment: usize) -> Result<(usize, usize), ()> {
+	let alignments = [preferred_alignment, LargePageSize::SIZE, BasePageSize::SIZE];
+
+	for alignment in alignments.iter().filter(|a| **a <= preferred_alignment) {
+		if let Ok(physical_address) = allocate_aligned(size, *alignment) {
+			if *alignment < preferred_alignment {
+				debug!(
+					"Allocated {:#X} bytes with alignment {:#X} instead of {:#X}",
+					size, alignment, preferred_alignment
+				);
+			}
+
+			return Ok((physical_address, *alignment));
+		}
+	}
+
+	Err(())
 }
 
 /// This function must only be called from mm::deallocate!
@@ -132,11 +324,28 @@ pub fn deallocate(physical_address: usize, size: usize) {
 		BasePageSize::SIZE
 	);
 
+	// Single frames are cached by the current core. A full cache returns half of its frames.
+	if size == BasePageSize::SIZE {
+		let cached = with_frame_cache(|cache| {
+			if cache.count == FRAME_CACHE_SIZE {
+				cache.drain(FRAME_CACHE_SIZE - FRAME_BATCH);
+			}
+			cache.push(physical_address);
+		});
+		if cached.is_some() {
+			return;
+		}
+	}
+
 	PHYSICAL_FREE_LIST.lock().deallocate(physical_address, size);
 }
 
 pub fn print_information() {
-	PHYSICAL_FREE_LIST
-		.lock()
-		.print_information(" PHYSICAL MEMORY FREE LIST ");
+	if DUMP_FREE_LISTS {
+		PHYSICAL_FREE_LIST.lock().dump(" PHYSICAL MEMORY FREE LIST ");
+	} else {
+		PHYSICAL_FREE_LIST
+			.lock()
+			.print_information(" PHYSICAL MEMORY FREE LIST ");
+	}
 }
@@ -7,6 +7,7 @@
 
 use arch::x86_64::mm::paging::{BasePageSize, PageSize};
 use collections::Node;
+use config::DUMP_FREE_LISTS;
 use mm;
 use mm::freelist::{FreeList, FreeListEntry};
 use synch::spinlock::*;
@@ -114,10 +115,56 @@ pub fn reserve(virtual_address: usize, size: usize) {
 	);
 }
 
+/// Marks the hardcoded range `[virtual_address, virtual_address + size)` as permanently allocated,
+/// so that `allocate` never returns any part of it.
+///
+/// Parts outside of the range managed by this allocator can't be handed out anyway and are skipped.
+/// Fails if a part of the range has already been allocated.
+pub fn reserve_fixed(virtual_address: usize, size: usize) -> Result<(), ()> {
+	let start = virtual_address.max(mm::kernel_end_address());
+	let end = virtual_address
+		.checked_add(size)
+		.ok_or(())?
+		.min(kernel_heap_end());
+	if start >= end {
+		return Ok(());
+	}
+
+	KERNEL_FREE_LIST.lock().reserve(
+		align_down!(start, BasePageSize::SIZE),
+		align_up!(end, BasePageSize::SIZE) - align_down!(start, BasePageSize::SIZE),
+	)
+}
+
+/// Like `reserve`, but fails instead of panicking if `[virtual_address, virtual_address + size)`
+/// isn't a free range of the kernel heap.
+pub fn try_reserve(virtual_address: usize, size: usize) -> Result<(), ()> {
+	assert!(size > 0);
+	assert!(
+		virtual_address % BasePageSize::SIZE == 0 && size % BasePageSize::SIZE == 0,
+		"Range {:#X} ({:#X} bytes) is not page-aligned",
+		virtual_address,
+		size
+	);
+
+	let end = virtual_address.checked_add(size).ok_or(())?;
+	if virtual_address < mm::kernel_end_address() || end > kernel_heap_end() {
+		return Err(());
+	}
+
+	KERNEL_FREE_LIST.lock().reserve(virtual_address, size)
+}
+
 pub fn print_information() {
-	KERNEL_FREE_LIST
-		.lock()
-		.print_information(" KERNEL VIRTUAL MEMORY FREE LIST ");
+	if DUMP_FREE_LISTS {
+		KERNEL_FREE_LIST
+			.lock()
+			.dump(" KERNEL VIRTUAL MEMORY FREE LIST ");
+	} else {
+		KERNEL_FREE_LIST
+			.lock()
+			.print_information(" KERNEL VIRTUAL MEMORY FREE LIST ");
+	}
 }
 
 /// End of the virtual memory address space reserved for kernel memory.
@@ -2,4 +2,50 @@
 pub const KERNEL_STACK_SIZE: usize = 32_768;
 
 #[allow(dead_code)]
-pub const DEFAULT_STACK_SIZE: usize = 262_144;
\ No newline at end of file
+pub const DEFAULT_STACK_SIZE: usize = 262_144;
+/// Number of Interrupt Stack Tables (IST) allocated per core, between 1 and 7 (the architectural maximum).
+///
+/// The entries are assigned as follows (see `irq::install`):
+///   - IST1: Non-Maskable Interrupt, replaced by a dedicated stack for each task
+///   - IST2: Double Fault
+///   - IST3: Machine Check
+///
+/// All other exceptions and an exception, whose IST isn't configured, are handled on the current stack.
+/// Keep at least 2 entries if a double fault after a kernel stack overflow shall still be reported.
+#[allow(dead_code)]
+pub const IST_ENTRIES: usize = 4;
+/// Default size of the kernel heap, which can be overridden by the -kheap command-line parameter.
+pub const KERNEL_HEAP_SIZE: usize = 0x800000;
+/// Size of the heap of the unsafe domain, which is separated from the kernel heap.
+pub const UNSAFE_HEAP_SIZE: usize = 0x200000;
+/// Size of the static memory, which `mm::early` hands out before the kernel heap is initialized.
+pub const EARLY_HEAP_SIZE: usize = 0x4000;
+/// Size of the unmapped guard below the user heap (one 2 MiB page by default).
+/// A contiguous overrun of the kernel heap faults in the guard instead of reaching the user heap.
+pub const USER_HEAP_GUARD_SIZE: usize = 0x200000;
+/// Refuse demand paging of a reservation, which the free physical memory can't back (no overcommit).
+/// `sys_mmap` and `sys_sbrk` fail with `ENOMEM` instead of using the frames of such reservations.
+///
+/// Otherwise, the size of all reservations may exceed the physical memory and a task,
+/// which touches a page without a free frame, is aborted by the page fault handler.
+pub const STRICT_COMMIT: bool = false;
+/// Debugging aid: validate the invariants of the address space (see `mm::check_invariants`)
+/// at the end of `mm::init` and panic at the first violation.
+pub const CHECK_MM_INVARIANTS: bool = false;
+/// Debugging aid: `mm::print_information` dumps the physical and the virtual free lists
+/// entry by entry and validates them (see `FreeList::dump`).
+pub const DUMP_FREE_LISTS: bool = false;
+/// Interval in milliseconds, in which a low-priority kernel task audits the PKRU of all cores
+/// (see `mpk::audit_all_cores`). 0 disables the audit.
+pub const PKRU_AUDIT_INTERVAL: u64 = 0;
+/// Halt a core, whose PKRU audit fails, instead of only reporting it.
+pub const PKRU_AUDIT_HALT: bool = false;
+/// Time in milliseconds, which the boot processor waits for an application processor to come up.
+/// The remaining application processors aren't booted if one of them doesn't respond in time.
+pub const AP_BOOT_TIMEOUT: u64 = 1000;
+/// Count the PKRU writes of each core (see `mpk::pkru_write_count`), enabled by the feature `count-pkru-writes`.
+/// Disabled, the counting is removed at compile time and doesn't slow down the domain switches.
+pub const COUNT_PKRU_WRITES: bool = cfg!(feature = "count-pkru-writes");
+/// Number of consecutive page faults of a task at the same address, which are repeated without being resolved,
+/// after which the kernel panics with a fault storm instead of repeating the access again and again.
+pub const PAGE_FAULT_RETRY_LIMIT: u32 = 16;
@@ -0,0 +1,198 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Minimal ARP support for pure Rust applications, which use the raw network interface.
+//!
+//! The interface answers ARP requests for its configured IP address and
+//! resolves the MAC address of the gateway.
+
+use synch::spinlock::SpinlockIrqSave;
+
+/// Size of an Ethernet frame carrying an ARP packet for IPv4 over Ethernet
+pub const ARP_FRAME_SIZE: usize = 42;
+
+const ETH_HEADER_SIZE: usize = 14;
+const ETH_TYPE_ARP: [u8; 2] = [0x08, 0x06];
+const ARP_HTYPE_ETHERNET: [u8; 2] = [0x00, 0x01];
+const ARP_PTYPE_IPV4: [u8; 2] = [0x08, 0x00];
+const ARP_OPER_REQUEST: [u8; 2] = [0x00, 0x01];
+const ARP_OPER_REPLY: [u8; 2] = [0x00, 0x02];
+const BROADCAST_MAC: [u8; 6] = [0xff; 6];
+
+/// IP configuration of the interface and the resolved MAC address of the gateway
+struct ArpState {
+	ip: [u8; 4],
+	gateway: [u8; 4],
+	mac: [u8; 6],
+	gateway_mac: Option<[u8; 6]>,
+}
+
+safe_global_var!(static ARP_STATE: SpinlockIrqSave<ArpState> = SpinlockIrqSave::new(ArpState {
+	ip: [0; 4],
+	gateway: [0; 4],
+	mac: [0; 6],
+	gateway_mac: None,
+}));
+
+/// Converts the MAC address string provided by uhyve (e.g. "52:54:00:12:34:56") into its binary form.
+pub fn parse_mac(mac: &[u8]) -> Option<[u8; 6]> {
+	let mut result = [0u8; 6];
+	let mut digits = mac.iter().take_while(|c| **c != 0).filter(|c| **c != b':');
+
+	for byte in result.iter_mut() {
+		let high = (*digits.next()? as char).to_digit(16)?;
+		let low = (*digits.next()? as char).to_digit(16)?;
+		*byte = (high << 4 | low) as u8;
+	}
+
+	Some(result)
+}
+
+/// Configures the IP address, gateway and MAC address, for which the interface answers ARP requests.
+pub fn configure(ip: [u8; 4], gateway: [u8; 4], mac: [u8; 6]) {
+	let mut state = ARP_STATE.lock();
+	state.ip = ip;
+	state.gateway = gateway;
+	state.mac = mac;
+	state.gateway_mac = None;
+}
+
+/// Returns the MAC address of the gateway if it has already been resolved.
+pub fn gateway_mac() -> Option<[u8; 6]> {
+	ARP_STATE.lock().gateway_mac
+}
+
+fn build_frame(
+	oper: [u8; 2],
+	dst_mac: [u8; 6],
+	sender_mac: [u8; 6],
+	sender_ip: [u8; 4],
+	target_mac: [u8; 6],
+	target_ip: [u8; 4],
+) -> [u8; ARP_FRAME_SIZE] {
+	let mut frame = [0u8; ARP_FRAME_SIZE];
+
+	// Ethernet header
+	frame[0..6].copy_from_slice(&dst_mac);
+	frame[6..12].copy_from_slice(&sender_mac);
+	frame[12..14].copy_from_slice(&ETH_TYPE_ARP);
+
+	// ARP packet
+	let arp = &mut frame[ETH_HEADER_SIZE..];
+	arp[0..2].copy_from_slice(&ARP_HTYPE_ETHERNET);
+	arp[2..4].copy_from_slice(&ARP_PTYPE_IPV4);
+	arp[4] = 6;
+	arp[5] = 4;
+	arp[6..8].copy_from_slice(&oper);
+	arp[8..14].copy_from_slice(&sender_mac);
+	arp[14..18].copy_from_slice(&sender_ip);
+	arp[18..24].copy_from_slice(&target_mac);
+	arp[24..28].copy_from_slice(&target_ip);
+
+	frame
+}
+
+/// Builds a broadcast ARP request to resolve the MAC address of the configured gateway.
+pub fn gateway_request() -> [u8; ARP_FRAME_SIZE] {
+	let state = ARP_STATE.lock();
+	build_frame(
+		ARP_OPER_REQUEST,
+		BROADCAST_MAC,
+		state.mac,
+		state.ip,
+		[0; 6],
+		state.gateway,
+	)
+}
+
+/// Inspects a received Ethernet frame.
+///
+/// If the frame is an ARP packet sent by the gateway, the MAC address of the gateway is learned.
+/// If the frame is an ARP request for our IP address, the reply is returned and has to be sent
+/// by the caller.
+pub fn process_frame(frame: &[u8]) -> Option<[u8; ARP_FRAME_SIZE]> {
+	if frame.len() < ARP_FRAME_SIZE
+		|| frame[12..14] != ETH_TYPE_ARP
+		|| frame[14..16] != ARP_HTYPE_ETHERNET
+		|| frame[16..18] != ARP_PTYPE_IPV4
+	{
+		return None;
+	}
+
+	let arp = &frame[ETH_HEADER_SIZE..];
+	let mut sender_mac = [0u8; 6];
+	let mut sender_ip = [0u8; 4];
+	let mut target_ip = [0u8; 4];
+	sender_mac.copy_from_slice(&arp[8..14]);
+	sender_ip.copy_from_slice(&arp[14..18]);
+	target_ip.copy_from_slice(&arp[24..28]);
+
+	let mut state = ARP_STATE.lock();
+	if sender_ip == state.gateway {
+		state.gateway_mac = Some(sender_mac);
+	}
+
+	if arp[6..8] == ARP_OPER_REQUEST && target_ip == state.ip {
+		Some(build_frame(
+			ARP_OPER_REPLY,
+			sender_mac,
+			state.mac,
+			state.ip,
+			sender_mac,
+			sender_ip,
+		))
+	} else {
+		None
+	}
+}
+
+#[test]
+fn mac_from_string() {
+	assert_eq!(
+		parse_mac(b"52:54:00:12:34:5f\0"),
+		Some([0x52, 0x54, 0x00, 0x12, 0x34, 0x5f])
+	);
+	assert_eq!(parse_mac(b"52:54:00\0"), None);
+}
+
+#[test]
+fn reply_to_request() {
+	let mac = [0x52, 0x54, 0x00, 0x12, 0x34, 0x56];
+	let peer_mac = [0x52, 0x54, 0x00, 0xab, 0xcd, 0xef];
+	configure([10, 0, 5, 2], [10, 0, 5, 1], mac);
+
+	// an ARP request of the gateway for our IP address
+	let request = build_frame(
+		ARP_OPER_REQUEST,
+		BROADCAST_MAC,
+		peer_mac,
+		[10, 0, 5, 1],
+		[0; 6],
+		[10, 0, 5, 2],
+	);
+	let reply = process_frame(&request).unwrap();
+
+	assert_eq!(reply[0..6], peer_mac);
+	assert_eq!(reply[6..12], mac);
+	assert_eq!(reply[20..22], ARP_OPER_REPLY);
+	assert_eq!(reply[22..28], mac);
+	assert_eq!(reply[28..32], [10, 0, 5, 2]);
+	assert_eq!(reply[32..38], peer_mac);
+	assert_eq!(reply[38..42], [10, 0, 5, 1]);
+	assert_eq!(gateway_mac(), Some(peer_mac));
+
+	// requests for other IP addresses are ignored
+	let request = build_frame(
+		ARP_OPER_REQUEST,
+		BROADCAST_MAC,
+		peer_mac,
+		[10, 0, 5, 1],
+		[0; 6],
+		[10, 0, 5, 3],
+	);
+	assert!(process_frame(&request).is_none());
+}
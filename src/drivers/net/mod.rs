@@ -5,17 +5,26 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+pub mod arp;
 //pub mod rtl8139;
 pub mod uhyve;
 
 use alloc::boxed::Box;
+#[cfg(not(feature = "newlib"))]
+use arch::kernel::{get_gateway, get_ip};
 use core::ffi::c_void;
+#[cfg(not(feature = "newlib"))]
+use core::slice;
 use synch::spinlock::SpinlockIrqSave;
+#[cfg(not(feature = "newlib"))]
+use syscalls::copy_to_user;
 
 static NIC: SpinlockIrqSave<Option<Box<dyn NetworkInterface>>> = SpinlockIrqSave::new(None);
 
 pub fn init() -> Result<(), ()> {
 	let nic = uhyve::init()?;
+	#[cfg(not(feature = "newlib"))]
+	arp::configure(get_ip(), get_gateway(), nic.get_mac_address());
 	*NIC.lock() = Some(nic);
 
 	info!("Network initialized!");
@@ -40,6 +49,8 @@ pub trait NetworkInterface {
 	fn read(&mut self, buf: usize, len: usize) -> usize;
 	/// writr packet to the network interface
 	fn write(&self, buf: usize, len: usize) -> usize;
+	/// returns the MAC address of the network interface
+	fn get_mac_address(&self) -> [u8; 6];
 }
 
 #[no_mangle]
@@ -74,11 +85,44 @@ pub extern "C" fn sys_set_polling(mode: bool) {
 #[no_mangle]
 pub extern "C" fn sys_netread(buf: usize, len: usize) -> usize {
 	match &mut *NIC.lock() {
-		Some(nic) => nic.read(buf, len),
+		Some(nic) => {
+			let ret = nic.read(buf, len);
+
+			// Answer ARP requests for our IP address and learn the MAC address of the gateway.
+			#[cfg(not(feature = "newlib"))]
+			{
+				let frame = unsafe { slice::from_raw_parts(buf as *const u8, ret) };
+				if let Some(reply) = arp::process_frame(frame) {
+					nic.write(reply.as_ptr() as usize, reply.len());
+				}
+			}
+
+			ret
+		}
 		None => 0,
 	}
 }
 
+/// Copies the MAC address of the gateway to the user buffer `mac`.
+///
+/// Returns -1 if the gateway hasn't been resolved yet. In this case,
+/// an ARP request is sent and the caller has to try again after receiving packets.
+/// Returns `-EFAULT` if `mac` isn't a user buffer.
+#[cfg(not(feature = "newlib"))]
+#[no_mangle]
+pub extern "C" fn sys_gateway_mac(mac: *mut [u8; 6]) -> i32 {
+	if let Some(gateway_mac) = arp::gateway_mac() {
+		return copy_to_user(mac, &gateway_mac);
+	}
+
+	if let Some(nic) = &*NIC.lock() {
+		let request = arp::gateway_request();
+		nic.write(request.as_ptr() as usize, request.len());
+	}
+
+	-1
+}
+
 #[no_mangle]
 pub extern "C" fn sys_netwrite(buf: usize, len: usize) -> usize {
 	match &*NIC.lock() {
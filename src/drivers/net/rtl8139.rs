@@ -22,7 +22,7 @@ use smoltcp::wire::{EthernetAddress, IpAddress, IpCidr, Ipv4Address};
 use arch::x86_64::kernel::apic;
 use arch::x86_64::kernel::irq::*;
 use arch::x86_64::kernel::pci;
-use arch::x86_64::kernel::percore::core_scheduler;
+use arch::x86_64::kernel::percore::{core_scheduler, GsEntryGuard};
 use arch::x86_64::mm::paging::virt_to_phys;
 use drivers::net::{networkd, NETWORK_TASK_ID, NET_SEM};
 use scheduler;
@@ -303,8 +303,8 @@ extern "C" fn rtl8139_thread(arg: usize) {
 		outl(IOBASE + TCR, TCR_IFG | TCR_MXDMA0 | TCR_MXDMA1 | TCR_MXDMA2);
 	}
 
-	let rxbuffer = ::mm::allocate_iomem(RX_BUF_LEN);
-	let txbuffer = ::mm::allocate_iomem(NO_TX_BUFFERS * TX_BUF_LEN);
+	let rxbuffer = ::mm::allocate_iomem(RX_BUF_LEN, ::mm::CachePolicy::Uncached);
+	let txbuffer = ::mm::allocate_iomem(NO_TX_BUFFERS * TX_BUF_LEN, ::mm::CachePolicy::Uncached);
 	if txbuffer == 0 || rxbuffer == 0 {
 		error!("Unable to allocate buffers for RTL8139");
 		return;
@@ -390,6 +390,7 @@ unsafe fn tx_handler() {
 }
 
 extern "x86-interrupt" fn rtl8139_irqhandler(_stack_frame: &mut ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	debug!("Receive network interrupt from RTL8139");
 
 	unsafe {
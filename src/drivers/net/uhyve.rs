@@ -11,6 +11,7 @@ use core::ffi::c_void;
 use core::ptr::read_volatile;
 use core::sync::atomic::{AtomicBool, Ordering};
 use core::{ptr, str};
+use drivers::net::arp;
 use drivers::net::NetworkInterface;
 use synch;
 use syscalls::sys_sem_post;
@@ -20,7 +21,7 @@ use arch::x86_64::kernel::apic;
 #[cfg(target_arch = "x86_64")]
 use arch::x86_64::kernel::irq::*;
 #[cfg(target_arch = "x86_64")]
-use arch::x86_64::kernel::percore::core_scheduler;
+use arch::x86_64::kernel::percore::{core_scheduler, GsEntryGuard};
 #[cfg(target_arch = "x86_64")]
 use arch::x86_64::kernel::{get_gateway, get_ip};
 #[cfg(target_arch = "x86_64")]
@@ -135,6 +136,10 @@ impl NetworkInterface for UhyveNetwork {
 			0
 		}
 	}
+
+	fn get_mac_address(&self) -> [u8; 6] {
+		arp::parse_mac(&self.mac).unwrap_or([0; 6])
+	}
 }
 
 /// Datatype to receive packets from uhyve
@@ -231,6 +236,7 @@ pub fn init() -> Result<Box<dyn NetworkInterface>, ()> {
 
 #[cfg(target_arch = "x86_64")]
 extern "x86-interrupt" fn uhyve_irqhandler(_stack_frame: &mut ExceptionStackFrame) {
+	let _gs = GsEntryGuard::new();
 	debug!("Receive network interrupt from uhyve");
 	crate::drivers::net::sys_set_polling(true);
 	apic::eoi();
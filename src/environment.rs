@@ -20,17 +20,34 @@ pub use arch::aarch64::kernel::{
 	get_base_address, get_cmdline, get_cmdsize, get_image_size, is_single_kernel, is_uhyve,
 };
 
+use config::KERNEL_HEAP_SIZE;
+use core::mem;
 use core::slice::from_raw_parts;
 use core::str::from_utf8_unchecked;
+use core::sync::atomic::{AtomicBool, Ordering};
+use log::LevelFilter;
 use mm;
 
 safe_global_var!(static mut COMMAND_LINE_CPU_FREQUENCY: u16 = 0);
 safe_global_var!(static mut IS_PROXY: bool = false);
+safe_global_var!(static mut IS_LOG_JSON: bool = false);
+safe_global_var!(static mut IS_SELFTEST: bool = false);
+safe_global_var!(static mut IS_BENCH: bool = false);
 
-fn parse_command_line() {
+/// Flag, which occupies a whole page, so that its page can be frozen without affecting other data.
+#[repr(align(4096))]
+struct PageAlignedFlag(AtomicBool);
+
+/// The isolation macros check this flag with the PKRU of every domain.
+/// Hence, it isn't part of `.safe_data`, which only the kernel domain is able to read.
+/// Instead, `init` freezes its page after parsing the command line, so the flag is read-only after boot.
+static MPK_ENABLED: PageAlignedFlag = PageAlignedFlag(AtomicBool::new(true));
+
+/// Returns the command line passed by the loader.
+fn command_line() -> Option<&'static str> {
 	let cmdsize = get_cmdsize();
 	if cmdsize == 0 {
-		return;
+		return None;
 	}
 
 	// Convert the command-line into a Rust string slice.
@@ -42,6 +59,27 @@ fn parse_command_line() {
 		cmdline_str = isolate_function_strong!(from_utf8_unchecked(slice));
 	}
 
+	Some(cmdline_str)
+}
+
+/// Parses a size like `512K`, `64M` or `1G`. A plain number is interpreted as bytes.
+fn parse_size(size_str: &str) -> Option<usize> {
+	let (digits, shift) = match size_str.chars().last()? {
+		'k' | 'K' => (&size_str[..size_str.len() - 1], 10),
+		'm' | 'M' => (&size_str[..size_str.len() - 1], 20),
+		'g' | 'G' => (&size_str[..size_str.len() - 1], 30),
+		_ => (size_str, 0),
+	};
+
+	digits.parse::<usize>().ok()?.checked_mul(1 << shift)
+}
+
+fn parse_command_line() {
+	let cmdline_str = match command_line() {
+		Some(cmdline_str) => cmdline_str,
+		None => return,
+	};
+
 	// Check for the -freq option.
 	if let Some(freq_index) = cmdline_str.find("-freq") {
 		let cmdline_freq_str = cmdline_str.split_at(freq_index + "-freq".len()).1;
@@ -58,11 +96,28 @@ fn parse_command_line() {
 
 	// Check for the -proxy option.
 	unsafe { IS_PROXY = cmdline_str.find("-proxy").is_some(); }
+
+	// Check for the -logjson option.
+	unsafe { IS_LOG_JSON = cmdline_str.find("-logjson").is_some(); }
+
+	// Check for the -selftest option.
+	unsafe { IS_SELFTEST = cmdline_str.find("-selftest").is_some(); }
+
+	// Check for the -bench option.
+	unsafe { IS_BENCH = cmdline_str.find("-bench").is_some(); }
+
+	// Check for the -nompk option.
+	MPK_ENABLED.0.store(cmdline_str.find("-nompk").is_none(), Ordering::SeqCst);
 }
 
 pub fn init() {
 	parse_command_line();
 
+	// Nobody must be able to disable the enforcement later on.
+	#[cfg(target_arch = "x86_64")]
+	mm::freeze(&MPK_ENABLED as *const _ as usize, mem::size_of::<PageAlignedFlag>())
+		.expect("Unable to freeze the MPK_ENABLED flag");
+
 	if is_uhyve() || is_single_kernel() {
 		// We are running under uhyve or baremetal, which implies unikernel mode and no communication with "proxy".
 		unsafe { IS_PROXY = false; }
@@ -70,6 +125,17 @@ pub fn init() {
 		// We are running side-by-side to Linux, which implies communication with "proxy".
 		unsafe { IS_PROXY = true; }
 	}
+
+	if let Some(level) = get_log_level() {
+		::logging::set_level(level);
+	}
+
+	if !mpk_enabled() {
+		info!("MPK enforcement is disabled, all protection domains are accessible");
+		// Permissions, which have been restricted during the initialization of the memory, are opened again.
+		#[cfg(target_arch = "x86_64")]
+		::arch::mm::mpk::mpk_clear_pkru();
+	}
 }
 
 /// CPU Frequency in MHz if given through the -freq command-line parameter, otherwise zero.
@@ -77,8 +143,75 @@ pub fn get_command_line_cpu_frequency() -> u16 {
 	unsafe { COMMAND_LINE_CPU_FREQUENCY }
 }
 
+/// Size of the kernel heap in bytes if given through the -kheap command-line parameter,
+/// otherwise `config::KERNEL_HEAP_SIZE`.
+///
+/// In contrast to the other parameters, this one is already required by `mm::init`.
+/// Hence, the command line is parsed on demand.
+pub fn get_kernel_heap_size() -> usize {
+	let requested = command_line().and_then(|cmdline_str| {
+		let index = cmdline_str.find("-kheap")?;
+		let size_str = cmdline_str
+			.split_at(index + "-kheap".len())
+			.1
+			.split_whitespace()
+			.next()?;
+		let size = parse_size(size_str);
+		if size.is_none() {
+			warn!("Could not parse -kheap command line {}", size_str);
+		}
+
+		size
+	});
+
+	requested.unwrap_or(KERNEL_HEAP_SIZE)
+}
+
+/// Verbosity of the kernel messages if given through the -loglevel command-line parameter
+/// (`off`, `error`, `warn`, `info`, `debug` or `trace`).
+pub fn get_log_level() -> Option<LevelFilter> {
+	let cmdline_str = command_line()?;
+	let index = cmdline_str.find("-loglevel")?;
+	let level_str = cmdline_str
+		.split_at(index + "-loglevel".len())
+		.1
+		.split_whitespace()
+		.next()?;
+	let level = level_str.parse().ok();
+	if level.is_none() {
+		warn!("Could not parse -loglevel command line {}", level_str);
+	}
+
+	level
+}
+
 /// Whether HermitCore shall communicate with the "proxy" application over a network interface.
 /// Only valid after calling init()!
 pub fn is_proxy() -> bool {
 	unsafe { IS_PROXY }
 }
+
+/// Whether the PKRU restricts the access to the protection domains (disabled by the -nompk command-line parameter).
+///
+/// Without enforcement, the allocators still tag pages with their keys, but the PKRU stays fully open.
+/// This allows comparing the costs of the isolation with the same image. Only valid after calling init()!
+#[inline(always)]
+pub fn mpk_enabled() -> bool {
+	MPK_ENABLED.0.load(Ordering::Relaxed)
+}
+
+/// Whether log records shall be printed as single-line JSON objects (-logjson command-line parameter).
+/// Only valid after calling init(), the human readable format is used before.
+pub fn is_log_json() -> bool {
+	unsafe { IS_LOG_JSON }
+}
+
+/// Whether the kernel runs its self tests before the application is started (-selftest command-line parameter).
+pub fn is_selftest() -> bool {
+	unsafe { IS_SELFTEST }
+}
+
+/// Whether the kernel runs its micro-benchmarks before the application is started (-bench command-line parameter).
+pub fn is_bench() -> bool {
+	unsafe { IS_BENCH }
+}
@@ -77,6 +77,8 @@ mod mm;
 #[cfg(not(test))]
 mod runtime_glue;
 mod scheduler;
+#[cfg(not(test))]
+mod selftest;
 mod synch;
 mod syscalls;
 
@@ -89,15 +91,24 @@ use arch::percore::*;
 use core::alloc::GlobalAlloc;
 use mm::allocator::LockedHeap;
 
-#[cfg(not(test))]
-#[global_allocator]
+#[cfg_attr(not(test), global_allocator)]
 static mut ALLOCATOR: LockedHeap = LockedHeap::empty();
 
 /// Interface to allocate memory from system heap
-#[cfg(not(test))]
+///
+/// Returns a null pointer if `size` and `align` do not describe a valid layout.
 #[no_mangle]
 pub extern "C" fn sys_malloc(size: usize, align: usize) -> *mut u8 {
-	let layout: Layout = Layout::from_size_align(size, align).unwrap();
+	let layout: Layout = match Layout::from_size_align(size, align) {
+		Ok(layout) => layout,
+		Err(_) => {
+			debug!(
+				"sys_malloc: invalid layout (size 0x{:x}, align 0x{:x})",
+				size, align
+			);
+			return core::ptr::null_mut();
+		}
+	};
 	let ptr;
 
 	unsafe {
@@ -117,16 +128,42 @@ pub extern "C" fn sys_malloc(size: usize, align: usize) -> *mut u8 {
 }
 
 /// Interface to increase the size of a memory region
-#[cfg(not(test))]
+///
+/// Returns a null pointer if `size` and `align` do not describe a valid layout.
 #[no_mangle]
 pub extern "C" fn sys_realloc(ptr: *mut u8, size: usize, align: usize, new_size: usize) -> *mut u8 {
-	let layout: Layout = Layout::from_size_align(size, align).unwrap();
+	let layout: Layout = match Layout::from_size_align(size, align) {
+		Ok(layout) => layout,
+		Err(_) => {
+			debug!(
+				"sys_realloc: invalid layout (size 0x{:x}, align 0x{:x})",
+				size, align
+			);
+			return core::ptr::null_mut();
+		}
+	};
 	let new_ptr;
 
+	// Remember the protection key of the old block.
+	// If the block is moved, the new location has to be part of the same domain.
+	let key = if ptr.is_null() {
+		None
+	} else {
+		mm::region_type(ptr as usize)
+	};
+
 	unsafe {
 		new_ptr = ALLOCATOR.realloc(ptr, layout, new_size);
 	}
 
+	if !new_ptr.is_null() && new_ptr != ptr {
+		if let Some(key) = key {
+			if mm::region_type(new_ptr as usize) != Some(key) {
+				mm::set_region_key(new_ptr as usize, new_size, key);
+			}
+		}
+	}
+
 	trace!(
 		"sys_realloc: resize memory at 0x{:x}, new address 0x{:x}",
 		ptr as usize,
@@ -136,10 +173,20 @@ pub extern "C" fn sys_realloc(ptr: *mut u8, size: usize, align: usize, new_size:
 }
 
 /// Interface to deallocate a memory region from the system heap
-#[cfg(not(test))]
+///
+/// Requests with an invalid layout are ignored.
 #[no_mangle]
 pub extern "C" fn sys_free(ptr: *mut u8, size: usize, align: usize) {
-	let layout: Layout = Layout::from_size_align(size, align).unwrap();
+	let layout: Layout = match Layout::from_size_align(size, align) {
+		Ok(layout) => layout,
+		Err(_) => {
+			debug!(
+				"sys_free: invalid layout (size 0x{:x}, align 0x{:x})",
+				size, align
+			);
+			return;
+		}
+	};
 
 	trace!(
 		"sys_free: deallocate memory at 0x{:x} (size 0x{:x})",
@@ -200,6 +247,10 @@ extern "C" fn initd(_arg: usize) {
 	}
 	syscalls::init();
 
+	if config::PKRU_AUDIT_INTERVAL > 0 {
+		core_scheduler().spawn(arch::mm::mpk::audit_task, 0, scheduler::task::LOW_PRIO);
+	}
+
 	// give the IP thread time to initialize the network interface
 	core_scheduler().reschedule();
 
@@ -225,6 +276,14 @@ extern "C" fn initd(_arg: usize) {
         //performance_evaluation();
         //performance_evaluation2();
 
+        if environment::is_bench() {
+                selftest::run_benchmarks();
+        }
+
+        if environment::is_selftest() {
+                selftest::run_kernel_tests();
+        }
+
         user_start!(false);
         arch::processor::fpu_init();
         info!("Call runtime_entry");
@@ -310,6 +369,11 @@ fn boot_processor_main() -> ! {
 		arch::boot_application_processors();
 	}
 
+	// Neither the kernel nor the user domain may access the page tables from now on.
+	if environment::mpk_enabled() && arch::mm::paging::seal().is_err() {
+		warn!("Unable to seal the page tables");
+	}
+
         // Start the initd task.
 	let core_scheduler = core_scheduler();
 	core_scheduler.spawn(initd, 0, scheduler::task::NORMAL_PRIO);
@@ -334,3 +398,15 @@ fn application_processor_main() -> ! {
 		core_scheduler.reschedule_and_wait();
 	}
 }
+
+#[cfg(test)]
+mod test {
+	use super::*;
+
+	#[test]
+	fn malloc_invalid_alignment() {
+		assert!(sys_malloc(16, 3).is_null());
+		assert!(sys_realloc(core::ptr::null_mut(), 16, 3, 32).is_null());
+		sys_free(core::ptr::null_mut(), 16, 3);
+	}
+}
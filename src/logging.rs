@@ -6,6 +6,9 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use core::fmt;
+use core::fmt::Write;
+use environment;
 use log::{set_logger, set_max_level, LevelFilter, Metadata, Record};
 
 /// Data structure to filter kernel messages
@@ -21,7 +24,21 @@ impl log::Log for KernelLogger {
 	}
 
 	fn log(&self, record: &Record) {
-		if self.enabled(record.metadata()) {
+		if !self.enabled(record.metadata()) {
+			return;
+		}
+
+		if environment::is_log_json() {
+			// The record is written to the console directly, so it doesn't need a buffer.
+			let mut console = crate::console::CONSOLE.lock();
+			write_json(
+				&mut *console,
+				record,
+				crate::arch::processor::get_timer_ticks(),
+				crate::arch::percore::core_id(),
+			)
+			.unwrap();
+		} else {
 			println!(
 				"[{}][{}] {}",
 				crate::arch::percore::core_id(),
@@ -32,11 +49,50 @@ impl log::Log for KernelLogger {
 	}
 }
 
+/// Escapes the characters of a JSON string, which is written to the inner writer.
+struct JsonEscape<'a, W: Write>(&'a mut W);
+
+impl<'a, W: Write> Write for JsonEscape<'a, W> {
+	fn write_str(&mut self, s: &str) -> fmt::Result {
+		for character in s.chars() {
+			match character {
+				'"' => self.0.write_str("\\\"")?,
+				'\\' => self.0.write_str("\\\\")?,
+				'\n' => self.0.write_str("\\n")?,
+				'\r' => self.0.write_str("\\r")?,
+				'\t' => self.0.write_str("\\t")?,
+				c if (c as u32) < 0x20 => write!(self.0, "\\u{:04x}", c as u32)?,
+				c => self.0.write_char(c)?,
+			}
+		}
+
+		Ok(())
+	}
+}
+
+/// Writes `record` as a single-line JSON object, which is terminated by a newline.
+/// `timestamp` is the time since boot in microseconds.
+fn write_json<W: Write>(out: &mut W, record: &Record, timestamp: u64, core_id: usize) -> fmt::Result {
+	write!(out, "{{\"level\":\"{}\",\"target\":\"", record.level())?;
+	JsonEscape(out).write_str(record.target())?;
+	out.write_str("\",\"message\":\"")?;
+	write!(JsonEscape(out), "{}", record.args())?;
+	write!(out, "\",\"timestamp\":{},\"core\":{}}}\n", timestamp, core_id)
+}
+
 pub fn init() {
 	set_logger(&KernelLogger).expect("Can't initialize logger");
 	set_max_level(LevelFilter::Info);
 }
 
+/// Changes the verbosity of the kernel messages at runtime.
+///
+/// The log macros compare their level with this filter before they format their arguments.
+/// Hence, a disabled `trace!` costs a single load and branch.
+pub fn set_level(level: LevelFilter) {
+	set_max_level(level);
+}
+
 macro_rules! infoheader {
 	// This should work on paper, but it's currently not supported :(
 	// Refer to https://github.com/rust-lang/rust/issues/46569
@@ -61,3 +117,26 @@ macro_rules! infofooter {
 		info!("");
 		}};
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use log::Level;
+	use std::string::String;
+
+	#[test]
+	fn json_record() {
+		let mut out = String::new();
+		let record = Record::builder()
+			.level(Level::Warn)
+			.target("hermit::mm")
+			.args(format_args!("say \"hi\"\n{}\\", 42))
+			.build();
+
+		write_json(&mut out, &record, 1234, 3).unwrap();
+		assert_eq!(
+			out,
+			"{\"level\":\"WARN\",\"target\":\"hermit::mm\",\"message\":\"say \\\"hi\\\"\\n42\\\\\",\"timestamp\":1234,\"core\":3}\n"
+		);
+	}
+}
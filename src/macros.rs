@@ -110,6 +110,21 @@ macro_rules! unsafe_global_var {
         };
 }
 
+/// Adds `n` PKRU writes to the counter of the current core if `config::COUNT_PKRU_WRITES` is set.
+/// The PKRU has to permit the unsafe domain, which holds the counters.
+macro_rules! count_pkru_writes {
+	($n:expr) => {
+		if ::config::COUNT_PKRU_WRITES {
+			::arch::mm::mpk::count_pkru_writes($n);
+		}
+	};
+}
+
+/// Switches to the user stack and, if `$e` is set, to the user domain.
+///
+/// The domain switches of the macros only replace the permissions of the static keys 0 to 4
+/// (bits 0 to 9 of the PKRU). The dynamic keys keep the permissions of the task, e.g. the keys,
+/// which the scheduler closes for the task-local regions of other tasks.
 macro_rules! user_start {
 	($e:expr) => {
 		let user_stack_pointer = core_scheduler().current_task.borrow().user_stack_pointer;
@@ -130,15 +145,18 @@ macro_rules! user_start {
 				:
 				: "volatile");
 
-			if $e {
-				asm!("mov $$0xfc, %eax;
-				      xor %ecx, %ecx;
-			              xor %edx, %edx;
+			if $e && ::environment::mpk_enabled() {
+				count_pkru_writes!(1);
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x3fc, %eax;
+				      xor %edx, %edx;
 				      wrpkru;
 				      lfence"
 					:
 					:
-					: "eax", "ecx", "edx"
+					: "eax", "ecx", "edx", "cc"
 					: "volatile");
 			}
 		}
@@ -150,15 +168,20 @@ macro_rules! user_end {
 		// And finally start the application.
 		#[allow(unused)]
 		unsafe {
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				:
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x300, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					:
+					:
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+				count_pkru_writes!(1);
+			}
 
 			let kernel_stack_pointer = core_scheduler().current_task.borrow().kernel_stack_pointer;
 
@@ -197,15 +220,20 @@ macro_rules! kernel_enter {
 
 		#[allow(unused)]
 		unsafe {
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				:
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x300, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					:
+					:
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+				count_pkru_writes!(1);
+			}
 
 			asm!("mov %rsp, $0"
 				: "=r"(user_stack_pointer)
@@ -250,15 +278,20 @@ macro_rules! kernel_exit {
 
 			//println!("=========exit : {}/", $e);
 
-			asm!("mov $$0xfc, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				:
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				count_pkru_writes!(1);
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x3fc, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					:
+					:
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+			}
 		}
 	};
 }
@@ -272,15 +305,20 @@ macro_rules! kernel_function {
 		#[allow(unused)]
 		unsafe {
 			// switch permission
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				: 
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x300, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					: 
+					: 
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+				count_pkru_writes!(1);
+			}
 	
 			// Save user stack pointer and 
 			// switch stack to the kernel stack
@@ -315,15 +353,20 @@ macro_rules! kernel_function {
 				:
 				: "volatile");
 
-			asm!("mov $$0xfc, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				count_pkru_writes!(1);
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x3fc, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					: 
+					:
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+			}
 
 			temp_ret
 		}
@@ -339,15 +382,20 @@ macro_rules! kernel_function {
 		#[allow(unused)]
 		unsafe {
 			// switch permission
-			asm!("xor %eax, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				: 
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x300, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					: 
+					: 
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+				count_pkru_writes!(1);
+			}
 	
 			// Save user stack pointer and 
 			// switch stack to the kernel stack
@@ -372,78 +420,108 @@ macro_rules! kernel_function {
 				:
 				: "volatile");
 
-			asm!("mov $$0xfc, %eax;
-			      xor %ecx, %ecx;
-			      xor %edx, %edx;
-			      wrpkru;
-			      lfence"
-				: 
-				:
-				: "eax", "ecx", "edx"
-				: "volatile");
+			if ::environment::mpk_enabled() {
+				count_pkru_writes!(1);
+				asm!("xor %ecx, %ecx;
+				      rdpkru;
+				      and $$0xfffffc00, %eax;
+				      or $$0x3fc, %eax;
+				      xor %edx, %edx;
+				      wrpkru;
+				      lfence"
+					: 
+					:
+					: "eax", "ecx", "edx", "cc"
+					: "volatile");
+			}
 
 			temp_ret
 		}
 	}};
 }
 
+/// Calls a system call, which neither reads nor writes memory of the kernel domains.
+///
+/// In contrast to `kernel_function!`, the PKRU isn't switched and the call stays on the user stack.
+/// Hence, the function runs with the permissions of the application and faults as soon as it
+/// touches protected memory. Use it only for trivial system calls like `sys_getpagesize`.
+macro_rules! kernel_function_nopkru {
+	($f:ident($($x:tt)*)) => {{
+		$f($($x)*)
+	}};
+}
+
+/// Denies the access to the safe domain until `isolation_end!`.
+///
+/// The PKRU is modified instead of being overwritten with a precomputed value, because the sections
+/// may run while other keys have been opened temporarily (e.g., the sealed page tables).
+/// Restoring the PKRU by XRSTOR isn't cheaper than WRPKRU. Hence, the only fast path is to skip
+/// WRPKRU if the permission is already set, e.g. in nested sections. The LFENCE follows the label,
+/// so that the section isn't executed speculatively before the check of the PKRU has been resolved.
+/// Without enforcement (`environment::mpk_enabled`), the macro doesn't touch the PKRU at all.
 macro_rules! isolation_start {
 	() => {
 		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      or $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
+		if ::environment::mpk_enabled() {
+			if ::config::COUNT_PKRU_WRITES {
+				let pkru = ::arch::mm::mpk::mpk_get_pkru();
+				if pkru | mm::UNSAFE_PERMISSION_IN != pkru {
+					count_pkru_writes!(1);
+				}
+			}
+			asm!("xor %ecx, %ecx;
+			      rdpkru;
+			      mov %eax, %edx;
+			      or $0, %edx;
+			      cmp %eax, %edx;
+			      je 1f;
+			      mov %edx, %eax;
+			      xor %edx, %edx;
+			      wrpkru;
+			      1:
+			      lfence"
+				:
+				: "r"(mm::UNSAFE_PERMISSION_IN)
+				: "eax", "ecx", "edx", "cc"
+				: "volatile");
+		}
 	};
 }
 
+/// Allows the access to the safe domain again. Skips WRPKRU if the access is already allowed.
 macro_rules! isolation_end {
 	() => {
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT)
-			: "eax", "ecx", "edx"
-			: "volatile"); 
+		if ::environment::mpk_enabled() {
+			if ::config::COUNT_PKRU_WRITES {
+				let pkru = ::arch::mm::mpk::mpk_get_pkru();
+				if pkru & mm::UNSAFE_PERMISSION_OUT != pkru {
+					count_pkru_writes!(1);
+				}
+			}
+			asm!("xor %ecx, %ecx;
+			      rdpkru;
+			      mov %eax, %edx;
+			      and $0, %edx;
+			      cmp %eax, %edx;
+			      je 1f;
+			      mov %edx, %eax;
+			      xor %edx, %edx;
+			      wrpkru;
+			      1:
+			      lfence"
+				:
+				: "r"(mm::UNSAFE_PERMISSION_OUT)
+				: "eax", "ecx", "edx", "cc"
+				: "volatile");
+		}
 	};
 }
 
 macro_rules! isolation_wrapper {
 	($f:ident($($x:tt)*)) => {{
-		//unsafe{ ::UNSAFE_COUNTER += 1; }
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      or $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_IN)
-			: "eax", "ecx", "edx"
-			: "volatile");
-
+		isolation_start!();
 		let temp_ret = $f($($x)*);
-
-		asm!("xor %ecx, %ecx;
-		      rdpkru;
-		      and $0, %eax;
-		      xor %edx, %edx;
-		      wrpkru;
-		      lfence"
-			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT)
-			: "eax", "ecx", "edx"
-			: "volatile"); 
+		isolation_end!();
 
 		temp_ret
 	}};
@@ -451,13 +529,7 @@ macro_rules! isolation_wrapper {
 
 macro_rules! print_this_page {
     ($addr: expr) => {
-		use x86_64::mm::paging::{BasePageSize, LargePageSize, print_page_table_entry};
-		if ($addr as usize) <= mm::kernel_end_address() {
-			print_page_table_entry::<LargePageSize>($addr as usize);
-		}
-		else {
-			print_page_table_entry::<BasePageSize>($addr as usize);
-		}
+		::x86_64::mm::paging::print_entry($addr as usize);
 	};
 }
 
@@ -532,6 +604,8 @@ macro_rules! unshare_local_var {
 	};
 }
 
+/// Runs the function with the permissions of the unsafe domain on the isolated stack.
+/// Without enforcement (`environment::mpk_enabled`), the PKRU is written back unchanged.
 macro_rules! isolate_function_weak {
 	($f:ident($($x:tt)*)) => {{
 		//unsafe{ ::UNSAFE_COUNTER += 1; }
@@ -541,6 +615,8 @@ macro_rules! isolate_function_weak {
                 use config::DEFAULT_STACK_SIZE;
 
 		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		// Both PKRU writes are counted before the stack is switched.
+		count_pkru_writes!(2);
 		let mut __current_rbp: usize = 0;
 		let mut __current_rsp: usize = 0;
 		let mut __count:usize = 0;
@@ -567,7 +643,7 @@ macro_rules! isolate_function_weak {
 		      wrpkru;
 		      lfence"
 			: 
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
+			: "r"(__isolated_stack),"r"(mm::unsafe_permission_in())
 			: "eax", "ecx", "edx"
 			: "volatile");
 
@@ -581,7 +657,7 @@ macro_rules! isolate_function_weak {
 		      lfence;
 		      mov $1, %rsp"
 			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
+			: "r"(mm::unsafe_permission_out()),"r"(__current_rsp)
 			: "eax", "ecx", "edx"
 			: "volatile");
 
@@ -597,6 +673,8 @@ macro_rules! isolate_function_weak {
                 use config::DEFAULT_STACK_SIZE;
 
 		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		// Both PKRU writes are counted before the stack is switched.
+		count_pkru_writes!(2);
 		let mut __current_rbp: usize = 0;
 		let mut __current_rsp: usize = 0;
 		let mut __count:usize = 0;
@@ -619,7 +697,7 @@ macro_rules! isolate_function_weak {
 		      wrpkru;
 		      lfence"
 			: 
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
+			: "r"(__isolated_stack),"r"(mm::unsafe_permission_in())
 			: "eax", "ecx", "edx"
 			: "volatile");
 
@@ -633,7 +711,7 @@ macro_rules! isolate_function_weak {
 		      lfence;
 		      mov $1, %rsp"
 			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
+			: "r"(mm::unsafe_permission_out()),"r"(__current_rsp)
 			: "eax", "ecx", "edx"
 			: "volatile");
 
@@ -642,12 +720,15 @@ macro_rules! isolate_function_weak {
 	}};
 }
 
+/// Like `isolate_function_weak!`, but the stack frame of the caller isn't shared with the function.
 macro_rules! isolate_function_strong {
 	($f:ident($($x:tt)*)) => {{
 		//unsafe{ ::UNSAFE_COUNTER += 1; }
 		use x86_64::kernel::percore::core_scheduler;
                 use config::DEFAULT_STACK_SIZE;
 		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		// Both PKRU writes are counted before the stack is switched.
+		count_pkru_writes!(2);
 		let mut __current_rsp: usize = 0;
 
 		asm!("mov %rsp, $0;
@@ -661,7 +742,7 @@ macro_rules! isolate_function_strong {
 		      lfence;
                       pop %rdx; pop %rcx; pop %rax"
 			: "=r"(__current_rsp)
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
+			: "r"(__isolated_stack),"r"(mm::unsafe_permission_in())
 			:: "volatile");
 
 		let temp_ret = $f($($x)*);
@@ -674,7 +755,7 @@ macro_rules! isolate_function_strong {
 		      lfence;
 		      mov $1, %rsp"
 			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
+			: "r"(mm::unsafe_permission_out()),"r"(__current_rsp)
 			: "eax", "ecx", "edx" : "volatile");
 
 		temp_ret
@@ -685,6 +766,8 @@ macro_rules! isolate_function_strong {
 		use x86_64::kernel::percore::core_scheduler;
                 use config::DEFAULT_STACK_SIZE;
 		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		// Both PKRU writes are counted before the stack is switched.
+		count_pkru_writes!(2);
 		let mut __current_rsp: usize = 0;
 
 		asm!("mov %rsp, $0;
@@ -698,7 +781,7 @@ macro_rules! isolate_function_strong {
 		      lfence;
                       pop %rdx; pop %rcx; pop %rax"
 			: "=r"(__current_rsp)
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
+			: "r"(__isolated_stack),"r"(mm::unsafe_permission_in())
 			:: "volatile");
 
 		let temp_ret = $p.$f($($x)*);
@@ -711,7 +794,7 @@ macro_rules! isolate_function_strong {
 		      lfence;
 		      mov $1, %rsp"
 			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
+			: "r"(mm::unsafe_permission_out()),"r"(__current_rsp)
 			: "eax", "ecx", "edx" : "volatile");
 
 		temp_ret
@@ -722,6 +805,8 @@ macro_rules! isolate_function_strong {
 		use x86_64::kernel::percore::core_scheduler;
                 use config::DEFAULT_STACK_SIZE;
 		let __isolated_stack = core_scheduler().current_task.borrow().stacks.isolated_stack + DEFAULT_STACK_SIZE;
+		// Both PKRU writes are counted before the stack is switched.
+		count_pkru_writes!(2);
 		let mut __current_rsp: usize = 0;
 
 		asm!("mov %rsp, $0;
@@ -733,7 +818,7 @@ macro_rules! isolate_function_strong {
 		      wrpkru;
 		      lfence"
 			: "=r"(__current_rsp)
-			: "r"(__isolated_stack),"r"(mm::UNSAFE_PERMISSION_IN)
+			: "r"(__isolated_stack),"r"(mm::unsafe_permission_in())
 			: "eax", "ecx", "edx"
 			: "volatile");
 
@@ -747,7 +832,7 @@ macro_rules! isolate_function_strong {
 		      lfence;
 		      mov $1, %rsp"
 			:
-			: "r"(mm::UNSAFE_PERMISSION_OUT),"r"(__current_rsp)
+			: "r"(mm::unsafe_permission_out()),"r"(__current_rsp)
 			: "eax", "ecx", "edx"
 			: "volatile");
 
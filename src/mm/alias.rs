@@ -0,0 +1,65 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reference counts of physical frames, which are mapped at more than one virtual address.
+//!
+//! A frame, which is mapped again by `mm::map_existing`, counts one reference per additional mapping.
+//! Releasing any of the mappings drops a reference, so the frame is only returned to the physical
+//! memory allocator together with its last mapping.
+
+use alloc::collections::BTreeMap;
+use arch;
+use arch::mm::paging::{BasePageSize, PageSize};
+use synch::spinlock::SpinlockIrqSave;
+
+/// Number of additional mappings per aliased frame
+safe_global_var!(static ALIASES: SpinlockIrqSave<BTreeMap<usize, usize>> = SpinlockIrqSave::new(BTreeMap::new()));
+
+/// Records an additional mapping of the frames in `[physical_address, physical_address + size)`.
+pub fn add(physical_address: usize, size: usize) {
+	let mut aliases = ALIASES.lock();
+	for frame in (physical_address..physical_address + size).step_by(BasePageSize::SIZE) {
+		*aliases.entry(frame).or_insert(0) += 1;
+	}
+}
+
+/// Returns `true` if any frame in `[physical_address, physical_address + size)` is mapped more than once.
+pub fn is_aliased(physical_address: usize, size: usize) -> bool {
+	ALIASES
+		.lock()
+		.range(physical_address..physical_address + size)
+		.next()
+		.is_some()
+}
+
+/// Drops a mapping of the frames in `[physical_address, physical_address + size)`.
+/// Frames without further mappings are returned to the physical memory allocator.
+pub fn release(physical_address: usize, size: usize) {
+	let mut aliases = ALIASES.lock();
+	if aliases.range(physical_address..physical_address + size).next().is_none() {
+		drop(aliases);
+		arch::mm::physicalmem::deallocate(physical_address, size);
+		return;
+	}
+
+	for frame in (physical_address..physical_address + size).step_by(BasePageSize::SIZE) {
+		let remaining = match aliases.get_mut(&frame) {
+			Some(count) => {
+				*count -= 1;
+				*count
+			}
+			None => {
+				arch::mm::physicalmem::deallocate(frame, BasePageSize::SIZE);
+				continue;
+			}
+		};
+
+		if remaining == 0 {
+			aliases.remove(&frame);
+		}
+	}
+}
@@ -15,17 +15,14 @@ use core::ptr::NonNull;
 use core::{mem, ptr};
 use core::cell::UnsafeCell;
 use core::marker::Sync;
+use mm;
+use mm::arena;
 use mm::hole::{Hole, HoleList};
 use mm::kernel_end_address;
 use synch::spinlock::*;
 
-/// Size of the preallocated space for the Bootstrap Allocator.
-const BOOTSTRAP_HEAP_SIZE: usize = 4096;
-
 /// A fixed size heap backed by a linked list of free memory blocks.
 pub struct Heap {
-	first_block: [u8; BOOTSTRAP_HEAP_SIZE],
-	index: usize,
 	bottom: usize,
 	size: usize,
 	#[cfg(not(test))]
@@ -38,8 +35,6 @@ impl Heap {
 	/// Creates an empty heap. All allocate calls will return `None`.
 	pub const fn empty() -> Heap {
 		Heap {
-			first_block: [0xCC; BOOTSTRAP_HEAP_SIZE],
-			index: 0,
 			bottom: 0,
 			size: 0,
 			holes: HoleList::empty(),
@@ -64,26 +59,15 @@ impl Heap {
 	/// given address is invalid.
 	pub unsafe fn new(heap_bottom: usize, heap_size: usize) -> Heap {
 		Heap {
-			first_block: [0xCC; BOOTSTRAP_HEAP_SIZE],
-			index: 0,
 			bottom: heap_bottom,
 			size: heap_size,
 			holes: HoleList::new(heap_bottom, heap_size),
 		}
 	}
 
-	/// An allocation using the always available Bootstrap Allocator.
+	/// An allocation using the early bump allocator, which serves the heap until it is initialized.
 	unsafe fn alloc_bootstrap(&mut self, layout: Layout) -> Result<NonNull<u8>, AllocErr> {
-		let ptr = &mut self.first_block[self.index] as *mut u8;
-		let size = align_up!(layout.size(), HoleList::min_size());
-
-		// Bump the heap index and align it up to the next boundary.
-		self.index = align_up!(self.index + size, HoleList::min_size());
-		if self.index >= BOOTSTRAP_HEAP_SIZE {
-			Err(AllocErr)
-		} else {
-			Ok(NonNull::new(ptr).unwrap())
-		}
+		NonNull::new(mm::early::allocate(layout)).ok_or(AllocErr)
 	}
 
 	/// Allocates a chunk of the given size with the given alignment. Returns a pointer to the
@@ -116,7 +100,7 @@ impl Heap {
 	pub unsafe fn deallocate(&mut self, ptr: NonNull<u8>, layout: Layout) {
 		let address = ptr.as_ptr() as usize;
 
-		// We never deallocate memory of the Bootstrap Allocator.
+		// We never deallocate memory of the early allocator.
 		// It would only increase the management burden and we wouldn't save
 		// any significant amounts of memory.
 		// So check if this is a pointer allocated by the System Allocator.
@@ -191,8 +175,6 @@ impl LockedHeap {
 	/// given address is invalid.
 	pub unsafe fn new(heap_bottom: usize, heap_size: usize) -> LockedHeap {
 		LockedHeap(UnsafeCell::new(Heap {
-			first_block: [0xCC; BOOTSTRAP_HEAP_SIZE],
-			index: 0,
 			bottom: heap_bottom,
 			size: heap_size,
 			holes: HoleList::new(heap_bottom, heap_size),
@@ -214,21 +196,50 @@ impl DerefMut for LockedHeap {
     }
 }
 
-unsafe impl GlobalAlloc for LockedHeap {
-	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+impl LockedHeap {
+	/// Allocates memory from the global heap, bypassing the per-core arenas.
+	pub unsafe fn alloc_global(&self, layout: Layout) -> *mut u8 {
         let _guard = LOCK.lock();
         let data = &mut *self.0.get();
 	    data.allocate_first_fit(layout)
 			.ok()
 			.map_or(ptr::null_mut() as *mut u8, |allocation| allocation.as_ptr())
 	}
-	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+
+	/// Returns memory to the global heap, bypassing the per-core arenas.
+	pub unsafe fn dealloc_global(&self, ptr: *mut u8, layout: Layout) {
         let _guard = LOCK.lock();
 		let data = &mut *self.0.get();
 		data.deallocate(NonNull::new_unchecked(ptr), layout)
 	}
 }
 
+unsafe impl GlobalAlloc for LockedHeap {
+	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+		// the unsafe domain allocates from its own heap, until it is exhausted
+		if mm::in_unsafe_domain() {
+			let ptr = mm::unsafe_heap_allocate(layout);
+			if !ptr.is_null() {
+				return ptr;
+			}
+		}
+
+		// small allocations are served by the arena of the current core
+		if let Some(ptr) = arena::allocate(self, &layout) {
+			return ptr;
+		}
+
+		self.alloc_global(arena::block_layout(layout))
+	}
+	unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+		if mm::is_unsafe_heap(ptr) {
+			mm::unsafe_heap_deallocate(ptr, layout);
+		} else if !arena::deallocate(self, ptr, &layout) {
+			self.dealloc_global(ptr, arena::block_layout(layout))
+		}
+	}
+}
+
 /*
 unsafe impl GlobalAlloc for LockedHeap {
 	unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
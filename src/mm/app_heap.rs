@@ -0,0 +1,124 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Heaps, which the application creates in addition to the user heap.
+//!
+//! Each heap owns a region, which is protected by a single key and only holds the data of the
+//! application. The application is able to write the whole region, so the bookkeeping of a heap
+//! (a free list of the region) lives in the kernel and is looked up by the handle of the heap,
+//! which is an index into a slab. Hence, a corrupted region can't mislead the kernel.
+
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
+use arch::mm::paging::{BasePageSize, PageSize};
+use core::ptr;
+use mm;
+use mm::freelist::FreeList;
+use mm::AllocError;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Granularity of the allocations, which keeps the free list short
+const BLOCK_SIZE: usize = 16;
+
+struct AppHeap {
+	start: usize,
+	size: usize,
+	/// Free parts of the region
+	free: FreeList,
+}
+
+/// Slab of the heaps, indexed by their handles. Slots of destroyed heaps are reused.
+safe_global_var!(static HEAPS: SpinlockIrqSave<Vec<Option<AppHeap>>> = SpinlockIrqSave::new(Vec::new()));
+
+/// Creates a heap, which is able to hold `size` bytes and is protected by `key`. Returns its handle.
+pub fn create(size: usize, key: u8) -> Result<usize, AllocError> {
+	let region_size = align_up!(size, BasePageSize::SIZE);
+	let start = if key == 0 {
+		mm::try_user_allocate(region_size, true)?
+	} else {
+		mm::try_key_allocate(region_size, key)?
+	};
+
+	let mut free = FreeList::new();
+	free.deallocate(start, region_size);
+	let app_heap = AppHeap {
+		start: start,
+		size: region_size,
+		free: free,
+	};
+
+	let mut heaps = HEAPS.lock();
+	let handle = match heaps.iter().position(|slot| slot.is_none()) {
+		Some(handle) => {
+			heaps[handle] = Some(app_heap);
+			handle
+		}
+		None => {
+			heaps.push(Some(app_heap));
+			heaps.len() - 1
+		}
+	};
+	debug!(
+		"Created heap {} at {:#X} -- {:#X} with protection key {}",
+		handle,
+		start,
+		start + region_size,
+		key
+	);
+
+	Ok(handle)
+}
+
+/// Returns the size and the alignment of a block, which holds `layout`.
+fn block(layout: Layout) -> (usize, usize) {
+	(
+		align_up!(layout.size(), BLOCK_SIZE),
+		layout.align().max(BLOCK_SIZE),
+	)
+}
+
+/// Returns a null pointer if the handle is invalid or the heap is exhausted.
+pub fn allocate(handle: usize, layout: Layout) -> *mut u8 {
+	let (size, align) = block(layout);
+	match HEAPS.lock().get_mut(handle) {
+		Some(Some(app_heap)) => app_heap
+			.free
+			.allocate_aligned(size, align)
+			.map_or(ptr::null_mut(), |address| address as *mut u8),
+		_ => ptr::null_mut(),
+	}
+}
+
+/// `ptr` has to be returned by `allocate` of the same heap with the same `layout`.
+/// Returns `Err` if the handle is invalid or the block doesn't belong to the heap.
+pub fn deallocate(handle: usize, ptr: *mut u8, layout: Layout) -> Result<(), ()> {
+	let (size, _) = block(layout);
+	match HEAPS.lock().get_mut(handle) {
+		Some(Some(app_heap)) => {
+			let address = ptr as usize;
+			match address.checked_add(size) {
+				Some(end) if address >= app_heap.start && end <= app_heap.start + app_heap.size => {}
+				_ => return Err(()),
+			}
+
+			app_heap.free.deallocate(address, size);
+			Ok(())
+		}
+		_ => Err(()),
+	}
+}
+
+/// Releases the heap together with all of its allocations.
+pub fn destroy(handle: usize) -> Result<(), ()> {
+	let app_heap = match HEAPS.lock().get_mut(handle) {
+		Some(slot) => slot.take().ok_or(())?,
+		None => return Err(()),
+	};
+
+	mm::deallocate(app_heap.start, app_heap.size);
+	Ok(())
+}
@@ -0,0 +1,204 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Per-core heap arenas, which satisfy small allocations without taking the lock of the global heap.
+//!
+//! Each core caches free blocks of a few size classes. An empty cache is refilled by carving
+//! a page from the global heap, a cache holding too many blocks returns half of them.
+
+use alloc::alloc::Layout;
+use arch::irq;
+use arch::mm::paging::{BasePageSize, PageSize};
+use arch::percore::core_id;
+use core::ptr;
+use mm;
+use mm::allocator::LockedHeap;
+
+/// Maximum number of cores, which own an arena. Additional cores use the global heap.
+const MAX_ARENAS: usize = 64;
+
+/// Block sizes of the size classes. The smallest block is a cache line.
+const SIZE_CLASSES: [usize; 6] = [64, 128, 256, 512, 1024, 2048];
+
+/// Size of the chunk, which is taken from the global heap to refill a size class.
+/// It has to be a single 4 KiB page, because `refill` sets the protection key of the whole chunk.
+const SLAB_SIZE: usize = BasePageSize::SIZE;
+
+/// Maximum number of bytes cached per size class before blocks are returned to the global heap.
+const MAX_CACHED_SIZE: usize = 4 * SLAB_SIZE;
+
+struct Arena {
+	/// Bottom of the global heap, which the cached blocks belong to.
+	heap_bottom: usize,
+	/// Heads of the lists of free blocks. The link to the next block is stored in the block itself.
+	free: [usize; SIZE_CLASSES.len()],
+	/// Number of free blocks per size class.
+	count: [usize; SIZE_CLASSES.len()],
+}
+
+impl Arena {
+	const fn new() -> Self {
+		Self {
+			heap_bottom: 0,
+			free: [0; SIZE_CLASSES.len()],
+			count: [0; SIZE_CLASSES.len()],
+		}
+	}
+
+	/// Forgets all cached blocks if the global heap has been replaced.
+	fn validate(&mut self, heap: &LockedHeap) {
+		if self.heap_bottom != heap.bottom() {
+			*self = Self::new();
+			self.heap_bottom = heap.bottom();
+		}
+	}
+
+	fn pop(&mut self, class: usize) -> Option<usize> {
+		let block = self.free[class];
+		if block == 0 {
+			return None;
+		}
+
+		self.free[class] = unsafe { ptr::read(block as *const usize) };
+		self.count[class] -= 1;
+		Some(block)
+	}
+
+	fn push(&mut self, class: usize, block: usize) {
+		unsafe {
+			ptr::write(block as *mut usize, self.free[class]);
+		}
+		self.free[class] = block;
+		self.count[class] += 1;
+	}
+
+	/// Takes a slab from the global heap and splits it into blocks of the given size class.
+	fn refill(&mut self, heap: &LockedHeap, class: usize) -> bool {
+		let slab = unsafe { heap.alloc_global(Layout::from_size_align_unchecked(SLAB_SIZE, SLAB_SIZE)) };
+		if slab.is_null() {
+			return false;
+		}
+
+		// Arena pages belong to the domain of the kernel heap. The slab is a whole, aligned 4 KiB page,
+		// so re-keying it doesn't touch any other allocation.
+		let slab = slab as usize;
+		if mm::region_type(slab) != Some(mm::KERNEL_HEAP_REGION) {
+			mm::set_region_key(slab, SLAB_SIZE, mm::KERNEL_HEAP_REGION);
+		}
+
+		let size = SIZE_CLASSES[class];
+		for block in (slab..slab + SLAB_SIZE).step_by(size).rev() {
+			self.push(class, block);
+		}
+
+		true
+	}
+
+	/// Returns half of the cached blocks of the given size class to the global heap.
+	fn balance(&mut self, heap: &LockedHeap, class: usize) {
+		let size = SIZE_CLASSES[class];
+		let layout = unsafe { Layout::from_size_align_unchecked(size, size) };
+
+		while self.count[class] * size > MAX_CACHED_SIZE / 2 {
+			let block = self.pop(class).unwrap();
+			unsafe {
+				heap.dealloc_global(block as *mut u8, layout);
+			}
+		}
+	}
+}
+
+safe_global_var!(static mut ARENAS: [Arena; MAX_ARENAS] = [
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+	Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(), Arena::new(),
+]);
+
+/// Returns the size class, which is able to hold `layout`, or `None` for large requests.
+fn size_class(layout: &Layout) -> Option<usize> {
+	let size = layout.size().max(layout.align());
+	SIZE_CLASSES.iter().position(|class| size <= *class)
+}
+
+/// Returns the layout of the block, which holds an allocation of `layout`.
+///
+/// Small allocations always occupy a whole block of their size class, even if the global heap serves them.
+/// Hence, every small block can be cached by an arena after it has been freed.
+pub fn block_layout(layout: Layout) -> Layout {
+	match size_class(&layout) {
+		Some(class) => unsafe { Layout::from_size_align_unchecked(SIZE_CLASSES[class], SIZE_CLASSES[class]) },
+		None => layout,
+	}
+}
+
+/// Runs `f` on the arena of the current core with disabled interrupts.
+/// Returns `None` if the request has to be served by the global heap.
+fn with_arena<F, R>(heap: &LockedHeap, f: F) -> Option<R>
+where
+	F: FnOnce(&mut Arena) -> Option<R>,
+{
+	// The bootstrap allocator never frees its memory, so it doesn't benefit from an arena.
+	if heap.bottom() == 0 {
+		return None;
+	}
+
+	let irq = irq::nested_disable();
+	let core_id = core_id();
+	let result = if core_id < MAX_ARENAS {
+		let arena = unsafe { &mut ARENAS[core_id] };
+		arena.validate(heap);
+		f(arena)
+	} else {
+		None
+	};
+	irq::nested_enable(irq);
+
+	result
+}
+
+/// Allocates a small block from the arena of the current core.
+pub fn allocate(heap: &LockedHeap, layout: &Layout) -> Option<*mut u8> {
+	let class = size_class(layout)?;
+
+	with_arena(heap, |arena| {
+		if arena.count[class] == 0 && !arena.refill(heap, class) {
+			return None;
+		}
+
+		arena.pop(class).map(|block| block as *mut u8)
+	})
+}
+
+/// Caches a small block in the arena of the current core.
+/// Returns `false` if the block has to be returned to the global heap.
+pub fn deallocate(heap: &LockedHeap, ptr: *mut u8, layout: &Layout) -> bool {
+	let class = match size_class(layout) {
+		Some(class) => class,
+		None => return false,
+	};
+	let address = ptr as usize;
+
+	// Blocks of a previous heap must not be mixed into the current one.
+	if address < heap.bottom() || address >= heap.top() {
+		return false;
+	}
+
+	with_arena(heap, |arena| {
+		arena.push(class, address);
+		if arena.count[class] * SIZE_CLASSES[class] > MAX_CACHED_SIZE {
+			arena.balance(heap, class);
+		}
+
+		Some(())
+	})
+	.is_some()
+}
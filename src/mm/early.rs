@@ -0,0 +1,92 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Bump allocator for the early boot, before the kernel heap is initialized.
+//!
+//! An uninitialized `Heap` serves its allocations from here. During the early paging setup,
+//! these are the nodes of the physical and the virtual free lists, which `arch::mm::init`
+//! and `reserve_fixed` create before `mm::init` sets up the kernel heap.
+//!
+//! Its memory is a static array of the kernel image, which the loader has already mapped.
+//! Allocations are never released. `finalize` closes the allocator as soon as the kernel heap
+//! is available and records the used part of the array. The array belongs to the kernel image,
+//! so the physical and the virtual memory allocator never hand out this range.
+
+use alloc::alloc::Layout;
+use config::EARLY_HEAP_SIZE;
+use core::ptr;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Backing memory of the allocator, aligned to a page
+#[repr(C, align(4096))]
+struct EarlyHeap([u8; EARLY_HEAP_SIZE]);
+
+pub struct BumpAllocator {
+	heap: EarlyHeap,
+	/// Offset of the first free byte in `heap`
+	next: usize,
+	/// Set by `finalize`, afterwards every allocation fails
+	finalized: bool,
+}
+
+impl BumpAllocator {
+	pub const fn new() -> Self {
+		Self {
+			heap: EarlyHeap([0; EARLY_HEAP_SIZE]),
+			next: 0,
+			finalized: false,
+		}
+	}
+
+	/// Returns a null pointer if the allocator is finalized or exhausted.
+	pub fn allocate(&mut self, layout: Layout) -> *mut u8 {
+		if self.finalized {
+			return ptr::null_mut();
+		}
+
+		let bottom = self.heap.0.as_mut_ptr() as usize;
+		let start = align_up!(bottom + self.next, layout.align());
+		let end = match start.checked_add(layout.size()) {
+			Some(end) if end <= bottom + EARLY_HEAP_SIZE => end,
+			_ => return ptr::null_mut(),
+		};
+
+		self.next = end - bottom;
+		start as *mut u8
+	}
+
+	/// Closes the allocator and returns the range `[start, end)`, which has been handed out.
+	pub fn finalize(&mut self) -> (usize, usize) {
+		self.finalized = true;
+		self.used()
+	}
+
+	/// Returns the range `[start, end)`, which has been handed out so far.
+	pub fn used(&self) -> (usize, usize) {
+		let bottom = self.heap.0.as_ptr() as usize;
+		(bottom, bottom + self.next)
+	}
+}
+
+static EARLY_ALLOCATOR: SpinlockIrqSave<BumpAllocator> = SpinlockIrqSave::new(BumpAllocator::new());
+
+/// Allocates memory, which lives until the system shuts down.
+/// Returns a null pointer after `finalize` or if the early heap is exhausted.
+pub fn allocate(layout: Layout) -> *mut u8 {
+	EARLY_ALLOCATOR.lock().allocate(layout)
+}
+
+/// Closes the early allocator. Called by `mm::init` as soon as the kernel heap is initialized.
+pub fn finalize() {
+	let (start, end) = EARLY_ALLOCATOR.lock().finalize();
+	debug!(
+		"Early allocator used {:#X} bytes at {:#X} -- {:#X}",
+		end - start,
+		start,
+		end
+	);
+}
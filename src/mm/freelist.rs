@@ -9,6 +9,23 @@ use alloc::rc::Rc;
 use collections::{DoublyLinkedList, Node};
 use core::cell::RefCell;
 
+/// Maximum number of entries, which are printed by `FreeList::dump`.
+const MAX_DUMPED_ENTRIES: usize = 256;
+
+/// The first inconsistency of a free list, which has been found by `FreeList::validate`.
+/// Each variant carries the start address of the offending entry.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum FreelistError {
+	/// The entry doesn't cover any memory (its end isn't above its start).
+	EmptyRange(usize),
+	/// The entry starts below its predecessor.
+	NonMonotonic(usize),
+	/// The entry starts within its predecessor.
+	Overlapping(usize),
+	/// The list loops back to this entry.
+	Cycle(usize),
+}
+
 pub struct FreeListEntry {
 	pub start: usize,
 	pub end: usize,
@@ -214,6 +231,88 @@ impl FreeList {
 		}
 	}
 
+	/// Returns the number of bytes, which are covered by the free list.
+	pub fn size(&self) -> usize {
+		self.list
+			.iter()
+			.map(|node| {
+				let borrowed = node.borrow();
+				borrowed.value.end - borrowed.value.start
+			})
+			.sum()
+	}
+
+	/// Walks the list and returns the first entry, which is empty, isn't sorted by its
+	/// address, overlaps its predecessor or closes a cycle.
+	pub fn validate(&self) -> Result<(), FreelistError> {
+		let mut previous: Option<(usize, usize)> = None;
+		// Floyd's cycle detection: `hare` advances by two entries per entry of `iter`.
+		let mut hare = self.list.iter();
+		hare.next();
+
+		for node in self.list.iter() {
+			let (start, end) = {
+				let borrowed = node.borrow();
+				(borrowed.value.start, borrowed.value.end)
+			};
+
+			if end <= start {
+				return Err(FreelistError::EmptyRange(start));
+			}
+			if let Some((previous_start, previous_end)) = previous {
+				if start < previous_start {
+					return Err(FreelistError::NonMonotonic(start));
+				} else if start < previous_end {
+					return Err(FreelistError::Overlapping(start));
+				}
+			}
+			previous = Some((start, end));
+
+			for _ in 0..2 {
+				match hare.next() {
+					Some(ref other) if Rc::ptr_eq(other, &node) => {
+						return Err(FreelistError::Cycle(start));
+					}
+					Some(_) => {}
+					None => return Ok(()),
+				}
+			}
+		}
+
+		Ok(())
+	}
+
+	/// Prints each entry with its index and size, followed by the result of `validate`.
+	pub fn dump(&self, header: &str) {
+		infoheader!(header);
+
+		// The walk is always bounded, so a corrupted list (e.g. with a cycle) doesn't keep the dump running.
+		let result = self.validate();
+		for (i, node) in self.list.iter().take(MAX_DUMPED_ENTRIES).enumerate() {
+			let (region_start, region_end) = {
+				let borrowed = node.borrow();
+				(borrowed.value.start, borrowed.value.end)
+			};
+			info!(
+				"{:>4}: {:#016X} - {:#016X} ({:#X} bytes)",
+				i,
+				region_start,
+				region_end,
+				region_end.wrapping_sub(region_start)
+			);
+		}
+		if result.is_ok() {
+			// A valid list is finite.
+			let count = self.list.iter().count();
+			if count > MAX_DUMPED_ENTRIES {
+				info!("... {} more entries", count - MAX_DUMPED_ENTRIES);
+			}
+		}
+		info!("Validation: {:?}", result);
+
+		infofooter!();
+	}
+
 	pub fn print_information(&self, header: &str) {
 		infoheader!(header);
 
@@ -285,3 +384,70 @@ fn deallocate() {
 		assert!(node.borrow_mut().value.end != 0x10000);
 	}
 }
+
+#[test]
+fn size() {
+	let mut freelist = FreeList::new();
+	let entry = Node::new(FreeListEntry {
+		start: 0x10000,
+		end: 0x100000,
+	});
+
+	freelist.list.push(entry);
+	assert_eq!(freelist.size(), 0xF0000);
+
+	let addr = freelist.allocate(0x1000);
+	assert_eq!(freelist.size(), 0xEF000);
+
+	freelist.deallocate(addr.unwrap(), 0x1000);
+	assert_eq!(freelist.size(), 0xF0000);
+}
+
+#[test]
+fn validate() {
+	let mut freelist = FreeList::new();
+	freelist.list.push(Node::new(FreeListEntry::new(0x10000, 0x20000)));
+	freelist.list.push(Node::new(FreeListEntry::new(0x30000, 0x40000)));
+	assert_eq!(freelist.validate(), Ok(()));
+
+	// allocations and deallocations keep the list consistent
+	let addr = freelist.allocate_aligned(0x1000, 0x4000).unwrap();
+	freelist.deallocate(addr, 0x1000);
+	assert_eq!(freelist.validate(), Ok(()));
+
+	let mut overlapping = FreeList::new();
+	overlapping.list.push(Node::new(FreeListEntry::new(0x10000, 0x20000)));
+	overlapping.list.push(Node::new(FreeListEntry::new(0x18000, 0x28000)));
+	assert_eq!(overlapping.validate(), Err(FreelistError::Overlapping(0x18000)));
+
+	let mut unsorted = FreeList::new();
+	unsorted.list.push(Node::new(FreeListEntry::new(0x30000, 0x40000)));
+	unsorted.list.push(Node::new(FreeListEntry::new(0x10000, 0x20000)));
+	assert_eq!(unsorted.validate(), Err(FreelistError::NonMonotonic(0x10000)));
+
+	let mut empty = FreeList::new();
+	empty.list.push(Node::new(FreeListEntry::new(0x10000, 0x10000)));
+	assert_eq!(empty.validate(), Err(FreelistError::EmptyRange(0x10000)));
+}
+
+#[test]
+fn dump_cycle() {
+	let y = Node::new(FreeListEntry::new(0x50000, 0x60000));
+	let m = Node::new(FreeListEntry::new(0x10000, 0x20000));
+	let x = Node::new(FreeListEntry::new(0x30000, 0x40000));
+
+	// Mount the nodes through a second list to end up with y -> m -> x -> y.
+	let mut freelist = FreeList::new();
+	let mut other = DoublyLinkedList::new();
+	other.push(y.clone());
+	freelist.list.push(y.clone());
+	freelist.list.push(x.clone());
+	other.push(Node::new(FreeListEntry::new(0x70000, 0x80000)));
+	other.remove(y.clone());
+	freelist.list.push(y.clone());
+	freelist.list.insert_before(m, x);
+
+	// the unsorted entry is found before the cycle, the dump still terminates
+	assert_eq!(freelist.validate(), Err(FreelistError::NonMonotonic(0x10000)));
+	freelist.dump("Free list with a cycle");
+}
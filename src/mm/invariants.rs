@@ -0,0 +1,62 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Validation of the global invariants of the address space.
+
+use arch;
+use arch::mm::paging::PageTableEntryFlags;
+use mm;
+
+/// The first violated invariant together with the address, at which it has been found.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum InvariantViolation {
+	/// A page outside the kernel image is writable and executable without `allow_wx`.
+	WritableExecutable(usize),
+	/// A page is tagged with a protection key, but isn't accessible from the user space.
+	/// The processor only applies protection keys to user pages, so the key isn't enforced.
+	UnenforcedKey(usize),
+	/// The null page is mapped without `allow_null`.
+	NullPageMapped(usize),
+	/// The `.safe_data` and `.unsafe_data` sections overlap each other or partially overlap the kernel image.
+	OverlappingSections(usize),
+}
+
+/// Walks the page tables and returns the first violation of the invariants of the address space.
+///
+/// The loader maps the kernel image writable and executable, so it is exempted from the W^X check.
+pub fn check_invariants() -> Result<(), InvariantViolation> {
+	let kernel_start = mm::kernel_start_address();
+	let kernel_end = mm::kernel_end_address();
+
+	if let Err((section, _)) = mm::validate_data_sections(
+		("kernel image", kernel_start, kernel_end),
+		(".safe_data", mm::SAFE_DATA_START, mm::SAFE_DATA_START + mm::DATA_SECTION_SIZE),
+		(".unsafe_data", mm::UNSAFE_DATA_START, mm::UNSAFE_DATA_START + mm::DATA_SECTION_SIZE),
+	) {
+		return Err(InvariantViolation::OverlappingSections(section.1));
+	}
+
+	for (start, size, flags, pkey) in arch::mm::paging::mapped_regions() {
+		if start == 0 && !flags.contains(PageTableEntryFlags::ALLOW_NULL) {
+			return Err(InvariantViolation::NullPageMapped(start));
+		}
+
+		if flags.violates_wx() {
+			if start < kernel_start {
+				return Err(InvariantViolation::WritableExecutable(start));
+			} else if start + size > kernel_end {
+				return Err(InvariantViolation::WritableExecutable(start.max(kernel_end)));
+			}
+		}
+
+		if pkey != 0 && !flags.contains(PageTableEntryFlags::USER_ACCESSIBLE) {
+			return Err(InvariantViolation::UnenforcedKey(start));
+		}
+	}
+
+	Ok(())
+}
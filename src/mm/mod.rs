@@ -5,20 +5,39 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+mod alias;
 pub mod allocator;
+pub mod app_heap;
+mod arena;
+pub mod early;
 pub mod freelist;
 mod hole;
+mod invariants;
+mod reclaim;
+mod reservation;
+mod scratch;
 #[cfg(test)]
 mod test;
+mod unsafe_heap;
 
+use alloc::alloc::Layout;
 use arch;
-use arch::mm::paging::{BasePageSize, HugePageSize, LargePageSize, PageSize, PageTableEntryFlags};
+use arch::mm::paging::{
+	get_leaf_entry, set_pkey_on_page_table_entry, BasePageSize, HugePageSize, LargePageSize,
+	PageSize, PageTableEntryFlags,
+};
+use arch::mm::mpk;
+pub use arch::mm::paging::CachePolicy;
 use arch::mm::physicalmem::total_memory_size;
 #[cfg(feature = "newlib")]
 use arch::mm::virtualmem::kernel_heap_end;
+use config::{CHECK_MM_INVARIANTS, USER_HEAP_GUARD_SIZE};
 use core::mem;
 use core::sync::atomic::spin_loop_hint;
 use environment;
+pub use self::invariants::{check_invariants, InvariantViolation};
+pub use self::scratch::Arena;
+use synch::spinlock::{Spinlock, SpinlockIrqSave};
 
 #[allow(unused)]
 /// Physical and virtual address of the first 2 MiB page that maps the kernel.
@@ -45,14 +64,127 @@ safe_global_var!(static mut USER_HEAP_SIZE: usize = 0);
 pub const SAFE_MEM_REGION: u8 = 1;
 pub const UNSAFE_MEM_REGION: u8 = 2;
 pub const SHARED_MEM_REGION: u8 = 3;
+/// Protection key of the page tables after `paging::seal`. Neither the kernel (PKRU 0x300)
+/// nor the user domain (PKRU 0x3FC) may access it, only the paging module opens it transiently.
+pub const PAGE_TABLE_MEM_REGION: u8 = 4;
+/// Protection key of the kernel heap in both the pure Rust and the newlib build.
+///
+/// The heap backs the global allocator, which is also used by code within `isolation_start!`
+/// and `isolation_end!`. The safe domain is closed there, so the heap belongs to the unsafe domain.
+/// Memory of the safe domain has to be allocated explicitly (e.g., by `allocate`).
+pub const KERNEL_HEAP_REGION: u8 = UNSAFE_MEM_REGION;
 //pub const USER_MEM_REGION: u8 = 10;
 
-pub const UNSAFE_PERMISSION_IN: u32 = 0xC;
+/// Virtual and physical start address of the .safe_data section
+const SAFE_DATA_START: usize = 0x400000;
+/// Virtual and physical start address of the .unsafe_data section
+const UNSAFE_DATA_START: usize = 0x600000;
+/// Size of the .safe_data and .unsafe_data section
+const DATA_SECTION_SIZE: usize = 0x200000;
+/// Size of the unmapped guard at the end of the .safe_data section.
+/// The linker script keeps the section below this guard.
+const DATA_GUARD_SIZE: usize = 0x1000;
+
+/// PKRU bits, which deny the access to the safe domain.
+pub const UNSAFE_PERMISSION_IN: u32 = mpk::MpkPerm::MpkNone.to_pkru_bits(SAFE_MEM_REGION);
 pub const UNSAFE_PERMISSION_OUT: u32 = !UNSAFE_PERMISSION_IN;
 
+/// Bits, which the isolation macros set in the PKRU to deny the access to the safe domain.
+/// Without enforcement (`environment::mpk_enabled`), no bit is set and the PKRU stays open.
+#[inline(always)]
+pub fn unsafe_permission_in() -> u32 {
+	if environment::mpk_enabled() {
+		UNSAFE_PERMISSION_IN
+	} else {
+		0
+	}
+}
+
+/// Mask, which the isolation macros apply to the PKRU to allow the access to the safe domain again.
+#[inline(always)]
+pub fn unsafe_permission_out() -> u32 {
+	!unsafe_permission_in()
+}
+
 //pub const USER_PERMISSION_IN: u32 = 0xfC;
 //pub const USER_PERMISSION_OUT: u32 = !USER_PERMISSION_IN;
 
+/// Byte pattern, which overwrites freed regions in debug builds
+const POISON_PATTERN: u8 = 0xDE;
+
+/// Number of freed virtual ranges, which are kept unmapped in debug builds
+const QUARANTINE_SLOTS: usize = 16;
+
+/// Ring buffer of quarantined virtual ranges and the index of the next slot to be replaced
+safe_global_var!(static QUARANTINE: SpinlockIrqSave<([(usize, usize); QUARANTINE_SLOTS], usize)> =
+	SpinlockIrqSave::new(([(0, 0); QUARANTINE_SLOTS], 0)));
+
+/// Serializes `swap_mapping`, so that concurrent swaps of the same page neither lose nor duplicate a frame.
+/// A waiting core keeps its interrupts enabled, so that it acknowledges the shootdown of the holder.
+safe_global_var!(static SWAP_LOCK: Spinlock<()> = Spinlock::new(()));
+
+/// Maximum number of boot phases, which are recorded by `PhaseTimer`
+const MAX_INIT_PHASES: usize = 8;
+
+/// Records the completion of the boot phases of the memory management.
+///
+/// The timer isn't calibrated while `init` runs. Hence, the timestamps are given in CPU cycles.
+pub struct PhaseTimer {
+	start: u64,
+	phases: [(&'static str, u64); MAX_INIT_PHASES],
+	count: usize,
+}
+
+impl PhaseTimer {
+	pub const fn new() -> Self {
+		Self {
+			start: 0,
+			phases: [("", 0); MAX_INIT_PHASES],
+			count: 0,
+		}
+	}
+
+	/// Marks the begin of the first phase.
+	pub fn start(&mut self, timestamp: u64) {
+		self.start = timestamp;
+		self.count = 0;
+	}
+
+	/// Marks the completion of `phase` and logs the time spent on it.
+	pub fn phase(&mut self, phase: &'static str, timestamp: u64) {
+		let last = if self.count > 0 {
+			self.phases[self.count - 1].1
+		} else {
+			self.start
+		};
+
+		info!(
+			"mm::init: {} finished after {} cycles (+{} cycles)",
+			phase,
+			timestamp - self.start,
+			timestamp - last
+		);
+
+		if self.count < MAX_INIT_PHASES {
+			self.phases[self.count] = (phase, timestamp);
+			self.count += 1;
+		}
+	}
+
+	/// Returns the recorded phases and the timestamps of their completion.
+	pub fn phases(&self) -> &[(&'static str, u64)] {
+		&self.phases[..self.count]
+	}
+}
+
+safe_global_var!(static mut INIT_PHASES: PhaseTimer = PhaseTimer::new());
+
+fn init_phase(phase: &'static str) {
+	unsafe {
+		INIT_PHASES.phase(phase, arch::processor::get_timestamp());
+	}
+}
+
 pub fn kernel_start_address() -> usize {
 	unsafe { KERNEL_START_ADDRESS }
 }
@@ -61,6 +193,22 @@ pub fn kernel_end_address() -> usize {
 	unsafe { KERNEL_END_ADDRESS }
 }
 
+/// Returns the size of the kernel heap.
+pub fn kernel_heap_size() -> usize {
+	unsafe { HEAP_END_ADDRESS - HEAP_START_ADDRESS }
+}
+
+/// Returns the size of the user heap.
+pub fn user_heap_size() -> usize {
+	unsafe { USER_HEAP_END_ADDRESS - USER_HEAP_START_ADDRESS }
+}
+
+/// Returns the start address of the user heap, which is preceded by an unmapped guard of
+/// `config::USER_HEAP_GUARD_SIZE` bytes.
+pub fn user_heap_start() -> usize {
+	unsafe { USER_HEAP_START_ADDRESS }
+}
+
 #[cfg(feature = "newlib")]
 pub fn task_heap_start() -> usize {
 	unsafe { USER_HEAP_START_ADDRESS }
@@ -77,16 +225,25 @@ fn map_heap<S: PageSize>(virt_addr: usize, size: usize, is_kernel: bool) -> usiz
 
 	if is_kernel {
 		// map the kernel heap
-		flags.normal().writable().execute_disable().pkey(UNSAFE_MEM_REGION);
+		flags.normal().writable().execute_disable().pkey(KERNEL_HEAP_REGION);
 	} else {
 		// map the user heap
 		flags.normal().writable().execute_disable();
 	}
 	while i < align_down!(size, S::SIZE) {
-		match arch::mm::physicalmem::allocate_aligned(S::SIZE, S::SIZE) {
-			Ok(phys_addr) => {
-				arch::mm::paging::map::<S>(virt_addr + i, phys_addr, 1, flags);
-                i += S::SIZE;
+		// fall back to smaller pages if the physical memory is too fragmented
+		match arch::mm::physicalmem::allocate_best_effort(S::SIZE, S::SIZE) {
+			Ok((phys_addr, HugePageSize::SIZE)) => {
+				arch::mm::paging::map::<HugePageSize>(virt_addr + i, phys_addr, 1, flags);
+				i += S::SIZE;
+			}
+			Ok((phys_addr, LargePageSize::SIZE)) => {
+				arch::mm::paging::map::<LargePageSize>(virt_addr + i, phys_addr, S::SIZE / LargePageSize::SIZE, flags);
+				i += S::SIZE;
+			}
+			Ok((phys_addr, _)) => {
+				arch::mm::paging::map::<BasePageSize>(virt_addr + i, phys_addr, S::SIZE / BasePageSize::SIZE, flags);
+				i += S::SIZE;
 			}
 			Err(_) => {
 				error!("Unable to allocate page frame of size 0x{:x}", S::SIZE);
@@ -115,10 +272,16 @@ pub fn init() {
 		info!("get_image_size: {:#X}", environment::get_image_size());
 	}
 
+	unsafe {
+		INIT_PHASES.start(arch::processor::get_timestamp());
+	}
+
 	arch::mm::init();
+	init_phase("arch init");
 	arch::mm::init_page_tables();
 	// Init the first pages for BOOT_INFO, Multiboot, SMP info, and so on. 
 	init_pages_before_kernel();
+	init_phase("page-table init");
 
 	info!("Total memory size: {} MB", total_memory_size() >> 20);
 
@@ -135,7 +298,7 @@ pub fn init() {
 	//info!("reserved space {} KB", reserved_space >> 10);
 	info!("reserved space {:#X}", reserved_space);
 
-	if total_memory_size() < kernel_end_address() + reserved_space + LargePageSize::SIZE {
+	if total_memory_size() < kernel_end_address() + reserved_space + 2 * LargePageSize::SIZE {
 		error!("No enough memory available!");
 
 		loop {
@@ -143,10 +306,30 @@ pub fn init() {
 		}
 	}
 
+	check_data_sections(kernel_start_address(), kernel_end_address());
+
+	// The general allocator must never hand out the hardcoded regions.
+	for &(name, start, size) in [
+		("boot information", 0, LargePageSize::SIZE),
+		(".safe_data", SAFE_DATA_START, DATA_SECTION_SIZE),
+		(".unsafe_data", UNSAFE_DATA_START, DATA_SECTION_SIZE),
+	]
+	.iter()
+	{
+		assert!(
+			arch::mm::virtualmem::reserve_fixed(start, size).is_ok(),
+			"Virtual range of {} at {:#X} ({:#X} bytes) is already in use",
+			name,
+			start,
+			size
+		);
+	}
+
 	/* Init  .safe_data section */
 	allocate_safe_data();
 	/* Init  .unsafe_data section */
 	allocate_unsafe_data();
+	init_phase("safe/unsafe data");
 
 	let mut map_addr: usize;
 	let mut map_size: usize;
@@ -156,10 +339,12 @@ pub fn init() {
 		info!("An application with a C-based runtime is running on top of HermitCore!");
 
 		let size = 2 * LargePageSize::SIZE;
-		let start = allocate(size, true);
+		let start = try_allocate_mapped(size, region_flags(KERNEL_HEAP_REGION, true)).unwrap();
 		unsafe {
 			::ALLOCATOR.init(start, size);
 		}
+		// From now on, the kernel heap serves the allocations.
+		early::finalize();
 
 		info!("Kernel heap size: {} MB", size >> 20);
 		let user_heap_size = align_down!(
@@ -168,10 +353,11 @@ pub fn init() {
 		);
 
 		map_addr = kernel_heap_end();
-		map_size = user_heap_size + size;
+		map_size = size;
 		unsafe {
                         HEAP_START_ADDRESS = map_addr;
-                        USER_HEAP_START_ADDRESS = HEAP_START_ADDRESS + size;
+                        // the guard between both heaps stays unmapped
+                        USER_HEAP_START_ADDRESS = HEAP_START_ADDRESS + size + align_up!(USER_HEAP_GUARD_SIZE, LargePageSize::SIZE);
                         USER_HEAP_SIZE = user_heap_size;
                         USER_HEAP_END_ADDRESS = USER_HEAP_START_ADDRESS + USER_HEAP_SIZE; 
 
@@ -180,13 +366,21 @@ pub fn init() {
                         map_size -= counter;
                         map_addr += counter;
 
-                        // remap kernel heap
-                        for i in 0..size/LargePageSize::SIZE {
-                                let mut flags = PageTableEntryFlags::empty();
-                                flags.normal().writable().execute_disable().pkey(UNSAFE_MEM_REGION);
-                                let physical_addr = align_down!(arch::mm::paging::virtual_to_physical(HEAP_START_ADDRESS +  i*LargePageSize::SIZE), LargePageSize::SIZE);
-                                arch::mm::paging::map::<LargePageSize>(HEAP_START_ADDRESS +  i*LargePageSize::SIZE, physical_addr, 1, flags);
+                        // map user heap behind the guard
+                        let counter = map_heap::<LargePageSize>(USER_HEAP_START_ADDRESS, USER_HEAP_SIZE, false);
+                        if counter != USER_HEAP_SIZE {
+                                panic!("User Heap Map fails!!");
                         }
+
+                        // remap kernel heap with a single TLB shootdown
+                        arch::mm::paging::with_deferred_flush(|| {
+                                for i in 0..size/LargePageSize::SIZE {
+                                        let mut flags = PageTableEntryFlags::empty();
+                                        flags.normal().writable().execute_disable().pkey(KERNEL_HEAP_REGION);
+                                        let physical_addr = align_down!(arch::mm::paging::virtual_to_physical(HEAP_START_ADDRESS +  i*LargePageSize::SIZE), LargePageSize::SIZE);
+                                        arch::mm::paging::map::<LargePageSize>(HEAP_START_ADDRESS +  i*LargePageSize::SIZE, physical_addr, 1, flags);
+                                }
+                        });
                 }
 	}
 
@@ -198,12 +392,26 @@ pub fn init() {
 		// Afterwards, we already use the heap and map the rest into
 		// the virtual address space.
 
-		let virt_size: usize = 4*LargePageSize::SIZE; // kernel heap is 8MB
+		let available = align_down!(
+			total_memory_size() - kernel_end_address() - reserved_space,
+			LargePageSize::SIZE
+		);
+		// leave at least one large page to the user heap
+		let max_size = available - LargePageSize::SIZE;
+		let requested = environment::get_kernel_heap_size();
+		let virt_size = align_up!(requested, LargePageSize::SIZE).max(LargePageSize::SIZE);
+		let virt_size = if virt_size > max_size {
+			warn!(
+				"Requested kernel heap of {} MB exceeds the available memory, use {} MB",
+				requested >> 20,
+				max_size >> 20
+			);
+			max_size
+		} else {
+			virt_size
+		};
 		unsafe {
-			USER_HEAP_SIZE = align_down!(
-				total_memory_size() - kernel_end_address() - reserved_space,
-				LargePageSize::SIZE
-			) - virt_size;
+			USER_HEAP_SIZE = available - virt_size;
 		}
 
 		let virt_addr = if has_1gib_pages && virt_size > HugePageSize::SIZE {
@@ -222,23 +430,20 @@ pub fn init() {
 			virt_addr
 		);
 
-		// try to map a huge page
-		let mut counter = if has_1gib_pages && virt_size > HugePageSize::SIZE {
+		// try to map a huge page, map_heap falls back to smaller pages
+		let counter = if has_1gib_pages && virt_size > HugePageSize::SIZE {
 			map_heap::<HugePageSize>(virt_addr, HugePageSize::SIZE, true)
 		} else {
-			0
+			map_heap::<LargePageSize>(virt_addr, LargePageSize::SIZE, true)
 		};
 
-		if counter == 0 {
-			// fall back to large pages
-			counter = map_heap::<LargePageSize>(virt_addr, LargePageSize::SIZE, true);
-		}
-
 		unsafe {
 			HEAP_START_ADDRESS = virt_addr;
 			// init the kernel heap
 			::ALLOCATOR.init(virt_addr, virt_size);
 		}
+		// From now on, the kernel heap serves the allocations.
+		early::finalize();
 
 		map_addr = virt_addr + counter;
 		map_size = virt_size - counter;
@@ -259,6 +464,8 @@ pub fn init() {
 	        }
         }
 
+	init_phase("heap map");
+
 	unsafe {
 		HEAP_END_ADDRESS = map_addr;
 
@@ -267,6 +474,15 @@ pub fn init() {
 			HEAP_START_ADDRESS, HEAP_END_ADDRESS, map_size
 		);
 	}
+
+	unsafe_heap::init();
+	init_phase("unsafe heap");
+
+	if CHECK_MM_INVARIANTS {
+		if let Err(violation) = check_invariants() {
+			panic!("Memory invariant violated: {:?}", violation);
+		}
+	}
 }
 
 pub fn init_user_allocator() {
@@ -274,7 +490,9 @@ pub fn init_user_allocator() {
         {
 		// User Heap Initialization
 		let user_heap_size: usize = unsafe {USER_HEAP_SIZE};
-		let user_heap_start_addr = arch::mm::virtualmem::allocate_aligned(user_heap_size, LargePageSize::SIZE).unwrap();
+		// the guard below the user heap stays unmapped and is never released
+		let guard_size = align_up!(USER_HEAP_GUARD_SIZE, LargePageSize::SIZE);
+		let user_heap_start_addr = arch::mm::virtualmem::allocate_aligned(guard_size + user_heap_size, LargePageSize::SIZE).unwrap() + guard_size;
 		// Map user heap
 		let map_count = map_heap::<LargePageSize>(user_heap_start_addr, user_heap_size, false);
 		if map_count != user_heap_size {
@@ -286,141 +504,866 @@ pub fn init_user_allocator() {
 			USER_HEAP_END_ADDRESS = user_heap_start_addr + user_heap_size;
 			::ALLOCATOR.init(user_heap_start_addr, user_heap_size);
 		}
+
+		init_phase("user alloc");
         }
 }
+
+/// Prints the free lists and the memory usage. With `config::DUMP_FREE_LISTS`, the free lists
+/// are dumped entry by entry and validated.
 pub fn print_information() {
 	arch::mm::physicalmem::print_information();
 	arch::mm::virtualmem::print_information();
+	info!("Page tables: {} KiB", arch::mm::paging::page_table_memory() >> 10);
+
+	let (reserved, committed) = reservation::accounting();
+	info!(
+		"Reserved: {} KiB, committed to demand paging: {} KiB",
+		reserved >> 10,
+		committed >> 10
+	);
+
+	for (key, &(in_use, pages)) in mpk::key_usage().iter().enumerate() {
+		if in_use {
+			info!("Protection key {:2}: {} pages", key, pages);
+		} else if pages > 0 {
+			// The pages have kept the key after it has been freed.
+			warn!("Protection key {:2} is free, but still tags {} pages", key, pages);
+		}
+	}
 }
 
-pub fn allocate_iomem(sz: usize) -> usize {
+/// Prints all mapped virtual memory regions with their flags and protection keys.
+pub fn dump_map() {
+	infoheader!(" MAPPED MEMORY REGIONS ");
+
+	for (start, size, flags, pkey) in arch::mm::paging::mapped_regions() {
+		info!(
+			"{:#016X} - {:#016X} pkey {:2} {:?}",
+			start,
+			start + size,
+			pkey,
+			flags
+		);
+	}
+
+	infofooter!();
+}
+
+/// Allocates `sz` bytes of memory for a device, which are mapped with the caching `policy`.
+///
+/// Without a Page Attribute Table, write-combining memory is mapped uncached.
+pub fn allocate_iomem(sz: usize, policy: CachePolicy) -> usize {
 	let size = align_up!(sz, BasePageSize::SIZE);
+	let policy = if policy == CachePolicy::WriteCombining && !arch::processor::supports_pat() {
+		CachePolicy::Uncached
+	} else {
+		policy
+	};
 
 	let physical_address = arch::mm::physicalmem::allocate(size).unwrap();
 	let virtual_address = arch::mm::virtualmem::allocate(size).unwrap();
 
 	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().execute_disable();
+	flags.normal().writable().execute_disable().cache_policy(policy);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 
 	virtual_address
 }
 
+/// Unmaps device memory, which has been allocated by `allocate_iomem`, and releases its
+/// virtual address range and its physical memory.
+///
+/// In contrast to `deallocate`, the memory is neither zeroed nor poisoned, because writes to
+/// device memory may have side effects, and the range is immediately available again.
+pub fn deallocate_iomem(virtual_address: usize, sz: usize) {
+	let size = align_up!(sz, BasePageSize::SIZE);
+	let physical_address = match get_leaf_entry(virtual_address) {
+		Some((entry, _)) => entry.address(),
+		None => panic!(
+			"No page table entry for virtual address {:#X}",
+			virtual_address
+		),
+	};
+
+	arch::mm::paging::unmap::<BasePageSize>(virtual_address, size / BasePageSize::SIZE);
+	arch::mm::virtualmem::deallocate(virtual_address, size);
+	arch::mm::physicalmem::deallocate(physical_address, size);
+}
+
 fn init_pages_before_kernel()
 {
 	let virtual_address = 0x0usize;
 	let physical_address = 0x0usize;
 	let count = 0x200000usize / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().execute_disable().pkey(SAFE_MEM_REGION);
+	flags.normal().writable().execute_disable().allow_null().pkey(SAFE_MEM_REGION);
 	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 
 	/* The first 4kb page is used by user (as a null pointer) */
 	arch::mm::paging::set_pkey_on_page_table_entry::<BasePageSize>(0x0usize, 1, 0x00u8);
 }
 
-pub fn allocate(sz: usize, execute_disable: bool) -> usize {
-	let size = align_up!(sz, BasePageSize::SIZE);
+/// Allocates and maps a single page, which belongs to the domain `region`.
+///
+/// This is a fast path for the frequent single-page allocations (GDT, TSS, per-core data),
+/// which avoids the page range iteration of `allocate` and friends.
+/// Returns the virtual and the physical address of the page.
+pub fn allocate_page(region: u8, mut flags: PageTableEntryFlags) -> (usize, usize) {
+	let physical_address = arch::mm::physicalmem::allocate(BasePageSize::SIZE).unwrap();
+	let virtual_address = arch::mm::virtualmem::allocate(BasePageSize::SIZE).unwrap();
 
-	let physical_address = arch::mm::physicalmem::allocate(size).unwrap();
-	let virtual_address = arch::mm::virtualmem::allocate(size).unwrap();
+	flags.pkey(region);
+	arch::mm::paging::map_page::<BasePageSize>(virtual_address, physical_address, flags);
 
-	let count = size / BasePageSize::SIZE;
+	(virtual_address, physical_address)
+}
+
+/// Reasons, why a `try_*allocate` function couldn't provide the requested memory.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum AllocError {
+	/// There is no free physical memory of the requested size.
+	OutOfPhysicalMemory,
+	/// There is no free virtual address range of the requested size.
+	OutOfVirtualMemory,
+	/// The memory would be writable and executable, which isn't permitted for the user domain.
+	WritableExecutable,
+	/// The requested virtual address range isn't free.
+	AddressInUse,
+}
+
+/// Reasons, why `protect` couldn't change the protection of a range.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ProtectError {
+	/// A page of the range isn't mapped or is a 1 GiB page.
+	NotMapped,
+	/// A page of the range has been frozen by `freeze`.
+	Frozen,
+}
+
+/// Outcome of `handle_demand_fault`
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DemandFault {
+	/// The page isn't backed on demand, so the fault has to be handled otherwise.
+	Unhandled,
+	/// A new page has been mapped.
+	Mapped,
+	/// Another core has mapped the page in the meantime, so the access is just repeated.
+	Retry,
+}
+
+/// Returns the flags of writable memory, which is tagged with `key`.
+fn region_flags(key: u8, execute_disable: bool) -> PageTableEntryFlags {
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(SAFE_MEM_REGION);
+	flags.normal().writable().pkey(key);
 	if execute_disable {
 		flags.execute_disable();
+	} else {
+		flags.allow_wx();
 	}
-	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 
-	virtual_address
+	flags
 }
 
-pub fn unsafe_allocate(sz: usize, execute_disable: bool) -> usize {
+/// Allocates physical memory and a virtual address range of `sz` bytes and maps them with `flags`.
+/// The memory is zeroed before it is mapped. If only one of the allocators succeeds, its memory is released again before the error is returned.
+fn try_allocate_mapped(sz: usize, flags: PageTableEntryFlags) -> Result<usize, AllocError> {
+	try_allocate_mapped_at(None, sz, flags)
+}
+
+/// Like `try_allocate_mapped`, but maps the memory at `fixed_address` if it is given.
+/// The range at `fixed_address` has to be page-aligned and free.
+fn try_allocate_mapped_at(
+	fixed_address: Option<usize>,
+	sz: usize,
+	flags: PageTableEntryFlags,
+) -> Result<usize, AllocError> {
 	let size = align_up!(sz, BasePageSize::SIZE);
 
-	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
-	let virtual_address = arch::mm::virtualmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
+	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE)
+		.map_err(|_| AllocError::OutOfPhysicalMemory)?;
+	let virtual_address = match fixed_address {
+		Some(virtual_address) => arch::mm::virtualmem::try_reserve(virtual_address, size)
+			.map(|_| virtual_address)
+			.map_err(|_| AllocError::AddressInUse),
+		None => arch::mm::virtualmem::allocate_aligned(size, BasePageSize::SIZE)
+			.map_err(|_| AllocError::OutOfVirtualMemory),
+	};
+	let virtual_address = match virtual_address {
+		Ok(virtual_address) => virtual_address,
+		Err(err) => {
+			arch::mm::physicalmem::deallocate(physical_address, size);
+			return Err(err);
+		}
+	};
+
+	// The frames may still hold data of their previous owner.
+	arch::mm::paging::zero_frames(physical_address, size);
 
 	let count = size / BasePageSize::SIZE;
-	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable().pkey(UNSAFE_MEM_REGION);
-	if execute_disable {
-		flags.execute_disable();
+	if size < LargePageSize::SIZE {
+		// small clusters (e.g. 16 KiB buffers) are written into a single PT
+		arch::mm::paging::map_cluster(virtual_address, physical_address, count, flags);
+	} else {
+		arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 	}
-	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+
+	Ok(virtual_address)
+}
+
+/// Maps the existing physical memory at `physical_address` to a new virtual address range of `sz` bytes,
+/// which is tagged with `key`. Returns 0 if `physical_address` isn't page-aligned or no range is available.
+///
+/// The new mapping is an alias of the memory, so both virtual addresses have to be released by `deallocate`.
+/// The frames are only returned to the physical memory allocator together with their last mapping.
+pub fn map_existing(physical_address: usize, sz: usize, key: u8, execute_disable: bool) -> usize {
+	let size = align_up!(sz, BasePageSize::SIZE);
+	if size == 0 || physical_address % BasePageSize::SIZE != 0 {
+		return 0;
+	}
+
+	let virtual_address = match arch::mm::virtualmem::allocate_aligned(size, BasePageSize::SIZE) {
+		Ok(virtual_address) => virtual_address,
+		Err(()) => return 0,
+	};
+
+	alias::add(physical_address, size);
+	let count = size / BasePageSize::SIZE;
+	arch::mm::paging::map::<BasePageSize>(
+		virtual_address,
+		physical_address,
+		count,
+		region_flags(key, execute_disable),
+	);
 
 	virtual_address
 }
 
+/// Allocates and maps memory of the safe domain or returns the reason, why this isn't possible.
+pub fn try_allocate(sz: usize, execute_disable: bool) -> Result<usize, AllocError> {
+	try_allocate_mapped(sz, region_flags(SAFE_MEM_REGION, execute_disable))
+}
+
+/// Like `try_allocate`, but panics if the memory is exhausted.
+pub fn allocate(sz: usize, execute_disable: bool) -> usize {
+	try_allocate(sz, execute_disable).unwrap()
+}
+
+/// Allocates and maps memory of the unsafe domain or returns the reason, why this isn't possible.
+pub fn try_unsafe_allocate(sz: usize, execute_disable: bool) -> Result<usize, AllocError> {
+	try_allocate_mapped(sz, region_flags(UNSAFE_MEM_REGION, execute_disable))
+}
+
+/// Like `try_unsafe_allocate`, but panics if the memory is exhausted.
+pub fn unsafe_allocate(sz: usize, execute_disable: bool) -> usize {
+	try_unsafe_allocate(sz, execute_disable).unwrap()
+}
+
+/// Allocates and maps writable, non-executable memory, which is protected by `key`.
+pub fn try_key_allocate(sz: usize, key: u8) -> Result<usize, AllocError> {
+	try_allocate_mapped(sz, region_flags(key, true))
+}
+
+/// Allocates and maps shared memory or returns the reason, why this isn't possible.
+pub fn try_shared_allocate(sz: usize, execute_disable: bool) -> Result<usize, AllocError> {
+	try_allocate_mapped(sz, region_flags(SHARED_MEM_REGION, execute_disable))
+}
+
+/// Like `try_shared_allocate`, but panics if the memory is exhausted.
 pub fn shared_allocate(sz: usize, execute_disable: bool) -> usize {
+	try_shared_allocate(sz, execute_disable).unwrap()
+}
+
+/// Allocates and maps shared memory like `try_shared_allocate`, but uses 2 MiB pages as far as possible.
+///
+/// A large shared buffer occupies fewer page table entries and TLB entries this way.
+/// The part of the buffer, which doesn't fill a whole 2 MiB page, is mapped with 4 KiB pages.
+/// The buffer is released by `deallocate`.
+pub fn try_shared_allocate_large(sz: usize, execute_disable: bool) -> Result<usize, AllocError> {
 	let size = align_up!(sz, BasePageSize::SIZE);
+	let large_size = align_down!(size, LargePageSize::SIZE);
+	if large_size == 0 {
+		return try_shared_allocate(sz, execute_disable);
+	}
 
-	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
-	let virtual_address = arch::mm::virtualmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
+	// The allocators expect a size, which is a multiple of the alignment.
+	// Hence, the unused tail of the last 2 MiB is returned right away.
+	let aligned_size = align_up!(size, LargePageSize::SIZE);
+	let physical_address = arch::mm::physicalmem::allocate_aligned(aligned_size, LargePageSize::SIZE)
+		.map_err(|_| AllocError::OutOfPhysicalMemory)?;
+	let virtual_address = match arch::mm::virtualmem::allocate_aligned(aligned_size, LargePageSize::SIZE) {
+		Ok(virtual_address) => virtual_address,
+		Err(_) => {
+			arch::mm::physicalmem::deallocate(physical_address, aligned_size);
+			return Err(AllocError::OutOfVirtualMemory);
+		}
+	};
+	if aligned_size > size {
+		arch::mm::physicalmem::deallocate(physical_address + size, aligned_size - size);
+		arch::mm::virtualmem::deallocate(virtual_address + size, aligned_size - size);
+	}
+
+	// The frames may still hold data of their previous owner.
+	arch::mm::paging::zero_frames(physical_address, size);
 
-	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
 	flags.normal().writable().pkey(SHARED_MEM_REGION);
 	if execute_disable {
 		flags.execute_disable();
+	} else {
+		flags.allow_wx();
+	}
+	arch::mm::paging::map::<LargePageSize>(
+		virtual_address,
+		physical_address,
+		large_size / LargePageSize::SIZE,
+		flags,
+	);
+	if size > large_size {
+		arch::mm::paging::map::<BasePageSize>(
+			virtual_address + large_size,
+			physical_address + large_size,
+			(size - large_size) / BasePageSize::SIZE,
+			flags,
+		);
 	}
-	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
 
-	virtual_address
+	Ok(virtual_address)
 }
 
-pub fn user_allocate(sz: usize, execute_disable: bool) -> usize {
-	let size = align_up!(sz, BasePageSize::SIZE);
+/// Allocates and maps writable memory for the user domain.
+///
+/// User memory has to be W^X. Hence, a request for executable memory fails with `AllocError::WritableExecutable`.
+pub fn try_user_allocate(sz: usize, execute_disable: bool) -> Result<usize, AllocError> {
+	try_user_map(None, sz, true, execute_disable)
+}
 
-	let physical_address = arch::mm::physicalmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
-	let virtual_address = arch::mm::virtualmem::allocate_aligned(size, BasePageSize::SIZE).unwrap();
+/// Allocates and maps readable memory for the user domain, which is optionally `writable` and executable.
+///
+/// With `fixed_address`, the memory is mapped exactly at this address and the range has to be free.
+/// Otherwise, `AllocError::AddressInUse` is returned. Writable memory has to be W^X.
+pub fn try_user_map(
+	fixed_address: Option<usize>,
+	sz: usize,
+	writable: bool,
+	execute_disable: bool,
+) -> Result<usize, AllocError> {
+	if writable && !execute_disable {
+		warn!("Refuse to allocate {} bytes of writable and executable user memory", sz);
+		return Err(AllocError::WritableExecutable);
+	}
 
-	let count = size / BasePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
-	flags.normal().writable();
+	flags.normal();
+	if writable {
+		flags.writable();
+	}
 	if execute_disable {
 		flags.execute_disable();
 	}
-	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+	try_allocate_mapped_at(fixed_address, sz, flags)
+}
 
-	virtual_address
+/// Like `try_user_allocate`, but panics if the memory is exhausted.
+/// A request for executable memory is refused and 0 is returned.
+pub fn user_allocate(sz: usize, execute_disable: bool) -> usize {
+	match try_user_allocate(sz, execute_disable) {
+		Ok(virtual_address) => virtual_address,
+		Err(AllocError::WritableExecutable) => 0,
+		Err(err) => panic!("Unable to allocate {} bytes of user memory: {:?}", sz, err),
+	}
+}
+
+/// Allocates memory of the unsafe domain from a heap, which is separated from the kernel heap.
+/// Returns a null pointer if the heap is exhausted.
+///
+/// The global allocator uses this heap for every allocation within the unsafe domain
+/// (see `in_unsafe_domain`), e.g. of a function isolated by `isolate_function_weak!`.
+pub fn unsafe_heap_allocate(layout: Layout) -> *mut u8 {
+	unsafe_heap::allocate(layout)
+}
+
+/// Releases memory, which has been allocated by `unsafe_heap_allocate` with the same `layout`.
+pub fn unsafe_heap_deallocate(ptr: *mut u8, layout: Layout) {
+	unsafe_heap::deallocate(ptr, layout)
+}
+
+/// Returns `true` if `ptr` has been allocated by `unsafe_heap_allocate`.
+pub fn is_unsafe_heap(ptr: *mut u8) -> bool {
+	unsafe_heap::contains(ptr)
+}
+
+/// Returns `true` if the current context runs in the unsafe domain, i.e. within `isolation_start!`
+/// and `isolation_end!` or in an isolated function: the PKRU denies the access to the safe domain,
+/// but permits the one to the unsafe domain.
+pub fn in_unsafe_domain() -> bool {
+	if !environment::mpk_enabled() {
+		return false;
+	}
+
+	let pkru = mpk::mpk_get_pkru();
+	pkru & UNSAFE_PERMISSION_IN == UNSAFE_PERMISSION_IN && mpk::would_allow(pkru, UNSAFE_MEM_REGION, true)
+}
+
+/// Returns the address range `[bottom, top)` of the heap of the unsafe domain.
+pub fn unsafe_heap_range() -> (usize, usize) {
+	unsafe_heap::range()
+}
+
+/// Reserves `size` bytes of virtual address space aligned to `alignment` without mapping them.
+///
+/// No other allocation can use the range until it is passed to `release_virtual`.
+/// Returns 0 if no such range is available.
+pub fn reserve_virtual(size: usize, alignment: usize) -> usize {
+	reservation::reserve(size, alignment)
+}
+
+/// Maps the pages of the reservation at `virtual_address` with `flags` at their first access.
+pub fn reserve_on_demand(virtual_address: usize, flags: PageTableEntryFlags) -> Result<(), ()> {
+	reservation::set_demand_paging(virtual_address, flags)
+}
+
+/// Returns `true` if `size` bytes of memory may be committed without breaking `config::STRICT_COMMIT`.
+pub fn may_commit(size: usize) -> bool {
+	reservation::may_map(size)
+}
+
+/// Releases a range, which has been reserved by `reserve_virtual`.
+/// Pages, which have been mapped on demand, are released as well.
+pub fn release_virtual(virtual_address: usize, size: usize) -> Result<(), ()> {
+	reservation::release(virtual_address, size)
+}
+
+/// Maps the missing page at `virtual_address` if it belongs to a reservation with demand paging
+/// or to a reclaimed page of the user heap. The faulting access can be repeated unless `Unhandled` is returned.
+pub fn handle_demand_fault(virtual_address: usize) -> DemandFault {
+	match reservation::handle_fault(virtual_address) {
+		DemandFault::Unhandled => reclaim::handle_fault(virtual_address),
+		resolution => resolution,
+	}
+}
+
+/// Unmaps the pages of the user heap, which only contain zeros and haven't been accessed
+/// since the previous call, and returns their frames. Returns the number of reclaimed bytes.
+///
+/// A reclaimed page is mapped again as a zeroed page at its next access.
+pub fn reclaim_user_heap() -> usize {
+	reclaim::reclaim_user_heap()
+}
+
+/// Returns the range `[start, end)` of the user heap. `sys_madvise` and the reclaimed pages
+/// of `reclaim_user_heap` are bounded by it.
+pub fn user_heap_bounds() -> (usize, usize) {
+	(user_heap_start(), user_heap_start() + user_heap_size())
+}
+
+/// Returns `true` if `[virtual_address, virtual_address + size)` lies within the user heap.
+pub fn is_user_heap_range(virtual_address: usize, size: usize) -> bool {
+	let (start, end) = user_heap_bounds();
+	match virtual_address.checked_add(size) {
+		Some(range_end) => virtual_address >= start && range_end <= end,
+		None => false,
+	}
+}
+
+/// Unmaps the pages of the user heap in `[virtual_address, virtual_address + size)` and returns their
+/// frames, regardless of their content. A discarded page is mapped again as a zeroed page at its next access.
+/// Returns the number of released bytes.
+pub fn discard_user_pages(virtual_address: usize, size: usize) -> usize {
+	reclaim::discard(virtual_address, size)
+}
+
+/// Maps the reclaimed or discarded pages of the user heap in `[virtual_address, virtual_address + size)`
+/// ahead of their next access. Fails if the physical memory is exhausted.
+pub fn prefault_user_pages(virtual_address: usize, size: usize) -> Result<(), ()> {
+	reclaim::prefault(virtual_address, size)
+}
+
+/// Samples the activity of the protection domains: returns the number of pages per protection key,
+/// which have been accessed since the previous call.
+pub fn sample_access_by_key() -> [u64; 16] {
+	arch::mm::paging::sample_access_by_key()
+}
+
+/// Makes the pages covering `[virtual_address, virtual_address + size)` writable or read-only.
+///
+/// Frozen pages are refused, even if they would stay read-only.
+pub fn protect(virtual_address: usize, size: usize, writable: bool) -> Result<(), ProtectError> {
+	if is_frozen(virtual_address, size) {
+		return Err(ProtectError::Frozen);
+	}
+
+	arch::mm::paging::set_writable(virtual_address, size, writable).map_err(|()| ProtectError::NotMapped)
+}
+
+/// Makes the pages covering `[virtual_address, virtual_address + size)` read-only for good,
+/// e.g. a configuration, which is loaded once, or a code buffer after its generation.
+///
+/// In contrast to `protect`, this can't be undone: `protect` refuses the pages afterwards,
+/// they are never mapped again and they must not be deallocated.
+pub fn freeze(virtual_address: usize, size: usize) -> Result<(), ()> {
+	arch::mm::paging::freeze(virtual_address, size)
+}
+
+/// Returns `true` if any page of `[virtual_address, virtual_address + size)` has been frozen.
+pub fn is_frozen(virtual_address: usize, size: usize) -> bool {
+	arch::mm::paging::is_frozen(virtual_address, size)
+}
+
+/// Returns the protection key of the page that maps `virtual_address`
+/// or `None` if the address isn't mapped.
+pub fn region_type(virtual_address: usize) -> Option<u8> {
+	get_leaf_entry(virtual_address).map(|(entry, _)| entry.pkey())
+}
+
+/// Returns `true` if every byte of `[virtual_address, virtual_address + size)` is mapped
+/// and belongs to the user domain (protection key 0).
+///
+/// The protection key is the only criterion. The kernel runs in ring 0 as well,
+/// so every page is accessible from the "user space" in terms of the page tables.
+pub fn is_user_range(virtual_address: usize, size: usize) -> bool {
+	if virtual_address == 0 || size == 0 {
+		return false;
+	}
+
+	let end = match virtual_address.checked_add(size) {
+		Some(end) => end,
+		None => return false,
+	};
+	let mut addr = virtual_address;
+
+	while addr < end {
+		match get_leaf_entry(addr) {
+			Some((entry, page_size)) if entry.pkey() == 0 => {
+				addr = align_down!(addr, page_size) + page_size;
+			}
+			_ => return false,
+		}
+	}
+
+	true
+}
+
+/// Tags all 4 KiB pages covering `[virtual_address, virtual_address + size)` with the protection key `key`.
+///
+/// A 2 MiB page, which isn't covered completely, is split before, so that the key doesn't
+/// spill over to unrelated data. Other data sharing the 4 KiB pages at the boundaries still
+/// ends up in the same domain. Stale TLB entries, including those of global pages
+/// on other cores, are flushed by `set_pkey_on_page_table_entry`.
+pub fn set_region_key(virtual_address: usize, size: usize, key: u8) {
+	let start = align_down!(virtual_address, BasePageSize::SIZE);
+	let end = align_up!(virtual_address + size, BasePageSize::SIZE);
+	let mut addr = start;
+
+	while addr < end {
+		let (_, page_size) = get_leaf_entry(addr).unwrap_or_else(|| {
+			panic!("No page table entry for virtual address {:#X}", addr)
+		});
+		let page = align_down!(addr, page_size);
+		let covered = page >= start && page + page_size <= end;
+
+		match page_size {
+			HugePageSize::SIZE if covered => {
+				set_pkey_on_page_table_entry::<HugePageSize>(addr, 1, key)
+			}
+			HugePageSize::SIZE => panic!("Unable to tag a part of the 1 GiB page at {:#X}", page),
+			LargePageSize::SIZE if covered => {
+				set_pkey_on_page_table_entry::<LargePageSize>(addr, 1, key)
+			}
+			LargePageSize::SIZE => {
+				arch::mm::paging::split_large_page(addr);
+				continue;
+			}
+			_ => set_pkey_on_page_table_entry::<BasePageSize>(addr, 1, key),
+		}
+
+		addr = page + page_size;
+	}
+}
+
+/// Moves the shared region `[virtual_address, virtual_address + size)` into the safe domain,
+/// so that only the kernel keeps access to it. With `zero`, the contents are cleared before.
+///
+/// Fails if any page of the region isn't shared or if its frames are still mapped at another
+/// address (see `map_existing`), which would keep the memory accessible to the peer.
+pub fn privatize_shared(virtual_address: usize, sz: usize, zero: bool) -> Result<(), ()> {
+	let size = align_up!(sz, BasePageSize::SIZE);
+	let end = virtual_address + size;
+	let mut addr = virtual_address;
+
+	while addr < end {
+		match get_leaf_entry(addr) {
+			Some((entry, page_size))
+				if entry.pkey() == SHARED_MEM_REGION
+					&& !alias::is_aliased(align_down!(entry.address(), page_size), page_size) =>
+			{
+				addr = align_down!(addr, page_size) + page_size;
+			}
+			_ => return Err(()),
+		}
+	}
+
+	if zero {
+		zero_shared(virtual_address, size);
+	}
+	// flushes the stale translations on all cores
+	set_region_key(virtual_address, size, SAFE_MEM_REGION);
+
+	Ok(())
+}
+
+/// Runs `f` inside the unsafe domain and returns its result.
+///
+/// In contrast to `isolation_start!`/`isolation_end!`, the previous PKRU value is restored
+/// afterwards, so calls can be nested.
+pub fn with_unsafe<R, F: FnOnce() -> R>(f: F) -> R {
+	let pkru = mpk::mpk_get_pkru();
+	mpk::mpk_set_pkru(pkru | unsafe_permission_in());
+	mpk::mpk_set_perm(UNSAFE_MEM_REGION, mpk::MpkPerm::MpkRw);
+
+	let result = f();
+
+	mpk::mpk_set_pkru(pkru);
+	result
+}
+
+/// Remaps the pages at `first` and `second` to the physical frame of each other (double-buffering).
+///
+/// Both pages keep their flags and protection key. Each page stays mapped during the swap,
+/// so a concurrent access to `first` observes either its previous or its new content.
+/// The two entries are written one after the other while the swap holds `SWAP_LOCK`.
+/// In between, both pages map the frame of `second` for a core, which walks the page tables.
+/// The other cores flush their TLBs once after both writes and have acknowledged it when this function returns.
+/// Fails if the addresses aren't mapped by pages of the same size or belong to different domains.
+pub fn swap_mapping(first: usize, second: usize) -> Result<(), ()> {
+	let _lock = SWAP_LOCK.lock();
+	let (first_entry, page_size) = get_leaf_entry(first).ok_or(())?;
+	let (second_entry, second_page_size) = get_leaf_entry(second).ok_or(())?;
+
+	if page_size != second_page_size
+		|| first % page_size != 0
+		|| second % page_size != 0
+		|| first_entry.pkey() != second_entry.pkey()
+	{
+		return Err(());
+	}
+
+	let mut first_flags = PageTableEntryFlags::from_bits_truncate(first_entry.get_flags());
+	first_flags.pkey(first_entry.pkey());
+	let mut second_flags = PageTableEntryFlags::from_bits_truncate(second_entry.get_flags());
+	second_flags.pkey(second_entry.pkey());
+
+	// A single shootdown covers both entries.
+	arch::mm::paging::with_deferred_flush(|| match page_size {
+		HugePageSize::SIZE => {
+			let frame = arch::mm::paging::remap_atomic::<HugePageSize>(first, second_entry.address(), first_flags);
+			arch::mm::paging::remap_atomic::<HugePageSize>(second, frame, second_flags);
+		}
+		LargePageSize::SIZE => {
+			let frame = arch::mm::paging::remap_atomic::<LargePageSize>(first, second_entry.address(), first_flags);
+			arch::mm::paging::remap_atomic::<LargePageSize>(second, frame, second_flags);
+		}
+		_ => {
+			let frame = arch::mm::paging::remap_atomic::<BasePageSize>(first, second_entry.address(), first_flags);
+			arch::mm::paging::remap_atomic::<BasePageSize>(second, frame, second_flags);
+		}
+	});
+
+	Ok(())
+}
+
+/// Returns `true` if the ranges `[start, end)` of `a` and `b` share at least one byte.
+fn ranges_overlap(a: (usize, usize), b: (usize, usize)) -> bool {
+	a.0 < b.1 && b.0 < a.1
+}
+
+/// A named range `[start, end)`
+type NamedRange = (&'static str, usize, usize);
+
+/// Verifies that the .safe_data and .unsafe_data sections, which are mapped with different protection keys,
+/// don't overlap each other. The linker script places both sections in the kernel image.
+/// Hence, each section has to lie either completely inside or completely outside of the image,
+/// otherwise the heap behind the image would be mapped over a part of it.
+fn check_data_sections(kernel_start: usize, kernel_end: usize) {
+	let kernel = ("kernel image", kernel_start, kernel_end);
+	let safe = (".safe_data", SAFE_DATA_START, SAFE_DATA_START + DATA_SECTION_SIZE);
+	let unsafe_ = (".unsafe_data", UNSAFE_DATA_START, UNSAFE_DATA_START + DATA_SECTION_SIZE);
+
+	if let Err((a, b)) = validate_data_sections(kernel, safe, unsafe_) {
+		panic!(
+			"{} ({:#X} - {:#X}) overlaps {} ({:#X} - {:#X})",
+			a.0, a.1, a.2, b.0, b.1, b.2
+		);
+	}
+}
+
+/// Returns the conflicting ranges if the data sections overlap each other or partially overlap the kernel image.
+fn validate_data_sections(
+	kernel: NamedRange,
+	safe: NamedRange,
+	unsafe_: NamedRange,
+) -> Result<(), (NamedRange, NamedRange)> {
+	if ranges_overlap((safe.1, safe.2), (unsafe_.1, unsafe_.2)) {
+		return Err((safe, unsafe_));
+	}
+
+	for section in [safe, unsafe_].iter() {
+		let inside = kernel.1 <= section.1 && section.2 <= kernel.2;
+		if ranges_overlap((section.1, section.2), (kernel.1, kernel.2)) && !inside {
+			return Err((*section, kernel));
+		}
+	}
+
+	Ok(())
 }
 
 fn allocate_safe_data() {
-    let safe_data_start = 0x400000usize;
-	let aligned_size = 0x200000usize;
 	/* We harcode the physical address here */
-	let physical_address = 0x400000usize;
+	let physical_address = SAFE_DATA_START;
 	//let physical_address = arch::mm::physicalmem::allocate_aligned(aligned_size, LargePageSize::SIZE).unwrap();
-	let count = aligned_size / LargePageSize::SIZE;
+	let count = DATA_SECTION_SIZE / LargePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
 	flags.normal().writable().pkey(SAFE_MEM_REGION);
 	flags.execute_disable();
-	arch::mm::paging::map::<LargePageSize>(safe_data_start, physical_address, count, flags);
-	info!("safe .data starts at (virt_address: {:#X}, phys_address: {:#X}), size: {:#X}", safe_data_start, physical_address, aligned_size);
+	arch::mm::paging::map::<LargePageSize>(SAFE_DATA_START, physical_address, count, flags);
+
+	/* Unmap the last page of the section, so an overrun can't reach the unsafe data */
+	let guard_address = SAFE_DATA_START + DATA_SECTION_SIZE - DATA_GUARD_SIZE;
+	arch::mm::paging::split_large_page(guard_address);
+	arch::mm::paging::unmap::<BasePageSize>(guard_address, DATA_GUARD_SIZE / BasePageSize::SIZE);
+	info!(
+		"safe .data starts at (virt_address: {:#X}, phys_address: {:#X}), size: {:#X}, guard at {:#X}",
+		SAFE_DATA_START,
+		physical_address,
+		DATA_SECTION_SIZE - DATA_GUARD_SIZE,
+		guard_address
+	);
 }
 
 fn allocate_unsafe_data() {
-    let unsafe_data_start = 0x600000usize;
-	let aligned_size = 0x200000usize;
 	/* We harcode the physical address here */
-	let physical_address = 0x600000usize;
-	let count = aligned_size / LargePageSize::SIZE;
+	let physical_address = UNSAFE_DATA_START;
+	let count = DATA_SECTION_SIZE / LargePageSize::SIZE;
 	let mut flags = PageTableEntryFlags::empty();
 	flags.normal().writable().pkey(UNSAFE_MEM_REGION);
 	flags.execute_disable();
-	arch::mm::paging::map::<LargePageSize>(unsafe_data_start, physical_address, count, flags);
-	info!("unsafe .data starts at (virt_address: {:#X}, phys_address: {:#X}), size: {:#X}", unsafe_data_start, physical_address, aligned_size);
+	arch::mm::paging::map::<LargePageSize>(UNSAFE_DATA_START, physical_address, count, flags);
+	info!("unsafe .data starts at (virt_address: {:#X}, phys_address: {:#X}), size: {:#X}", UNSAFE_DATA_START, physical_address, DATA_SECTION_SIZE);
+}
+
+/// Overwrites a freed region with `POISON_PATTERN`, so a use-after-free doesn't observe stale data.
+///
+/// The region may belong to any domain. Hence, all protection keys are opened temporarily.
+fn poison(virtual_address: usize, size: usize) {
+	let pkru = mpk::mpk_get_pkru();
+	mpk::mpk_set_pkru(0);
+	unsafe {
+		core::ptr::write_bytes(virtual_address as *mut u8, POISON_PATTERN, size);
+	}
+	mpk::mpk_set_pkru(pkru);
 }
 
+/// Zeroes a freed shared region page by page, so the peer can't observe its contents after the frames are reused.
+/// Only the shared key is opened, the permissions of the other domains stay as they are.
+fn zero_shared(virtual_address: usize, size: usize) {
+	let pkru = mpk::mpk_get_pkru();
+	mpk::mpk_set_pkru(pkru & !mpk::MpkPerm::MpkNone.to_pkru_bits(SHARED_MEM_REGION));
+	for page in (virtual_address..virtual_address + size).step_by(BasePageSize::SIZE) {
+		unsafe {
+			core::ptr::write_bytes(page as *mut u8, 0, BasePageSize::SIZE);
+		}
+	}
+	mpk::mpk_set_pkru(pkru);
+}
+
+/// Keeps the virtual range of a freed region unmapped for a grace period.
+/// The oldest range in the quarantine is returned to the virtual memory allocator.
+fn quarantine(virtual_address: usize, size: usize) {
+	let mut guard = QUARANTINE.lock();
+	let (ref mut ranges, ref mut next) = *guard;
+	let (old_address, old_size) = mem::replace(&mut ranges[*next], (virtual_address, size));
+	*next = (*next + 1) % QUARANTINE_SLOTS;
+	drop(guard);
+
+	if old_size > 0 {
+		arch::mm::virtualmem::deallocate(old_address, old_size);
+	}
+}
+
+/// Unmaps `[virtual_address, virtual_address + size)`, which may consist of pages of different sizes.
+fn unmap_range(virtual_address: usize, size: usize) {
+	let end = virtual_address + size;
+	let mut addr = virtual_address;
+
+	while addr < end {
+		let page_size = match get_leaf_entry(addr) {
+			Some((_, page_size)) => page_size,
+			None => panic!("No page table entry for virtual address {:#X}", addr),
+		};
+		assert!(
+			addr % page_size == 0 && addr + page_size <= end,
+			"Range {:#X} - {:#X} covers a part of a page of {:#X} bytes",
+			virtual_address,
+			end,
+			page_size
+		);
+
+		// Unmap all pages of the same size at once, so the TLBs are flushed only once.
+		let mut run_end = addr + page_size;
+		while run_end + page_size <= end
+			&& get_leaf_entry(run_end).map(|(_, size)| size) == Some(page_size)
+		{
+			run_end += page_size;
+		}
+
+		let count = (run_end - addr) / page_size;
+		match page_size {
+			HugePageSize::SIZE => arch::mm::paging::unmap::<HugePageSize>(addr, count),
+			LargePageSize::SIZE => arch::mm::paging::unmap::<LargePageSize>(addr, count),
+			_ => arch::mm::paging::unmap::<BasePageSize>(addr, count),
+		}
+		addr = run_end;
+	}
+}
+
+/// Unmaps a region and returns its memory to the allocators.
+///
+/// Shared memory is zeroed before its frames are released, because the peer may observe them after their reuse.
 pub fn deallocate(virtual_address: usize, sz: usize) {
+	shared_deallocate(virtual_address, sz, true)
+}
+
+/// Like `deallocate`, but `zero` selects whether a shared region is zeroed before its frames are released.
+///
+/// Skipping the zeroing is only appropriate if the region never held data, which the peer must not see,
+/// or if the caller has already cleared it.
+pub fn shared_deallocate(virtual_address: usize, sz: usize, zero: bool) {
 	let size = align_up!(sz, BasePageSize::SIZE);
+	assert!(
+		!is_frozen(virtual_address, size),
+		"Deallocation of the frozen range at {:#X}",
+		virtual_address
+	);
+
+	if let Some((entry, _)) = get_leaf_entry(virtual_address) {
+		// The content of frames, which are still mapped at another address, is preserved.
+		if !alias::is_aliased(entry.address(), size) {
+			if zero && entry.pkey() == SHARED_MEM_REGION {
+				zero_shared(virtual_address, size);
+			} else if cfg!(debug_assertions) {
+				poison(virtual_address, size);
+			}
+		}
 
-	if let Some(entry) = arch::mm::paging::get_page_table_entry::<BasePageSize>(virtual_address) {
-		arch::mm::virtualmem::deallocate(virtual_address, size);
-		arch::mm::physicalmem::deallocate(entry.address(), size);
+		unmap_range(virtual_address, size);
+		if cfg!(debug_assertions) {
+			// A dangling pointer into this range faults until the range is reused.
+			quarantine(virtual_address, size);
+		} else {
+			arch::mm::virtualmem::deallocate(virtual_address, size);
+		}
+		alias::release(entry.address(), size);
 	} else {
 		panic!(
 			"No page table entry for virtual address {:#X}",
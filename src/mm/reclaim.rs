@@ -0,0 +1,240 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reclaim of idle pages of the user heap.
+//!
+//! Pages of the user heap, which only contain zeros and haven't been accessed for a whole pass,
+//! are unmapped and their frames are returned to the physical memory allocator.
+//! The heap allocator doesn't know about it, so the page fault handler maps new zeroed memory
+//! as soon as a reclaimed page is accessed again.
+//!
+//! `discard` unmaps pages on request of the application regardless of their content and `prefault`
+//! maps them again ahead of their next access (`MADV_DONTNEED` and `MADV_WILLNEED`).
+//!
+//! The kernel itself allocates from the user heap. Hence, every allocation may fault on a reclaimed
+//! page and the bookkeeping uses a fixed table, which is never locked while memory is allocated.
+
+use arch;
+use arch::mm::paging::{BasePageSize, LargePageSize, PageSize, PageTableEntry, PageTableEntryFlags};
+use core::ptr;
+use core::sync::atomic::spin_loop_hint;
+use mm;
+use mm::DemandFault;
+use synch::spinlock::SpinlockIrqSave;
+
+/// Maximum number of reclaimed pages, which haven't been accessed again.
+const MAX_RECLAIMED_PAGES: usize = 256;
+
+#[derive(Clone, Copy)]
+struct ReclaimedPage {
+	start: usize,
+	size: usize,
+	/// Flags and protection key of the page before it was reclaimed
+	flags: PageTableEntryFlags,
+	/// Set while a page fault handler maps the page again
+	pending: bool,
+}
+
+safe_global_var!(static RECLAIMED: SpinlockIrqSave<[Option<ReclaimedPage>; MAX_RECLAIMED_PAGES]> =
+	SpinlockIrqSave::new([None; MAX_RECLAIMED_PAGES]));
+
+/// Unmaps the idle pages of the user heap and returns their frames.
+/// Returns the number of reclaimed bytes.
+pub fn reclaim_user_heap() -> usize {
+	let mut reclaimed = 0;
+
+	let (start, end) = mm::user_heap_bounds();
+	for (address, size, entry) in arch::mm::paging::idle_pages(start, end) {
+		// The page is recorded before it is unmapped, so that a fault always finds it.
+		let slot = match record(address, size, entry) {
+			Some(slot) => slot,
+			None => break,
+		};
+
+		if arch::mm::paging::take_zero_page(address, size, entry) {
+			arch::mm::physicalmem::deallocate(entry.address(), size);
+			reclaimed += size;
+		} else {
+			RECLAIMED.lock()[slot] = None;
+		}
+	}
+
+	if reclaimed > 0 {
+		debug!("Reclaimed {:#X} bytes of the user heap", reclaimed);
+	}
+
+	reclaimed
+}
+
+/// Records a page, which is about to be unmapped, so that a fault maps new zeroed memory.
+/// Returns `None` if the table is full.
+fn record(start: usize, size: usize, entry: PageTableEntry) -> Option<usize> {
+	let mut flags = PageTableEntryFlags::from_bits_truncate(entry.get_flags());
+	flags.remove(PageTableEntryFlags::HUGE_PAGE | PageTableEntryFlags::ACCESSED | PageTableEntryFlags::DIRTY);
+	flags.pkey(entry.pkey());
+
+	let mut pages = RECLAIMED.lock();
+	let slot = pages.iter().position(|page| page.is_none())?;
+	pages[slot] = Some(ReclaimedPage {
+		start: start,
+		size: size,
+		flags: flags,
+		pending: false,
+	});
+
+	Some(slot)
+}
+
+/// Unmaps the pages of the user heap in `[start, start + size)` and returns their frames,
+/// regardless of their content. Returns the number of released bytes.
+///
+/// A 2 MiB page, which is only partially covered, is split first. If the table of reclaimed pages
+/// is full, the remaining pages stay mapped and are zeroed instead, so every discarded page reads
+/// as zeros at its next access.
+pub fn discard(start: usize, size: usize) -> usize {
+	let end = start + size;
+	for &boundary in [start, end].iter() {
+		if boundary % LargePageSize::SIZE != 0 {
+			if let Some((_, LargePageSize::SIZE)) = arch::mm::paging::get_leaf_entry(boundary) {
+				arch::mm::paging::split_large_page(boundary);
+			}
+		}
+	}
+
+	let mut released = 0;
+	let mut address = start;
+	while address < end {
+		let (entry, page_size) = match arch::mm::paging::get_leaf_entry(address) {
+			Some(leaf) => leaf,
+			None => {
+				// already reclaimed or discarded
+				address += BasePageSize::SIZE;
+				continue;
+			}
+		};
+		let page_end = align_down!(address, page_size) + page_size;
+
+		// 1 GiB pages aren't split, so they are only zeroed.
+		let slot = if page_size <= LargePageSize::SIZE {
+			record(address, page_size, entry)
+		} else {
+			None
+		};
+
+		match slot {
+			Some(_) => {
+				if page_size == LargePageSize::SIZE {
+					arch::mm::paging::unmap::<LargePageSize>(address, 1);
+				} else {
+					arch::mm::paging::unmap::<BasePageSize>(address, 1);
+				}
+				// The frames may still be mapped elsewhere (see `mm::map_existing`).
+				mm::alias::release(entry.address(), page_size);
+				released += page_size;
+			}
+			None => unsafe {
+				ptr::write_bytes(address as *mut u8, 0, page_end.min(end) - address);
+			},
+		}
+
+		address = page_end;
+	}
+
+	if released > 0 {
+		debug!("Discarded {:#X} bytes of the user heap", released);
+	}
+
+	released
+}
+
+/// Maps the reclaimed or discarded pages in `[start, start + size)` ahead of their next access.
+/// Fails if the physical memory is exhausted.
+pub fn prefault(start: usize, size: usize) -> Result<(), ()> {
+	for page in (align_down!(start, BasePageSize::SIZE)..start + size).step_by(BasePageSize::SIZE) {
+		if arch::mm::paging::get_leaf_entry(page).is_none() && handle_fault(page) == DemandFault::Unhandled {
+			return Err(());
+		}
+	}
+
+	Ok(())
+}
+
+/// Maps zeroed memory at `virtual_address` if its page has been reclaimed.
+pub fn handle_fault(virtual_address: usize) -> DemandFault {
+	let (slot, page) = loop {
+		let mut pages = RECLAIMED.lock();
+		let slot = match pages.iter().position(|page| match page {
+			Some(page) => page.start <= virtual_address && virtual_address < page.start + page.size,
+			None => false,
+		}) {
+			Some(slot) => slot,
+			// Another core may have mapped the page in the meantime.
+			None => {
+				return if mm::is_user_heap_range(virtual_address, 1)
+					&& arch::mm::paging::get_leaf_entry(virtual_address).is_some()
+				{
+					DemandFault::Retry
+				} else {
+					DemandFault::Unhandled
+				};
+			}
+		};
+
+		let page = pages[slot].as_mut().unwrap();
+		if !page.pending {
+			page.pending = true;
+			break (slot, *page);
+		}
+
+		// Wait until the other core has mapped the page.
+		drop(pages);
+		spin_loop_hint();
+	};
+
+	// Allocating the frames may fault on another reclaimed page, so the table isn't locked.
+	let mapped = map_zeroed(page.start, page.size, page.flags);
+	let mut pages = RECLAIMED.lock();
+	if mapped {
+		pages[slot] = None;
+		DemandFault::Mapped
+	} else {
+		if let Some(page) = pages[slot].as_mut() {
+			page.pending = false;
+		}
+		DemandFault::Unhandled
+	}
+}
+
+/// Maps new zeroed memory with `flags` at `[start, start + size)`, preferably as a 2 MiB page.
+/// The frames are cleared before they are mapped, so that the memory never exposes old data.
+fn map_zeroed(start: usize, size: usize, flags: PageTableEntryFlags) -> bool {
+	if size == LargePageSize::SIZE {
+		if let Ok(physical_address) =
+			arch::mm::physicalmem::allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE)
+		{
+			arch::mm::paging::zero_frames(physical_address, LargePageSize::SIZE);
+			arch::mm::paging::map::<LargePageSize>(start, physical_address, 1, flags);
+			return true;
+		}
+	}
+
+	for page in (start..start + size).step_by(BasePageSize::SIZE) {
+		if arch::mm::paging::get_page_table_entry::<BasePageSize>(page).is_some() {
+			// mapped by a previous attempt
+			continue;
+		}
+
+		let physical_address = match arch::mm::physicalmem::allocate(BasePageSize::SIZE) {
+			Ok(physical_address) => physical_address,
+			Err(()) => return false,
+		};
+		arch::mm::paging::zero_frames(physical_address, BasePageSize::SIZE);
+		arch::mm::paging::map_page::<BasePageSize>(page, physical_address, flags);
+	}
+
+	true
+}
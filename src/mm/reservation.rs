@@ -0,0 +1,229 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Reservations of virtual address space, which are mapped separately or on demand.
+//!
+//! A reservation takes its range from the virtual memory allocator, so no other allocation
+//! can reuse it until it is released. Pages of a reservation with demand paging are mapped
+//! by the page fault handler at their first access.
+//!
+//! The size of all reservations with demand paging may exceed the physical memory (overcommit).
+//! With `config::STRICT_COMMIT`, demand paging is refused instead if the pages, which the
+//! reservations may still fault in, don't fit into the free physical memory. Memory, which
+//! is committed by other means (`sys_mmap` and `sys_sbrk`), is checked by `may_map`, so
+//! that it doesn't consume the frames of the outstanding pages either.
+
+use alloc::vec::Vec;
+use arch;
+use arch::mm::paging::{BasePageSize, LargePageSize, PageSize, PageTableEntryFlags};
+use config::STRICT_COMMIT;
+use mm::DemandFault;
+use synch::spinlock::SpinlockIrqSave;
+
+#[derive(Clone, Copy)]
+struct Reservation {
+	start: usize,
+	end: usize,
+	/// Flags of the pages, which are mapped on demand, or `None` if the owner maps the pages itself
+	demand_flags: Option<PageTableEntryFlags>,
+	/// Number of bytes, which have been mapped on demand
+	backed: usize,
+}
+
+impl Reservation {
+	fn size(&self) -> usize {
+		self.end - self.start
+	}
+
+	/// Number of bytes, which the page fault handler may still map
+	fn outstanding(&self) -> usize {
+		if self.demand_flags.is_some() {
+			self.size() - self.backed
+		} else {
+			0
+		}
+	}
+}
+
+/// Returns `true` if demand paging of `size` more bytes may be enabled.
+///
+/// `outstanding` is the number of bytes, which existing reservations may still fault in.
+/// Without `strict`, memory is overcommitted and the check always succeeds.
+pub(crate) fn may_commit(strict: bool, outstanding: usize, size: usize, free: usize) -> bool {
+	!strict
+		|| outstanding
+			.checked_add(size)
+			.map_or(false, |committed| committed <= free)
+}
+
+safe_global_var!(static RESERVATIONS: SpinlockIrqSave<Vec<Reservation>> = SpinlockIrqSave::new(Vec::new()));
+
+/// Reserves `size` bytes of virtual address space aligned to `alignment` without mapping them.
+/// Returns 0 if no such range is available.
+pub fn reserve(size: usize, alignment: usize) -> usize {
+	let size = align_up!(size, BasePageSize::SIZE);
+	let alignment = align_up!(alignment.max(BasePageSize::SIZE), BasePageSize::SIZE);
+
+	// The virtual memory allocator expects a size, which is a multiple of the alignment.
+	let aligned_size = align_up!(size, alignment);
+	let start = match arch::mm::virtualmem::allocate_aligned(aligned_size, alignment) {
+		Ok(start) => start,
+		Err(()) => return 0,
+	};
+	if aligned_size > size {
+		arch::mm::virtualmem::deallocate(start + size, aligned_size - size);
+	}
+
+	RESERVATIONS.lock().push(Reservation {
+		start: start,
+		end: start + size,
+		demand_flags: None,
+		backed: 0,
+	});
+
+	start
+}
+
+/// Returns `true` if `size` bytes may be committed outside of a reservation.
+///
+/// With `config::STRICT_COMMIT`, the free physical memory has to back them in addition to
+/// the outstanding pages of all reservations with demand paging.
+pub fn may_map(size: usize) -> bool {
+	let reservations = RESERVATIONS.lock();
+	let outstanding: usize = reservations.iter().map(|r| r.outstanding()).sum();
+	may_commit(STRICT_COMMIT, outstanding, size, arch::mm::physicalmem::free_memory_size())
+}
+
+/// Lets the page fault handler map the pages of the reservation at `start` with `flags` at their first access.
+///
+/// Fails if there is no such reservation or, with `config::STRICT_COMMIT`, if the free physical
+/// memory can't back the whole reservation in addition to the outstanding pages of the other ones.
+pub fn set_demand_paging(start: usize, flags: PageTableEntryFlags) -> Result<(), ()> {
+	let mut reservations = RESERVATIONS.lock();
+	let outstanding: usize = reservations.iter().map(|r| r.outstanding()).sum();
+	let reservation = reservations.iter_mut().find(|r| r.start == start).ok_or(())?;
+	if reservation.demand_flags.is_none() {
+		let free = arch::mm::physicalmem::free_memory_size();
+		if !may_commit(STRICT_COMMIT, outstanding, reservation.size(), free) {
+			debug!(
+				"Refuse to commit {:#X} bytes, {:#X} bytes are outstanding and {:#X} bytes are free",
+				reservation.size(),
+				outstanding,
+				free
+			);
+			return Err(());
+		}
+	}
+	reservation.demand_flags = Some(flags);
+
+	Ok(())
+}
+
+/// Returns the range of the reservation at `start` to the virtual memory allocator.
+///
+/// Pages, which have been mapped on demand, are unmapped and their frames are released.
+/// Pages, which the owner has mapped itself, have to be unmapped before, otherwise the
+/// reservation is kept and an error is returned.
+pub fn release(start: usize, size: usize) -> Result<(), ()> {
+	let reservation = {
+		let mut reservations = RESERVATIONS.lock();
+		// `size` is rounded up to whole pages like in `reserve`.
+		let index = reservations
+			.iter()
+			.position(|r| r.start == start && size <= r.size() && r.size() - size < BasePageSize::SIZE)
+			.ok_or(())?;
+		if reservations[index].demand_flags.is_none() && is_mapped(&reservations[index]) {
+			debug!("Reservation at {:#X} has still mapped pages", start);
+			return Err(());
+		}
+		reservations.swap_remove(index)
+	};
+
+	// Pages mapped on demand may have been merged into large pages in the meantime.
+	let mut page = reservation.start;
+	while page < reservation.end {
+		match arch::mm::paging::get_leaf_entry(page) {
+			Some((entry, size)) => {
+				if size == BasePageSize::SIZE {
+					arch::mm::paging::unmap::<BasePageSize>(page, 1);
+				} else {
+					arch::mm::paging::unmap::<LargePageSize>(page, 1);
+				}
+				arch::mm::physicalmem::deallocate(entry.address(), size);
+				page += size;
+			}
+			None => page += BasePageSize::SIZE,
+		}
+	}
+
+	arch::mm::virtualmem::deallocate(reservation.start, reservation.size());
+	Ok(())
+}
+
+/// Returns `true` if any page of the reservation is mapped.
+fn is_mapped(reservation: &Reservation) -> bool {
+	(reservation.start..reservation.end)
+		.step_by(BasePageSize::SIZE)
+		.any(|page| arch::mm::paging::get_leaf_entry(page).is_some())
+}
+
+/// Maps the page, which contains `virtual_address`, if it belongs to a reservation with demand paging.
+/// The new page is filled with zeros.
+pub fn handle_fault(virtual_address: usize) -> DemandFault {
+	let flags = {
+		let reservations = RESERVATIONS.lock();
+		match reservations
+			.iter()
+			.find(|r| r.start <= virtual_address && virtual_address < r.end)
+			.and_then(|r| r.demand_flags)
+		{
+			Some(flags) => flags,
+			None => return DemandFault::Unhandled,
+		}
+	};
+
+	let page = align_down!(virtual_address, BasePageSize::SIZE);
+	if arch::mm::paging::get_leaf_entry(page).is_some() {
+		// Another core has mapped the page after the fault.
+		return DemandFault::Retry;
+	}
+
+	let physical_address = match arch::mm::physicalmem::allocate(BasePageSize::SIZE) {
+		Ok(physical_address) => physical_address,
+		Err(()) => return DemandFault::Unhandled,
+	};
+	// The frame is cleared before it becomes visible, so no other core can read its previous content.
+	arch::mm::paging::zero_frames(physical_address, BasePageSize::SIZE);
+	if !arch::mm::paging::map_page_if_absent::<BasePageSize>(page, physical_address, flags) {
+		// Another core has mapped the page in the meantime.
+		arch::mm::physicalmem::deallocate(physical_address, BasePageSize::SIZE);
+		return DemandFault::Retry;
+	}
+
+	if let Some(reservation) = RESERVATIONS
+		.lock()
+		.iter_mut()
+		.find(|r| r.start <= virtual_address && virtual_address < r.end)
+	{
+		reservation.backed += BasePageSize::SIZE;
+	}
+
+	DemandFault::Mapped
+}
+
+/// Returns the number of reserved bytes and the number of bytes, which are committed to demand paging.
+pub fn accounting() -> (usize, usize) {
+	let reservations = RESERVATIONS.lock();
+	let reserved = reservations.iter().map(|r| r.size()).sum();
+	let committed = reservations
+		.iter()
+		.filter(|r| r.demand_flags.is_some())
+		.map(|r| r.size())
+		.sum();
+
+	(reserved, committed)
+}
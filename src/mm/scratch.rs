@@ -0,0 +1,77 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Scratch arenas for request-scoped allocations.
+//!
+//! An arena owns a region, which is protected by a single key, and hands out memory by bumping
+//! a pointer. Single objects are never freed. Instead, `reset` releases all of them at once,
+//! so that the scratch memory of a request is recycled for the next one.
+
+use arch::mm::paging::{BasePageSize, PageSize};
+use core::ptr;
+use mm;
+use mm::AllocError;
+
+pub struct Arena {
+	start: usize,
+	size: usize,
+	/// Offset of the first free byte
+	next: usize,
+	key: u8,
+}
+
+impl Arena {
+	/// Allocates a region of at least `size` bytes, which is protected by `key`.
+	pub fn new(size: usize, key: u8) -> Result<Self, AllocError> {
+		let size = align_up!(size, BasePageSize::SIZE);
+		let start = mm::try_key_allocate(size, key)?;
+
+		Ok(Self {
+			start: start,
+			size: size,
+			next: 0,
+			key: key,
+		})
+	}
+
+	/// Returns a null pointer if the arena is exhausted. `align` has to be a power of two.
+	pub fn alloc(&mut self, size: usize, align: usize) -> *mut u8 {
+		debug_assert!(align.is_power_of_two());
+
+		let start = align_up!(self.start + self.next, align);
+		match start.checked_add(size) {
+			Some(end) if end <= self.start + self.size => {
+				self.next = end - self.start;
+				start as *mut u8
+			}
+			_ => ptr::null_mut(),
+		}
+	}
+
+	/// Releases all allocations of the arena at once.
+	///
+	/// The memory isn't cleared, so pointers returned by `alloc` must not be used afterwards.
+	pub fn reset(&mut self) {
+		self.next = 0;
+	}
+
+	/// Returns the number of allocated bytes, including the padding for the alignment.
+	pub fn used(&self) -> usize {
+		self.next
+	}
+
+	/// Returns the protection key of the arena.
+	pub fn key(&self) -> u8 {
+		self.key
+	}
+}
+
+impl Drop for Arena {
+	fn drop(&mut self) {
+		mm::deallocate(self.start, self.size);
+	}
+}
@@ -38,10 +38,10 @@ fn new_max_heap() -> Heap {
 fn empty() {
 	let mut heap = Heap::empty();
 	let layout = Layout::from_size_align(1, 1).unwrap();
-	// we have 4 kbyte static memory
+	// the early allocator serves the empty heap from its static memory
 	assert!(heap.allocate_first_fit(layout.clone()).is_ok());
 
-	let layout = Layout::from_size_align(0x1000, align_of::<usize>());
+	let layout = Layout::from_size_align(::config::EARLY_HEAP_SIZE, align_of::<usize>());
 	let addr = heap.allocate_first_fit(layout.unwrap());
 	assert!(addr.is_err());
 }
@@ -282,3 +282,77 @@ fn extend_fragmented_heap() {
 	// Try to allocate there
 	assert!(heap.allocate_first_fit(layout_2.clone()).is_ok());
 }
+
+#[test]
+fn init_phases() {
+	let mut timer = PhaseTimer::new();
+	timer.start(100);
+	timer.phase("arch init", 150);
+	timer.phase("page-table init", 400);
+	timer.phase("heap map", 1000);
+
+	let phases = timer.phases();
+	assert_eq!(phases.len(), 3);
+	assert_eq!(phases[0].0, "arch init");
+	assert_eq!(phases[1].0, "page-table init");
+	assert_eq!(phases[2].0, "heap map");
+	assert!(phases.windows(2).all(|w| w[0].1 <= w[1].1));
+}
+
+#[test]
+fn data_sections_must_not_overlap() {
+	let kernel = ("kernel image", 0x200000, 0x800000);
+	let safe = (".safe_data", 0x400000, 0x600000);
+	let unsafe_ = (".unsafe_data", 0x600000, 0x800000);
+	assert!(validate_data_sections(kernel, safe, unsafe_).is_ok());
+
+	// a grown .safe_data section reaches into .unsafe_data
+	let grown = (".safe_data", 0x400000, 0x601000);
+	assert!(validate_data_sections(kernel, grown, unsafe_).is_err());
+
+	// the kernel image ends in the middle of .unsafe_data
+	let kernel = ("kernel image", 0x200000, 0x700000);
+	let (section, image) = validate_data_sections(kernel, safe, unsafe_).unwrap_err();
+	assert_eq!(section.0, ".unsafe_data");
+	assert_eq!(image.0, "kernel image");
+
+	// sections outside of the image are fine
+	let kernel = ("kernel image", 0x200000, 0x400000);
+	assert!(validate_data_sections(kernel, safe, unsafe_).is_ok());
+}
+
+#[test]
+fn strict_commit_refuses_overcommit() {
+	const MIB: usize = 1024 * 1024;
+
+	// by default, memory is overcommitted
+	assert!(reservation::may_commit(false, 0, 2 * MIB, MIB));
+
+	// in strict mode, a reservation exceeding the free memory fails immediately
+	assert!(reservation::may_commit(true, 0, MIB, MIB));
+	assert!(!reservation::may_commit(true, 0, 2 * MIB, MIB));
+
+	// outstanding pages of other reservations count as well
+	assert!(!reservation::may_commit(true, MIB / 2, MIB, MIB));
+	assert!(!reservation::may_commit(true, usize::MAX, 1, usize::MAX));
+}
+
+#[test]
+fn early_allocator_bumps_until_finalized() {
+	let mut early = Box::new(early::BumpAllocator::new());
+	let (bottom, _) = early.used();
+
+	let first = early.allocate(Layout::from_size_align(3, 1).unwrap()) as usize;
+	assert_eq!(first, bottom);
+	let second = early.allocate(Layout::from_size_align(16, 8).unwrap()) as usize;
+	assert_eq!(second, bottom + 8);
+	assert_eq!(early.used(), (bottom, bottom + 24));
+
+	// exhausted
+	let size = ::config::EARLY_HEAP_SIZE;
+	assert!(early.allocate(Layout::from_size_align(size, 1).unwrap()).is_null());
+
+	// finalized
+	assert_eq!(early.finalize(), (bottom, bottom + 24));
+	assert!(early.allocate(Layout::from_size_align(1, 1).unwrap()).is_null());
+}
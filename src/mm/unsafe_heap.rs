@@ -0,0 +1,71 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Heap of the unsafe domain, which is separated from the kernel heap.
+//!
+//! The heap structure, its lock and its free list live in `UNSAFE_MEM_REGION`.
+//! Hence, code of the unsafe domain is able to allocate without leaving its domain and
+//! a corrupted free list of this heap can't corrupt the bookkeeping of the kernel heap.
+
+use alloc::alloc::Layout;
+use config::UNSAFE_HEAP_SIZE;
+use core::ptr::{self, NonNull};
+use core::sync::atomic::{AtomicUsize, Ordering};
+use mm;
+use mm::allocator::Heap;
+use synch::spinlock::SpinlockIrqSave;
+
+unsafe_global_var!(static HEAP: SpinlockIrqSave<Heap> = SpinlockIrqSave::new(Heap::empty()));
+
+/// Range `[BOTTOM, TOP)` of the heap, which is checked by `contains` without taking the lock.
+unsafe_global_var!(static BOTTOM: AtomicUsize = AtomicUsize::new(0));
+unsafe_global_var!(static TOP: AtomicUsize = AtomicUsize::new(0));
+
+/// Maps the memory of the unsafe heap. Allocations before are served by the kernel heap.
+pub fn init() {
+	let start = mm::unsafe_allocate(UNSAFE_HEAP_SIZE, true);
+	unsafe {
+		HEAP.lock().init(start, UNSAFE_HEAP_SIZE);
+	}
+	BOTTOM.store(start, Ordering::SeqCst);
+	TOP.store(start + UNSAFE_HEAP_SIZE, Ordering::SeqCst);
+
+	info!(
+		"Unsafe Heap is located at 0x{:x} -- 0x{:x}",
+		start,
+		start + UNSAFE_HEAP_SIZE
+	);
+}
+
+/// Returns a null pointer if the heap is exhausted.
+pub fn allocate(layout: Layout) -> *mut u8 {
+	HEAP.lock()
+		.allocate_first_fit(layout)
+		.ok()
+		.map_or(ptr::null_mut(), |allocation| allocation.as_ptr())
+}
+
+/// `ptr` has to be returned by `allocate` with the same `layout`.
+pub fn deallocate(ptr: *mut u8, layout: Layout) {
+	if let Some(ptr) = NonNull::new(ptr) {
+		unsafe {
+			HEAP.lock().deallocate(ptr, layout);
+		}
+	}
+}
+
+/// Returns the range `[bottom, top)` of the heap.
+pub fn range() -> (usize, usize) {
+	let heap = HEAP.lock();
+	(heap.bottom(), heap.top())
+}
+
+/// Returns `true` if `ptr` has been allocated from this heap.
+pub fn contains(ptr: *mut u8) -> bool {
+	let ptr = ptr as usize;
+	BOTTOM.load(Ordering::Relaxed) <= ptr && ptr < TOP.load(Ordering::Relaxed)
+}
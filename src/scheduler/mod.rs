@@ -11,19 +11,29 @@ pub mod task;
 use alloc::boxed::Box;
 use alloc::collections::{BTreeMap, VecDeque};
 use alloc::rc::Rc;
+use alloc::vec::Vec;
 use arch;
 use arch::irq;
+use arch::mm::paging::{BasePageSize, PageSize};
 use arch::percore::*;
+use arch::scheduler::TaskStacks;
 use arch::switch;
-use core::cell::RefCell;
-use core::sync::atomic::{AtomicU32, AtomicUsize, Ordering};
+use config::KERNEL_STACK_SIZE;
+use core::cell::{RefCell, RefMut};
+use core::cmp;
+use core::sync::atomic::{AtomicU16, AtomicU32, AtomicUsize, Ordering};
+use mm;
 use scheduler::task::*;
+use synch::semaphore::Semaphore;
 use synch::spinlock::*;
 
 /// Time slice of a task in microseconds.
 /// When this time has elapsed and the scheduler is called, it may switch to another ready task.
 pub const TASK_TIME_SLICE: u64 = 10_000;
 
+/// Remaining time slice in microseconds, below which `checkpoint` gives up the CPU.
+pub const CHECKPOINT_THRESHOLD: u64 = TASK_TIME_SLICE / 10;
+
 safe_global_var!(static NEXT_CORE_ID: AtomicUsize = AtomicUsize::new(1));
 safe_global_var!(static NO_TASKS: AtomicU32 = AtomicU32::new(0));
 #[allow(unused)]
@@ -33,12 +43,25 @@ safe_global_var!(static mut SCHEDULERS: Option<BTreeMap<usize, &PerCoreScheduler
 /// Map between Task ID and Task Control Block
 safe_global_var!(static mut TASKS: Option<SpinlockIrqSave<BTreeMap<TaskId, Rc<RefCell<Task>>>>> = None);
 safe_global_var!(static TID_COUNTER: AtomicU32 = AtomicU32::new(0));
+/// Protection keys bound to a task by `task_local_alloc` (bit n = key n)
+safe_global_var!(static TASK_LOCAL_KEYS: AtomicU16 = AtomicU16::new(0));
+
+/// Receiver of the remaining time slice of the current task
+#[derive(Clone, Copy)]
+pub enum BoostTarget {
+	/// A specific ready task on the same core
+	Task(TaskId),
+	/// The next ready task with exactly this priority
+	Band(Priority),
+}
 
 struct SchedulerState {
 	/// Queue of tasks, which are ready
 	ready_queue: PriorityTaskQueue,
 	/// Whether the scheduler CPU has been halted
 	is_halted: bool,
+	/// Pending requests to move a task of this core to another core
+	migrations: Vec<(TaskId, usize)>,
 }
 
 pub struct PerCoreScheduler {
@@ -58,18 +81,32 @@ pub struct PerCoreScheduler {
 	pub blocked_tasks: SpinlockIrqSave<BlockedTaskQueue>,
 	/// Processor Timer Tick when we last switched the current task.
 	last_task_switch_tick: u64,
+	/// Receiver of the remaining time slice at the next scheduling decision
+	boost: Option<BoostTarget>,
 }
 
 impl PerCoreScheduler {
 	/// Spawn a new task.
 	pub fn spawn(&self, func: extern "C" fn(usize), arg: usize, prio: Priority) -> TaskId {
+		self.spawn_with_stacks(func, arg, prio, TaskStacks::new())
+	}
+
+	/// Spawn a new task, which runs on the given stacks.
+	fn spawn_with_stacks(
+		&self,
+		func: extern "C" fn(usize),
+		arg: usize,
+		prio: Priority,
+		stacks: TaskStacks,
+	) -> TaskId {
 		// Create the new task.
 		let tid = get_tid();
-		let task = Rc::new(RefCell::new(Task::new(
+		let task = Rc::new(RefCell::new(Task::new_with_stacks(
 			tid,
 			self.core_id,
 			TaskStatus::TaskReady,
 			prio,
+			stacks,
 		)));
 		task.borrow_mut().create_stack_frame(func, arg);
 
@@ -163,6 +200,56 @@ impl PerCoreScheduler {
 		tid
 	}
 
+	/// Donates the remaining time slice of the current task to the given target for one scheduling round.
+	///
+	/// The priorities of the tasks aren't changed. Hence, the current task gets the CPU back as soon
+	/// as the donated time slice has elapsed. If no task matches the target, this behaves like a yield.
+	pub fn boost(&mut self, target: BoostTarget) -> Result<(), ()> {
+		if let BoostTarget::Task(id) = target {
+			let task = unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() };
+			match task {
+				Some(ref t) if t.borrow().core_id == self.core_id => {}
+				_ => return Err(()),
+			}
+		}
+
+		self.boost = Some(target);
+		self.reschedule();
+
+		Ok(())
+	}
+
+	/// Returns the remaining time slice of the current task in microseconds.
+	pub fn remaining_time_slice(&self) -> u64 {
+		(self.last_task_switch_tick + TASK_TIME_SLICE).saturating_sub(arch::processor::get_timer_ticks())
+	}
+
+	/// Cooperative preemption point for long-running loops.
+	///
+	/// Switches to another ready task with the same priority if the time slice of the current task
+	/// is nearly exhausted. Hence, the task continues its next iteration with a fresh time slice
+	/// instead of being preempted by the timer in the middle of it.
+	pub fn checkpoint(&mut self) {
+		if self.remaining_time_slice() > CHECKPOINT_THRESHOLD {
+			return;
+		}
+
+		let irq = irq::nested_disable();
+
+		// Let the time slice expire now, so that the scheduler picks the next task of our priority.
+		let expired = arch::processor::get_timer_ticks().saturating_sub(TASK_TIME_SLICE + 1);
+		self.last_task_switch_tick = expired;
+		self.scheduler();
+
+		// No other task was ready. Continue with a fresh time slice instead of
+		// entering the scheduler at every following checkpoint.
+		if self.last_task_switch_tick == expired {
+			self.last_task_switch_tick = arch::processor::get_timer_ticks();
+		}
+
+		irq::nested_enable(irq);
+	}
+
 	/// Save the FPU context for the current FPU owner and restore it for the current task,
 	/// which wants to use the FPU now.
 	pub fn fpu_switch(&mut self) {
@@ -187,19 +274,71 @@ impl PerCoreScheduler {
 
 	/// Check if a finished task could be deleted.
 	fn cleanup_tasks(&mut self) {
-		// Pop the first finished task and remove it from the TASKS list, which implicitly deallocates all associated memory.
+		// Pop the first finished task, remove it from the TASKS list and release all associated memory.
 		if let Some(id) = self.finished_tasks.pop_front() {
 			debug!("Cleaning up task {}", id);
 
 			let task = unsafe { TASKS.as_ref().unwrap().lock().remove(&id) };
-			// wakeup tasks, which are waiting for task with the identifier id
+			// release its memory and wakeup tasks, which are waiting for task with the identifier id
 			match task {
-				Some(t) => t.borrow().wakeup.lock().wakeup_all(),
+				Some(t) => {
+					t.borrow_mut().cleanup();
+					t.borrow().wakeup.lock().wakeup_all();
+				}
 				None => {}
 			}
 		}
 	}
 
+	/// Moves the ready tasks with a pending migration request to the ready queues of their target cores.
+	///
+	/// Requests for the current task and for blocked tasks stay pending until the task is ready.
+	fn process_migrations(&mut self) {
+		let mut migrated = Vec::new();
+
+		{
+			let mut state_locked = self.state.lock();
+			let current_id = self.current_task.borrow().id;
+			let mut i = 0;
+
+			while i < state_locked.migrations.len() {
+				let (id, target_core) = state_locked.migrations[i];
+				let task = unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() };
+				if let Some(task) = task {
+					let status = task.borrow().status;
+					if id == current_id || status == TaskStatus::TaskBlocked {
+						i += 1;
+						continue;
+					}
+
+					if status == TaskStatus::TaskReady && state_locked.ready_queue.remove(task.clone()) {
+						migrated.push((task, target_core));
+					}
+				}
+
+				// Requests for finished tasks are dropped.
+				state_locked.migrations.swap_remove(i);
+			}
+		}
+
+		for (task, target_core) in migrated {
+			// The registers of this core may still hold the FPU state of the task.
+			if Rc::ptr_eq(&self.fpu_owner, &task) {
+				task.borrow_mut().last_fpu_state.save_switched();
+				self.fpu_owner = self.idle_task.clone();
+			}
+
+			debug!("Migrating task {} from core {} to core {}", task.borrow().id, self.core_id, target_core);
+			task.borrow_mut().core_id = target_core;
+
+			let mut state_locked = get_scheduler(target_core).state.lock();
+			state_locked.ready_queue.push(task);
+			if state_locked.is_halted {
+				arch::wakeup_core(target_core);
+			}
+		}
+	}
+
 	/// Triggers the scheduler to reschedule the tasks.
 	/// Interrupt flag will be cleared during the reschedule
 	pub fn reschedule(&mut self) {
@@ -225,6 +364,7 @@ impl PerCoreScheduler {
 		// Someone wants to give up the CPU
 		// => we have time to cleanup the system
 		self.cleanup_tasks();
+		self.process_migrations();
 
 		// Get information about the current task.
 		let (id, last_stack_pointer, kernel_stack_pointer, user_stack_pointer, prio, status) = {
@@ -244,12 +384,33 @@ impl PerCoreScheduler {
 		state_locked.is_halted = false;
 
 		let mut new_task = None;
+		let mut boosted = false;
 
-		if status == TaskStatus::TaskRunning {
+		if status == TaskStatus::TaskRunning
+			&& state_locked.migrations.iter().any(|&(task_id, _)| task_id == id)
+		{
+			// The current task has to move to another core.
+			// Give up the CPU, so that the next scheduler call finds it in the ready queue.
+			debug!("Current task {} is migrating.", id);
+			new_task = Some(
+				state_locked
+					.ready_queue
+					.pop()
+					.unwrap_or_else(|| self.idle_task.clone()),
+			);
+		} else if status == TaskStatus::TaskRunning {
 			// A task is currently running.
+			// Check if it donates its time slice to another task.
+			if let Some(target) = self.boost.take() {
+				new_task = take_boost_target(&mut state_locked.ready_queue, self.core_id, target);
+				boosted = new_task.is_some();
+			}
+
 			// Check if a task with a higher priority is available.
 			let higher_prio = Priority::from(prio.into() + 1);
-			if let Some(task) = state_locked.ready_queue.pop_with_prio(higher_prio) {
+			if new_task.is_some() {
+				debug!("Current task donates its time slice.");
+			} else if let Some(task) = state_locked.ready_queue.pop_with_prio(higher_prio) {
 				// This higher priority task becomes the new task.
 				debug!("Task with a higher priority is available.");
 				new_task = Some(task);
@@ -321,7 +482,16 @@ impl PerCoreScheduler {
 					new_user_stack_pointer
 				);
 				self.current_task = task;
-				self.last_task_switch_tick = arch::processor::get_timer_ticks();
+				// Hide the task-local regions of all other tasks from the new task (see `task_local_alloc`).
+				let keys = TASK_LOCAL_KEYS.load(Ordering::SeqCst);
+				if keys != 0 && ::environment::mpk_enabled() {
+					let own = self.current_task.borrow().local_keys;
+					arch::scheduler::update_saved_pkru(new_stack_pointer, keys & !own, own);
+				}
+				if !boosted {
+					// A boosted task only gets the remaining time slice of the donating task.
+					self.last_task_switch_tick = arch::processor::get_timer_ticks();
+				}
 
 				// Unlock the state and reenable interrupts.
 				drop(state_locked);
@@ -341,6 +511,29 @@ impl PerCoreScheduler {
 	}
 }
 
+/// Removes the task, which receives the donated time slice, from the ready queue.
+fn take_boost_target(
+	ready_queue: &mut PriorityTaskQueue,
+	core_id: usize,
+	target: BoostTarget,
+) -> Option<Rc<RefCell<Task>>> {
+	match target {
+		BoostTarget::Task(id) => {
+			let task = unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() }?;
+			{
+				let borrowed = task.borrow();
+				if borrowed.core_id != core_id || borrowed.status != TaskStatus::TaskReady {
+					return None;
+				}
+			}
+
+			ready_queue.remove(task.clone());
+			Some(task)
+		}
+		BoostTarget::Band(prio) => ready_queue.pop_from_band(prio),
+	}
+}
+
 fn get_tid() -> TaskId {
 	loop {
 		let id = TaskId::from(TID_COUNTER.fetch_add(1, Ordering::SeqCst));
@@ -362,6 +555,15 @@ pub fn abort() {
 	core_scheduler().exit(-1);
 }
 
+/// Returns the task, which runs on the current core.
+///
+/// Returns `None` before the scheduler of the core has been installed or if the task is already borrowed,
+/// e.g. by the code, which has been interrupted by an exception. Hence, exception handlers can inspect
+/// the current task without touching the per-core variables themselves.
+pub fn current_task_ref() -> Option<RefMut<'static, Task>> {
+	try_core_scheduler().and_then(|core_scheduler| core_scheduler.current_task.try_borrow_mut().ok())
+}
+
 /// Add a per-core scheduler for the current core.
 pub fn add_current_core() {
 	// Create an idle task for this core.
@@ -391,10 +593,12 @@ pub fn add_current_core() {
 		state: SpinlockIrqSave::new(SchedulerState {
 			ready_queue: PriorityTaskQueue::new(),
 			is_halted: false,
+			migrations: Vec::new(),
 		}),
 		finished_tasks: VecDeque::new(),
 		blocked_tasks: SpinlockIrqSave::new(BlockedTaskQueue::new()),
 		last_task_switch_tick: 0,
+		boost: None,
 	});
 
 	let scheduler = Box::into_raw(boxed_scheduler);
@@ -416,6 +620,229 @@ pub fn get_scheduler(core_id: usize) -> &'static PerCoreScheduler {
 	result.unwrap()
 }
 
+/// Moves the task `id` to the core `target_core`.
+///
+/// The scheduler of the core, which owns the task, carries out the move at its next invocation.
+/// A running task is moved after it has been switched out, a blocked task after its wakeup.
+pub fn migrate_task(id: TaskId, target_core: usize) -> Result<(), ()> {
+	if unsafe { !SCHEDULERS.as_ref().unwrap().contains_key(&target_core) } {
+		return Err(());
+	}
+
+	let task = unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() }.ok_or(())?;
+	let source_core = {
+		let borrowed = task.borrow();
+		match borrowed.status {
+			TaskStatus::TaskIdle | TaskStatus::TaskFinished | TaskStatus::TaskInvalid => {
+				return Err(());
+			}
+			_ => borrowed.core_id,
+		}
+	};
+
+	if source_core == target_core {
+		return Ok(());
+	}
+
+	{
+		let mut state_locked = get_scheduler(source_core).state.lock();
+		state_locked.migrations.retain(|&(task_id, _)| task_id != id);
+		state_locked.migrations.push((id, target_core));
+	}
+
+	if source_core == core_id() {
+		core_scheduler().reschedule();
+	} else {
+		arch::wakeup_core(source_core);
+	}
+
+	Ok(())
+}
+
+/// Allocates `size` bytes of memory for the current task, which are protected by `key`.
+///
+/// `key` has to be a dynamically allocated protection key, which is bound to the current task by the
+/// first allocation. The scheduler denies the access to the key in the PKRU of every other task, when
+/// it switches to it, and permits it for the owner. Tasks, which are running on other cores, lose the
+/// access at their next context switch. The region is released when the task is torn down.
+/// Returns 0 if the key is invalid or bound to another task, the memory limit of the task is reached
+/// or the memory is exhausted.
+pub fn task_local_alloc(size: usize, key: u8) -> usize {
+	if size == 0 || !arch::mm::mpk::mpk_pkey_is_allocated(key) {
+		return 0;
+	}
+
+	let size = align_up!(size, BasePageSize::SIZE);
+	let current_task = core_scheduler().current_task.clone();
+	let mut borrowed = current_task.borrow_mut();
+	let bit = 1u16 << key;
+	let bound = borrowed.local_keys & bit != 0;
+	if !bound && TASK_LOCAL_KEYS.fetch_or(bit, Ordering::SeqCst) & bit != 0 {
+		debug!("Protection key {} is already bound to another task", key);
+		return 0;
+	}
+
+	let address = if borrowed.charge_memory(size).is_err() {
+		0
+	} else {
+		match mm::try_key_allocate(size, key) {
+			Ok(address) => {
+				borrowed.local_regions.push(TaskLocalRegion::new(address, size));
+				address
+			}
+			Err(err) => {
+				debug!("Unable to allocate a task-local region of {} bytes: {:?}", size, err);
+				borrowed.uncharge_memory(size);
+				0
+			}
+		}
+	};
+
+	if !bound {
+		if address != 0 {
+			borrowed.local_keys |= bit;
+			// The key may still be closed, if it has been bound to a terminated task before.
+			arch::mm::mpk::mpk_set_perm(key, arch::mm::mpk::MpkPerm::MpkRw);
+		} else {
+			TASK_LOCAL_KEYS.fetch_and(!bit, Ordering::SeqCst);
+		}
+	}
+
+	address
+}
+
+/// Unbinds the protection keys `keys` of a terminated task (see `task_local_alloc`) and permits the
+/// access to them on the current core, which releases the task-local regions of the task.
+fn release_local_keys(keys: u16) {
+	if keys == 0 {
+		return;
+	}
+
+	TASK_LOCAL_KEYS.fetch_and(!keys, Ordering::SeqCst);
+	let pkru = arch::mm::mpk::mpk_get_pkru();
+	arch::mm::mpk::mpk_set_pkru(pkru & !arch::mm::mpk::no_access_bits(keys));
+}
+
+/// Creates a semaphore with the initial `value`, which is owned by the current task.
+///
+/// The semaphore is destroyed when the task is torn down, so other tasks must not use it afterwards.
+pub fn task_semaphore(value: isize) -> *const Semaphore {
+	let semaphore = Box::new(Semaphore::new(value));
+	let address = &*semaphore as *const Semaphore;
+	core_scheduler().current_task.borrow_mut().semaphores.push(semaphore);
+
+	address
+}
+
+/// Releases `size` bytes of memory, which have been accounted to the task `id`.
+/// Nothing happens if the task has already terminated.
+pub fn uncharge_memory(id: TaskId, size: usize) {
+	if let Some(task) = unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() } {
+		task.borrow_mut().uncharge_memory(size);
+	}
+}
+
+/// Records `prio` as the priority, which the waiters of the semaphore at `source` lend to the task `id`,
+/// so that it isn't starved by tasks, whose priority lies in between (priority inheritance).
+/// `None` withdraws the priority lent through `source`.
+///
+/// The task runs with the highest of its base priority and all priorities, which are currently lent to it.
+/// Hence, withdrawing the priority of one semaphore keeps a boost inherited through another one.
+pub fn lend_priority(id: TaskId, source: usize, prio: Option<Priority>) {
+	let task = match unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() } {
+		Some(task) => task,
+		None => return,
+	};
+
+	let effective_prio = {
+		let mut borrowed = task.borrow_mut();
+		borrowed.lent_prios.retain(|&(lender, _)| lender != source);
+		if let Some(prio) = prio {
+			if prio > borrowed.base_prio {
+				borrowed.lent_prios.push((source, prio));
+			}
+		}
+
+		let base_prio = borrowed.base_prio;
+		borrowed
+			.lent_prios
+			.iter()
+			.map(|&(_, prio)| prio)
+			.fold(base_prio, cmp::max)
+	};
+
+	if task.borrow().prio != effective_prio {
+		debug!("Task {} runs with priority {}", id, effective_prio);
+		set_priority(&task, effective_prio);
+	}
+}
+
+fn set_priority(task: &Rc<RefCell<Task>>, prio: Priority) {
+	let core_id = task.borrow().core_id;
+	let mut state_locked = get_scheduler(core_id).state.lock();
+
+	// The ready queue is sorted by the priority, so a ready task has to be queued again.
+	let queued =
+		task.borrow().status == TaskStatus::TaskReady && state_locked.ready_queue.remove(task.clone());
+	task.borrow_mut().prio = prio;
+	if queued {
+		state_locked.ready_queue.push(task.clone());
+	}
+}
+
+/// Registers the function at `handler` for the page faults of the task `id`, 0 removes the handler.
+///
+/// Instead of aborting the task, an unhandled page fault redirects it to the handler, which is invoked
+/// with the fault address and the page fault error code on the stack of the task. The handler is reset
+/// before it is invoked and the task is terminated once the handler returns.
+/// Only faults of user code (see `signal::is_user_context`) are delivered to the handler.
+pub fn set_fault_handler(id: TaskId, handler: usize) -> Result<(), ()> {
+	let task = unsafe { TASKS.as_ref().unwrap().lock().get(&id).cloned() }.ok_or(())?;
+	task.borrow_mut().page_fault_handler = if handler == 0 { None } else { Some(handler) };
+
+	Ok(())
+}
+
+/// Spawns a task on the current core, whose stack and user stack have `stack_size` bytes.
+///
+/// The size is rounded up to whole pages and an unmapped guard page lies below each stack,
+/// so that an overflow faults. Fails if `stack_size` is smaller than `KERNEL_STACK_SIZE`.
+pub fn spawn_with_stack(
+	func: extern "C" fn(usize),
+	arg: usize,
+	prio: Priority,
+	stack_size: usize,
+) -> Result<TaskId, ()> {
+	if stack_size < KERNEL_STACK_SIZE {
+		return Err(());
+	}
+
+	Ok(core_scheduler().spawn_with_stacks(func, arg, prio, TaskStacks::with_stack_size(stack_size)))
+}
+
+/// Prints all tasks together with their memory usage.
+pub fn task_list() {
+	let tasks = unsafe { TASKS.as_ref().unwrap().lock() };
+
+	info!("{:>6} {:>5} {:>14} {:>5} {:>12} {:>12}", "ID", "CORE", "STATUS", "PRIO", "MEMORY", "LIMIT");
+	for (id, task) in tasks.iter() {
+		// A task, which is currently modified, is skipped.
+		if let Ok(task) = task.try_borrow() {
+			if task.memory_limit == usize::MAX {
+				info!(
+					"{:>6} {:>5} {:>14?} {:>5} {:>12} {:>12}",
+					id, task.core_id, task.status, task.prio.into(), task.memory_usage, "unlimited"
+				);
+			} else {
+				info!(
+					"{:>6} {:>5} {:>14?} {:>5} {:>12} {:>12}",
+					id, task.core_id, task.status, task.prio.into(), task.memory_usage, task.memory_limit
+				);
+			}
+		}
+	}
+}
+
 pub fn join(id: TaskId) -> Result<(), ()> {
 	debug!("Waiting for task {}", id);
 
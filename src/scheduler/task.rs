@@ -6,7 +6,9 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use alloc::boxed::Box;
 use alloc::rc::Rc;
+use alloc::vec::Vec;
 use arch;
 use arch::mm::paging::{BasePageSize, PageSize};
 use arch::processor::msb;
@@ -17,6 +19,7 @@ use core::fmt;
 //use core::ptr::{write_bytes, copy_nonoverlapping};
 use mm;
 use scheduler;
+use synch::semaphore::Semaphore;
 use synch::spinlock::SpinlockIrqSave;
 
 /// The status of the task - used for scheduling
@@ -243,62 +246,105 @@ impl PriorityTaskQueue {
 		None
 	}
 
+	/// Pop the next task, which has exactly the priority `prio`
+	pub fn pop_from_band(&mut self, prio: Priority) -> Option<Rc<RefCell<Task>>> {
+		let i = prio.into() as usize;
+		if i < NO_PRIORITIES && self.prio_bitmap & (1 << i) != 0 {
+			return self.pop_from_queue(i);
+		}
+
+		None
+	}
+
 	/// Remove a specific task from the priority queue.
-	pub fn remove(&mut self, task: Rc<RefCell<Task>>) {
+	/// Returns `false` if the task isn't in the queue.
+	pub fn remove(&mut self, task: Rc<RefCell<Task>>) -> bool {
 		let i = task.borrow().prio.into() as usize;
 		//assert!(i < NO_PRIORITIES, "Priority {} is too high", i);
 
 		let mut curr = self.queues[i].head.clone();
-		let mut next_curr;
+		while let Some(curr_task) = curr {
+			if Rc::ptr_eq(&curr_task, &task) {
+				let (prev, next) = {
+					let mut borrowed = curr_task.borrow_mut();
+					(borrowed.prev.take(), borrowed.next.take())
+				};
+
+				match prev {
+					Some(ref t) => t.borrow_mut().next = next.clone(),
+					None => self.queues[i].head = next.clone(),
+				}
 
-		loop {
-			match curr {
-				None => {
-					break;
+				match next {
+					Some(ref t) => t.borrow_mut().prev = prev.clone(),
+					None => self.queues[i].tail = prev.clone(),
 				}
-				Some(ref curr_task) => {
-					if Rc::ptr_eq(&curr_task, &task) {
-						let (mut prev, mut next) = {
-							let borrowed = curr_task.borrow_mut();
-							(borrowed.prev.clone(), borrowed.next.clone())
-						};
-
-						match prev {
-							Some(ref mut t) => {
-								t.borrow_mut().next = next.clone();
-							}
-							None => {}
-						};
-
-						match next {
-							Some(ref mut t) => {
-								t.borrow_mut().prev = prev.clone();
-							}
-							None => {}
-						};
-
-						break;
-					}
 
-					next_curr = curr_task.borrow().next.clone();
+				if self.queues[i].head.is_none() {
+					self.prio_bitmap &= !(1 << i as u64);
 				}
+
+				return true;
 			}
 
-			curr = next_curr.clone();
+			curr = curr_task.borrow().next.clone();
 		}
 
-		let new_head = match self.queues[i].head {
-			Some(ref curr_task) => Rc::ptr_eq(&curr_task, &task),
-			None => false,
-		};
+		false
+	}
+}
 
-		if new_head {
-			self.queues[i].head = task.borrow().next.clone();
+/// Realize a first-in, first-out queue for tasks
+///
+/// In contrast to `PriorityTaskQueue`, the priority of the tasks is ignored.
+/// Tasks are always popped in the order, in which they were pushed.
+pub struct FifoTaskQueue {
+	list: DoublyLinkedList<Rc<RefCell<Task>>>,
+}
 
-			if self.queues[i].head.is_none() {
-				self.prio_bitmap &= !(1 << i as u64);
+impl FifoTaskQueue {
+	/// Creates an empty FIFO queue for tasks
+	pub const fn new() -> Self {
+		Self {
+			list: DoublyLinkedList::new(),
+		}
+	}
+
+	/// Add a task at the end of the queue
+	pub fn push(&mut self, task: Rc<RefCell<Task>>) {
+		self.list.push(Node::new(task));
+	}
+
+	/// Pop the task, which has been waiting for the longest time
+	pub fn pop(&mut self) -> Option<Rc<RefCell<Task>>> {
+		let node = self.list.head()?;
+		self.list.remove(node.clone());
+		let task = node.borrow().value.clone();
+		Some(task)
+	}
+
+	/// Returns the highest priority of the queued tasks or `None` if the queue is empty.
+	pub fn highest_prio(&self) -> Option<Priority> {
+		self.list
+			.iter()
+			.map(|node| {
+				let prio = node.borrow().value.borrow().prio;
+				prio
+			})
+			.max()
+	}
+
+	/// Remove a specific task from the queue.
+	/// Returns `false` if the task hasn't been queued.
+	pub fn remove(&mut self, task: Rc<RefCell<Task>>) -> bool {
+		for node in self.list.iter() {
+			if Rc::ptr_eq(&node.borrow().value, &task) {
+				self.list.remove(node.clone());
+				return true;
 			}
 		}
+
+		false
 	}
 }
 
@@ -333,6 +379,61 @@ impl Drop for TaskTLS {
 	}
 }
 
+/// Memory of a task, which is protected by a protection key and released together with the task
+pub struct TaskLocalRegion {
+	address: usize,
+	size: usize,
+}
+
+impl TaskLocalRegion {
+	pub fn new(address: usize, size: usize) -> Self {
+		Self {
+			address: address,
+			size: size,
+		}
+	}
+}
+
+impl Drop for TaskLocalRegion {
+	fn drop(&mut self) {
+		debug!(
+			"Deallocate task-local region at 0x{:x} (size 0x{:x})",
+			self.address, self.size
+		);
+		mm::deallocate(self.address, self.size);
+	}
+}
+
+/// Consecutive page faults of a task at the same address
+#[derive(Clone, Copy, Debug)]
+pub struct FaultStreak {
+	address: usize,
+	count: u32,
+}
+
+impl FaultStreak {
+	pub const fn new() -> Self {
+		Self { address: 0, count: 0 }
+	}
+
+	/// Records a fault at `address` and returns the number of consecutive faults at this address.
+	pub fn record(&mut self, address: usize) -> u32 {
+		if self.count > 0 && self.address == address {
+			self.count = self.count.saturating_add(1);
+		} else {
+			self.address = address;
+			self.count = 1;
+		}
+
+		self.count
+	}
+
+	/// Forgets the faults, e.g. after the last one has been resolved.
+	pub fn reset(&mut self) {
+		self.count = 0;
+	}
+}
+
 /// A task control block, which identifies either a process or a thread
 #[repr(align(64))]
 pub struct Task {
@@ -342,6 +443,10 @@ pub struct Task {
 	pub status: TaskStatus,
 	/// Task priority,
 	pub prio: Priority,
+	/// Priority of the task without a boost by priority inheritance (see `scheduler::lend_priority`)
+	pub base_prio: Priority,
+	/// Priorities lent by the waiters of the semaphores, which the task holds, keyed by the semaphore
+	pub lent_prios: Vec<(usize, Priority)>,
 	/// Last stack pointer before a context switch to another task
 	pub last_stack_pointer: usize,
 	/// Last %rsp value on the kernel stack before a context switch to another task
@@ -362,8 +467,26 @@ pub struct Task {
 	pub wakeup: SpinlockIrqSave<BlockedTaskQueue>,
 	/// Task Thread-Local-Storage (TLS)
 	pub tls: Option<Rc<RefCell<TaskTLS>>>,
+	/// Regions allocated by `scheduler::task_local_alloc`, which aren't shared with clones
+	pub local_regions: Vec<TaskLocalRegion>,
+	/// Protection keys of `local_regions` (bit n = key n), which other tasks can't access
+	pub local_keys: u16,
+	/// Semaphores created by `scheduler::task_semaphore`, which are destroyed together with the task
+	pub semaphores: Vec<Box<Semaphore>>,
 	/// Reason why wakeup() has been called the last time
 	pub last_wakeup_reason: WakeupReason,
+	/// Memory mapped for this task (stacks and heap growth) in bytes
+	pub memory_usage: usize,
+	/// Upper bound of `memory_usage` (RLIMIT_AS), `usize::MAX` if unlimited
+	pub memory_limit: usize,
+	/// Ceiling of `memory_limit` (hard limit of RLIMIT_AS), which can only be lowered
+	pub memory_max: usize,
+	/// Handler of isolation violations (SIGSEGV), the task is aborted if there is none
+	pub fault_handler: Option<extern "C" fn(i32)>,
+	/// Address of the handler of page faults, which receives the fault address and the error code
+	pub page_fault_handler: Option<usize>,
+	/// Consecutive page faults at the same address, see `config::PAGE_FAULT_RETRY_LIMIT`
+	pub fault_streak: FaultStreak,
 	/// lwIP error code for this task
 	#[cfg(feature = "newlib")]
 	pub lwip_errno: i32,
@@ -375,24 +498,63 @@ pub trait TaskFrame {
 }
 
 impl Task {
+	/// Releases the resources owned by the finished task: its task-local regions, its semaphores,
+	/// its reference to the TLS and its stacks.
+	///
+	/// The scheduler calls it once it has switched away from the task, so the stacks aren't in use anymore.
+	/// References to the task, which are still held elsewhere, only keep the task control block alive.
+	pub fn cleanup(&mut self) {
+		debug!("Releasing the resources of task {}", self.id);
+
+		super::release_local_keys(self.local_keys);
+		self.local_keys = 0;
+		self.local_regions.clear();
+		self.semaphores.clear();
+		self.tls = None;
+		self.stacks.release();
+		self.memory_usage = 0;
+	}
+
 	pub fn new(tid: TaskId, core_id: usize, task_status: TaskStatus, task_prio: Priority) -> Task {
+		Self::new_with_stacks(tid, core_id, task_status, task_prio, TaskStacks::new())
+	}
+
+	/// Creates a task like `new`, which runs on the given stacks.
+	pub fn new_with_stacks(
+		tid: TaskId,
+		core_id: usize,
+		task_status: TaskStatus,
+		task_prio: Priority,
+		stacks: TaskStacks,
+	) -> Task {
 		debug!("Creating new task {}", tid);
 
 		Task {
 			id: tid,
 			status: task_status,
 			prio: task_prio,
+			base_prio: task_prio,
 			last_stack_pointer: 0,
 			kernel_stack_pointer: 0,
 			user_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
 			core_id: core_id,
-			stacks: TaskStacks::new(),
+			memory_usage: stacks.size(),
+			stacks: stacks,
 			next: None,
 			prev: None,
 			wakeup: SpinlockIrqSave::new(BlockedTaskQueue::new()),
 			tls: None,
+			local_regions: Vec::new(),
+			local_keys: 0,
+			semaphores: Vec::new(),
+			lent_prios: Vec::new(),
 			last_wakeup_reason: WakeupReason::Custom,
+			memory_limit: usize::MAX,
+			memory_max: usize::MAX,
+			fault_handler: None,
+			page_fault_handler: None,
+			fault_streak: FaultStreak::new(),
 			#[cfg(feature = "newlib")]
 			lwip_errno: 0,
 		}
@@ -405,6 +567,7 @@ impl Task {
 			id: tid,
 			status: TaskStatus::TaskIdle,
 			prio: IDLE_PRIO,
+			base_prio: IDLE_PRIO,
 			last_stack_pointer: 0,
 			kernel_stack_pointer: 0,
 			user_stack_pointer: 0,
@@ -415,7 +578,17 @@ impl Task {
 			prev: None,
 			wakeup: SpinlockIrqSave::new(BlockedTaskQueue::new()),
 			tls: None,
+			local_regions: Vec::new(),
+			local_keys: 0,
+			semaphores: Vec::new(),
+			lent_prios: Vec::new(),
 			last_wakeup_reason: WakeupReason::Custom,
+			memory_usage: 0,
+			memory_limit: usize::MAX,
+			memory_max: usize::MAX,
+			fault_handler: None,
+			page_fault_handler: None,
+			fault_streak: FaultStreak::new(),
 			#[cfg(feature = "newlib")]
 			lwip_errno: 0,
 		}
@@ -423,28 +596,61 @@ impl Task {
 
 	pub fn clone(tid: TaskId, core_id: usize, task: &Task) -> Task {
 		debug!("Cloning task {} from task {}", tid, task.id);
+		let stacks = TaskStacks::new();
 
 		Task {
 			id: tid,
 			status: TaskStatus::TaskReady,
-			prio: task.prio,
+			// a boost of the parent isn't inherited
+			prio: task.base_prio,
+			base_prio: task.base_prio,
 			last_stack_pointer: 0,
 			kernel_stack_pointer: 0,
 			user_stack_pointer: 0,
 			last_fpu_state: arch::processor::FPUState::new(),
 			core_id: core_id,
-			stacks: TaskStacks::new(),
+			memory_usage: stacks.size(),
+			stacks: stacks,
 			next: None,
 			prev: None,
 			wakeup: SpinlockIrqSave::new(BlockedTaskQueue::new()),
 			tls: task.tls.clone(),
+			local_regions: Vec::new(),
+			local_keys: 0,
+			semaphores: Vec::new(),
+			lent_prios: Vec::new(),
 			last_wakeup_reason: task.last_wakeup_reason,
+			// resource limits and the fault handler are inherited
+			memory_limit: task.memory_limit,
+			memory_max: task.memory_max,
+			fault_handler: task.fault_handler,
+			page_fault_handler: task.page_fault_handler,
+			fault_streak: FaultStreak::new(),
 			#[cfg(feature = "newlib")]
 			lwip_errno: 0,
 		}
 	}
 }
 
+impl Task {
+	/// Accounts `size` bytes of newly mapped memory to this task.
+	/// Fails without changing the usage if the limit (RLIMIT_AS) would be exceeded.
+	pub fn charge_memory(&mut self, size: usize) -> Result<(), ()> {
+		match self.memory_usage.checked_add(size) {
+			Some(usage) if usage <= self.memory_limit => {
+				self.memory_usage = usage;
+				Ok(())
+			}
+			_ => Err(()),
+		}
+	}
+
+	/// Releases `size` bytes of memory, which have been accounted to this task.
+	pub fn uncharge_memory(&mut self, size: usize) {
+		self.memory_usage = self.memory_usage.saturating_sub(size);
+	}
+}
+
 struct BlockedTask {
 	task: Rc<RefCell<Task>>,
 	wakeup_time: Option<u64>,
@@ -0,0 +1,1279 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Self tests and micro-benchmarks, which have to run inside of the kernel.
+//!
+//! `initd` runs them before the application is started, the tests with the -selftest
+//! and the benchmarks with the -bench command-line parameter.
+
+use alloc::alloc::Layout;
+use arch;
+use arch::percore::*;
+use config;
+use core::alloc::GlobalAlloc;
+use environment;
+use mm;
+use scheduler;
+use {sys_free, sys_malloc, sys_realloc, ALLOCATOR};
+
+/// Runs the micro-benchmarks and logs their results.
+pub fn run_benchmarks() {
+	bench_allocate_page();
+	bench_allocate_cluster();
+	bench_concurrent_faults();
+}
+
+/// Compares the latency of single-page allocations by `allocate` and `allocate_page`.
+fn bench_allocate_page() {
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+
+	let n = 1000;
+	let mut pages = [0usize; 1000];
+
+	let mut start = arch::processor::get_timestamp();
+	for page in pages.iter_mut() {
+		*page = mm::allocate(BasePageSize::SIZE, true);
+	}
+	let ticks = arch::processor::get_timestamp() - start;
+	for page in pages.iter() {
+		mm::deallocate(*page, BasePageSize::SIZE);
+	}
+	info!("allocate: {} ticks per page", ticks / n);
+
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable();
+	start = arch::processor::get_timestamp();
+	for page in pages.iter_mut() {
+		*page = mm::allocate_page(mm::SAFE_MEM_REGION, flags).0;
+	}
+	let ticks = arch::processor::get_timestamp() - start;
+	for page in pages.iter() {
+		mm::deallocate(*page, BasePageSize::SIZE);
+	}
+	info!("allocate_page: {} ticks per page", ticks / n);
+}
+
+/// Compares the throughput of 16 KiB allocations by `allocate`, which maps them as a cluster,
+/// and by a page-wise mapping of the same frames.
+fn bench_allocate_cluster() {
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+
+	const SIZE: usize = 4 * BasePageSize::SIZE;
+	let n = 1000;
+	let mut blocks = [0usize; 1000];
+
+	let mut start = arch::processor::get_timestamp();
+	for block in blocks.iter_mut() {
+		*block = mm::allocate(SIZE, true);
+	}
+	let ticks = arch::processor::get_timestamp() - start;
+	for block in blocks.iter() {
+		mm::deallocate(*block, SIZE);
+	}
+	info!("allocate (cluster): {} ticks per 16 KiB block", ticks / n);
+
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable().pkey(mm::SAFE_MEM_REGION);
+	start = arch::processor::get_timestamp();
+	for block in blocks.iter_mut() {
+		let physical_address =
+			arch::mm::physicalmem::allocate_aligned(SIZE, BasePageSize::SIZE).unwrap();
+		*block = arch::mm::virtualmem::allocate_aligned(SIZE, BasePageSize::SIZE).unwrap();
+		arch::mm::paging::map::<BasePageSize>(*block, physical_address, SIZE / BasePageSize::SIZE, flags);
+	}
+	let ticks = arch::processor::get_timestamp() - start;
+	for block in blocks.iter() {
+		mm::deallocate(*block, SIZE);
+	}
+	info!("allocate (page-wise): {} ticks per 16 KiB block", ticks / n);
+}
+
+/// Measures the latency of page faults, which map pages on demand, on all cores at the same time.
+/// Every fault allocates a frame, so this shows the contention of the physical memory allocator.
+fn bench_concurrent_faults() {
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+	use core::sync::atomic::{AtomicU64, Ordering};
+
+	const PAGES: usize = 1000;
+	static TICKS: AtomicU64 = AtomicU64::new(0);
+
+	extern "C" fn fault_pages(_arg: usize) {
+		let size = PAGES * BasePageSize::SIZE;
+		let start = mm::reserve_virtual(size, BasePageSize::SIZE);
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		mm::reserve_on_demand(start, flags).unwrap();
+
+		let ticks = arch::processor::get_timestamp();
+		for page in (start..start + size).step_by(BasePageSize::SIZE) {
+			unsafe {
+				core::ptr::write_volatile(page as *mut u8, 1);
+			}
+		}
+		TICKS.fetch_add(arch::processor::get_timestamp() - ticks, Ordering::SeqCst);
+
+		mm::release_virtual(start, size).unwrap();
+	}
+
+	let cores = arch::get_processor_count();
+	TICKS.store(0, Ordering::SeqCst);
+	let mut tasks = [None; 64];
+	for (core_id, task) in tasks.iter_mut().enumerate().take(cores) {
+		*task = Some(scheduler::get_scheduler(core_id).spawn(fault_pages, 0, scheduler::task::NORMAL_PRIO));
+	}
+	for task in tasks.iter().filter_map(|task| *task) {
+		let _ = scheduler::join(task);
+	}
+
+	info!(
+		"concurrent faults on {} cores: {} ticks per fault",
+		cores,
+		TICKS.load(Ordering::SeqCst) / (cores * PAGES) as u64
+	);
+}
+
+fn test_realloc_preserves_pkey() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	let size = BasePageSize::SIZE;
+	let new_size = 4 * BasePageSize::SIZE;
+	let ptr = sys_malloc(size, BasePageSize::SIZE);
+	if ptr.is_null() {
+		return Err(());
+	}
+	let heap_key = mm::region_type(ptr as usize);
+	mm::set_region_key(ptr as usize, size, mm::SHARED_MEM_REGION);
+
+	let new_ptr = sys_realloc(ptr, size, BasePageSize::SIZE, new_size);
+	if new_ptr.is_null() {
+		mm::set_region_key(ptr as usize, size, heap_key.unwrap());
+		return Err(());
+	}
+
+	// every page of the moved block is tagged, but not its neighbour
+	let tagged = (0..new_size / BasePageSize::SIZE).all(|i| {
+		mm::region_type(new_ptr as usize + i * BasePageSize::SIZE) == Some(mm::SHARED_MEM_REGION)
+	});
+	let neighbour = mm::region_type(new_ptr as usize + new_size);
+
+	// return the pages to the domain of the heap
+	mm::set_region_key(ptr as usize, size, heap_key.unwrap());
+	mm::set_region_key(new_ptr as usize, new_size, heap_key.unwrap());
+	sys_free(new_ptr, new_size, BasePageSize::SIZE);
+
+	if tagged && neighbour == heap_key {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_shared_allocate_large() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, LargePageSize, PageSize};
+
+	// two 2 MiB pages and a tail of three 4 KiB pages
+	let size = 2 * LargePageSize::SIZE + 3 * BasePageSize::SIZE;
+	let free_before = arch::mm::physicalmem::free_memory_size();
+
+	for _ in 0..2 {
+		let ptr = mm::try_shared_allocate_large(size, true).map_err(|_| ())?;
+		match arch::mm::paging::get_leaf_entry(ptr + LargePageSize::SIZE) {
+			Some((entry, LargePageSize::SIZE)) if entry.pkey() == mm::SHARED_MEM_REGION => {}
+			_ => return Err(()),
+		}
+		match arch::mm::paging::get_leaf_entry(ptr + 2 * LargePageSize::SIZE) {
+			Some((entry, BasePageSize::SIZE)) if entry.pkey() == mm::SHARED_MEM_REGION => {}
+			_ => return Err(()),
+		}
+
+		// the second round must not observe the data of the first one
+		let probes = [ptr + LargePageSize::SIZE, ptr + 2 * LargePageSize::SIZE];
+		for &probe in probes.iter() {
+			let data = unsafe { core::ptr::read_volatile(probe as *const u8) };
+			if data != 0 {
+				mm::deallocate(ptr, size);
+				return Err(());
+			}
+			unsafe {
+				core::ptr::write_volatile(probe as *mut u8, 0xAA);
+			}
+		}
+
+		mm::deallocate(ptr, size);
+	}
+
+	// the round trips must not leak frames
+	if arch::mm::physicalmem::free_memory_size() != free_before {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+fn test_try_allocate() -> Result<(), ()> {
+	let free_before = arch::mm::physicalmem::free_memory_size();
+
+	// an impossible request fails without leaking the memory of the other allocator
+	let size = 2 * arch::mm::physicalmem::total_memory_size();
+	if mm::try_allocate(size, true) != Err(mm::AllocError::OutOfPhysicalMemory) {
+		return Err(());
+	}
+	if mm::try_user_allocate(4096, false) != Err(mm::AllocError::WritableExecutable) {
+		return Err(());
+	}
+	if arch::mm::physicalmem::free_memory_size() != free_before {
+		return Err(());
+	}
+
+	let ptr = mm::try_shared_allocate(4096, true).map_err(|_| ())?;
+	mm::deallocate(ptr, 4096);
+
+	Ok(())
+}
+
+fn test_unsafe_heap() -> Result<(), ()> {
+	let layout = Layout::from_size_align(64, 8).unwrap();
+	let (bottom, top) = mm::unsafe_heap_range();
+
+	let unsafe_ptr = mm::unsafe_heap_allocate(layout) as usize;
+	let kernel_ptr = unsafe { ALLOCATOR.alloc(layout) } as usize;
+	let disjoint = unsafe_ptr != 0
+		&& kernel_ptr != 0
+		&& bottom <= unsafe_ptr
+		&& unsafe_ptr + layout.size() <= top
+		&& (kernel_ptr + layout.size() <= bottom || top <= kernel_ptr)
+		&& mm::region_type(unsafe_ptr) == Some(mm::UNSAFE_MEM_REGION);
+
+	mm::unsafe_heap_deallocate(unsafe_ptr as *mut u8, layout);
+	if kernel_ptr != 0 {
+		unsafe {
+			ALLOCATOR.dealloc(kernel_ptr as *mut u8, layout);
+		}
+	}
+
+	// the global allocator serves the unsafe domain from its own heap
+	let isolated_ptr = unsafe {
+		isolation_start!();
+		let ptr = ALLOCATOR.alloc(layout);
+		isolation_end!();
+		ptr
+	};
+	let routed = !environment::mpk_enabled() || mm::is_unsafe_heap(isolated_ptr);
+	// memory of the unsafe heap may be released outside of the unsafe domain
+	unsafe {
+		ALLOCATOR.dealloc(isolated_ptr, layout);
+	}
+
+	if disjoint && routed {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_watch_region() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageTableEntryFlags};
+
+	let size = 2 * 4096;
+	let buffer = mm::allocate(size, true);
+	let writable = |address: usize| {
+		arch::mm::paging::get_existing_flags::<BasePageSize>(address) & PageTableEntryFlags::WRITABLE.bits() != 0
+	};
+
+	arch::mm::paging::watch_region(buffer, size)?;
+	if writable(buffer) || writable(buffer + 4096) {
+		return Err(());
+	}
+
+	// the first write restores the write access of the written page only
+	unsafe {
+		*(buffer as *mut u8) = 0xAA;
+	}
+	let result = if writable(buffer) && !writable(buffer + 4096) && unsafe { *(buffer as *const u8) } == 0xAA {
+		Ok(())
+	} else {
+		Err(())
+	};
+
+	unsafe {
+		*((buffer + 4096) as *mut u8) = 0xBB;
+	}
+	mm::deallocate(buffer, size);
+
+	result
+}
+
+fn test_reclaim_user_heap() -> Result<(), ()> {
+	use arch::mm::paging::{LargePageSize, PageSize};
+
+	// at least one large page of the block is free of other allocations
+	let layout = Layout::from_size_align(2 * LargePageSize::SIZE, LargePageSize::SIZE).unwrap();
+	let block = unsafe { ALLOCATOR.alloc(layout) } as usize;
+	if block == 0 {
+		return Err(());
+	}
+	unsafe {
+		core::ptr::write_bytes(block as *mut u8, 0, layout.size());
+	}
+
+	// the first pass only clears the ACCESSED flags
+	mm::reclaim_user_heap();
+	let reclaimed = mm::reclaim_user_heap();
+	let unmapped = mm::region_type(block).is_none();
+
+	// a reclaimed page is mapped again as a zeroed page
+	let zero = unsafe { *(block as *const u64) } == 0;
+	unsafe {
+		*(block as *mut u64) = 42;
+	}
+	let writable = unsafe { *(block as *const u64) } == 42;
+	unsafe {
+		ALLOCATOR.dealloc(block as *mut u8, layout);
+	}
+
+	if reclaimed >= LargePageSize::SIZE && unmapped && zero && writable {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_task_local_alloc() -> Result<(), ()> {
+	use core::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+	static KEY: AtomicUsize = AtomicUsize::new(0);
+	static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+	static RELEASE: AtomicBool = AtomicBool::new(false);
+	static HIDDEN: AtomicBool = AtomicBool::new(false);
+
+	extern "C" fn allocate_local(_arg: usize) {
+		let key = KEY.load(Ordering::SeqCst) as u8;
+		let address = scheduler::task_local_alloc(4096, key);
+		if address != 0 && mm::region_type(address) == Some(key) {
+			ADDRESS.store(address, Ordering::SeqCst);
+		}
+		while !RELEASE.load(Ordering::SeqCst) {
+			core_scheduler().reschedule();
+		}
+	}
+
+	extern "C" fn probe_local(_arg: usize) {
+		let key = KEY.load(Ordering::SeqCst) as u8;
+		// the key is bound to the other task and closed in our PKRU
+		let pkru = arch::mm::mpk::mpk_get_pkru();
+		let closed = !environment::mpk_enabled() || !arch::mm::mpk::would_allow(pkru, key, false);
+		HIDDEN.store(closed && scheduler::task_local_alloc(4096, key) == 0, Ordering::SeqCst);
+	}
+
+	let key = arch::mm::mpk::mpk_pkey_alloc();
+	if key < 0 {
+		// no free protection key
+		return Ok(());
+	}
+	KEY.store(key as usize, Ordering::SeqCst);
+	ADDRESS.store(0, Ordering::SeqCst);
+	RELEASE.store(false, Ordering::SeqCst);
+	HIDDEN.store(false, Ordering::SeqCst);
+
+	let id = core_scheduler().spawn(allocate_local, 0, scheduler::task::NORMAL_PRIO);
+	let mut hidden = false;
+	for _ in 0..1000 {
+		if ADDRESS.load(Ordering::SeqCst) != 0 {
+			let prober = core_scheduler().spawn(probe_local, 0, scheduler::task::NORMAL_PRIO);
+			hidden = scheduler::join(prober).is_ok() && HIDDEN.load(Ordering::SeqCst);
+			break;
+		}
+		core_scheduler().reschedule();
+	}
+	RELEASE.store(true, Ordering::SeqCst);
+	let joined = scheduler::join(id);
+	// the finished task is torn down by the next pass of the scheduler
+	core_scheduler().reschedule();
+
+	let address = ADDRESS.load(Ordering::SeqCst);
+	let released = address != 0 && mm::region_type(address).is_none();
+	arch::mm::mpk::mpk_pkey_free(key as u8);
+
+	if joined.is_ok() && hidden && released {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_shared_zero_on_free() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+
+	let ptr = mm::shared_allocate(BasePageSize::SIZE, true);
+	unsafe {
+		core::ptr::write_bytes(ptr as *mut u8, 0xAA, BasePageSize::SIZE);
+	}
+	let physical_address = arch::mm::paging::virtual_to_physical(ptr);
+	mm::deallocate(ptr, BasePageSize::SIZE);
+
+	// inspect the released frame through a temporary mapping
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().execute_disable().pkey(mm::SAFE_MEM_REGION);
+	let view = arch::mm::virtualmem::allocate(BasePageSize::SIZE).map_err(|_| ())?;
+	arch::mm::paging::map_page::<BasePageSize>(view, physical_address, flags);
+	let zeroed = unsafe { core::slice::from_raw_parts(view as *const u8, BasePageSize::SIZE) }
+		.iter()
+		.all(|byte| *byte == 0);
+	arch::mm::paging::unmap::<BasePageSize>(view, 1);
+	arch::mm::virtualmem::deallocate(view, BasePageSize::SIZE);
+
+	if zeroed {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_global_page_rekey() -> Result<(), ()> {
+	use arch::kernel::signal;
+	use arch::mm::mpk::{self, MpkPerm};
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+	use core::sync::atomic::{AtomicUsize, Ordering};
+
+	static KEY: AtomicUsize = AtomicUsize::new(0);
+	static ADDRESS: AtomicUsize = AtomicUsize::new(0);
+	static STAGE: AtomicUsize = AtomicUsize::new(0);
+	static FAULTED: AtomicUsize = AtomicUsize::new(0);
+
+	extern "C" fn access_page(_arg: usize) {
+		let address = ADDRESS.load(Ordering::SeqCst);
+		// the global translation is cached on this core with the old key
+		if signal::probe_read(address) != 0 {
+			STAGE.store(3, Ordering::SeqCst);
+			return;
+		}
+		STAGE.store(1, Ordering::SeqCst);
+		while STAGE.load(Ordering::SeqCst) != 2 {
+			core_scheduler().reschedule();
+		}
+
+		// the stale TLB entry would still carry the previous key
+		let pkru = mpk::mpk_get_pkru();
+		mpk::mpk_set_perm(KEY.load(Ordering::SeqCst) as u8, MpkPerm::MpkNone);
+		signal::expect_fault(address);
+		let faulted = signal::probe_read(address) == 1 && !signal::disarm_fault();
+		mpk::mpk_set_pkru(pkru);
+		FAULTED.store(faulted as usize, Ordering::SeqCst);
+		STAGE.store(3, Ordering::SeqCst);
+	}
+
+	// a stale translation can only survive on another core
+	if !environment::mpk_enabled() || arch::get_processor_count() < 2 {
+		return Ok(());
+	}
+
+	let key = mpk::mpk_pkey_alloc();
+	if key < 0 {
+		// no free protection key
+		return Ok(());
+	}
+
+	let physical_address = arch::mm::physicalmem::allocate(BasePageSize::SIZE).map_err(|_| ())?;
+	let address = arch::mm::virtualmem::allocate(BasePageSize::SIZE).map_err(|_| ())?;
+	let mut flags = PageTableEntryFlags::empty();
+	flags
+		.normal()
+		.writable()
+		.execute_disable()
+		.global()
+		.pkey(mm::SAFE_MEM_REGION);
+	arch::mm::paging::map_page::<BasePageSize>(address, physical_address, flags);
+
+	KEY.store(key as usize, Ordering::SeqCst);
+	ADDRESS.store(address, Ordering::SeqCst);
+	STAGE.store(0, Ordering::SeqCst);
+	FAULTED.store(0, Ordering::SeqCst);
+
+	// the reader runs on another core, which caches the global page before it is re-keyed
+	let remote_core = if core_id() == 0 { 1 } else { 0 };
+	let id = scheduler::get_scheduler(remote_core).spawn(access_page, 0, scheduler::task::NORMAL_PRIO);
+	while STAGE.load(Ordering::SeqCst) == 0 {
+		core_scheduler().reschedule();
+	}
+	if STAGE.load(Ordering::SeqCst) == 1 {
+		mm::set_region_key(address, BasePageSize::SIZE, key as u8);
+		STAGE.store(2, Ordering::SeqCst);
+	}
+	let joined = scheduler::join(id);
+
+	arch::mm::paging::unmap::<BasePageSize>(address, 1);
+	arch::mm::virtualmem::deallocate(address, BasePageSize::SIZE);
+	arch::mm::physicalmem::deallocate(physical_address, BasePageSize::SIZE);
+	mpk::mpk_pkey_free(key as u8);
+
+	if joined.is_ok() && FAULTED.load(Ordering::SeqCst) == 1 {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_spawn_with_stack() -> Result<(), ()> {
+	use config::{DEFAULT_STACK_SIZE, KERNEL_STACK_SIZE};
+	use core::sync::atomic::{AtomicUsize, Ordering};
+
+	const FRAME_SIZE: usize = 1024;
+	const DEPTH: usize = 2 * DEFAULT_STACK_SIZE / FRAME_SIZE;
+	static REACHED: AtomicUsize = AtomicUsize::new(0);
+
+	#[inline(never)]
+	fn recurse(depth: usize) -> usize {
+		let frame = [depth as u8; FRAME_SIZE];
+		let byte = unsafe { core::ptr::read_volatile(&frame[depth % FRAME_SIZE]) } as usize;
+		if depth == 0 {
+			byte
+		} else {
+			recurse(depth - 1) + byte
+		}
+	}
+
+	extern "C" fn deep_recursion(_arg: usize) {
+		recurse(DEPTH);
+		REACHED.store(DEPTH, Ordering::SeqCst);
+	}
+
+	if scheduler::spawn_with_stack(deep_recursion, 0, scheduler::task::NORMAL_PRIO, KERNEL_STACK_SIZE - 1).is_ok() {
+		return Err(());
+	}
+
+	REACHED.store(0, Ordering::SeqCst);
+	let id = scheduler::spawn_with_stack(
+		deep_recursion,
+		0,
+		scheduler::task::NORMAL_PRIO,
+		4 * DEFAULT_STACK_SIZE,
+	)?;
+	let joined = scheduler::join(id);
+	// the finished task is torn down by the next pass of the scheduler
+	core_scheduler().reschedule();
+
+	if joined.is_ok() && REACHED.load(Ordering::SeqCst) == DEPTH {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_map_existing() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	let ptr = mm::allocate(BasePageSize::SIZE, true);
+	unsafe {
+		core::ptr::write_bytes(ptr as *mut u8, 0x5A, BasePageSize::SIZE);
+	}
+
+	let physical_address = arch::mm::paging::virtual_to_physical(ptr);
+	let alias = mm::map_existing(physical_address, BasePageSize::SIZE, mm::SHARED_MEM_REGION, true);
+	if alias == 0 {
+		mm::deallocate(ptr, BasePageSize::SIZE);
+		return Err(());
+	}
+
+	// both addresses refer to the same frame
+	let shared = unsafe {
+		let seen = core::ptr::read_volatile(alias as *const u8) == 0x5A;
+		core::ptr::write_volatile(alias as *mut u8, 0xA5);
+		seen && core::ptr::read_volatile(ptr as *const u8) == 0xA5
+	};
+
+	// releasing the alias keeps the frame and its content
+	mm::deallocate(alias, BasePageSize::SIZE);
+	let preserved = arch::mm::paging::virtual_to_physical(ptr) == physical_address
+		&& unsafe { core::slice::from_raw_parts((ptr + 1) as *const u8, BasePageSize::SIZE - 1) }
+			.iter()
+			.all(|byte| *byte == 0x5A);
+	mm::deallocate(ptr, BasePageSize::SIZE);
+
+	if shared && preserved {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_task_cleanup() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize};
+	use core::sync::atomic::{AtomicUsize, Ordering};
+
+	const TASKS: usize = 32;
+	static KEY: AtomicUsize = AtomicUsize::new(0);
+
+	extern "C" fn short_task(_arg: usize) {
+		let key = KEY.load(Ordering::SeqCst);
+		if key != 0 {
+			scheduler::task_local_alloc(4096, key as u8);
+		}
+		let semaphore = scheduler::task_semaphore(1);
+		unsafe {
+			(*semaphore).acquire(None);
+		}
+	}
+
+	// A page tagged with the key keeps it allocated while the tasks release their pages.
+	// The key is reclaimed together with this page at the end.
+	let key = arch::mm::mpk::mpk_pkey_alloc();
+	let anchor = if key > 0 {
+		let anchor = mm::try_key_allocate(BasePageSize::SIZE, key as u8).ok();
+		if anchor.is_none() {
+			arch::mm::mpk::mpk_pkey_free(key as u8);
+		}
+		anchor
+	} else {
+		None
+	};
+	KEY.store(if anchor.is_some() { key as usize } else { 0 }, Ordering::SeqCst);
+
+	// page tables, which have been allocated for new address ranges, are kept
+	let free_before = arch::mm::physicalmem::free_memory_size();
+	let page_tables_before = arch::mm::paging::page_table_memory();
+
+	let mut joined = true;
+	for _ in 0..TASKS {
+		let id = core_scheduler().spawn(short_task, 0, scheduler::task::NORMAL_PRIO);
+		if scheduler::join(id).is_err() {
+			joined = false;
+			break;
+		}
+	}
+
+	let page_tables = arch::mm::paging::page_table_memory() - page_tables_before;
+	let free_after = arch::mm::physicalmem::free_memory_size() + page_tables;
+	if let Some(anchor) = anchor {
+		mm::deallocate(anchor, BasePageSize::SIZE);
+	}
+
+	info!("free memory before {:#X}, after {:#X}", free_before, free_after);
+	if joined && free_after >= free_before {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_pkru_audit() -> Result<(), ()> {
+	// the page tables are closed outside the paging code on every core
+	if !arch::mm::mpk::audit_all_cores().is_empty() {
+		return Err(());
+	}
+
+	if !arch::mm::paging::page_tables_closed() {
+		// the page tables aren't sealed, so opening their key isn't detected
+		return Ok(());
+	}
+
+	// a context, which opens the key on its own, is reported
+	let pkru = arch::mm::mpk::mpk_get_pkru();
+	arch::mm::mpk::mpk_set_perm(mm::PAGE_TABLE_MEM_REGION, arch::mm::mpk::MpkPerm::MpkRw);
+	let detected = arch::mm::mpk::verify_pkru() == Err(mm::PAGE_TABLE_MEM_REGION);
+	arch::mm::mpk::mpk_set_pkru(pkru);
+
+	// user code, which grants itself access to the safe domain, is reported as well
+	let user_detected = arch::mm::mpk::check_pkru(arch::mm::mpk::USER_PKRU & !0xC, true) == Err(mm::SAFE_MEM_REGION);
+
+	if detected && user_detected {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_seal_page_tables() -> Result<(), ()> {
+	use arch::kernel::signal;
+	use arch::mm::paging::{self, BasePageSize, PageSize};
+
+	if !environment::mpk_enabled() {
+		return Ok(());
+	}
+
+	// the kernel has sealed the page tables during boot
+	if !paging::page_tables_closed() {
+		return Err(());
+	}
+
+	// the root table isn't accessible through the self-reference outside of the paging code
+	let root = 0xFFFF_FFFF_FFFF_F000usize;
+	signal::expect_fault(root);
+	let faulted = signal::probe_read(root) == 1 && !signal::disarm_fault();
+
+	// the paging code still maps and unmaps pages
+	let page = mm::allocate(BasePageSize::SIZE, true);
+	let mapped = signal::probe_write(page) == 0;
+	mm::deallocate(page, BasePageSize::SIZE);
+
+	if faulted && mapped && paging::page_tables_closed() {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_safe_data_guard() -> Result<(), ()> {
+	use arch::kernel::signal;
+
+	// the last page of the .safe_data section is the guard, the page below it is still mapped
+	let guard = 0x5FF000usize;
+	let below = guard - 0x1000;
+
+	signal::expect_fault(guard);
+	let faulted = signal::probe_read(guard) == 1 && !signal::disarm_fault();
+	let mapped = signal::probe_read(below) == 0;
+
+	if faulted && mapped {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_deallocate_iomem() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	const ROUNDS: usize = 256;
+	const SIZE: usize = 1024 * 1024;
+
+	// page tables, which have been allocated for new address ranges, are kept
+	let free_before = arch::mm::physicalmem::free_memory_size();
+	let page_tables_before = arch::mm::paging::page_table_memory();
+
+	for _ in 0..ROUNDS {
+		let address = mm::allocate_iomem(SIZE, mm::CachePolicy::Uncached);
+		mm::deallocate_iomem(address, SIZE);
+
+		if arch::mm::paging::get_page_table_entry::<BasePageSize>(address).is_some() {
+			return Err(());
+		}
+	}
+
+	let page_tables = arch::mm::paging::page_table_memory() - page_tables_before;
+	if arch::mm::physicalmem::free_memory_size() + page_tables >= free_before {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_write_combining_iomem() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+
+	// An odd number of pages, so that the physical memory is unlikely to be aligned to 2 MiB
+	let size = 3 * BasePageSize::SIZE;
+	let address = mm::allocate_iomem(size, mm::CachePolicy::WriteCombining);
+
+	let mapped = (0..size).step_by(BasePageSize::SIZE).all(|offset| {
+		match arch::mm::paging::get_leaf_entry(address + offset) {
+			// Without a Page Attribute Table, the memory is mapped uncached.
+			Some((entry, page_size)) => {
+				page_size == BasePageSize::SIZE
+					&& (entry.get_flags() & PageTableEntryFlags::HUGE_PAGE.bits() != 0)
+						== arch::processor::supports_pat()
+			}
+			None => false,
+		}
+	});
+
+	unsafe {
+		core::ptr::write_volatile((address + size - 8) as *mut u64, 0xdead_beef);
+	}
+	let written = unsafe { core::ptr::read_volatile((address + size - 8) as *const u64) } == 0xdead_beef;
+	mm::deallocate_iomem(address, size);
+
+	if mapped && written {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_deferred_flush() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+
+	const PAGES: usize = 8;
+	let first = mm::allocate(PAGES * BasePageSize::SIZE, true);
+	let second = mm::allocate(PAGES * BasePageSize::SIZE, true);
+	unsafe {
+		core::ptr::write_bytes(first as *mut u8, 0x11, PAGES * BasePageSize::SIZE);
+		core::ptr::write_bytes(second as *mut u8, 0x22, PAGES * BasePageSize::SIZE);
+	}
+	let first_frames = arch::mm::paging::virtual_to_physical(first);
+	let second_frames = arch::mm::paging::virtual_to_physical(second);
+
+	// remap the first range to the frames of the second one, page by page
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable().pkey(mm::SAFE_MEM_REGION);
+	arch::mm::paging::with_deferred_flush(|| {
+		for i in 0..PAGES {
+			arch::mm::paging::with_deferred_flush(|| {
+				arch::mm::paging::map::<BasePageSize>(
+					first + i * BasePageSize::SIZE,
+					second_frames + i * BasePageSize::SIZE,
+					1,
+					flags,
+				);
+			});
+		}
+	});
+
+	let remapped = (0..PAGES).all(|i| unsafe {
+		core::ptr::read_volatile((first + i * BasePageSize::SIZE) as *const u8) == 0x22
+	});
+
+	// restore the original frames before the ranges are released
+	arch::mm::paging::with_deferred_flush(|| {
+		arch::mm::paging::map::<BasePageSize>(first, first_frames, PAGES, flags);
+	});
+	mm::deallocate(first, PAGES * BasePageSize::SIZE);
+	mm::deallocate(second, PAGES * BasePageSize::SIZE);
+
+	if remapped {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_sample_access_by_key() -> Result<(), ()> {
+	use arch::mm::mpk::{self, MpkPerm};
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	if !environment::mpk_enabled() {
+		return Ok(());
+	}
+
+	// Pages of the static keys are accessed all the time, so only a fresh key shows the write.
+	let key = mpk::mpk_pkey_alloc();
+	if key < 0 {
+		// no free protection key
+		return Ok(());
+	}
+	let key = key as u8;
+	mpk::mpk_set_perm(key, MpkPerm::MpkRw);
+
+	let page = mm::allocate(BasePageSize::SIZE, true);
+	mm::set_region_key(page, BasePageSize::SIZE, key);
+
+	// start a fresh interval, which doesn't touch the page, then touch it
+	let _ = mm::sample_access_by_key();
+	let idle = mm::sample_access_by_key();
+	unsafe {
+		core::ptr::write_volatile(page as *mut u8, 1);
+	}
+	let accessed = mm::sample_access_by_key();
+
+	mm::set_region_key(page, BasePageSize::SIZE, mm::SAFE_MEM_REGION);
+	mm::deallocate(page, BasePageSize::SIZE);
+	mpk::mpk_pkey_free(key);
+
+	if idle[usize::from(key)] == 0 && accessed[usize::from(key)] > 0 {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_user_heap_guard() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, LargePageSize, PageSize};
+
+	let start = mm::user_heap_start();
+	let guard_start = start - align_up!(config::USER_HEAP_GUARD_SIZE, LargePageSize::SIZE);
+	let unmapped = (guard_start..start)
+		.step_by(BasePageSize::SIZE)
+		.all(|page| arch::mm::paging::get_leaf_entry(page).is_none());
+
+	if unmapped && arch::mm::paging::get_leaf_entry(start).is_some() {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_promote_large_page() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, LargePageSize, PageSize, PageTableEntryFlags};
+
+	let physical_address =
+		arch::mm::physicalmem::allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE).unwrap();
+	let virtual_address =
+		arch::mm::virtualmem::allocate_aligned(LargePageSize::SIZE, LargePageSize::SIZE).unwrap();
+	let count = LargePageSize::SIZE / BasePageSize::SIZE;
+	let mut flags = PageTableEntryFlags::empty();
+	flags.normal().writable().execute_disable().pkey(mm::SAFE_MEM_REGION);
+
+	// a page with other flags prevents the promotion
+	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, count, flags);
+	let mut read_only = PageTableEntryFlags::empty();
+	read_only.normal().execute_disable().pkey(mm::SAFE_MEM_REGION);
+	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, 1, read_only);
+	let refused = !arch::mm::paging::try_promote(virtual_address, LargePageSize::SIZE);
+
+	// identical pages are promoted and keep their contents
+	arch::mm::paging::map::<BasePageSize>(virtual_address, physical_address, 1, flags);
+	for i in 0..count {
+		unsafe {
+			core::ptr::write_volatile((virtual_address + i * BasePageSize::SIZE) as *mut usize, i);
+		}
+	}
+	// the page table, which has been allocated by the mapping, is released
+	let tables = arch::mm::paging::page_table_memory();
+	let promoted = arch::mm::paging::try_promote(virtual_address, LargePageSize::SIZE)
+		&& arch::mm::paging::page_table_memory() == tables - BasePageSize::SIZE
+		&& arch::mm::paging::get_leaf_entry(virtual_address).map(|(_, size)| size) == Some(LargePageSize::SIZE)
+		&& (0..count).all(|i| unsafe {
+			core::ptr::read_volatile((virtual_address + i * BasePageSize::SIZE) as *const usize) == i
+		});
+
+	arch::mm::paging::unmap::<LargePageSize>(virtual_address, 1);
+	arch::mm::virtualmem::deallocate(virtual_address, LargePageSize::SIZE);
+	arch::mm::physicalmem::deallocate(physical_address, LargePageSize::SIZE);
+
+	if refused && promoted {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_privatize_shared() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	let region = mm::shared_allocate(BasePageSize::SIZE, true);
+	unsafe {
+		core::ptr::write_volatile(region as *mut u8, 0x5A);
+	}
+
+	// a second mapping of the frames keeps the region shared
+	let physical_address = arch::mm::paging::virtual_to_physical(region);
+	let alias = mm::map_existing(physical_address, BasePageSize::SIZE, mm::SHARED_MEM_REGION, true);
+	let refused = mm::privatize_shared(region, BasePageSize::SIZE, true).is_err();
+	mm::deallocate(alias, BasePageSize::SIZE);
+
+	let privatized = mm::privatize_shared(region, BasePageSize::SIZE, true).is_ok()
+		&& mm::region_type(region) == Some(mm::SAFE_MEM_REGION)
+		&& unsafe { core::ptr::read_volatile(region as *const u8) } == 0;
+	mm::deallocate(region, BasePageSize::SIZE);
+
+	if refused && privatized {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_scratch_arena() -> Result<(), ()> {
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	let mut arena = mm::Arena::new(BasePageSize::SIZE, mm::UNSAFE_MEM_REGION).map_err(|_| ())?;
+
+	// fill the arena with small objects until it is exhausted
+	let first = arena.alloc(24, 8);
+	let mut count = 1;
+	while !arena.alloc(24, 8).is_null() {
+		count += 1;
+	}
+	let exhausted = count == BasePageSize::SIZE / 24 && arena.used() <= BasePageSize::SIZE;
+	let keyed = mm::region_type(first as usize) == Some(mm::UNSAFE_MEM_REGION);
+
+	// the next request reuses the same memory
+	arena.reset();
+	let reused = arena.alloc(24, 8) == first && arena.used() == 24;
+
+	if exhausted && keyed && reused {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_rekey_flush() -> Result<(), ()> {
+	use arch::kernel::signal;
+	use arch::mm::mpk::{self, MpkPerm};
+	use arch::mm::paging::{self, BasePageSize, PageSize};
+	use core::sync::atomic::{AtomicUsize, Ordering};
+
+	static KEY: AtomicUsize = AtomicUsize::new(0);
+	static PAGE: AtomicUsize = AtomicUsize::new(0);
+	static STAGE: AtomicUsize = AtomicUsize::new(0);
+	static REMOTE_FAULTED: AtomicUsize = AtomicUsize::new(0);
+
+	extern "C" fn read_remote(_arg: usize) {
+		let page = PAGE.load(Ordering::SeqCst);
+		// the translation of the page is cached on this core with the old key
+		if signal::probe_read(page) != 0 {
+			STAGE.store(3, Ordering::SeqCst);
+			return;
+		}
+		STAGE.store(1, Ordering::SeqCst);
+		while STAGE.load(Ordering::SeqCst) != 2 {
+			core_scheduler().reschedule();
+		}
+
+		// the key, which the other core has set, applies here as well
+		let key = KEY.load(Ordering::SeqCst) as u8;
+		let pkru = mpk::mpk_get_pkru();
+		mpk::mpk_set_perm(key, MpkPerm::MpkNone);
+		signal::expect_fault(page);
+		let faulted = signal::probe_read(page) == 1 && !signal::disarm_fault();
+		mpk::mpk_set_pkru(pkru);
+		REMOTE_FAULTED.store(faulted as usize, Ordering::SeqCst);
+		STAGE.store(3, Ordering::SeqCst);
+	}
+
+	if !environment::mpk_enabled() {
+		return Ok(());
+	}
+
+	let key = mpk::mpk_pkey_alloc();
+	if key < 0 {
+		return Err(());
+	}
+	let key = key as u8;
+
+	// changing the permission of a key doesn't touch the TLB of this core
+	let flushes = paging::local_flush_count();
+	mpk::mpk_set_perm(key, MpkPerm::MpkNone);
+	mpk::mpk_set_perm(key, MpkPerm::MpkRw);
+	let no_flush = paging::local_flush_count() == flushes;
+
+	// the translation of the page is cached with the old key
+	let page = mm::allocate(BasePageSize::SIZE, true);
+	let cached = signal::probe_read(page) == 0;
+
+	// the new key applies without a flush by the caller
+	mpk::mpk_mem_set_key::<BasePageSize>(page, BasePageSize::SIZE, key);
+	mpk::mpk_set_perm(key, MpkPerm::MpkNone);
+	signal::expect_fault(page);
+	let faulted = signal::probe_read(page) == 1 && !signal::disarm_fault();
+	mpk::mpk_set_perm(key, MpkPerm::MpkRw);
+	mpk::mpk_mem_set_key::<BasePageSize>(page, BasePageSize::SIZE, mm::SAFE_MEM_REGION);
+
+	// another core, which has cached the translation, observes the new key as well
+	let remote_faulted = if arch::get_processor_count() > 1 {
+		KEY.store(usize::from(key), Ordering::SeqCst);
+		PAGE.store(page, Ordering::SeqCst);
+		STAGE.store(0, Ordering::SeqCst);
+		REMOTE_FAULTED.store(0, Ordering::SeqCst);
+
+		let remote_core = if core_id() == 0 { 1 } else { 0 };
+		let id = scheduler::get_scheduler(remote_core).spawn(read_remote, 0, scheduler::task::NORMAL_PRIO);
+		while STAGE.load(Ordering::SeqCst) == 0 {
+			core_scheduler().reschedule();
+		}
+		if STAGE.load(Ordering::SeqCst) == 1 {
+			mpk::mpk_mem_set_key::<BasePageSize>(page, BasePageSize::SIZE, key);
+			STAGE.store(2, Ordering::SeqCst);
+		}
+		let joined = scheduler::join(id).is_ok();
+		mpk::mpk_mem_set_key::<BasePageSize>(page, BasePageSize::SIZE, mm::SAFE_MEM_REGION);
+		joined && REMOTE_FAULTED.load(Ordering::SeqCst) == 1
+	} else {
+		true
+	};
+
+	mm::deallocate(page, BasePageSize::SIZE);
+	mpk::mpk_pkey_free(key);
+
+	if no_flush && cached && faulted && remote_faulted {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+/// Tests, which have to run inside of the kernel. They are run by `initd` with the -selftest command-line parameter.
+const KERNEL_TESTS: &[(&str, fn() -> Result<(), ()>)] = &[
+	("test_freeze", test_freeze),
+	("test_write_combining_iomem", test_write_combining_iomem),
+	("test_key_usage", test_key_usage),
+	("test_task_local_alloc", test_task_local_alloc),
+	("test_reclaim_user_heap", test_reclaim_user_heap),
+	("test_promote_large_page", test_promote_large_page),
+	("test_deferred_flush", test_deferred_flush),
+	("test_pkru_audit", test_pkru_audit),
+	("test_seal_page_tables", test_seal_page_tables),
+	("test_rekey_flush", test_rekey_flush),
+	("test_unsafe_heap", test_unsafe_heap),
+	("test_realloc_preserves_pkey", test_realloc_preserves_pkey),
+	("test_safe_data_guard", test_safe_data_guard),
+	("test_global_page_rekey", test_global_page_rekey),
+	("test_sample_access_by_key", test_sample_access_by_key),
+	("test_shared_allocate_large", test_shared_allocate_large),
+	("test_try_allocate", test_try_allocate),
+	("test_watch_region", test_watch_region),
+	("test_shared_zero_on_free", test_shared_zero_on_free),
+	("test_spawn_with_stack", test_spawn_with_stack),
+	("test_map_existing", test_map_existing),
+	("test_task_cleanup", test_task_cleanup),
+	("test_deallocate_iomem", test_deallocate_iomem),
+	("test_user_heap_guard", test_user_heap_guard),
+	("test_privatize_shared", test_privatize_shared),
+	("test_scratch_arena", test_scratch_arena),
+	("test_priority_inheritance", test_priority_inheritance),
+	("test_lent_priorities", test_lent_priorities),
+];
+
+/// Runs the tests of `KERNEL_TESTS` and logs their results.
+pub fn run_kernel_tests() {
+	let failed = KERNEL_TESTS
+		.iter()
+		.filter(|(name, test)| {
+			let result = test();
+			info!("{}: {:?}", name, result);
+			result.is_err()
+		})
+		.count();
+
+	info!("{} of {} kernel tests failed", failed, KERNEL_TESTS.len());
+}
+
+fn test_freeze() -> Result<(), ()> {
+	use arch::kernel::signal;
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	/// Page of the kernel image, which is never deallocated. Hence, freezing it doesn't leak memory.
+	#[repr(C, align(4096))]
+	struct Page([u8; 4096]);
+	static mut FROZEN_PAGE: Page = Page([0; 4096]);
+
+	let page = unsafe { &FROZEN_PAGE as *const Page as usize };
+	unsafe {
+		core::ptr::write_volatile(page as *mut u8, 0x5A);
+	}
+	if mm::freeze(page, BasePageSize::SIZE).is_err() {
+		return Err(());
+	}
+
+	signal::expect_fault(page);
+	let faulted = signal::probe_write(page) == 1 && !signal::disarm_fault();
+	let refused = mm::protect(page, BasePageSize::SIZE, true) == Err(mm::ProtectError::Frozen);
+	let preserved = unsafe { core::ptr::read_volatile(page as *const u8) } == 0x5A;
+
+	if faulted && refused && preserved {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_key_usage() -> Result<(), ()> {
+	use arch::mm::mpk;
+	use arch::mm::paging::{BasePageSize, PageSize};
+
+	let key = mpk::mpk_pkey_alloc();
+	if key < 0 {
+		// no free protection key
+		return Ok(());
+	}
+	let key = key as u8;
+
+	let size = 2 * BasePageSize::SIZE;
+	let address = match mm::try_key_allocate(size, key) {
+		Ok(address) => address,
+		Err(_) => {
+			mpk::mpk_pkey_free(key);
+			return Err(());
+		}
+	};
+	let tagged = mpk::key_usage()[key as usize] == (true, 2);
+
+	// the key is reclaimed together with its last page
+	mm::deallocate(address, size);
+	let released = mpk::key_usage()[key as usize] == (false, 0);
+
+	if tagged && released {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_priority_inheritance() -> Result<(), ()> {
+	use core::sync::atomic::{AtomicUsize, Ordering};
+	use scheduler::task::{HIGH_PRIO, LOW_PRIO, NORMAL_PRIO};
+	use synch::semaphore::Semaphore;
+
+	static LOCK: Semaphore = Semaphore::new(1);
+	static DONE: Semaphore = Semaphore::new(0);
+	static ORDER: AtomicUsize = AtomicUsize::new(0);
+	static HIGH_FINISHED: AtomicUsize = AtomicUsize::new(0);
+	static MEDIUM_FINISHED: AtomicUsize = AtomicUsize::new(0);
+
+	extern "C" fn high(_arg: usize) {
+		LOCK.acquire(None);
+		LOCK.release();
+		HIGH_FINISHED.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+		DONE.release();
+	}
+
+	extern "C" fn medium(_arg: usize) {
+		// never blocks, so it starves every task with a lower priority
+		arch::processor::udelay(50_000);
+		MEDIUM_FINISHED.store(ORDER.fetch_add(1, Ordering::SeqCst), Ordering::SeqCst);
+		DONE.release();
+	}
+
+	extern "C" fn low(_arg: usize) {
+		LOCK.acquire(None);
+		core_scheduler().spawn(high, 0, HIGH_PRIO);
+		core_scheduler().spawn(medium, 0, NORMAL_PRIO);
+
+		// the high-priority task blocks on the lock and lends its priority to us
+		core_scheduler().reschedule();
+		arch::processor::udelay(1_000);
+		LOCK.release();
+		DONE.release();
+	}
+
+	ORDER.store(0, Ordering::SeqCst);
+	core_scheduler().spawn(low, 0, LOW_PRIO);
+	for _ in 0..3 {
+		DONE.acquire(None);
+	}
+
+	if HIGH_FINISHED.load(Ordering::SeqCst) < MEDIUM_FINISHED.load(Ordering::SeqCst) {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn test_lent_priorities() -> Result<(), ()> {
+	use core::sync::atomic::{AtomicBool, Ordering};
+	use scheduler::task::{HIGH_PRIO, LOW_PRIO, NORMAL_PRIO};
+
+	static PASSED: AtomicBool = AtomicBool::new(false);
+
+	extern "C" fn holder(_arg: usize) {
+		let task = core_scheduler().current_task.clone();
+		let id = task.borrow().id;
+		let prio = || task.borrow().prio;
+
+		// the waiters of two semaphores lend their priorities
+		scheduler::lend_priority(id, 1, Some(HIGH_PRIO));
+		scheduler::lend_priority(id, 2, Some(NORMAL_PRIO));
+		let boosted = prio() == HIGH_PRIO;
+
+		// releasing the first semaphore keeps the priority lent through the second one
+		scheduler::lend_priority(id, 1, None);
+		let kept = prio() == NORMAL_PRIO;
+
+		scheduler::lend_priority(id, 2, None);
+		let restored = prio() == LOW_PRIO;
+
+		PASSED.store(boosted && kept && restored, Ordering::SeqCst);
+	}
+
+	PASSED.store(false, Ordering::SeqCst);
+	let id = core_scheduler().spawn(holder, 0, LOW_PRIO);
+	if scheduler::join(id).is_ok() && PASSED.load(Ordering::SeqCst) {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
@@ -0,0 +1,123 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use arch::percore::*;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use scheduler;
+use scheduler::task::FifoTaskQueue;
+use synch::spinlock::SpinlockIrqSave;
+
+/// An event count, which lets a producer notify consumers without taking a lock on its fast path.
+///
+/// The event count consists of a sequence counter, which is incremented by every notification,
+/// and a queue of waiting tasks. A consumer takes a snapshot of the counter with `prepare_wait`,
+/// re-checks its condition and calls `wait` only if the condition still doesn't hold.
+/// `wait` returns immediately if a notification has happened after the snapshot.
+/// Hence, a producer publishing between the check and `wait` can't cause a lost wakeup.
+///
+/// # Examples
+///
+/// ```
+/// // consumer
+/// loop {
+///     if let Some(item) = ring.pop() {
+///         break item;
+///     }
+///
+///     let key = event.prepare_wait();
+///     if ring.is_empty() {
+///         event.wait(key);
+///     }
+/// }
+///
+/// // producer
+/// ring.push(item);
+/// event.notify();
+/// ```
+pub struct EventCount {
+	/// Number of notifications so far
+	sequence: AtomicUsize,
+	/// Waiting tasks in the order of their arrival
+	queue: SpinlockIrqSave<FifoTaskQueue>,
+	/// Number of tasks in `queue`, which lets `notify` skip the lock if nobody waits
+	waiters: AtomicUsize,
+}
+
+unsafe impl Sync for EventCount {}
+unsafe impl Send for EventCount {}
+
+impl EventCount {
+	pub const fn new() -> Self {
+		Self {
+			sequence: AtomicUsize::new(0),
+			queue: SpinlockIrqSave::new(FifoTaskQueue::new()),
+			waiters: AtomicUsize::new(0),
+		}
+	}
+
+	/// Returns the key, which has to be passed to `wait` after the condition has been re-checked.
+	pub fn prepare_wait(&self) -> usize {
+		self.sequence.load(Ordering::SeqCst)
+	}
+
+	/// Blocks the current task until a notification happens after `prepare_wait` returned `key`.
+	/// Returns immediately if such a notification has already happened.
+	pub fn wait(&self, key: usize) {
+		let core_scheduler = core_scheduler();
+
+		loop {
+			{
+				let mut queue = self.queue.lock();
+
+				// `notify` increments the counter before it takes the lock.
+				// Hence, either we observe the new value or `notify` finds us in the queue.
+				self.waiters.fetch_add(1, Ordering::SeqCst);
+				if self.sequence.load(Ordering::SeqCst) != key {
+					self.waiters.fetch_sub(1, Ordering::SeqCst);
+					return;
+				}
+
+				core_scheduler
+					.blocked_tasks
+					.lock()
+					.add(core_scheduler.current_task.clone(), None);
+				queue.push(core_scheduler.current_task.clone());
+			}
+
+			// Switch to the next task.
+			core_scheduler.reschedule();
+
+			// A wakeup, which doesn't stem from `notify`, leaves us in the queue.
+			// Dequeue ourselves, so that the next iteration doesn't queue us a second time.
+			if self
+				.queue
+				.lock()
+				.remove(core_scheduler.current_task.clone())
+			{
+				self.waiters.fetch_sub(1, Ordering::SeqCst);
+			}
+		}
+	}
+
+	/// Wakes up all tasks, which are waiting for a notification.
+	/// Has to be called after the producer has published its data.
+	pub fn notify(&self) {
+		self.sequence.fetch_add(1, Ordering::SeqCst);
+
+		// Fast path: no task has announced itself as waiting.
+		if self.waiters.load(Ordering::SeqCst) == 0 {
+			return;
+		}
+
+		let mut queue = self.queue.lock();
+		while let Some(task) = queue.pop() {
+			self.waiters.fetch_sub(1, Ordering::SeqCst);
+			let core_scheduler = scheduler::get_scheduler(task.borrow().core_id);
+			core_scheduler.blocked_tasks.lock().custom_wakeup(task);
+		}
+	}
+}
@@ -8,6 +8,7 @@
 
 //! Synchronization primitives
 
+pub mod eventcount;
 pub mod recmutex;
 pub mod semaphore;
 pub mod spinlock;
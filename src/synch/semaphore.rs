@@ -6,16 +6,36 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+use alloc::alloc::{alloc, Layout};
+use alloc::boxed::Box;
 use arch::percore::*;
+use core::ptr;
 use scheduler;
-use scheduler::task::{PriorityTaskQueue, WakeupReason};
+use scheduler::task::{FifoTaskQueue, TaskId, WakeupReason};
 use synch::spinlock::SpinlockIrqSave;
 
 struct SemaphoreState {
 	/// Resource available count
 	count: isize,
-	/// Priority queue of waiting tasks
-	queue: PriorityTaskQueue,
+	/// Waiting tasks in the order of their arrival
+	queue: FifoTaskQueue,
+	/// Task, which has acquired a resource most recently and hasn't released it yet
+	holder: Option<TaskId>,
+}
+
+impl SemaphoreState {
+	/// Makes `task` the holder after it has acquired a resource of the semaphore at `source`.
+	/// The remaining waiters lend their priority to the new holder instead of the previous one.
+	fn acquired(&mut self, task: TaskId, source: usize) {
+		if let Some(previous) = self.holder {
+			if previous != task {
+				scheduler::lend_priority(previous, source, None);
+			}
+		}
+
+		self.holder = Some(task);
+		scheduler::lend_priority(task, source, self.queue.highest_prio());
+	}
 }
 
 /// A counting, blocking, semaphore.
@@ -25,6 +45,17 @@ struct SemaphoreState {
 /// until the counter is positive, and each release will increment the counter
 /// and unblock any threads if necessary.
 ///
+/// Blocked threads are woken up in the order of their arrival,
+/// regardless of their priority.
+///
+/// A thread, which blocks, lends its priority to the holder of the semaphore
+/// until the holder releases it or the thread stops waiting (priority inheritance). Hence, threads with a
+/// priority in between can't starve the holder and thereby the waiting thread.
+/// For a counting semaphore, only the thread, which has acquired a resource
+/// most recently, is considered as the holder. A release by another thread doesn't
+/// change the priority of the holder. A holder of several semaphores runs with the
+/// highest priority, which the waiters of any of them lend it.
+///
 /// # Examples
 ///
 /// ```
@@ -64,11 +95,27 @@ impl Semaphore {
 		Self {
 			state: SpinlockIrqSave::new(SemaphoreState {
 				count: count,
-				queue: PriorityTaskQueue::new(),
+				queue: FifoTaskQueue::new(),
+				holder: None,
 			}),
 		}
 	}
 
+	/// Like `new`, but places the semaphore on the heap.
+	///
+	/// In contrast to `Box::new`, an exhausted heap doesn't abort the kernel, but returns `Err(())`.
+	pub fn try_new_boxed(count: isize) -> Result<Box<Self>, ()> {
+		let semaphore = unsafe { alloc(Layout::new::<Self>()) } as *mut Self;
+		if semaphore.is_null() {
+			return Err(());
+		}
+
+		unsafe {
+			ptr::write(semaphore, Self::new(count));
+			Ok(Box::from_raw(semaphore))
+		}
+	}
+
 	/// Acquires a resource of this semaphore, blocking the current thread until
 	/// it can do so or until the wakeup time has elapsed.
 	///
@@ -87,6 +134,8 @@ impl Semaphore {
 				if locked_state.count > 0 {
 					// Successfully acquired the semaphore.
 					locked_state.count -= 1;
+					let current = core_scheduler.current_task.borrow().id;
+					locked_state.acquired(current, self.source());
 					return true;
 				} else if core_scheduler.current_task.borrow().last_wakeup_reason
 					== WakeupReason::Timer
@@ -96,6 +145,12 @@ impl Semaphore {
 					locked_state
 						.queue
 						.remove(core_scheduler.current_task.clone());
+
+					// The holder keeps only the priority, which the remaining waiters lend it.
+					if let Some(holder) = locked_state.holder {
+						let prio = locked_state.queue.highest_prio();
+						scheduler::lend_priority(holder, self.source(), prio);
+					}
 					return false;
 				}
 
@@ -106,6 +161,12 @@ impl Semaphore {
 					.lock()
 					.add(core_scheduler.current_task.clone(), wakeup_time);
 				locked_state.queue.push(core_scheduler.current_task.clone());
+
+				// Lend our priority to the holder, so that it releases the semaphore soon.
+				if let Some(holder) = locked_state.holder {
+					let prio = locked_state.queue.highest_prio();
+					scheduler::lend_priority(holder, self.source(), prio);
+				}
 			}
 
 			// Switch to the next task.
@@ -113,11 +174,18 @@ impl Semaphore {
 		}
 	}
 
+	/// Identifies the semaphore in the priorities, which are lent to its holder.
+	fn source(&self) -> usize {
+		self as *const Self as usize
+	}
+
 	pub fn try_acquire(&self) -> bool {
 		let mut locked_state = self.state.lock();
 
 		if locked_state.count > 0 {
 			locked_state.count -= 1;
+			let current = core_scheduler().current_task.borrow().id;
+			locked_state.acquired(current, self.source());
 			true
 		} else {
 			false
@@ -127,12 +195,20 @@ impl Semaphore {
 	/// Release a resource from this semaphore.
 	///
 	/// This will increment the number of resources in this semaphore by 1 and
-	/// will notify any pending waiters in `acquire` or `access` if necessary.
+	/// will notify the longest waiting task in `acquire` or `access` if necessary.
 	pub fn release(&self) {
 		let mut locked_state = self.state.lock();
 		locked_state.count += 1;
 
-		// Wake up any task that has been waiting for this semaphore.
+		// The holder gives up the priority, which it has inherited from the waiting tasks.
+		// Another task may release a resource as well, but it doesn't own the boost of the holder.
+		let current = core_scheduler().current_task.borrow().id;
+		if locked_state.holder == Some(current) {
+			locked_state.holder = None;
+			scheduler::lend_priority(current, self.source(), None);
+		}
+
+		// Wake up the task that has been waiting for this semaphore for the longest time.
 		if let Some(task) = locked_state.queue.pop() {
 			let core_scheduler = scheduler::get_scheduler(task.borrow().core_id);
 			core_scheduler.blocked_tasks.lock().custom_wakeup(task);
@@ -228,6 +228,39 @@ impl<T: ?Sized> SpinlockIrqSave<T> {
 			data: unsafe { &mut *self.data.get() },
 		}
 	}
+
+	/// Tries to obtain the lock within a budget of `spins` busy-waiting iterations.
+	///
+	/// In contrast to `lock`, no ticket is drawn before the lock is free.
+	/// Hence, giving up does not stall the tasks queued behind us.
+	/// Returns `None` if the lock is still held after the budget is exhausted.
+	/// This is intended for interrupt and fault handlers, which must not wedge the core.
+	pub fn lock_timeout(&self, spins: u64) -> Option<SpinlockIrqSaveGuard<T>> {
+		let irq = irq::nested_disable();
+
+		for _ in 0..spins {
+			let ticket = self.queue.load(Ordering::SeqCst);
+			if self.dequeue.load(Ordering::SeqCst) == ticket + 1
+				&& self
+					.queue
+					.compare_exchange(ticket, ticket + 1, Ordering::SeqCst, Ordering::SeqCst)
+					.is_ok()
+			{
+				self.irq.store(irq, Ordering::SeqCst);
+				return Some(SpinlockIrqSaveGuard {
+					//queue: &self.queue,
+					dequeue: &self.dequeue,
+					irq: &self.irq,
+					data: unsafe { &mut *self.data.get() },
+				});
+			}
+
+			spin_loop_hint();
+		}
+
+		irq::nested_enable(irq);
+		None
+	}
 }
 
 impl<T: ?Sized + fmt::Debug> fmt::Debug for SpinlockIrqSave<T> {
@@ -265,3 +298,19 @@ impl<'a, T: ?Sized> Drop for SpinlockIrqSaveGuard<'a, T> {
 		irq::nested_enable(irq);
 	}
 }
+
+#[test]
+fn lock_timeout() {
+	let spinlock = SpinlockIrqSave::new(0);
+
+	{
+		let mut data = spinlock.lock_timeout(1).unwrap();
+		*data = 2;
+	}
+
+	let guard = spinlock.lock();
+	assert!(spinlock.lock_timeout(1000).is_none());
+	drop(guard);
+
+	assert_eq!(*spinlock.lock_timeout(1).unwrap(), 2);
+}
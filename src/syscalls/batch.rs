@@ -0,0 +1,89 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Batched system calls, which execute several simple operations within a single kernel entry.
+
+use core::{mem, ptr};
+use errno::*;
+use mm;
+use synch::semaphore::Semaphore;
+use syscalls::semaphore::{__sys_sem_post, __sys_sem_trywait};
+use syscalls::tasks::__sys_getpid;
+use syscalls::timer::{__sys_clock_gettime, timespec};
+use syscalls::user::user_slice;
+
+/// Maximum number of operations of a single batch, which bounds the time spent in the kernel.
+pub const MAX_BATCH_OPS: usize = 256;
+
+/// Returns the id of the current task. No arguments.
+pub const BATCH_GETPID: u32 = 0;
+/// Releases the semaphore `arg0`.
+pub const BATCH_SEM_POST: u32 = 1;
+/// Tries to acquire the semaphore `arg0` without blocking.
+pub const BATCH_SEM_TRYWAIT: u32 = 2;
+/// Writes the time of clock `arg0` to the timespec at `arg1`.
+pub const BATCH_CLOCK_GETTIME: u32 = 3;
+
+/// A single operation of a batch
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct BatchOp {
+	pub op: u32,
+	pub arg0: usize,
+	pub arg1: usize,
+}
+
+fn execute(op: &BatchOp) -> i64 {
+	match op.op {
+		BATCH_GETPID => i64::from(__sys_getpid()),
+		BATCH_SEM_POST | BATCH_SEM_TRYWAIT if op.arg0 == 0 => i64::from(-EINVAL),
+		BATCH_SEM_POST => i64::from(__sys_sem_post(op.arg0 as *const Semaphore)),
+		BATCH_SEM_TRYWAIT => i64::from(__sys_sem_trywait(op.arg0 as *const Semaphore)),
+		BATCH_CLOCK_GETTIME if op.arg1 == 0 => i64::from(-EINVAL),
+		BATCH_CLOCK_GETTIME => i64::from(__sys_clock_gettime(op.arg0 as u64, op.arg1 as *mut timespec)),
+		_ => i64::from(-ENOSYS),
+	}
+}
+
+#[no_mangle]
+fn __sys_batch(ops: *const BatchOp, results: *mut i64, n: usize) -> i32 {
+	if n == 0 {
+		return 0;
+	}
+	if n > MAX_BATCH_OPS {
+		return -E2BIG;
+	}
+	let ops = match user_slice(ops, n) {
+		Ok(ops) => ops,
+		Err(err) => return err,
+	};
+	if !mm::is_user_range(results as usize, n * mem::size_of::<i64>()) {
+		return -EFAULT;
+	}
+
+	for (i, op) in ops.iter().enumerate() {
+		// Copy the operation, so the application can't change it while it is executed.
+		let op = unsafe { ptr::read_volatile(op) };
+		let result = execute(&op);
+		unsafe {
+			ptr::write_unaligned(results.add(i), result);
+		}
+	}
+
+	n as i32
+}
+
+/// Executes the `n` operations at `ops` in order and stores the result of each one at `results`.
+///
+/// All operations share a single kernel entry, which amortizes the PKRU switches of the individual
+/// system calls. An unknown operation yields `-ENOSYS` as its result, but doesn't stop the batch.
+/// Returns the number of executed operations or a negative error code.
+#[no_mangle]
+pub extern "C" fn sys_batch(ops: *const BatchOp, results: *mut i64, n: usize) -> i32 {
+	let ret = kernel_function!(__sys_batch(ops, results, n));
+	return ret;
+}
@@ -0,0 +1,122 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+use alloc::boxed::Box;
+use errno::*;
+use synch::eventcount::EventCount;
+use syscalls::user::copy_to_user;
+
+#[no_mangle]
+fn __sys_eventcount_init(ec: *mut *mut EventCount) -> i32 {
+	if ec.is_null() {
+		return -EINVAL;
+	}
+
+	// Create a new boxed event count and return a pointer to the raw memory.
+	let temp = Box::into_raw(Box::new(EventCount::new()));
+	let ret = copy_to_user(ec, &temp);
+	if ret != 0 {
+		// The caller will never see the event count.
+		unsafe {
+			drop(Box::from_raw(temp));
+		}
+	}
+
+	ret
+}
+
+#[no_mangle]
+pub extern "C" fn sys_eventcount_init(ec: *mut *mut EventCount) -> i32 {
+	let ret = kernel_function!(__sys_eventcount_init(ec));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_eventcount_destroy(ec: *mut EventCount) -> i32 {
+	if ec.is_null() {
+		return -EINVAL;
+	}
+
+	// Consume the pointer to the raw memory into a Box again
+	// and drop the Box to free the associated memory.
+	unsafe {
+		drop(Box::from_raw(ec));
+	}
+	0
+}
+
+#[no_mangle]
+pub extern "C" fn sys_eventcount_destroy(ec: *mut EventCount) -> i32 {
+	let ret = kernel_function!(__sys_eventcount_destroy(ec));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_eventcount_prepare_wait(ec: *const EventCount) -> usize {
+	if ec.is_null() {
+		return 0;
+	}
+
+	let eventcount = unsafe {
+								isolation_start!();
+								let temp = &*ec;
+								isolation_end!();
+								temp
+							};
+	eventcount.prepare_wait()
+}
+
+/// Returns the key for `sys_eventcount_wait`. The caller has to re-check its condition afterwards.
+#[no_mangle]
+pub extern "C" fn sys_eventcount_prepare_wait(ec: *const EventCount) -> usize {
+	let ret = kernel_function!(__sys_eventcount_prepare_wait(ec));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_eventcount_wait(ec: *const EventCount, key: usize) -> i32 {
+	if ec.is_null() {
+		return -EINVAL;
+	}
+
+	let eventcount = unsafe {
+								isolation_start!();
+								let temp = &*ec;
+								isolation_end!();
+								temp
+							};
+	eventcount.wait(key);
+	0
+}
+
+#[no_mangle]
+pub extern "C" fn sys_eventcount_wait(ec: *const EventCount, key: usize) -> i32 {
+	let ret = kernel_function!(__sys_eventcount_wait(ec, key));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_eventcount_notify(ec: *const EventCount) -> i32 {
+	if ec.is_null() {
+		return -EINVAL;
+	}
+
+	let eventcount = unsafe {
+								isolation_start!();
+								let temp = &*ec;
+								isolation_end!();
+								temp
+							};
+	eventcount.notify();
+	0
+}
+
+#[no_mangle]
+pub extern "C" fn sys_eventcount_notify(ec: *const EventCount) -> i32 {
+	let ret = kernel_function!(__sys_eventcount_notify(ec));
+	return ret;
+}
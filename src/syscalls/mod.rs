@@ -6,7 +6,9 @@
 // http://opensource.org/licenses/MIT>, at your option. This file may not be
 // copied, modified, or distributed except according to those terms.
 
+mod batch;
 mod condvar;
+mod eventcount;
 mod interfaces;
 #[cfg(feature = "newlib")]
 mod lwip;
@@ -18,8 +20,11 @@ mod spinlock;
 mod system;
 mod tasks;
 mod timer;
+mod user;
 
+pub use self::batch::*;
 pub use self::condvar::*;
+pub use self::eventcount::*;
 pub use self::processor::*;
 pub use self::random::*;
 pub use self::recmutex::*;
@@ -28,6 +33,7 @@ pub use self::spinlock::*;
 pub use self::system::*;
 pub use self::tasks::*;
 pub use self::timer::*;
+pub use self::user::{copy_to_user, user_slice};
 use environment;
 #[cfg(feature = "newlib")]
 use synch::spinlock::SpinlockIrqSave;
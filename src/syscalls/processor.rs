@@ -31,3 +31,15 @@ pub extern "C" fn sys_get_processor_frequency() -> u16 {
         let ret = kernel_function!(__sys_get_processor_frequency());
         return ret;
 }
+
+/** Returns the ID of the core, which executes the calling task. */
+#[no_mangle]
+fn __sys_get_core_id() -> usize {
+        arch::percore::core_id()
+}
+
+#[no_mangle]
+pub extern "C" fn sys_get_core_id() -> usize {
+        let ret = kernel_function!(__sys_get_core_id());
+        return ret;
+}
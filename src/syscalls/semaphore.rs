@@ -6,10 +6,28 @@
 // copied, modified, or distributed except according to those terms.
 
 use alloc::boxed::Box;
+use alloc::vec::Vec;
 use arch;
+use core::ptr;
 use errno::*;
 use synch::semaphore::Semaphore;
-use mm;
+use synch::spinlock::SpinlockIrqSave;
+use syscalls::user::copy_to_user;
+
+/// Sorted addresses of the semaphores, which have been created by `sys_sem_init` and not destroyed yet
+safe_global_var!(static SEMAPHORES: SpinlockIrqSave<Vec<usize>> = SpinlockIrqSave::new(Vec::new()));
+
+/// Checks that `sem` is a handle, which has been issued by `sys_sem_init`.
+///
+/// Any other pointer is refused with `-EINVAL` and must not be dereferenced.
+fn check_semaphore(sem: *const Semaphore) -> Result<(), i32> {
+	if SEMAPHORES.lock().binary_search(&(sem as usize)).is_err() {
+		debug!("{:#X} isn't a semaphore", sem as usize);
+		return Err(-EINVAL);
+	}
+
+	Ok(())
+}
 
 #[no_mangle]
 fn __sys_sem_init(sem: *mut *mut Semaphore, value: u32) -> i32 {
@@ -19,13 +37,26 @@ fn __sys_sem_init(sem: *mut *mut Semaphore, value: u32) -> i32 {
 	}
 
 	// Create a new boxed semaphore and return a pointer to the raw memory.
-	let boxed_semaphore = Box::new(Semaphore::new(value as isize));
+	let boxed_semaphore = match Semaphore::try_new_boxed(value as isize) {
+		Ok(boxed_semaphore) => boxed_semaphore,
+		Err(()) => {
+			let _ = copy_to_user(sem, &ptr::null_mut());
+			return -ENOMEM;
+		}
+	};
 	let temp = Box::into_raw(boxed_semaphore);
-	unsafe {
-		isolation_start!();
-		*sem = temp;
-		isolation_end!();
+	let ret = copy_to_user(sem, &temp);
+	if ret != 0 {
+		// The caller will never see the semaphore.
+		unsafe {
+			drop(Box::from_raw(temp));
+		}
+		return ret;
 	}
+
+	let mut semaphores = SEMAPHORES.lock();
+	let index = semaphores.binary_search(&(temp as usize)).unwrap_err();
+	semaphores.insert(index, temp as usize);
 	0
 }
 
@@ -41,11 +72,25 @@ fn __sys_sem_destroy(sem: *mut Semaphore) -> i32 {
 		return -EINVAL;
 	}
 
+	// The handle is withdrawn first, so that it can't be destroyed twice.
+	{
+		let mut semaphores = SEMAPHORES.lock();
+		match semaphores.binary_search(&(sem as usize)) {
+			Ok(index) => {
+				semaphores.remove(index);
+			}
+			Err(_) => {
+				debug!("{:#X} isn't a semaphore", sem as usize);
+				return -EINVAL;
+			}
+		}
+	}
+
 	// Consume the pointer to the raw memory into a Box again
 	// and drop the Box to free the associated memory.
-	/*unsafe {
-		isolate_function_strong!(Box::from_raw(sem));
-	}*/
+	unsafe {
+		drop(Box::from_raw(sem));
+	}
 	0
 }
 
@@ -56,10 +101,13 @@ pub extern "C" fn sys_sem_destroy(sem: *mut Semaphore) -> i32 {
 }
 
 #[no_mangle]
-fn __sys_sem_post(sem: *const Semaphore) -> i32 {
+pub(crate) fn __sys_sem_post(sem: *const Semaphore) -> i32 {
 	if sem.is_null() {
 		return -EINVAL;
 	}
+	if let Err(errno) = check_semaphore(sem) {
+		return errno;
+	}
 
 	// Get a reference to the given semaphore and release it.
 	let semaphore = unsafe {
@@ -79,10 +127,13 @@ pub extern "C" fn sys_sem_post(sem: *const Semaphore) -> i32 {
 }
 
 #[no_mangle]
-fn __sys_sem_trywait(sem: *const Semaphore) -> i32 {
+pub(crate) fn __sys_sem_trywait(sem: *const Semaphore) -> i32 {
 	if sem.is_null() {
 		return -EINVAL;
 	}
+	if let Err(errno) = check_semaphore(sem) {
+		return errno;
+	}
 
 	// Get a reference to the given semaphore and acquire it in a non-blocking fashion.
 	let semaphore = unsafe {
@@ -110,13 +161,9 @@ fn __sys_sem_timedwait(sem: *const Semaphore, ms: u32) -> i32 {
 	if sem.is_null() {
 		return -EINVAL;
 	}
-
-	// Calculate the absolute wakeup time in processor timer ticks out of the relative timeout in milliseconds.
-	let wakeup_time = if ms > 0 {
-		Some(arch::processor::get_timer_ticks() + u64::from(ms) * 1000)
-	} else {
-		None
-	};
+	if let Err(errno) = check_semaphore(sem) {
+		return errno;
+	}
 
 	// Get a reference to the given semaphore and wait until we have acquired it or the wakeup time has elapsed.
 	let semaphore = unsafe {
@@ -125,19 +172,64 @@ fn __sys_sem_timedwait(sem: *const Semaphore, ms: u32) -> i32 {
 								isolation_end!();
 								temp
 							};
-	if semaphore.acquire(wakeup_time) {
+
+	// A timeout of zero polls the semaphore, sys_sem_wait blocks without a timeout.
+	let acquired = if ms > 0 {
+		// Calculate the absolute wakeup time in processor timer ticks out of the relative timeout in milliseconds.
+		let wakeup_time = arch::processor::get_timer_ticks() + u64::from(ms) * 1000;
+		semaphore.acquire(Some(wakeup_time))
+	} else {
+		semaphore.try_acquire()
+	};
+
+	if acquired {
 		0
 	} else {
 		-ETIME
 	}
 }
 
+/// Waits at most `ms` milliseconds for the semaphore.
+/// A timeout of zero returns immediately, use `sys_sem_wait` to block without a timeout.
 #[no_mangle]
 pub extern "C" fn sys_sem_timedwait(sem: *const Semaphore, ms: u32) -> i32 {
 	return kernel_function!(__sys_sem_timedwait(sem, ms));
 }
 
+#[no_mangle]
+fn __sys_sem_wait(sem: *const Semaphore) -> i32 {
+	if sem.is_null() {
+		return -EINVAL;
+	}
+	if let Err(errno) = check_semaphore(sem) {
+		return errno;
+	}
+
+	// Get a reference to the given semaphore and wait until we have acquired it.
+	let semaphore = unsafe {
+								isolation_start!();
+								let temp = &*sem;
+								isolation_end!();
+								temp
+							};
+	semaphore.acquire(None);
+	0
+}
+
+/// Blocks until the semaphore has been acquired.
+#[no_mangle]
+pub extern "C" fn sys_sem_wait(sem: *const Semaphore) -> i32 {
+	let ret = kernel_function!(__sys_sem_wait(sem));
+	return ret;
+}
+
+/// Waits at most `ms` milliseconds for the semaphore.
+/// In contrast to `sys_sem_timedwait`, a timeout of zero keeps its former meaning and blocks without a timeout.
 #[no_mangle]
 pub extern "C" fn sys_sem_cancelablewait(sem: *const Semaphore, ms: u32) -> i32 {
-	sys_sem_timedwait(sem, ms)
+	if ms == 0 {
+		sys_sem_wait(sem)
+	} else {
+		sys_sem_timedwait(sem, ms)
+	}
 }
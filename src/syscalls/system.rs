@@ -21,16 +21,539 @@
 // OF CONTRACT, TORT OR OTHERWISE, ARISING FROM, OUT OF OR IN CONNECTION
 // WITH THE SOFTWARE OR THE USE OR OTHER DEALINGS IN THE SOFTWARE.
 
+use alloc::alloc::Layout;
+use alloc::vec::Vec;
 use arch;
-//use mm;
+use arch::kernel::signal;
+use arch::mm::paging::{BasePageSize, PageSize, PageTableEntryFlags};
+use arch::percore::*;
+use core::ptr;
+use errno::*;
+use log::LevelFilter;
+use logging;
+use mm;
+use scheduler;
+use scheduler::task::TaskId;
+use synch::spinlock::SpinlockIrqSave;
+use syscalls::user::copy_to_user;
 
 #[no_mangle]
 fn __sys_getpagesize() -> i32 {
 	arch::mm::paging::get_application_page_size() as i32
 }
 
+/// The page size is a constant, so the call doesn't need to enter the kernel domain.
 #[no_mangle]
 pub extern "C" fn sys_getpagesize() -> i32 {
-	let ret = kernel_function!(__sys_getpagesize());
+	let ret = kernel_function_nopkru!(__sys_getpagesize());
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_swap_pages(first: usize, second: usize) -> i32 {
+	// Only pages of the user domain may be exchanged, otherwise the caller could pull
+	// a frame of the kernel or of an isolated domain into its own address space.
+	if !mm::is_user_range(first, BasePageSize::SIZE) || !mm::is_user_range(second, BasePageSize::SIZE) {
+		return -EINVAL;
+	}
+
+	match mm::swap_mapping(first, second) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
+
+/// Exchanges the physical frames behind the pages at `first` and `second`.
+///
+/// Both addresses have to be aligned to the size of the page, which maps them,
+/// and both pages have to belong to the user domain (see `mm::is_user_range`).
+/// The exchange isn't atomic as a whole: while it runs, both pages may map the frame of `second`
+/// (see `mm::swap_mapping`). Hence, a concurrent write to `first` may be visible through `second`.
+#[no_mangle]
+pub extern "C" fn sys_swap_pages(first: usize, second: usize) -> i32 {
+	let ret = kernel_function!(__sys_swap_pages(first, second));
+	return ret;
+}
+
+/// Memory statistics, which are reported by `sys_meminfo`. All sizes are given in bytes.
+#[repr(C)]
+#[derive(Clone, Copy)]
+pub struct MemInfo {
+	/// Size of the physical memory
+	pub total: usize,
+	/// Physical memory, which isn't allocated yet
+	pub free: usize,
+	/// Size of the kernel heap
+	pub kernel_heap: usize,
+	/// Size of the user heap
+	pub user_heap: usize,
+	/// Memory occupied by page tables, which have been allocated at runtime
+	pub page_tables: usize,
+}
+
+#[no_mangle]
+fn __sys_meminfo(out: *mut MemInfo) -> i32 {
+	if out.is_null() {
+		return -EINVAL;
+	}
+
+	let info = MemInfo {
+		total: arch::mm::physicalmem::total_memory_size(),
+		free: arch::mm::physicalmem::free_memory_size(),
+		kernel_heap: mm::kernel_heap_size(),
+		user_heap: mm::user_heap_size(),
+		page_tables: arch::mm::paging::page_table_memory(),
+	};
+
+	copy_to_user(out, &info)
+}
+
+/// Reports the size of the physical memory, the free physical memory, the sizes of both heaps
+/// and the memory occupied by page tables.
+#[no_mangle]
+pub extern "C" fn sys_meminfo(out: *mut MemInfo) -> i32 {
+	let ret = kernel_function!(__sys_meminfo(out));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_check_invariants() -> i32 {
+	match mm::check_invariants() {
+		Ok(()) => 0,
+		Err(violation) => {
+			warn!("Memory invariant violated: {:?}", violation);
+			-EFAULT
+		}
+	}
+}
+
+/// Validates the invariants of the address space (see `mm::check_invariants`).
+/// Returns `-EFAULT` and logs the first violation if any invariant doesn't hold.
+#[no_mangle]
+pub extern "C" fn sys_check_invariants() -> i32 {
+	let ret = kernel_function!(__sys_check_invariants());
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_set_log_level(level: u32) -> i32 {
+	let level = match level {
+		0 => LevelFilter::Off,
+		1 => LevelFilter::Error,
+		2 => LevelFilter::Warn,
+		3 => LevelFilter::Info,
+		4 => LevelFilter::Debug,
+		5 => LevelFilter::Trace,
+		_ => return -EINVAL,
+	};
+
+	logging::set_level(level);
+	0
+}
+
+/// Changes the verbosity of the kernel messages from 0 (off) through 5 (trace).
+/// Returns `-EINVAL` for any other level.
+#[no_mangle]
+pub extern "C" fn sys_set_log_level(level: u32) -> i32 {
+	let ret = kernel_function!(__sys_set_log_level(level));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_pkru_stats() -> u64 {
+	arch::mm::mpk::pkru_write_count(core_id() as u32)
+}
+
+/// Returns the number of PKRU writes of the current core since the boot, including the two writes
+/// of this call. The writes are only counted if `config::COUNT_PKRU_WRITES` is set, otherwise 0 is returned.
+#[no_mangle]
+pub extern "C" fn sys_pkru_stats() -> u64 {
+	let ret = kernel_function!(__sys_pkru_stats());
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_arm_fault(address: usize) -> i32 {
+	signal::expect_fault(address);
+	0
+}
+
+#[no_mangle]
+fn __sys_disarm_fault() -> bool {
+	signal::disarm_fault()
+}
+
+/// Accesses the byte at `address` with the permissions of the caller and expects the access to fault.
+/// A write stores the byte, which is already there. Returns 0 if the access faulted or
+/// -1 if it succeeded. Any other fault than the expected one is handled as usual.
+#[no_mangle]
+pub extern "C" fn sys_expect_fault(address: usize, write: bool) -> i32 {
+	let _ = kernel_function!(__sys_arm_fault(address));
+	// The probe runs outside of the kernel domain, so the PKRU of the caller applies.
+	let faulted = if write {
+		signal::probe_write(address)
+	} else {
+		signal::probe_read(address)
+	};
+	let armed = kernel_function!(__sys_disarm_fault());
+
+	if faulted == 1 && !armed {
+		0
+	} else {
+		-1
+	}
+}
+
+/// Ranges, which have been reserved by `sys_reserve_virtual`, together with the task, which reserved them.
+/// Only these ranges may be passed to `sys_release_virtual`.
+safe_global_var!(static RESERVATIONS: SpinlockIrqSave<Vec<(usize, usize, TaskId)>> = SpinlockIrqSave::new(Vec::new()));
+
+#[no_mangle]
+fn __sys_reserve_virtual(size: usize, alignment: usize) -> usize {
+	if size == 0 {
+		return 0;
+	}
+
+	// The reserved range counts against RLIMIT_AS, even if its pages are never touched.
+	if core_scheduler()
+		.current_task
+		.borrow_mut()
+		.charge_memory(size)
+		.is_err()
+	{
+		return 0;
+	}
+
+	let virtual_address = mm::reserve_virtual(size, alignment);
+	if virtual_address == 0 {
+		core_scheduler().current_task.borrow_mut().uncharge_memory(size);
+	} else {
+		let mut flags = PageTableEntryFlags::empty();
+		flags.normal().writable().execute_disable();
+		if mm::reserve_on_demand(virtual_address, flags).is_err() {
+			// The reservation can't be backed by physical memory in strict commit mode.
+			mm::release_virtual(virtual_address, size).unwrap();
+			core_scheduler().current_task.borrow_mut().uncharge_memory(size);
+			return 0;
+		}
+
+		let owner = core_scheduler().current_task.borrow().id;
+		RESERVATIONS.lock().push((virtual_address, size, owner));
+	}
+
+	virtual_address
+}
+
+/// Reserves `size` bytes of user memory aligned to `alignment`, whose pages are mapped at their first access.
+/// Returns 0 if no such range is available, if the reservation exceeds the RLIMIT_AS of the task
+/// or, with `config::STRICT_COMMIT`, if the free physical memory can't back the reservation.
+#[no_mangle]
+pub extern "C" fn sys_reserve_virtual(size: usize, alignment: usize) -> usize {
+	let ret = kernel_function!(__sys_reserve_virtual(size, alignment));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_release_virtual(virtual_address: usize, size: usize) -> i32 {
+	let id = core_scheduler().current_task.borrow().id;
+	let mut reservations = RESERVATIONS.lock();
+	let index = match reservations
+		.iter()
+		.position(|&(start, reserved, _)| (start, reserved) == (virtual_address, size))
+	{
+		Some(index) => index,
+		None => return -EINVAL,
+	};
+	if reservations[index].2 != id {
+		return -EPERM;
+	}
+
+	match mm::release_virtual(virtual_address, size) {
+		Ok(()) => {
+			reservations.swap_remove(index);
+			core_scheduler().current_task.borrow_mut().uncharge_memory(size);
+			0
+		}
+		Err(()) => -EINVAL,
+	}
+}
+
+/// Releases a range, which has been reserved by `sys_reserve_virtual`, together with its mapped pages.
+/// `size` has to match the reservation. Returns `-EINVAL` for any other range and `-EPERM`
+/// if the range has been reserved by another task.
+#[no_mangle]
+pub extern "C" fn sys_release_virtual(virtual_address: usize, size: usize) -> i32 {
+	let ret = kernel_function!(__sys_release_virtual(virtual_address, size));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_heap_create(size: usize, key: u8) -> i32 {
+	if size == 0 || (key != 0 && !arch::mm::mpk::mpk_pkey_is_allocated(key)) {
+		return -EINVAL;
+	}
+
+	match mm::app_heap::create(size, key) {
+		Ok(handle) => handle as i32,
+		Err(err) => {
+			debug!("sys_heap_create: unable to create a heap of {} bytes: {:?}", size, err);
+			-ENOMEM
+		}
+	}
+}
+
+/// Creates a heap of `size` bytes, whose memory is protected by `key`. Its bookkeeping stays in the kernel.
+/// `key` has to be 0 (user domain) or a dynamically allocated protection key.
+/// Returns the handle of the heap or a negative error code.
+#[no_mangle]
+pub extern "C" fn sys_heap_create(size: usize, key: u8) -> i32 {
+	let ret = kernel_function!(__sys_heap_create(size, key));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_heap_alloc(handle: i32, size: usize, align: usize) -> *mut u8 {
+	match Layout::from_size_align(size, align) {
+		Ok(layout) if handle >= 0 && size > 0 => mm::app_heap::allocate(handle as usize, layout),
+		_ => ptr::null_mut(),
+	}
+}
+
+/// Allocates memory from the heap `handle`.
+/// Returns a null pointer if the handle or the layout is invalid or the heap is exhausted.
+#[no_mangle]
+pub extern "C" fn sys_heap_alloc(handle: i32, size: usize, align: usize) -> *mut u8 {
+	let ret = kernel_function!(__sys_heap_alloc(handle, size, align));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_heap_free(handle: i32, ptr: *mut u8, size: usize, align: usize) -> i32 {
+	let layout = match Layout::from_size_align(size, align) {
+		Ok(layout) if handle >= 0 && !ptr.is_null() => layout,
+		_ => return -EINVAL,
+	};
+
+	match mm::app_heap::deallocate(handle as usize, ptr, layout) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
+
+/// Returns memory, which has been allocated by `sys_heap_alloc` with the same layout, to the heap `handle`.
+#[no_mangle]
+pub extern "C" fn sys_heap_free(handle: i32, ptr: *mut u8, size: usize, align: usize) -> i32 {
+	let ret = kernel_function!(__sys_heap_free(handle, ptr, size, align));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_heap_destroy(handle: i32) -> i32 {
+	if handle < 0 {
+		return -EINVAL;
+	}
+
+	match mm::app_heap::destroy(handle as usize) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
+
+/// Releases the heap `handle` together with all of its allocations.
+#[no_mangle]
+pub extern "C" fn sys_heap_destroy(handle: i32) -> i32 {
+	let ret = kernel_function!(__sys_heap_destroy(handle));
+	return ret;
+}
+
+/// Pages may be read.
+pub const PROT_READ: i32 = 0x1;
+/// Pages may be written.
+pub const PROT_WRITE: i32 = 0x2;
+/// Pages may be executed.
+pub const PROT_EXEC: i32 = 0x4;
+
+/// Changes are shared with other mappings of the same object (not supported).
+pub const MAP_SHARED: i32 = 0x01;
+/// Changes are private to the mapping.
+pub const MAP_PRIVATE: i32 = 0x02;
+/// The mapping has to be placed exactly at the requested address.
+pub const MAP_FIXED: i32 = 0x10;
+/// The mapping isn't backed by a file and its contents are initialized to zero.
+pub const MAP_ANONYMOUS: i32 = 0x20;
+
+/// Ranges, which have been mapped by `sys_mmap`, together with the task they are accounted to.
+/// Only these ranges may be passed to `sys_munmap`.
+safe_global_var!(static MAPPINGS: SpinlockIrqSave<Vec<(usize, usize, TaskId)>> = SpinlockIrqSave::new(Vec::new()));
+
+#[no_mangle]
+fn __sys_mmap(addr: *mut u8, len: usize, prot: i32, flags: i32) -> *mut u8 {
+	let failed = |errno: i32| (-errno) as usize as *mut u8;
+
+	if len == 0 || len % BasePageSize::SIZE != 0 {
+		return failed(EINVAL);
+	}
+	// Only anonymous private mappings are supported. PROT_NONE would require a mapping without access.
+	if flags & (MAP_ANONYMOUS | MAP_PRIVATE | MAP_SHARED) != MAP_ANONYMOUS | MAP_PRIVATE
+		|| prot & (PROT_READ | PROT_WRITE | PROT_EXEC) == 0
+	{
+		return failed(EINVAL);
+	}
+
+	let fixed_address = if flags & MAP_FIXED != 0 {
+		if addr as usize % BasePageSize::SIZE != 0 {
+			return failed(EINVAL);
+		}
+		Some(addr as usize)
+	} else {
+		// Without MAP_FIXED, the address is only a hint, which is ignored.
+		None
+	};
+
+	if !mm::may_commit(len)
+		|| core_scheduler()
+			.current_task
+			.borrow_mut()
+			.charge_memory(len)
+			.is_err()
+	{
+		return failed(ENOMEM);
+	}
+
+	match mm::try_user_map(
+		fixed_address,
+		len,
+		prot & PROT_WRITE != 0,
+		prot & PROT_EXEC == 0,
+	) {
+		Ok(virtual_address) => {
+			let owner = core_scheduler().current_task.borrow().id;
+			MAPPINGS.lock().push((virtual_address, len, owner));
+			virtual_address as *mut u8
+		}
+		Err(err) => {
+			debug!("sys_mmap: unable to map {:#X} bytes: {:?}", len, err);
+			core_scheduler().current_task.borrow_mut().uncharge_memory(len);
+			match err {
+				mm::AllocError::OutOfPhysicalMemory | mm::AllocError::OutOfVirtualMemory => failed(ENOMEM),
+				mm::AllocError::WritableExecutable | mm::AllocError::AddressInUse => failed(EINVAL),
+			}
+		}
+	}
+}
+
+/// Maps `len` bytes of zeroed memory, which are accessible with the protection `prot`.
+///
+/// Only anonymous private mappings (`MAP_ANONYMOUS | MAP_PRIVATE`) are supported and `len` has
+/// to be a non-zero multiple of the page size. With `MAP_FIXED`, the memory is mapped exactly at
+/// `addr`, which has to be page-aligned and free. Writable memory must not be executable.
+/// On failure, `-EINVAL` or `-ENOMEM` is returned as a pointer.
+#[no_mangle]
+pub extern "C" fn sys_mmap(addr: *mut u8, len: usize, prot: i32, flags: i32) -> *mut u8 {
+	let ret = kernel_function!(__sys_mmap(addr, len, prot, flags));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_mprotect(addr: *mut u8, len: usize, prot: i32) -> i32 {
+	if len == 0
+		|| addr as usize % BasePageSize::SIZE != 0
+		|| prot & PROT_READ == 0
+		|| prot & PROT_EXEC != 0
+		|| !mm::is_user_range(addr as usize, len)
+	{
+		return -EINVAL;
+	}
+
+	match mm::protect(addr as usize, len, prot & PROT_WRITE != 0) {
+		Ok(()) => 0,
+		Err(mm::ProtectError::Frozen) => -EPERM,
+		Err(mm::ProtectError::NotMapped) => -ENOMEM,
+	}
+}
+
+/// Makes the user memory at `addr` read-only or writable, depending on `PROT_WRITE` in `prot`.
+/// Executable memory isn't supported. Returns `-EPERM` if a page has been frozen by `mm::freeze`.
+#[no_mangle]
+pub extern "C" fn sys_mprotect(addr: *mut u8, len: usize, prot: i32) -> i32 {
+	let ret = kernel_function!(__sys_mprotect(addr, len, prot));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_munmap(addr: *mut u8, len: usize) -> i32 {
+	if len == 0 || len % BasePageSize::SIZE != 0 {
+		return -EINVAL;
+	}
+
+	if mm::is_frozen(addr as usize, len) {
+		return -EPERM;
+	}
+
+	let owner = {
+		let mut mappings = MAPPINGS.lock();
+		match mappings
+			.iter()
+			.position(|&(start, size, _)| (start, size) == (addr as usize, len))
+		{
+			Some(index) => mappings.swap_remove(index).2,
+			None => return -EINVAL,
+		}
+	};
+
+	mm::deallocate(addr as usize, len);
+	// The mapping may have been created by another thread.
+	scheduler::uncharge_memory(owner, len);
+	0
+}
+
+/// Unmaps a range, which has been mapped by `sys_mmap`.
+/// Only whole mappings can be unmapped, otherwise `-EINVAL` is returned.
+#[no_mangle]
+pub extern "C" fn sys_munmap(addr: *mut u8, len: usize) -> i32 {
+	let ret = kernel_function!(__sys_munmap(addr, len));
+	return ret;
+}
+
+/// The application expects to access the range soon.
+pub const MADV_WILLNEED: i32 = 3;
+/// The application doesn't need the content of the range anymore.
+pub const MADV_DONTNEED: i32 = 4;
+
+#[no_mangle]
+fn __sys_madvise(addr: *mut u8, len: usize, advice: i32) -> i32 {
+	if len == 0
+		|| addr as usize % BasePageSize::SIZE != 0
+		|| len % BasePageSize::SIZE != 0
+		|| !mm::is_user_heap_range(addr as usize, len)
+	{
+		return -EINVAL;
+	}
+
+	match advice {
+		MADV_DONTNEED => {
+			if mm::is_frozen(addr as usize, len) {
+				return -EPERM;
+			}
+
+			mm::discard_user_pages(addr as usize, len);
+			0
+		}
+		MADV_WILLNEED => match mm::prefault_user_pages(addr as usize, len) {
+			Ok(()) => 0,
+			Err(()) => -ENOMEM,
+		},
+		_ => -EINVAL,
+	}
+}
+
+/// Advises the kernel how the application uses a range of the user heap.
+///
+/// With `MADV_DONTNEED`, the pages are unmapped and their frames are returned to the physical memory.
+/// The next access maps a new zeroed page. With `MADV_WILLNEED`, discarded pages are mapped again
+/// right away. Returns `-EINVAL` if the range isn't page-aligned or doesn't lie within the user heap.
+#[no_mangle]
+pub extern "C" fn sys_madvise(addr: *mut u8, len: usize, advice: i32) -> i32 {
+	let ret = kernel_function!(__sys_madvise(addr, len, advice));
 	return ret;
 }
@@ -7,16 +7,20 @@
 
 use arch;
 use arch::kernel::get_processor_count;
+use arch::kernel::signal::SIGSEGV;
 use arch::percore::*;
 use core::isize;
+use core::mem;
 use core::sync::atomic::{AtomicUsize, Ordering};
 use errno::*;
 #[cfg(feature = "newlib")]
 use mm::{task_heap_end, task_heap_start};
 use scheduler;
-use scheduler::task::{Priority, TaskId};
+use scheduler::task::{Priority, TaskId, NO_PRIORITIES};
+use scheduler::BoostTarget;
 use syscalls;
 use syscalls::timer::timespec;
+use syscalls::user::{copy_to_user, user_slice};
 use mm;
 
 #[cfg(feature = "newlib")]
@@ -24,7 +28,7 @@ pub type SignalHandler = extern "C" fn(i32);
 pub type Tid = u32;
 
 #[no_mangle]
-fn __sys_getpid() -> Tid {
+pub(crate) fn __sys_getpid() -> Tid {
 	safe_core_scheduler().current_task.borrow().id.into() as Tid
 }
 
@@ -78,6 +82,94 @@ pub extern "C" fn sys_abort() -> ! {
 	sys_exit(-1);
 }
 
+/// Resource limit of the address space (only RLIMIT_AS is supported)
+pub const RLIMIT_AS: i32 = 9;
+/// Value of an unlimited resource
+pub const RLIM_INFINITY: u64 = u64::MAX;
+
+#[derive(Copy, Clone, Debug)]
+#[repr(C)]
+pub struct rlimit {
+	/// Soft limit
+	pub rlim_cur: u64,
+	/// Hard limit (ceiling for rlim_cur)
+	pub rlim_max: u64,
+}
+
+#[no_mangle]
+fn __sys_getrlimit(resource: i32, rlim: *mut rlimit) -> i32 {
+	if resource != RLIMIT_AS {
+		return -EINVAL;
+	}
+
+	let to_rlim = |limit: usize| match limit {
+		usize::MAX => RLIM_INFINITY,
+		limit => limit as u64,
+	};
+	let result = {
+		let task = core_scheduler().current_task.borrow();
+		rlimit {
+			rlim_cur: to_rlim(task.memory_limit),
+			rlim_max: to_rlim(task.memory_max),
+		}
+	};
+
+	copy_to_user(rlim, &result)
+}
+
+/// Returns the limit of the given resource of the current task.
+#[no_mangle]
+pub extern "C" fn sys_getrlimit(resource: i32, rlim: *mut rlimit) -> i32 {
+	let ret = kernel_function!(__sys_getrlimit(resource, rlim));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_setrlimit(resource: i32, rlim: *const rlimit) -> i32 {
+	if resource != RLIMIT_AS {
+		return -EINVAL;
+	}
+
+	if !mm::is_user_range(rlim as usize, mem::size_of::<rlimit>()) {
+		return -EFAULT;
+	}
+
+	let limit = unsafe {
+		isolation_start!();
+		let temp = *rlim;
+		isolation_end!();
+		temp
+	};
+	if limit.rlim_cur > limit.rlim_max {
+		return -EINVAL;
+	}
+
+	let from_rlim = |limit: u64| {
+		if limit >= usize::MAX as u64 {
+			usize::MAX
+		} else {
+			limit as usize
+		}
+	};
+	let mut task = core_scheduler().current_task.borrow_mut();
+	// the hard limit can only be lowered
+	if from_rlim(limit.rlim_max) > task.memory_max {
+		return -EPERM;
+	}
+	task.memory_limit = from_rlim(limit.rlim_cur);
+	task.memory_max = from_rlim(limit.rlim_max);
+	0
+}
+
+/// Limits the memory, which can be mapped or reserved for the current task (RLIMIT_AS).
+/// Further growth beyond the limit fails with `-ENOMEM`. Raising the hard limit fails with `-EPERM`.
+/// Tasks cloned afterwards inherit the limits.
+#[no_mangle]
+pub extern "C" fn sys_setrlimit(resource: i32, rlim: *const rlimit) -> i32 {
+	let ret = kernel_function!(__sys_setrlimit(resource, rlim));
+	return ret;
+}
+
 #[cfg(feature = "newlib")]
 safe_global_var!(static SBRK_COUNTER: AtomicUsize = AtomicUsize::new(0));
 
@@ -95,9 +187,24 @@ fn __sys_sbrk(incr: isize) -> usize {
 	let old_end;
 
 	if incr >= 0 {
+		if !mm::may_commit(incr as usize)
+			|| core_scheduler()
+				.current_task
+				.borrow_mut()
+				.charge_memory(incr as usize)
+				.is_err()
+		{
+			return -ENOMEM as usize;
+		}
+
 		old_end = SBRK_COUNTER.fetch_add(incr as usize, Ordering::SeqCst);
 		assert!(task_heap_end >= old_end + incr as usize);
 	} else {
+		core_scheduler()
+			.current_task
+			.borrow_mut()
+			.uncharge_memory(incr.abs() as usize);
+
 		old_end = SBRK_COUNTER.fetch_sub(incr.abs() as usize, Ordering::SeqCst);
 		assert!(task_heap_start < old_end - incr.abs() as usize);
 	}
@@ -143,17 +250,11 @@ pub extern "C" fn sys_msleep(ms: u32) {
 }
 
 #[no_mangle]
-pub extern "C" fn sys_nanosleep(rqtp: *const timespec, _rmtp: *mut timespec) -> i32 {
-	assert!(
-		!rqtp.is_null(),
-		"sys_nanosleep called with a zero rqtp parameter"
-	);
-	let requested_time = unsafe {
-									isolation_start!();
-									let temp = &*rqtp;
-									isolation_end!();
-									temp
-								};
+fn __sys_nanosleep(rqtp: *const timespec, rmtp: *mut timespec) -> i32 {
+	let requested_time = match user_slice(rqtp, 1) {
+		Ok(requested_time) => requested_time[0],
+		Err(errno) => return errno,
+	};
 	if requested_time.tv_sec < 0
 		|| requested_time.tv_nsec < 0
 		|| requested_time.tv_nsec > 999_999_999
@@ -162,13 +263,56 @@ pub extern "C" fn sys_nanosleep(rqtp: *const timespec, _rmtp: *mut timespec) ->
 		return -EINVAL;
 	}
 
-	let microseconds =
-		(requested_time.tv_sec as u64) * 1_000_000 + (requested_time.tv_nsec as u64) / 1_000;
-	sys_usleep(microseconds);
+	// A huge request just sleeps for the longest representable time.
+	let microseconds = (requested_time.tv_sec as u64)
+		.saturating_mul(1_000_000)
+		.saturating_add((requested_time.tv_nsec as u64) / 1_000);
+	if microseconds <= (scheduler::TASK_TIME_SLICE as u64) {
+		// Not enough time to set a wakeup timer, so just do busy-waiting.
+		arch::processor::udelay(microseconds);
+		return 0;
+	}
+
+	// Block the current task like a semaphore with a timeout.
+	let wakeup_time = arch::processor::get_timer_ticks().saturating_add(microseconds);
+	let core_scheduler = core_scheduler();
+	let current_task = core_scheduler.current_task.clone();
+	core_scheduler
+		.blocked_tasks
+		.lock()
+		.add(current_task, Some(wakeup_time));
+	core_scheduler.reschedule();
+
+	// The task has been woken up before its wakeup time.
+	let now = arch::processor::get_timer_ticks();
+	if now < wakeup_time {
+		if !rmtp.is_null() {
+			let remaining = wakeup_time - now;
+			let remaining_time = timespec {
+				tv_sec: (remaining / 1_000_000) as i64,
+				tv_nsec: ((remaining % 1_000_000) * 1000) as i64,
+			};
+			let ret = copy_to_user(rmtp, &remaining_time);
+			if ret != 0 {
+				return ret;
+			}
+		}
+
+		return -EINTR;
+	}
 
 	0
 }
 
+/// Suspends the current task for the time given by `rqtp`.
+/// If the task is woken up earlier, `-EINTR` is returned and the remaining time is written to `rmtp`
+/// unless it is null.
+#[no_mangle]
+pub extern "C" fn sys_nanosleep(rqtp: *const timespec, rmtp: *mut timespec) -> i32 {
+	let ret = kernel_function!(__sys_nanosleep(rqtp, rmtp));
+	return ret;
+}
+
 #[cfg(feature = "newlib")]
 #[no_mangle]
 fn __sys_clone(id: *mut Tid, func: extern "C" fn(usize), arg: usize) -> i32 {
@@ -200,6 +344,73 @@ pub extern "C" fn sys_yield() {
 	kernel_exit!("sys_yield");
 }
 
+#[no_mangle]
+fn __sys_sched_yield() -> i32 {
+	core_scheduler().reschedule();
+	0
+}
+
+/// Gives up the remaining time slice of the current task, e.g. in a busy-wait loop.
+/// The task stays ready and runs again as soon as no other task of a higher or the same priority is ready.
+/// Always returns 0.
+#[no_mangle]
+pub extern "C" fn sys_sched_yield() -> i32 {
+	let ret = kernel_function!(__sys_sched_yield());
+	return ret;
+}
+
+/// Gives up the CPU if the time slice of the current task is nearly exhausted.
+/// Long-running loops call it at natural boundaries to avoid being preempted in the middle of an iteration.
+#[no_mangle]
+pub extern "C" fn sys_sched_checkpoint() {
+	kernel_enter!("sys_sched_checkpoint");
+	core_scheduler().checkpoint();
+	kernel_exit!("sys_sched_checkpoint");
+}
+
+#[no_mangle]
+fn __sys_sched_boost(target: u32, is_prio: bool) -> i32 {
+	let target = if is_prio {
+		if target as usize >= NO_PRIORITIES {
+			return -EINVAL;
+		}
+
+		BoostTarget::Band(Priority::from(target as u8))
+	} else {
+		BoostTarget::Task(TaskId::from(target))
+	};
+
+	match core_scheduler().boost(target) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
+
+/// Donates the remaining time slice of the current task for one scheduling round.
+///
+/// `target` is a task id or, if `is_prio` is set, a priority band.
+#[no_mangle]
+pub extern "C" fn sys_sched_boost(target: u32, is_prio: bool) -> i32 {
+	let ret = kernel_function!(__sys_sched_boost(target, is_prio));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_sched_migrate(id: Tid, core_id: u32) -> i32 {
+	match scheduler::migrate_task(TaskId::from(id), core_id as usize) {
+		Ok(()) => 0,
+		Err(()) => -EINVAL,
+	}
+}
+
+/// Moves the task `id` to the core `core_id`.
+/// A running or blocked task is moved as soon as it's switched out or woken up.
+#[no_mangle]
+pub extern "C" fn sys_sched_migrate(id: Tid, core_id: u32) -> i32 {
+	let ret = kernel_function!(__sys_sched_migrate(id, core_id));
+	return ret;
+}
+
 #[cfg(feature = "newlib")]
 #[no_mangle]
 pub extern "C" fn sys_kill(dest: Tid, signum: i32) -> i32 {
@@ -217,6 +428,53 @@ pub extern "C" fn sys_signal(_handler: SignalHandler) -> i32 {
 	0
 }
 
+#[no_mangle]
+fn __sys_sigaction(signum: i32, handler: Option<extern "C" fn(i32)>) -> i32 {
+	if signum != SIGSEGV {
+		return -EINVAL;
+	}
+
+	core_scheduler().current_task.borrow_mut().fault_handler = handler;
+	0
+}
+
+/// Registers `handler` for isolation violations (`SIGSEGV`) of the current task.
+///
+/// On a protection key violation of user code, the handler runs on the stack of the task. Once the handler
+/// returns, the faulting instruction is executed again, so the handler has to remove the cause of the fault.
+/// Without a handler (`None`), the task is aborted.
+/// The handler is reset before it is invoked and threads inherit the handler of their creator.
+#[no_mangle]
+pub extern "C" fn sys_sigaction(signum: i32, handler: Option<extern "C" fn(i32)>) -> i32 {
+	let ret = kernel_function!(__sys_sigaction(signum, handler));
+	return ret;
+}
+
+#[no_mangle]
+fn __sys_set_fault_handler(id: Tid, handler: usize) -> i32 {
+	// A task may only redirect its own faults.
+	if id != __sys_getpid() {
+		return -EPERM;
+	}
+
+	match scheduler::set_fault_handler(TaskId::from(id), handler) {
+		Ok(()) => 0,
+		Err(()) => -ESRCH,
+	}
+}
+
+/// Registers `handler` as `extern "C" fn(fault_address: usize, error: u32)` for the page faults of the task `id`.
+///
+/// A page fault, which isn't handled otherwise, redirects the task to the handler instead of aborting it.
+/// `error` is the page fault error code. The task is terminated once the handler returns and
+/// 0 removes the handler. Only faults of user code are delivered and `id` has to be the calling task,
+/// otherwise `-EPERM` is returned.
+#[no_mangle]
+pub extern "C" fn sys_set_fault_handler(id: Tid, handler: usize) -> i32 {
+	let ret = kernel_function!(__sys_set_fault_handler(id, handler));
+	return ret;
+}
+
 #[no_mangle]
 fn __sys_spawn(
 	id: *mut Tid,
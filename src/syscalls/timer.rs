@@ -8,6 +8,7 @@
 use arch;
 use errno::*;
 use syscalls::sys_usleep;
+use syscalls::user::copy_to_user;
 use mm;
 
 #[derive(Copy, Clone, Debug)]
@@ -80,17 +81,11 @@ pub extern "C" fn sys_clock_getres(clock_id: u64, res: *mut timespec) -> i32 {
 }
 
 #[no_mangle]
-fn __sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
-	assert!(
-		!tp.is_null(),
-		"sys_clock_gettime called with a zero tp parameter"
-	);
-	let result = unsafe {
-							isolation_start!();
-							let temp = &mut *tp;
-							isolation_end!();
-							temp
-						};
+pub(crate) fn __sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
+	if tp.is_null() {
+		debug!("sys_clock_gettime called with a zero tp parameter, returning -EINVAL");
+		return -EINVAL;
+	}
 
 	match clock_id {
 		CLOCK_REALTIME | CLOCK_MONOTONIC => {
@@ -100,8 +95,12 @@ fn __sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
 				microseconds += arch::get_boot_time();
 			}
 
-			microseconds_to_timespec(microseconds, result);
-			0
+			let mut result = timespec {
+				tv_sec: 0,
+				tv_nsec: 0,
+			};
+			microseconds_to_timespec(microseconds, &mut result);
+			copy_to_user(tp, &result)
 		}
 		_ => {
 			debug!(
@@ -113,6 +112,11 @@ fn __sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
 	}
 }
 
+/// Writes the current time of the clock `clock_id` to `tp`.
+///
+/// `CLOCK_MONOTONIC` counts the time since boot, `CLOCK_REALTIME` additionally includes the boot time.
+/// Both are derived from the processor timer ticks with a resolution of 1 microsecond.
+/// Returns `-EINVAL` for other clocks or a null `tp`.
 #[no_mangle]
 pub extern "C" fn sys_clock_gettime(clock_id: u64, tp: *mut timespec) -> i32 {
 	kernel_function!(__sys_clock_gettime(clock_id, tp))
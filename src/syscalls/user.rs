@@ -0,0 +1,58 @@
+// Copyright (c) 2020 RWTH Aachen University
+//
+// Licensed under the Apache License, Version 2.0, <LICENSE-APACHE or
+// http://apache.org/licenses/LICENSE-2.0> or the MIT license <LICENSE-MIT or
+// http://opensource.org/licenses/MIT>, at your option. This file may not be
+// copied, modified, or distributed except according to those terms.
+
+//! Helpers to exchange data with buffers provided by the application.
+
+use core::{mem, ptr, slice};
+use errno::*;
+use mm;
+
+/// Copies `value` to the user buffer `dst`.
+///
+/// The whole destination has to be part of the user domain. Otherwise, nothing is written
+/// and `-EFAULT` is returned. The value is staged in a bounce buffer on the kernel stack,
+/// so `dst` may even overlap with `value`.
+pub fn copy_to_user<T: Copy>(dst: *mut T, value: &T) -> i32 {
+	if !mm::is_user_range(dst as usize, mem::size_of::<T>()) {
+		debug!(
+			"copy_to_user: {:#X} ({} bytes) isn't a user buffer",
+			dst as usize,
+			mem::size_of::<T>()
+		);
+		return -EFAULT;
+	}
+
+	let bounce = *value;
+	unsafe {
+		ptr::copy(&bounce as *const T as *const u8, dst as *mut u8, mem::size_of::<T>());
+	}
+
+	0
+}
+
+/// Returns the user buffer of `len` elements at `ptr` as a slice.
+///
+/// The whole buffer has to be mapped and part of the user domain (see `mm::is_user_range`).
+/// Otherwise, e.g. for a pointer into the kernel or a dangling or misaligned pointer, `-EFAULT`
+/// is returned. The application may still modify the elements, while the kernel reads them.
+/// Hence, an element has to be copied before it is validated.
+pub fn user_slice<'a, T>(ptr: *const T, len: usize) -> Result<&'a [T], i32> {
+	if len == 0 {
+		return Ok(&[]);
+	}
+
+	let size = match len.checked_mul(mem::size_of::<T>()) {
+		Some(size) => size,
+		None => return Err(-EFAULT),
+	};
+	if ptr as usize % mem::align_of::<T>() != 0 || !mm::is_user_range(ptr as usize, size) {
+		debug!("user_slice: {:#X} ({} bytes) isn't a user buffer", ptr as usize, size);
+		return Err(-EFAULT);
+	}
+
+	Ok(unsafe { slice::from_raw_parts(ptr, len) })
+}
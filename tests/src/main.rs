@@ -45,17 +45,48 @@ fn test_syscall_cost2() {
 	println!("sys_getpid {} s", elapsed);
 }
 
-fn vulnerable_function(string: String, address: *mut String) {
-	unsafe {
-		println!("bafore writing");
-		*address = string;
-		println!("after writing");
+fn test_syscall_cost3() {
+	extern "C" {
+		fn sys_getpagesize() -> i32;
+	}
+
+	// sys_getpagesize doesn't switch the PKRU, compare with sys_getpid in test_syscall_cost2
+	use std::time::Instant;
+	let now = Instant::now();
+	for _ in 0..100000000 {
+		unsafe {
+			let _ = sys_getpagesize();
+		}
 	}
+	let elapsed = now.elapsed().as_secs_f64();
+	println!("sys_getpagesize {} s", elapsed);
 }
 
-fn security_evaluation_user_isolation() {
-	let s = "hello".to_string();
-	vulnerable_function(s, 0x400000usize as *mut _);
+fn security_evaluation_user_isolation() -> Result<(), ()> {
+	extern "C" {
+		fn sys_expect_fault(address: usize, write: bool) -> i32;
+	}
+
+	// the .safe_data section of the kernel must be neither readable nor writable
+	let safe_data = 0x400000usize;
+	if unsafe { sys_expect_fault(safe_data, false) } != 0 {
+		println!("reading {:#X} succeeded", safe_data);
+		return Err(());
+	}
+	if unsafe { sys_expect_fault(safe_data, true) } != 0 {
+		println!("writing {:#X} succeeded", safe_data);
+		return Err(());
+	}
+
+	// memory of the application is accessible
+	let local = Box::new(0u8);
+	let address = &*local as *const u8 as usize;
+	if unsafe { sys_expect_fault(address, true) } != -1 {
+		println!("writing {:#X} faulted", address);
+		return Err(());
+	}
+
+	Ok(())
 }
 
 //static COUNTER: AtomicU32 = AtomicU32::new(8);
@@ -69,6 +100,7 @@ fn test_threading() -> Result<(), ()> {
 	extern "C" {
 		fn sys_getpid() -> u32;
 		fn sys_getprio(id: *const u32) -> i32;
+		fn sys_sched_yield() -> i32;
 	}
 
 	// Make a vector to hold the children which are spawned.
@@ -97,7 +129,7 @@ fn test_threading() -> Result<(), ()> {
 
 	println!("before join");
         while COUNTER.load(Ordering::Relaxed) > 0 {
-            unsafe { asm!("pause" :::: "volatile"); }
+            unsafe { sys_sched_yield(); }
         }
         while let Some(i) = results.lock().unwrap().pop() {
             println!("{}", i);
@@ -118,8 +150,8 @@ fn main() {
 /*	
         test_syscall_cost();
 	test_syscall_cost2();
+	test_syscall_cost3();
         test_threading();
-	security_evaluation_user_isolation();
         
         println!(
 		"Test {} ... {}",
@@ -200,6 +232,166 @@ fn main() {
 		stringify!(bench_sched_two_threads),
 		test_result(bench_sched_two_threads())
 	);
+	println!(
+		"Test {} ... {}",
+		stringify!(bench_sched_boost),
+		test_result(bench_sched_boost())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sched_boost),
+		test_result(test_sched_boost())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(bench_sched_checkpoint),
+		test_result(bench_sched_checkpoint())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(bench_batch_sem_post),
+		test_result(bench_batch_sem_post())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sem_init_concurrent),
+		test_result(test_sem_init_concurrent())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_swap_pages),
+		test_result(test_swap_pages())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(bench_alloc_concurrent),
+		test_result(bench_alloc_concurrent())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sem_fifo),
+		test_result(test_sem_fifo())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_copy_to_user_straddle),
+		test_result(test_copy_to_user_straddle())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_interrupt_in_swapgs_window),
+		test_result(test_interrupt_in_swapgs_window())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_rlimit_as),
+		test_result(test_rlimit_as())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sem_timedwait_poll),
+		test_result(test_sem_timedwait_poll())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_eventcount_no_lost_wakeup),
+		test_result(test_eventcount_no_lost_wakeup())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_meminfo),
+		test_result(test_meminfo())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_reserve_virtual),
+		test_result(test_reserve_virtual())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_page_table_memory),
+		test_result(test_page_table_memory())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_isolation_fault_handler),
+		test_result(test_isolation_fault_handler())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_batch_user_slice),
+		test_result(test_batch_user_slice())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sched_migrate),
+		test_result(test_sched_migrate())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_mmap),
+		test_result(test_mmap())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_page_fault_handler),
+		test_result(test_page_fault_handler())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_check_invariants),
+		test_result(test_check_invariants())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_clock_gettime),
+		test_result(test_clock_gettime())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(security_evaluation_user_isolation),
+		test_result(security_evaluation_user_isolation())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sched_yield),
+		test_result(test_sched_yield())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_set_log_level),
+		test_result(test_set_log_level())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_sem_bad_pointer),
+		test_result(test_sem_bad_pointer())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_nanosleep),
+		test_result(test_nanosleep())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_app_heaps),
+		test_result(test_app_heaps())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_pkru_stats),
+		test_result(test_pkru_stats())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(test_madvise),
+		test_result(test_madvise())
+	);
+	println!(
+		"Test {} ... {}",
+		stringify!(bench_isolation_sections),
+		test_result(bench_isolation_sections())
+	);
 	println!(
 		"Test {} ... {}",
 		stringify!(test_http_request),
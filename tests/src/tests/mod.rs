@@ -6,6 +6,8 @@ use std::fs::File;
 use std::io::Read;
 use std::io::Write;
 use std::net::TcpStream;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::Arc;
 use std::thread;
 use std::time::Instant;
 use std::vec;
@@ -366,3 +368,1402 @@ pub fn test_pkru_context_switch() -> Result<(), ()> {
 	Ok(())
 }
 
+extern "C" {
+	fn sys_sched_boost(target: u32, is_prio: bool) -> i32;
+}
+
+/// Priority of threads, which are created by the standard library
+const NORMAL_PRIO: u32 = 2;
+
+fn producer_consumer_throughput(boost: bool) -> f64 {
+	let n = 100000;
+	let buffered = Arc::new(AtomicUsize::new(0));
+	let done = Arc::new(AtomicBool::new(false));
+
+	let consumer = {
+		let buffered = buffered.clone();
+		let done = done.clone();
+		thread::spawn(move || {
+			while !done.load(Ordering::SeqCst) || buffered.load(Ordering::SeqCst) > 0 {
+				if buffered.load(Ordering::SeqCst) > 0 {
+					buffered.fetch_sub(1, Ordering::SeqCst);
+				} else {
+					thread::yield_now();
+				}
+			}
+		})
+	};
+
+	let now = Instant::now();
+	for _ in 0..n {
+		buffered.fetch_add(1, Ordering::SeqCst);
+		if boost {
+			unsafe {
+				sys_sched_boost(NORMAL_PRIO, true);
+			}
+		} else {
+			thread::yield_now();
+		}
+	}
+	done.store(true, Ordering::SeqCst);
+	consumer.join().unwrap();
+
+	n as f64 / now.elapsed().as_secs_f64()
+}
+
+pub fn bench_sched_boost() -> Result<(), ()> {
+	let plain = producer_consumer_throughput(false);
+	let boosted = producer_consumer_throughput(true);
+
+	println!(
+		"Producer/consumer throughput: {} items/s (yield), {} items/s (boost)",
+		plain, boosted
+	);
+
+	Ok(())
+}
+
+pub fn test_sched_boost() -> Result<(), ()> {
+	let core = unsafe { sys_get_core_id() };
+	let stop = Arc::new(AtomicBool::new(false));
+	let recording = Arc::new(AtomicBool::new(false));
+	// index of the first worker, which has run after recording has been enabled
+	let first = Arc::new(AtomicUsize::new(usize::MAX));
+
+	let workers: Vec<_> = (0..2)
+		.map(|i| {
+			let tid = Arc::new(AtomicUsize::new(usize::MAX));
+			let worker_core = Arc::new(AtomicUsize::new(usize::MAX));
+			let handle = {
+				let tid = tid.clone();
+				let worker_core = worker_core.clone();
+				let stop = stop.clone();
+				let recording = recording.clone();
+				let first = first.clone();
+				thread::spawn(move || {
+					tid.store(unsafe { sys_getpid() } as usize, Ordering::SeqCst);
+					while !stop.load(Ordering::SeqCst) {
+						worker_core.store(unsafe { sys_get_core_id() }, Ordering::SeqCst);
+						if recording.load(Ordering::SeqCst) {
+							let _ = first.compare_exchange(usize::MAX, i, Ordering::SeqCst, Ordering::SeqCst);
+						}
+						thread::yield_now();
+					}
+				})
+			};
+			(handle, tid, worker_core)
+		})
+		.collect();
+
+	// move both workers to our core, so that they compete with us for the CPU
+	let mut result = Ok(());
+	for (_, tid, worker_core) in workers.iter() {
+		while tid.load(Ordering::SeqCst) == usize::MAX {
+			thread::yield_now();
+		}
+		if unsafe { sys_sched_migrate(tid.load(Ordering::SeqCst) as u32, core as u32) } != 0 {
+			result = Err(());
+		}
+		let start = Instant::now();
+		while worker_core.load(Ordering::SeqCst) != core && start.elapsed().as_secs() < 1 {
+			thread::yield_now();
+		}
+		if worker_core.load(Ordering::SeqCst) != core {
+			result = Err(());
+		}
+	}
+
+	if result.is_ok() {
+		// Start with a fresh time slice, so that the timer doesn't preempt us before the boost.
+		thread::yield_now();
+		recording.store(true, Ordering::SeqCst);
+
+		// The second worker has to run next, although the first one may be ahead of it in the ready queue.
+		let target = workers[1].1.load(Ordering::SeqCst) as u32;
+		if unsafe { sys_sched_boost(target, false) } != 0 || first.load(Ordering::SeqCst) != 1 {
+			result = Err(());
+		}
+	}
+
+	stop.store(true, Ordering::SeqCst);
+	for (handle, _, _) in workers {
+		handle.join().unwrap();
+	}
+
+	result
+}
+
+extern "C" {
+	fn sys_sched_checkpoint();
+}
+
+/// Runs `nthreads` threads, which compute chunks of pi, and returns the average and the
+/// maximum time per chunk in ticks.
+fn pi_chunk_times(nthreads: usize, checkpoints: bool) -> (u64, u64) {
+	let chunks = 200;
+	let steps = 50_000;
+
+	let threads: Vec<_> = (0..nthreads)
+		.map(|_| {
+			thread::spawn(move || {
+				let step = 1.0 / (chunks * steps) as f64;
+				let mut sum = 0.0 as f64;
+				let mut total = 0;
+				let mut max = 0;
+
+				for chunk in 0..chunks {
+					let start = get_timestamp_rdtscp();
+					for i in chunk * steps..(chunk + 1) * steps {
+						let x = (i as f64 + 0.5) * step;
+						sum += 4.0 / (1.0 + x * x);
+					}
+					let ticks = get_timestamp_rdtscp() - start;
+					total += ticks;
+					max = max.max(ticks);
+
+					if checkpoints {
+						unsafe {
+							sys_sched_checkpoint();
+						}
+					}
+				}
+
+				let _ = unsafe { std::ptr::read_volatile(&sum) };
+				(total / chunks as u64, max)
+			})
+		})
+		.collect();
+
+	let mut avg = 0;
+	let mut max = 0;
+	for t in threads {
+		let (thread_avg, thread_max) = t.join().unwrap();
+		avg += thread_avg;
+		max = max.max(thread_max);
+	}
+
+	(avg / nthreads as u64, max)
+}
+
+pub fn bench_sched_checkpoint() -> Result<(), ()> {
+	let nthreads = 4;
+	let (avg, max) = pi_chunk_times(nthreads, false);
+	println!("Pi chunk time (preemption): avg {} ticks, max {} ticks", avg, max);
+
+	let (avg, max) = pi_chunk_times(nthreads, true);
+	println!("Pi chunk time (checkpoints): avg {} ticks, max {} ticks", avg, max);
+
+	Ok(())
+}
+
+extern "C" {
+	fn sys_sem_init(sem: *mut *const u8, value: u32) -> i32;
+	fn sys_sem_trywait(sem: *const u8) -> i32;
+	fn sys_sem_post(sem: *const u8) -> i32;
+	fn sys_sem_destroy(sem: *const u8) -> i32;
+}
+
+pub fn test_sem_init_concurrent() -> Result<(), ()> {
+	let nthreads = 8;
+	let iterations = 1000;
+	let mut children = vec::Vec::new();
+
+	for id in 0..nthreads {
+		children.push(thread::spawn(move || -> Result<(), ()> {
+			// every semaphore gets a thread-specific initial value,
+			// which reveals staged data from other cores
+			let value = (id + 1) as u32;
+
+			for _ in 0..iterations {
+				let mut sem: *const u8 = std::ptr::null();
+				if unsafe { sys_sem_init(&mut sem, value) } != 0 || sem.is_null() {
+					return Err(());
+				}
+
+				let drained = (0..value).all(|_| unsafe { sys_sem_trywait(sem) } == 0);
+				let empty = drained && unsafe { sys_sem_trywait(sem) } != 0;
+
+				unsafe {
+					sys_sem_post(sem);
+				}
+				if unsafe { sys_sem_destroy(sem) } != 0 || !empty {
+					return Err(());
+				}
+			}
+
+			Ok(())
+		}));
+	}
+
+	let mut result = Ok(());
+	for child in children {
+		if child.join().unwrap().is_err() {
+			result = Err(());
+		}
+	}
+
+	result
+}
+
+extern "C" {
+	fn sys_swap_pages(first: usize, second: usize) -> i32;
+}
+
+pub fn test_swap_pages() -> Result<(), ()> {
+	// the user heap is mapped with 2 MiB pages
+	let page_size = 2 * 1024 * 1024;
+	let layout = std::alloc::Layout::from_size_align(page_size, page_size).unwrap();
+	let (front, back) = unsafe { (std::alloc::alloc(layout), std::alloc::alloc(layout)) };
+	if front.is_null() || back.is_null() {
+		return Err(());
+	}
+
+	// a page of the safe domain can't be pulled into the user domain
+	const EINVAL: i32 = 22;
+	if unsafe { sys_swap_pages(front as usize, 0x400000) } != -EINVAL {
+		return Err(());
+	}
+
+	unsafe {
+		*(front as *mut u64) = 0xAAAA_AAAA_AAAA_AAAA;
+		*(back as *mut u64) = 0x5555_5555_5555_5555;
+	}
+
+	let done = Arc::new(AtomicBool::new(false));
+	let reader = {
+		let done = done.clone();
+		let front = front as usize;
+		thread::spawn(move || -> Result<(), ()> {
+			while !done.load(Ordering::SeqCst) {
+				let value = unsafe { std::ptr::read_volatile(front as *const u64) };
+				if value != 0xAAAA_AAAA_AAAA_AAAA && value != 0x5555_5555_5555_5555 {
+					return Err(());
+				}
+			}
+
+			Ok(())
+		})
+	};
+
+	let mut result = Ok(());
+	for _ in 0..10000 {
+		if unsafe { sys_swap_pages(front as usize, back as usize) } != 0 {
+			result = Err(());
+			break;
+		}
+	}
+	done.store(true, Ordering::SeqCst);
+
+	if reader.join().unwrap().is_err() {
+		result = Err(());
+	}
+
+	unsafe {
+		std::alloc::dealloc(front, layout);
+		std::alloc::dealloc(back, layout);
+	}
+
+	result
+}
+
+extern "C" {
+	fn sys_get_processor_count() -> usize;
+}
+
+pub fn bench_alloc_concurrent() -> Result<(), ()> {
+	let n = 100000;
+	let nthreads = unsafe { sys_get_processor_count() };
+	let mut ticks = [0u64; 2];
+
+	for (threads, ticks) in [1, nthreads].iter().zip(ticks.iter_mut()) {
+		let start = get_timestamp_rdtscp();
+		let children: Vec<_> = (0..*threads)
+			.map(|_| {
+				thread::spawn(move || {
+					for i in 0..n {
+						let data = Box::new([i as u8; 48]);
+						let _ = unsafe { std::ptr::read_volatile(&data[0]) };
+					}
+				})
+			})
+			.collect();
+
+		for child in children {
+			child.join().unwrap();
+		}
+
+		*ticks = get_timestamp_rdtscp() - start;
+		println!(
+			"Allocation time {} ticks ({} threads)",
+			*ticks / (n as u64),
+			threads
+		);
+	}
+
+	// The threads allocate from the arenas of their cores, so running them in parallel
+	// must not take longer than running them one after another.
+	if ticks[1] <= ticks[0] * nthreads as u64 {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+extern "C" {
+	fn sys_sem_timedwait(sem: *const u8, ms: u32) -> i32;
+	fn sys_sem_wait(sem: *const u8) -> i32;
+	fn sys_msleep(ms: u32);
+}
+
+pub fn test_sem_fifo() -> Result<(), ()> {
+	let nthreads = 4;
+	let mut sem: *const u8 = std::ptr::null();
+	if unsafe { sys_sem_init(&mut sem, 0) } != 0 {
+		return Err(());
+	}
+
+	let sem = sem as usize;
+	let order = Arc::new(std::sync::Mutex::new(Vec::new()));
+	let mut children = Vec::new();
+
+	for id in 0..nthreads {
+		let order = order.clone();
+		children.push(thread::spawn(move || {
+			unsafe {
+				sys_sem_wait(sem as *const u8);
+			}
+			order.lock().unwrap().push(id);
+		}));
+
+		// give the waiter time to block before the next one arrives
+		unsafe {
+			sys_msleep(100);
+		}
+	}
+
+	// release the waiters one by one and wait until the awakened one has reported
+	for released in 1..=nthreads {
+		unsafe {
+			sys_sem_post(sem as *const u8);
+		}
+		while order.lock().unwrap().len() < released {
+			thread::yield_now();
+		}
+	}
+
+	for child in children {
+		child.join().unwrap();
+	}
+
+	if *order.lock().unwrap() == (0..nthreads).collect::<Vec<_>>() {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+extern "C" {
+	fn sys_clock_gettime(clock_id: u64, tp: *mut u8) -> i32;
+}
+
+pub fn test_clock_gettime() -> Result<(), ()> {
+	const CLOCK_MONOTONIC: u64 = 4;
+	const EINVAL: i32 = 22;
+
+	let mut before = [0i64; 2];
+	let mut after = [0i64; 2];
+	if unsafe { sys_clock_gettime(CLOCK_MONOTONIC, before.as_mut_ptr() as *mut u8) } != 0 {
+		return Err(());
+	}
+	unsafe { sys_msleep(10) };
+	if unsafe { sys_clock_gettime(CLOCK_MONOTONIC, after.as_mut_ptr() as *mut u8) } != 0 {
+		return Err(());
+	}
+
+	let elapsed = (after[0] - before[0]) * 1_000_000_000 + (after[1] - before[1]);
+	println!("clock_gettime: slept for {} ns", elapsed);
+	if after[1] < 0 || after[1] > 999_999_999 || elapsed < 10_000_000 {
+		return Err(());
+	}
+
+	// unsupported clocks and a missing buffer are rejected
+	if unsafe { sys_clock_gettime(42, after.as_mut_ptr() as *mut u8) } != -EINVAL
+		|| unsafe { sys_clock_gettime(CLOCK_MONOTONIC, std::ptr::null_mut()) } != -EINVAL
+	{
+		return Err(());
+	}
+
+	Ok(())
+}
+
+pub fn test_copy_to_user_straddle() -> Result<(), ()> {
+	const CLOCK_MONOTONIC: u64 = 4;
+	const EFAULT: i32 = 14;
+
+	// The first page belongs to the user, the next one to the kernel.
+	// A timespec starting 8 bytes before the page boundary straddles both domains.
+	let tp = (4096 - 8) as *mut u64;
+	let pattern = 0xdead_beef_dead_beefu64;
+	unsafe {
+		core::ptr::write_volatile(tp, pattern);
+	}
+
+	let ret = unsafe { sys_clock_gettime(CLOCK_MONOTONIC, tp as *mut u8) };
+	let untouched = unsafe { core::ptr::read_volatile(tp) } == pattern;
+
+	if ret == -EFAULT && untouched {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn test_interrupt_in_swapgs_window() -> Result<(), ()> {
+	let before: usize;
+	let after: usize;
+
+	// Raise the wakeup interrupt (vector 121) while GS points to the staging buffer.
+	// The handler has to run with the per-core GS base and to restore the swapped one.
+	unsafe {
+		asm!("rdgsbase $0" : "=r"(before) ::: "volatile");
+		asm!("swapgs; int $$121; swapgs" :::: "volatile");
+		asm!("rdgsbase $0" : "=r"(after) ::: "volatile");
+	}
+
+	if before == after {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct rlimit {
+	rlim_cur: u64,
+	rlim_max: u64,
+}
+
+extern "C" {
+	fn sys_getrlimit(resource: i32, rlim: *mut rlimit) -> i32;
+	fn sys_setrlimit(resource: i32, rlim: *const rlimit) -> i32;
+}
+
+pub fn test_rlimit_as() -> Result<(), ()> {
+	const RLIMIT_AS: i32 = 9;
+	const RLIM_INFINITY: u64 = u64::MAX;
+	const LIMIT: u64 = 16 * 1024 * 1024;
+	const EPERM: i32 = 1;
+	const ENOMEM: i32 = 12;
+	const PROT_READ: i32 = 0x1;
+	const PROT_WRITE: i32 = 0x2;
+	const MAP_PRIVATE: i32 = 0x02;
+	const MAP_ANONYMOUS: i32 = 0x20;
+
+	// the limit of a thread doesn't affect the other ones
+	let child = thread::spawn(|| {
+		let limit = rlimit {
+			rlim_cur: LIMIT,
+			rlim_max: LIMIT,
+		};
+		let mut current = rlimit {
+			rlim_cur: 0,
+			rlim_max: 0,
+		};
+
+		let raised = rlimit {
+			rlim_cur: 2 * LIMIT,
+			rlim_max: 2 * LIMIT,
+		};
+		let size = 2 * LIMIT as usize;
+
+		unsafe {
+			sys_setrlimit(RLIMIT_AS, &limit) == 0
+				&& sys_getrlimit(RLIMIT_AS, &mut current) == 0
+				&& current.rlim_cur == LIMIT
+				&& current.rlim_max == LIMIT
+				// the hard limit can't be raised again
+				&& sys_setrlimit(RLIMIT_AS, &raised) == -EPERM
+				// neither a mapping nor a reservation may exceed the limit
+				&& sys_mmap(
+					core::ptr::null_mut(),
+					size,
+					PROT_READ | PROT_WRITE,
+					MAP_ANONYMOUS | MAP_PRIVATE,
+				) as isize == -(ENOMEM as isize)
+				&& sys_reserve_virtual(size, 4096) == 0
+		}
+	});
+	let child_ok = child.join().unwrap_or(false);
+
+	let mut current = rlimit {
+		rlim_cur: 0,
+		rlim_max: 0,
+	};
+	let main_ok =
+		unsafe { sys_getrlimit(RLIMIT_AS, &mut current) } == 0 && current.rlim_cur == RLIM_INFINITY;
+
+	if child_ok && main_ok {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn test_sem_timedwait_poll() -> Result<(), ()> {
+	const ETIME: i32 = 62;
+
+	let mut sem: *const u8 = std::ptr::null();
+	if unsafe { sys_sem_init(&mut sem, 0) } != 0 {
+		return Err(());
+	}
+
+	// a timeout of zero doesn't block
+	if unsafe { sys_sem_timedwait(sem, 0) } != -ETIME {
+		return Err(());
+	}
+
+	unsafe {
+		sys_sem_post(sem);
+	}
+	if unsafe { sys_sem_timedwait(sem, 0) } != 0 {
+		return Err(());
+	}
+
+	unsafe {
+		sys_sem_post(sem);
+	}
+	if unsafe { sys_sem_wait(sem) } != 0 {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+extern "C" {
+	fn sys_eventcount_init(ec: *mut *const u8) -> i32;
+	fn sys_eventcount_destroy(ec: *const u8) -> i32;
+	fn sys_eventcount_prepare_wait(ec: *const u8) -> usize;
+	fn sys_eventcount_wait(ec: *const u8, key: usize) -> i32;
+	fn sys_eventcount_notify(ec: *const u8) -> i32;
+}
+
+pub fn test_eventcount_no_lost_wakeup() -> Result<(), ()> {
+	let rounds = 100;
+	let mut ec: *const u8 = std::ptr::null();
+	if unsafe { sys_eventcount_init(&mut ec) } != 0 {
+		return Err(());
+	}
+
+	// a notification between the check and the wait lets the wait return immediately
+	let key = unsafe { sys_eventcount_prepare_wait(ec) };
+	unsafe {
+		sys_eventcount_notify(ec);
+		sys_eventcount_wait(ec, key);
+	}
+
+	// a producer publishing while the consumer is about to block must not get lost
+	let ec = ec as usize;
+	let published = Arc::new(AtomicUsize::new(0));
+	let consumed = Arc::new(AtomicUsize::new(0));
+	let consumer = {
+		let published = published.clone();
+		let consumed = consumed.clone();
+		thread::spawn(move || {
+			for round in 1..=rounds {
+				loop {
+					let key = unsafe { sys_eventcount_prepare_wait(ec as *const u8) };
+					if published.load(Ordering::SeqCst) >= round {
+						break;
+					}
+					unsafe {
+						sys_eventcount_wait(ec as *const u8, key);
+					}
+				}
+				consumed.store(round, Ordering::SeqCst);
+			}
+		})
+	};
+
+	for round in 1..=rounds {
+		published.store(round, Ordering::SeqCst);
+		unsafe {
+			sys_eventcount_notify(ec as *const u8);
+		}
+		while consumed.load(Ordering::SeqCst) < round {
+			thread::yield_now();
+		}
+	}
+
+	consumer.join().unwrap();
+	unsafe {
+		sys_eventcount_destroy(ec as *const u8);
+	}
+
+	Ok(())
+}
+
+#[repr(C)]
+#[derive(Default)]
+struct MemInfo {
+	total: usize,
+	free: usize,
+	kernel_heap: usize,
+	user_heap: usize,
+	page_tables: usize,
+}
+
+extern "C" {
+	fn sys_meminfo(out: *mut MemInfo) -> i32;
+}
+
+pub fn test_meminfo() -> Result<(), ()> {
+	const EINVAL: i32 = 22;
+
+	if unsafe { sys_meminfo(std::ptr::null_mut()) } != -EINVAL {
+		return Err(());
+	}
+
+	let mut info = MemInfo::default();
+	if unsafe { sys_meminfo(&mut info) } != 0 {
+		return Err(());
+	}
+
+	println!(
+		"total {:#X}, free {:#X}, kernel heap {:#X}, user heap {:#X}",
+		info.total, info.free, info.kernel_heap, info.user_heap
+	);
+
+	if info.total == 0 || info.free > info.total || info.user_heap == 0 || info.kernel_heap == 0 {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+extern "C" {
+	fn sys_check_invariants() -> i32;
+}
+
+pub fn test_check_invariants() -> Result<(), ()> {
+	// the address space has to be consistent after the setup and after mapping new memory
+	if unsafe { sys_check_invariants() } != 0 {
+		return Err(());
+	}
+
+	let buffer = vec![0u8; 4 * 4096];
+	let clean = unsafe { sys_check_invariants() } == 0;
+	drop(buffer);
+
+	if clean {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+extern "C" {
+	fn sys_reserve_virtual(size: usize, alignment: usize) -> usize;
+	fn sys_release_virtual(addr: usize, size: usize) -> i32;
+}
+
+pub fn test_reserve_virtual() -> Result<(), ()> {
+	const EPERM: i32 = 1;
+	const EINVAL: i32 = 22;
+	let size = 4 * 4096;
+
+	let first = unsafe { sys_reserve_virtual(size, 4096) };
+	let second = unsafe { sys_reserve_virtual(size, 4096) };
+	if first == 0 || second == 0 {
+		return Err(());
+	}
+
+	// reservations must not share a page
+	if first < second + size && second < first + size {
+		return Err(());
+	}
+
+	// the first access to the third page maps it, a fresh page contains zeros
+	let ptr = (first + 2 * 4096 + 8) as *mut u64;
+	unsafe {
+		if std::ptr::read_volatile(ptr) != 0 {
+			return Err(());
+		}
+		std::ptr::write_volatile(ptr, 0xdead_beef);
+		if std::ptr::read_volatile(ptr) != 0xdead_beef {
+			return Err(());
+		}
+	}
+
+	// only the task, which has reserved the range, may release it
+	let foreign = thread::spawn(move || unsafe { sys_release_virtual(second, size) });
+	if foreign.join().unwrap_or(0) != -EPERM {
+		return Err(());
+	}
+
+	if unsafe { sys_release_virtual(first, size) } != 0 {
+		return Err(());
+	}
+	if unsafe { sys_release_virtual(second, size) } != 0 {
+		return Err(());
+	}
+
+	// a released range can't be released twice
+	if unsafe { sys_release_virtual(first, size) } != -EINVAL {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+#[repr(C)]
+struct BatchOp {
+	op: u32,
+	arg0: usize,
+	arg1: usize,
+}
+
+extern "C" {
+	fn sys_batch(ops: *const BatchOp, results: *mut i64, n: usize) -> i32;
+}
+
+pub fn bench_batch_sem_post() -> Result<(), ()> {
+	const BATCH_SEM_POST: u32 = 1;
+	let n = 100;
+
+	let mut sem: *const u8 = std::ptr::null();
+	if unsafe { sys_sem_init(&mut sem, 0) } != 0 {
+		return Err(());
+	}
+
+	// cache warmup
+	unsafe {
+		sys_sem_post(sem);
+	}
+
+	let start = get_timestamp_rdtscp();
+	for _ in 0..n {
+		unsafe {
+			sys_sem_post(sem);
+		}
+	}
+	let single = get_timestamp_rdtscp() - start;
+
+	let ops: Vec<BatchOp> = (0..n)
+		.map(|_| BatchOp {
+			op: BATCH_SEM_POST,
+			arg0: sem as usize,
+			arg1: 0,
+		})
+		.collect();
+	let mut results = vec![-1i64; n];
+
+	let start = get_timestamp_rdtscp();
+	let executed = unsafe { sys_batch(ops.as_ptr(), results.as_mut_ptr(), n) };
+	let batched = get_timestamp_rdtscp() - start;
+
+	println!(
+		"{} sem_post: {} ticks (single syscalls), {} ticks (batch)",
+		n, single, batched
+	);
+
+	// all posts of both runs have been applied
+	let mut count = 0;
+	while unsafe { sys_sem_trywait(sem) } == 0 {
+		count += 1;
+	}
+
+	if executed == n as i32 && results.iter().all(|r| *r == 0) && count == 2 * n + 1 && batched <= single {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn bench_isolation_sections() -> Result<(), ()> {
+	extern "C" {
+		fn sys_getpid() -> u32;
+	}
+	let n = 100_000;
+
+	let mut sem: *const u8 = std::ptr::null();
+	if unsafe { sys_sem_init(&mut sem, 0) } != 0 {
+		return Err(());
+	}
+
+	// cache warmup
+	unsafe {
+		sys_getpid();
+		sys_sem_post(sem);
+	}
+
+	// sys_getpid doesn't contain an isolation section, sys_sem_post accesses the semaphore within one
+	let start = get_timestamp_rdtscp();
+	for _ in 0..n {
+		unsafe {
+			sys_getpid();
+		}
+	}
+	let getpid = (get_timestamp_rdtscp() - start) / n;
+
+	let start = get_timestamp_rdtscp();
+	for _ in 0..n {
+		unsafe {
+			sys_sem_post(sem);
+		}
+	}
+	let sem_post = (get_timestamp_rdtscp() - start) / n;
+
+	println!(
+		"sys_getpid: {} ticks, sys_sem_post: {} ticks, isolation section and semaphore: {} ticks",
+		getpid,
+		sem_post,
+		sem_post.saturating_sub(getpid)
+	);
+
+	// all posts have been applied
+	let mut count = 0;
+	while unsafe { sys_sem_trywait(sem) } == 0 {
+		count += 1;
+	}
+
+	if count == n + 1 {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+fn page_table_memory() -> Result<usize, ()> {
+	let mut info = MemInfo::default();
+	if unsafe { sys_meminfo(&mut info) } != 0 {
+		return Err(());
+	}
+
+	Ok(info.page_tables)
+}
+
+pub fn test_page_table_memory() -> Result<(), ()> {
+	// a 2 MiB aligned reservation doesn't share its page table with other mappings
+	let size = 2 * 1024 * 1024;
+	let addr = unsafe { sys_reserve_virtual(size, size) };
+	if addr == 0 {
+		return Err(());
+	}
+
+	let before = page_table_memory()?;
+	unsafe {
+		std::ptr::write_volatile((addr + size / 2) as *mut u8, 1);
+	}
+	let mapped = page_table_memory()?;
+
+	if unsafe { sys_release_virtual(addr, size) } != 0 {
+		return Err(());
+	}
+	let released = page_table_memory()?;
+
+	println!(
+		"page tables: {:#X} bytes before, {:#X} mapped, {:#X} released",
+		before, mapped, released
+	);
+
+	if mapped > before && released < mapped {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+extern "C" {
+	fn sys_sigaction(signum: i32, handler: Option<extern "C" fn(i32)>) -> i32;
+}
+
+static FAULT_HANDLED: AtomicBool = AtomicBool::new(false);
+static FAULT_RESUMED: AtomicBool = AtomicBool::new(false);
+
+/// PKRU of the user domain and the same PKRU with access to the safe domain (protection key 1)
+const USER_PKRU: u32 = 0x3fc;
+const SAFE_PKRU: u32 = USER_PKRU & !0xc;
+
+fn write_pkru(pkru: u32) {
+	unsafe {
+		asm!("xor %ecx, %ecx; xor %edx, %edx; wrpkru; lfence"
+			:
+			: "{eax}"(pkru)
+			: "ecx", "edx"
+			: "volatile");
+	}
+}
+
+extern "C" fn isolation_fault_handler(signum: i32) {
+	if signum == 11 {
+		FAULT_HANDLED.store(true, Ordering::SeqCst);
+		// remove the cause of the fault, so that the access succeeds once the handler returns
+		write_pkru(SAFE_PKRU);
+	}
+}
+
+fn read_safe_data() {
+	// first byte of the .safe_data section, which belongs to the safe domain
+	let safe = 0x400000 as *const u8;
+	unsafe {
+		core::ptr::read_volatile(safe);
+	}
+	write_pkru(USER_PKRU);
+	FAULT_RESUMED.store(true, Ordering::SeqCst);
+}
+
+pub fn test_isolation_fault_handler() -> Result<(), ()> {
+	const SIGSEGV: i32 = 11;
+
+	if unsafe { sys_sigaction(SIGSEGV + 1, Some(isolation_fault_handler)) } >= 0 {
+		return Err(());
+	}
+
+	// the handler is invoked and the thread resumes at the faulting instruction after the handler returns
+	let child = thread::spawn(|| {
+		if unsafe { sys_sigaction(SIGSEGV, Some(isolation_fault_handler)) } != 0 {
+			return;
+		}
+		read_safe_data();
+	});
+	let _ = child.join();
+
+	if !FAULT_HANDLED.swap(false, Ordering::SeqCst) || !FAULT_RESUMED.swap(false, Ordering::SeqCst) {
+		return Err(());
+	}
+
+	// without a handler, the thread is aborted
+	let child = thread::spawn(read_safe_data);
+	let _ = child.join();
+
+	if FAULT_HANDLED.load(Ordering::SeqCst) || FAULT_RESUMED.load(Ordering::SeqCst) {
+		Err(())
+	} else {
+		Ok(())
+	}
+}
+
+pub fn test_batch_user_slice() -> Result<(), ()> {
+	const BATCH_GETPID: u32 = 0;
+	const EFAULT: i32 = 14;
+
+	let ops = [
+		BatchOp {
+			op: BATCH_GETPID,
+			arg0: 0,
+			arg1: 0,
+		},
+		BatchOp {
+			op: BATCH_GETPID,
+			arg0: 0,
+			arg1: 0,
+		},
+	];
+	let mut results = [0i64; 2];
+
+	if unsafe { sys_batch(ops.as_ptr(), results.as_mut_ptr(), ops.len()) } != 2 {
+		return Err(());
+	}
+
+	// a released reservation is unmapped
+	let reserved = unsafe { sys_reserve_virtual(4096, 4096) };
+	if reserved == 0 || unsafe { sys_release_virtual(reserved, 4096) } != 0 {
+		return Err(());
+	}
+
+	// operations in the safe domain, unaligned or unmapped operations are rejected
+	let safe = 0x400000 as *const BatchOp;
+	let unaligned = (ops.as_ptr() as usize + 1) as *const BatchOp;
+	let unmapped = reserved as *const BatchOp;
+	for invalid in &[safe, unaligned, unmapped] {
+		if unsafe { sys_batch(*invalid, results.as_mut_ptr(), 1) } != -EFAULT {
+			return Err(());
+		}
+	}
+
+	Ok(())
+}
+
+extern "C" {
+	fn sys_getpid() -> u32;
+	fn sys_get_core_id() -> usize;
+	fn sys_sched_migrate(id: u32, core_id: u32) -> i32;
+}
+
+pub fn test_sched_migrate() -> Result<(), ()> {
+	const EINVAL: i32 = 22;
+	let ncores = unsafe { sys_get_processor_count() };
+
+	if unsafe { sys_sched_migrate(sys_getpid(), ncores as u32) } != -EINVAL {
+		return Err(());
+	}
+	if ncores < 2 {
+		return Ok(());
+	}
+
+	let tid = Arc::new(AtomicUsize::new(usize::MAX));
+	let core = Arc::new(AtomicUsize::new(usize::MAX));
+	let stop = Arc::new(AtomicBool::new(false));
+	let worker = {
+		let tid = tid.clone();
+		let core = core.clone();
+		let stop = stop.clone();
+		thread::spawn(move || {
+			tid.store(unsafe { sys_getpid() } as usize, Ordering::SeqCst);
+			while !stop.load(Ordering::SeqCst) {
+				core.store(unsafe { sys_get_core_id() }, Ordering::SeqCst);
+				thread::yield_now();
+			}
+		})
+	};
+
+	while tid.load(Ordering::SeqCst) == usize::MAX || core.load(Ordering::SeqCst) == usize::MAX {
+		thread::yield_now();
+	}
+
+	let target = (core.load(Ordering::SeqCst) + 1) % ncores;
+	if unsafe { sys_sched_migrate(tid.load(Ordering::SeqCst) as u32, target as u32) } != 0 {
+		return Err(());
+	}
+
+	// the worker is moved at its next yield
+	let start = Instant::now();
+	while core.load(Ordering::SeqCst) != target && start.elapsed().as_secs() < 1 {
+		thread::yield_now();
+	}
+	let migrated = core.load(Ordering::SeqCst) == target;
+
+	stop.store(true, Ordering::SeqCst);
+	worker.join().unwrap();
+
+	if migrated {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+extern "C" {
+	fn sys_mmap(addr: *mut u8, len: usize, prot: i32, flags: i32) -> *mut u8;
+	fn sys_munmap(addr: *mut u8, len: usize) -> i32;
+}
+
+pub fn test_mmap() -> Result<(), ()> {
+	const EINVAL: i32 = 22;
+	const PROT_READ: i32 = 0x1;
+	const PROT_WRITE: i32 = 0x2;
+	const MAP_PRIVATE: i32 = 0x02;
+	const MAP_FIXED: i32 = 0x10;
+	const MAP_ANONYMOUS: i32 = 0x20;
+	let prot = PROT_READ | PROT_WRITE;
+	let flags = MAP_ANONYMOUS | MAP_PRIVATE;
+	let size = 2 * 4096;
+
+	// the length has to be a non-zero multiple of the page size
+	if unsafe { sys_mmap(std::ptr::null_mut(), 100, prot, flags) } as isize != -EINVAL as isize {
+		return Err(());
+	}
+
+	let addr = unsafe { sys_mmap(std::ptr::null_mut(), size, prot, flags) };
+	if (addr as isize) < 0 || addr.is_null() {
+		return Err(());
+	}
+	unsafe {
+		if std::ptr::read_volatile(addr.add(4096)) != 0 {
+			return Err(());
+		}
+		std::ptr::write_volatile(addr.add(4096), 0xAA);
+	}
+
+	// a fixed mapping must not overlap an existing one
+	if unsafe { sys_mmap(addr, size, prot, flags | MAP_FIXED) } as isize != -EINVAL as isize {
+		return Err(());
+	}
+
+	// only whole mappings can be unmapped
+	if unsafe { sys_munmap(addr, 4096) } != -EINVAL {
+		return Err(());
+	}
+	if unsafe { sys_munmap(addr, size) } != 0 {
+		return Err(());
+	}
+	if unsafe { sys_munmap(addr, size) } != -EINVAL {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+extern "C" {
+	fn sys_set_fault_handler(id: u32, handler: usize) -> i32;
+}
+
+static FAULT_ADDRESS: AtomicUsize = AtomicUsize::new(0);
+static FAULT_ERROR: AtomicUsize = AtomicUsize::new(0);
+
+extern "C" fn page_fault_handler(fault_address: usize, error: u32) {
+	FAULT_ERROR.store(error as usize, Ordering::SeqCst);
+	FAULT_ADDRESS.store(fault_address, Ordering::SeqCst);
+}
+
+pub fn test_page_fault_handler() -> Result<(), ()> {
+	const EPERM: i32 = 1;
+	// the fault was caused by a write (WR)
+	const WR: usize = 1 << 1;
+
+	// a task can't redirect the faults of another task
+	if unsafe { sys_set_fault_handler(u32::MAX, page_fault_handler as usize) } != -EPERM {
+		return Err(());
+	}
+	let parent = unsafe { sys_getpid() };
+	let child = thread::spawn(move || unsafe { sys_set_fault_handler(parent, page_fault_handler as usize) });
+	if child.join().unwrap_or(0) != -EPERM {
+		return Err(());
+	}
+
+	// the handler receives the fault address and the error code, the thread ends after the handler returns
+	let unmapped = Arc::new(AtomicUsize::new(0));
+	let address = unmapped.clone();
+	let child = thread::spawn(move || {
+		if unsafe { sys_set_fault_handler(sys_getpid(), page_fault_handler as usize) } != 0 {
+			return;
+		}
+
+		// a released reservation is unmapped and isn't backed on demand anymore
+		let addr = unsafe { sys_reserve_virtual(4096, 4096) };
+		if addr == 0 || unsafe { sys_release_virtual(addr, 4096) } != 0 {
+			return;
+		}
+		address.store(addr, Ordering::SeqCst);
+		unsafe {
+			std::ptr::write_volatile(addr as *mut u64, 42);
+		}
+	});
+	let _ = child.join();
+
+	let unmapped = unmapped.load(Ordering::SeqCst);
+	if unmapped != 0
+		&& FAULT_ADDRESS.load(Ordering::SeqCst) == unmapped
+		&& FAULT_ERROR.load(Ordering::SeqCst) & WR != 0
+	{
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn test_sched_yield() -> Result<(), ()> {
+	extern "C" {
+		fn sys_sched_yield() -> i32;
+	}
+
+	let done = Arc::new(AtomicBool::new(false));
+	let flag = done.clone();
+	let child = thread::spawn(move || {
+		flag.store(true, Ordering::SeqCst);
+	});
+
+	// wait for the child without burning the core
+	while !done.load(Ordering::SeqCst) {
+		if unsafe { sys_sched_yield() } != 0 {
+			return Err(());
+		}
+	}
+
+	child.join().map_err(|_| ())
+}
+
+pub fn test_set_log_level() -> Result<(), ()> {
+	const EINVAL: i32 = 22;
+
+	extern "C" {
+		fn sys_set_log_level(level: u32) -> i32;
+	}
+
+	// silence the kernel, then restore the default level (info)
+	let ret = unsafe { (sys_set_log_level(0), sys_set_log_level(6), sys_set_log_level(3)) };
+	if ret == (0, -EINVAL, 0) {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn test_sem_bad_pointer() -> Result<(), ()> {
+	const EINVAL: i32 = 22;
+
+	extern "C" {
+		fn sys_sem_post(sem: *const u8) -> i32;
+		fn sys_sem_trywait(sem: *const u8) -> i32;
+		fn sys_sem_destroy(sem: *const u8) -> i32;
+	}
+
+	// a mapped user buffer isn't a semaphore, only the handles of sys_sem_init are accepted
+	let bogus = Box::new([0u8; 64]);
+	let ptr = bogus.as_ptr();
+	let ret = unsafe { (sys_sem_post(ptr), sys_sem_trywait(ptr), sys_sem_destroy(ptr)) };
+	if ret != (-EINVAL, -EINVAL, -EINVAL) {
+		return Err(());
+	}
+
+	// a destroyed semaphore can't be used or destroyed again
+	let mut sem: *const u8 = std::ptr::null();
+	if unsafe { sys_sem_init(&mut sem, 1) } != 0 || unsafe { sys_sem_destroy(sem) } != 0 {
+		return Err(());
+	}
+	let ret = unsafe { (sys_sem_post(sem), sys_sem_destroy(sem)) };
+	if ret == (-EINVAL, -EINVAL) {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn test_nanosleep() -> Result<(), ()> {
+	const CLOCK_MONOTONIC: u64 = 4;
+	const EINVAL: i32 = 22;
+
+	extern "C" {
+		fn sys_nanosleep(rqtp: *const u8, rmtp: *mut u8) -> i32;
+	}
+
+	let request = [0i64, 20_000_000];
+	let mut remaining = [0i64; 2];
+	let mut before = [0i64; 2];
+	let mut after = [0i64; 2];
+	unsafe {
+		sys_clock_gettime(CLOCK_MONOTONIC, before.as_mut_ptr() as *mut u8);
+		if sys_nanosleep(request.as_ptr() as *const u8, remaining.as_mut_ptr() as *mut u8) != 0 {
+			return Err(());
+		}
+		sys_clock_gettime(CLOCK_MONOTONIC, after.as_mut_ptr() as *mut u8);
+	}
+
+	let elapsed = (after[0] - before[0]) * 1_000_000_000 + (after[1] - before[1]);
+	println!("nanosleep: slept for {} ns", elapsed);
+	if elapsed < request[1] {
+		return Err(());
+	}
+
+	// negative nanoseconds are rejected
+	let invalid = [0i64, -1];
+	if unsafe { sys_nanosleep(invalid.as_ptr() as *const u8, std::ptr::null_mut()) } != -EINVAL {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+pub fn test_app_heaps() -> Result<(), ()> {
+	extern "C" {
+		fn sys_heap_create(size: usize, key: u8) -> i32;
+		fn sys_heap_alloc(handle: i32, size: usize, align: usize) -> *mut u8;
+		fn sys_heap_free(handle: i32, ptr: *mut u8, size: usize, align: usize) -> i32;
+		fn sys_heap_destroy(handle: i32) -> i32;
+	}
+
+	let first = unsafe { sys_heap_create(0x10000, 0) };
+	let second = unsafe { sys_heap_create(0x10000, 0) };
+	if first < 0 || second < 0 || first == second {
+		return Err(());
+	}
+
+	// both heaps hand out memory of their own regions
+	let a = unsafe { sys_heap_alloc(first, 64, 8) };
+	let b = unsafe { sys_heap_alloc(second, 64, 8) };
+	if a.is_null() || b.is_null() || (a as usize).max(b as usize) - (a as usize).min(b as usize) < 0x10000 {
+		return Err(());
+	}
+	unsafe {
+		core::ptr::write_bytes(a, 0xAA, 64);
+		core::ptr::write_bytes(b, 0x55, 64);
+	}
+
+	// a block can't be returned to another heap
+	if unsafe { sys_heap_free(second, a, 64, 8) } == 0 || unsafe { sys_heap_free(first, a, 64, 8) } != 0 {
+		return Err(());
+	}
+
+	if unsafe { sys_heap_destroy(first) } != 0 || unsafe { sys_heap_destroy(second) } != 0 {
+		return Err(());
+	}
+
+	// the handles are invalid afterwards
+	if !unsafe { sys_heap_alloc(first, 64, 8) }.is_null() || unsafe { sys_heap_destroy(first) } == 0 {
+		return Err(());
+	}
+
+	Ok(())
+}
+
+pub fn test_pkru_stats() -> Result<(), ()> {
+	extern "C" {
+		fn sys_pkru_stats() -> u64;
+	}
+
+	// The kernel of the tests counts the PKRU writes (feature count-pkru-writes, see the test-kernel target).
+	// Each system call switches the PKRU twice.
+	let before = unsafe { sys_pkru_stats() };
+	let after = unsafe { sys_pkru_stats() };
+	println!("pkru_stats: {} PKRU writes on the current core", after);
+
+	if before > 0 && after >= before + 2 {
+		Ok(())
+	} else {
+		Err(())
+	}
+}
+
+pub fn test_madvise() -> Result<(), ()> {
+	extern "C" {
+		fn sys_madvise(addr: *mut u8, len: usize, advice: i32) -> i32;
+	}
+
+	const EINVAL: i32 = 22;
+	const MADV_WILLNEED: i32 = 3;
+	const MADV_DONTNEED: i32 = 4;
+	let page_size = 4096;
+	let layout = std::alloc::Layout::from_size_align(2 * page_size, page_size).unwrap();
+	let buffer = unsafe { std::alloc::alloc(layout) };
+	if buffer.is_null() {
+		return Err(());
+	}
+
+	unsafe {
+		std::ptr::write_bytes(buffer, 0xAA, 2 * page_size);
+	}
+
+	// the range has to be page-aligned and lie within the user heap
+	let stack = 0u64;
+	let rejected = unsafe { sys_madvise(buffer.add(1), page_size, MADV_DONTNEED) } == -EINVAL
+		&& unsafe { sys_madvise(buffer, page_size + 1, MADV_DONTNEED) } == -EINVAL
+		&& unsafe { sys_madvise(&stack as *const u64 as *mut u8, page_size, MADV_DONTNEED) } == -EINVAL
+		&& unsafe { sys_madvise(buffer, page_size, 42) } == -EINVAL;
+
+	// the discarded page reads as zeros, the other one keeps its content
+	let discarded = unsafe { sys_madvise(buffer, page_size, MADV_DONTNEED) } == 0
+		&& unsafe { sys_madvise(buffer, page_size, MADV_WILLNEED) } == 0
+		&& unsafe { std::ptr::read_volatile(buffer) } == 0
+		&& unsafe { std::ptr::read_volatile(buffer.add(page_size - 1)) } == 0
+		&& unsafe { std::ptr::read_volatile(buffer.add(page_size)) } == 0xAA;
+
+	// a discarded page is mapped again by the first access, a later WILLNEED finds it mapped
+	let faulted_in = unsafe { sys_madvise(buffer.add(page_size), page_size, MADV_DONTNEED) } == 0
+		&& unsafe { std::ptr::read_volatile(buffer.add(page_size)) } == 0
+		&& unsafe { sys_madvise(buffer.add(page_size), page_size, MADV_WILLNEED) } == 0
+		&& unsafe {
+			std::ptr::write_volatile(buffer.add(page_size), 0x55);
+			std::ptr::read_volatile(buffer.add(page_size))
+		} == 0x55;
+
+	unsafe {
+		std::alloc::dealloc(buffer, layout);
+	}
+
+	if rejected && discarded && faulted_in {
+		Ok(())
+	} else {
+		Err(())
+	}
+}